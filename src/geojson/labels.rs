@@ -0,0 +1,239 @@
+use std::f32::consts::PI;
+
+use bevy::{prelude::*, render::view::RenderLayers};
+use bevy_map_viewer::{MapViewerMarker, TileMapResources, ZoomChangedEvent};
+use rstar::RTreeObject;
+
+use crate::workspace::Workspace;
+
+use super::{MapFeature, SYMBOL_SIZE};
+
+const LABEL_FONT_SIZE: f32 = 13.0;
+// Rough width of a glyph compared to the font size, used to size the collision boxes.
+const LABEL_CHAR_WIDTH: f32 = 0.55;
+// Long roads get a label repeated every this many pixels.
+const LABEL_LINE_SPACING: f32 = 400.0;
+const LABEL_ELEVATION: f32 = 600.0;
+
+#[derive(Component)]
+pub struct LabelMarker;
+
+/// A label which has passed the zoom checks but has not yet been checked for collisions.
+struct LabelCandidate {
+    text: String,
+    position: Vec2,
+    angle: f32,
+    // Bigger features get placed first so they win any collisions.
+    priority: f32,
+}
+
+/// Rebuilds the label layer whenever the map zoom changes, or the camera has zoomed far enough
+/// that the old layout would overlap or leave gaps.
+#[allow(clippy::too_many_arguments)]
+pub fn respawn_labels(
+    mut commands: Commands,
+    labels_query: Query<Entity, With<LabelMarker>>,
+    tile_map_manager: Res<TileMapResources>,
+    workspace: Res<Workspace>,
+    zoom_change: EventReader<ZoomChangedEvent>,
+    projection: Query<&Projection, With<MapViewerMarker>>,
+    asset_server: Res<AssetServer>,
+    mut last_scale: Local<f32>,
+) {
+    let scale = match projection.single() {
+        Ok(Projection::Orthographic(proj)) => proj.scale,
+        _ => return,
+    };
+    let scale_changed = *last_scale <= 0.0 || !(0.8..=1.25).contains(&(scale / *last_scale));
+    if zoom_change.is_empty() && !scale_changed {
+        return;
+    }
+    *last_scale = scale;
+
+    for entity in labels_query.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    let Some(selection) = &workspace.workspace else {
+        return;
+    };
+    if tile_map_manager.zoom_manager.zoom_level < 14 {
+        return;
+    }
+
+    let mut candidates: Vec<LabelCandidate> = Vec::new();
+    for request in workspace.get_rendered_requests() {
+        if request.get_processed_data().size() == 0 || !request.get_visible() {
+            continue;
+        }
+        let style = request.get_style();
+        let Some(field) = style.label_field else {
+            continue;
+        };
+        if tile_map_manager.zoom_manager.zoom_level < style.label_min_zoom {
+            continue;
+        }
+        for feature in request
+            .get_processed_data()
+            .locate_in_envelope_intersecting(&selection.envelope())
+        {
+            candidates.extend(label_candidates(
+                feature,
+                &field,
+                scale,
+                tile_map_manager.clone(),
+            ));
+        }
+    }
+    candidates.sort_by(|a, b| b.priority.total_cmp(&a.priority));
+
+    let font = asset_server.load("fonts/BagnardSans.otf");
+    let mut placed: Vec<Rect> = Vec::new();
+    for candidate in candidates {
+        let rect = label_rect(&candidate, scale);
+        if placed.iter().any(|other| !other.intersect(rect).is_empty()) {
+            continue;
+        }
+        placed.push(rect);
+
+        commands.spawn((
+            Text2d::new(candidate.text),
+            TextFont {
+                font: font.clone(),
+                font_size: LABEL_FONT_SIZE,
+                ..default()
+            },
+            TextColor(Color::BLACK),
+            Transform::from_translation(candidate.position.extend(LABEL_ELEVATION))
+                .with_rotation(Quat::from_rotation_z(candidate.angle))
+                .with_scale(Vec3::new(scale, scale, 1.0)),
+            LabelMarker,
+            RenderLayers::layer(1),
+        ));
+    }
+}
+
+/// Keeps the labels the same size on screen between re-layouts.
+pub fn scale_labels(
+    mut labels: Query<&mut Transform, With<LabelMarker>>,
+    projection: Query<&Projection, (With<MapViewerMarker>, Changed<Projection>)>,
+) {
+    if let Ok(Projection::Orthographic(proj)) = projection.single() {
+        for mut transform in &mut labels {
+            transform.scale = Vec3::new(proj.scale, proj.scale, 1.0);
+        }
+    }
+}
+
+/// Works out where a feature's label(s) should go. Areas get a single label at their centroid,
+/// lines get one at their middle and then one every `LABEL_LINE_SPACING` pixels.
+fn label_candidates(
+    feature: &MapFeature,
+    field: &str,
+    scale: f32,
+    tile_map_resources: TileMapResources,
+) -> Vec<LabelCandidate> {
+    let Some(text) = feature.properties.get(field).and_then(|v| v.as_str()) else {
+        return Vec::new();
+    };
+    if text.is_empty() {
+        return Vec::new();
+    }
+    let points = feature.get_in_world_space(tile_map_resources);
+    let text_width = text.chars().count() as f32 * LABEL_FONT_SIZE * LABEL_CHAR_WIDTH * scale;
+
+    if feature.is_point() {
+        return vec![LabelCandidate {
+            text: text.to_string(),
+            // Sit the text just under the symbol rather than on top of it.
+            position: points[0] - Vec2::new(0.0, SYMBOL_SIZE * scale),
+            angle: 0.0,
+            priority: 0.0,
+        }];
+    }
+
+    if feature.closed {
+        let (min, max) = points.iter().fold(
+            (Vec2::splat(f32::MAX), Vec2::splat(f32::MIN)),
+            |(min, max), p| (min.min(*p), max.max(*p)),
+        );
+        // Small areas only get labelled once they are large enough on screen to hold the text.
+        if max.x - min.x < text_width * 0.5 {
+            return Vec::new();
+        }
+        return vec![LabelCandidate {
+            text: text.to_string(),
+            position: world_centroid(&points).unwrap_or((min + max) / 2.0),
+            angle: 0.0,
+            priority: (max.x - min.x) * (max.y - min.y),
+        }];
+    }
+
+    let length: f32 = points.windows(2).map(|w| w[0].distance(w[1])).sum();
+    if length < text_width {
+        return Vec::new();
+    }
+    let spacing = LABEL_LINE_SPACING * scale;
+    let count = ((length / spacing).floor() as usize).max(1);
+    let step = length / count as f32;
+    (0..count)
+        .filter_map(|i| point_along(&points, step * (i as f32 + 0.5)))
+        .map(|(position, angle)| LabelCandidate {
+            text: text.to_string(),
+            position,
+            angle,
+            priority: length,
+        })
+        .collect()
+}
+
+/// Finds the point `distance` along a line, and the angle of the segment it lands on.
+/// The angle is flipped where needed so the text is never upside down.
+fn point_along(points: &[Vec2], distance: f32) -> Option<(Vec2, f32)> {
+    let mut travelled = 0.0;
+    for segment in points.windows(2) {
+        let segment_length = segment[0].distance(segment[1]);
+        if segment_length > 0.0 && travelled + segment_length >= distance {
+            let direction = segment[1] - segment[0];
+            let position = segment[0] + direction * ((distance - travelled) / segment_length);
+            let mut angle = direction.y.atan2(direction.x);
+            if angle > PI / 2.0 {
+                angle -= PI;
+            } else if angle < -PI / 2.0 {
+                angle += PI;
+            }
+            return Some((position, angle));
+        }
+        travelled += segment_length;
+    }
+    None
+}
+
+/// Area weighted centroid of a ring, this is where the polygon labels go.
+fn world_centroid(points: &[Vec2]) -> Option<Vec2> {
+    let mut area = 0.0;
+    let mut centroid = Vec2::ZERO;
+    for i in 0..points.len() {
+        let a = points[i];
+        let b = points[(i + 1) % points.len()];
+        let cross = a.perp_dot(b);
+        area += cross;
+        centroid += (a + b) * cross;
+    }
+    if area.abs() < f32::EPSILON {
+        return None;
+    }
+    Some(centroid / (3.0 * area))
+}
+
+/// The space a label takes up in world space, rotated labels use their bounding box.
+fn label_rect(candidate: &LabelCandidate, scale: f32) -> Rect {
+    let width = candidate.text.chars().count() as f32 * LABEL_FONT_SIZE * LABEL_CHAR_WIDTH * scale;
+    let height = LABEL_FONT_SIZE * scale;
+    let (sin, cos) = candidate.angle.sin_cos();
+    let half = Vec2::new(
+        (width * cos.abs() + height * sin.abs()) / 2.0,
+        (width * sin.abs() + height * cos.abs()) / 2.0,
+    );
+    Rect::from_center_half_size(candidate.position, half)
+}
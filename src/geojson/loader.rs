@@ -33,6 +33,21 @@ pub fn get_data_from_string_osm(data: &str) -> Result<Vec<MapFeature>, Box<dyn s
         }
 
         */
+        if way.type_field == "node" {
+            // Untagged nodes are just the corners of ways, so only keep the ones with tags.
+            if let (Some(lat), Some(lon), Some(tags)) = (way.lat, way.lon, way.tags) {
+                features.push(MapFeature {
+                    id: way.id.to_string(),
+                    properties: tags,
+                    closed: false,
+                    geometry: geo::Polygon::new(
+                        geo::LineString(vec![geo::Coord { x: lat, y: lon }]),
+                        vec![],
+                    ),
+                });
+            }
+            continue;
+        }
         if let Some(geo) = way.geometry {
             let tags = way.tags.unwrap_or_default();
             features.push(MapFeature {
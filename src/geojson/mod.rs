@@ -10,9 +10,11 @@
 //! - Manage spatial indexing and querying of geographic data
 //! 
 //! ## Sub-modules
+//! - `labels`: Text labels for named features, with collision avoidance
 //! - `loader`: GeoJSON file loading and parsing utilities
 //! - `renderer`: 2D rendering of geographic features using tessellation
 //! - `shapes_plugin`: Interactive shape creation and editing tools
+//! - `symbols`: Svg icons drawn for point features and pins
 //! - `types`: Data structures and types for geographic features
 //! 
 //! ## Key Features
@@ -22,12 +24,16 @@
 //! - Style-based rendering with customizable colors and stroke properties
 //! - Integration with OpenStreetMap data via Overpass API
 
+mod labels;
 mod loader;
 mod renderer;
 mod shapes_plugin;
+mod symbols;
 mod types;
 
+pub use labels::*;
 pub use loader::*;
 pub use renderer::*;
 pub use shapes_plugin::*;
+pub use symbols::*;
 pub use types::*;
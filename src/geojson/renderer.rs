@@ -19,23 +19,33 @@ use lyon::{
 };
 use rstar::RTreeObject;
 
-use crate::workspace::Workspace;
+use crate::{
+    tools::{FeatureSelection, SELECTED_COLOR},
+    workspace::Workspace,
+};
 use bevy_map_viewer::ZoomChangedEvent;
 
 use super::MapFeature;
 #[derive(Component)]
 pub struct ShapeMarker;
 
+/// Builds the meshes of every visible area and line, coloured by the workspace's colour rules.
+/// Selected features are drawn in the selection colour, so they are rebuilt when it changes.
+#[allow(clippy::too_many_arguments)]
 pub fn respawn_shapes(
     mut commands: Commands,
     shapes_query: Query<(Entity, &ShapeMarker)>,
     tile_map_manager: Res<TileMapResources>,
     workspace: Res<Workspace>,
     zoom_change: EventReader<ZoomChangedEvent>,
+    selection: Res<FeatureSelection>,
+    mut rendered_selection: Local<Vec<String>>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
 ) {
-    if !zoom_change.is_empty() {
+    let selected = selection.get_ids();
+    if !zoom_change.is_empty() || *rendered_selection != selected {
+        *rendered_selection = selected.clone();
         let mut intersection_candidates: Vec<MapFeature> = Vec::new();
         let ws: crate::workspace::WorkspaceData = if let Some(ws) = workspace.workspace.clone() {
             ws
@@ -66,10 +76,23 @@ pub fn respawn_shapes(
         }
 
         for feature in intersection_candidates {
+            // Points are drawn as symbols instead.
+            if feature.is_point() {
+                continue;
+            }
             let shape = feature.get_in_world_space(tile_map_manager.clone());
             let shape_vertices: Vec<[f32; 3]> =
                 shape.iter().map(|point| [point.x, point.y, 0.0]).collect();
 
+            if selected.contains(&feature.id) {
+                let entry = hashmap
+                    .entry(("selected".to_string(), serde_json::Value::Bool(true)))
+                    .or_insert_with(MeshConstructor::new);
+                entry.add_shape(shape_vertices, feature.closed);
+                entry.add_color(SELECTED_COLOR);
+                continue;
+            }
+
             for (item, c) in &colors {
                 if let Some(value) = feature.properties.get(item.0.clone()) {
                     if value == &item.1 {
@@ -192,7 +215,6 @@ impl MeshConstructor {
         self.index_offset += geometry.vertices.len() as u32;
     }
     fn to_mesh(&self) -> Mesh {
-        
         Mesh::new(
             PrimitiveTopology::TriangleList,
             RenderAssetUsages::default(),
@@ -1,11 +1,22 @@
 use bevy::prelude::*;
 
-use super::respawn_shapes;
+use super::{
+    SymbolCache, respawn_labels, respawn_shapes, respawn_symbols, scale_labels, scale_symbols,
+};
 
 pub struct RenderPlugin;
 
 impl Plugin for RenderPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, respawn_shapes);
+        app.init_resource::<SymbolCache>().add_systems(
+            Update,
+            (
+                respawn_shapes,
+                respawn_labels,
+                scale_labels,
+                respawn_symbols,
+                scale_symbols,
+            ),
+        );
     }
 }
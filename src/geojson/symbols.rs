@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+
+use bevy::{
+    asset::RenderAssetUsages,
+    prelude::*,
+    render::{
+        mesh::{Indices, PrimitiveTopology},
+        view::RenderLayers,
+    },
+};
+use bevy_map_viewer::{MapViewerMarker, TileMapResources, ZoomChangedEvent};
+use lyon::{
+    extra::parser::{ParserOptions, PathParser, Source},
+    path::Path,
+    tessellation::{
+        BuffersBuilder, FillOptions, FillRule, FillTessellator, FillVertex, VertexBuffers,
+    },
+};
+use rstar::RTreeObject;
+use serde::{Deserialize, Serialize};
+
+use crate::workspace::Workspace;
+
+use super::MapFeature;
+
+// The built in icons are all drawn on a 24x24 grid, the same as the svgs in assets/buttons.
+const ICON_GRID: f32 = 24.0;
+// How big a symbol is on screen in pixels, this stays the same at every zoom level.
+pub const SYMBOL_SIZE: f32 = 18.0;
+const SYMBOL_ELEVATION: f32 = 400.0;
+
+/// The icons which can be drawn for point features and pins.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SymbolIcon {
+    #[default]
+    Dot,
+    Pin,
+    School,
+    Hospital,
+    Cafe,
+    Restaurant,
+    Parking,
+    Shop,
+    Bank,
+    Pharmacy,
+    Worship,
+    Bus,
+    Toilets,
+    Fuel,
+    Library,
+    Bar,
+}
+
+impl SymbolIcon {
+    pub const ALL: [SymbolIcon; 16] = [
+        SymbolIcon::Dot,
+        SymbolIcon::Pin,
+        SymbolIcon::School,
+        SymbolIcon::Hospital,
+        SymbolIcon::Cafe,
+        SymbolIcon::Restaurant,
+        SymbolIcon::Parking,
+        SymbolIcon::Shop,
+        SymbolIcon::Bank,
+        SymbolIcon::Pharmacy,
+        SymbolIcon::Worship,
+        SymbolIcon::Bus,
+        SymbolIcon::Toilets,
+        SymbolIcon::Fuel,
+        SymbolIcon::Library,
+        SymbolIcon::Bar,
+    ];
+
+    /// The svg path data for the icon, sub paths which overlap are cut out using the even odd rule.
+    pub fn paths(&self) -> &'static [&'static str] {
+        match self {
+            SymbolIcon::Dot => &["M12 7a5 5 0 1 0 0 10a5 5 0 1 0 0-10z"],
+            SymbolIcon::Pin => &[
+                "M12 2a8 8 0 0 0-2.565 15.572L12 21.992l2.565-4.42A8 8 0 0 0 12 2zm1.626 13.77-.391.11L12 18.008l-1.235-2.128-.391-.11a6 6 0 1 1 3.252 0z",
+                "M12 7a3 3 0 1 0 3 3 3 3 0 0 0-3-3zm0 4a1 1 0 1 1 1-1 1 1 0 0 1-1 1z",
+            ],
+            SymbolIcon::School => &["M12 3 3 9h18L12 3z M5 10h14v10H5z M10 14h4v6h-4z"],
+            SymbolIcon::Hospital => &["M3 3h18v18H3z M10 6h4v4h4v4h-4v4h-4v-4H6v-4h4z"],
+            SymbolIcon::Cafe => &[
+                "M4 8h12v6a5 5 0 0 1-5 5H9a5 5 0 0 1-5-5z M16 9h2a3 3 0 0 1 0 6h-2v-2h2a1 1 0 0 0 0-2h-2z",
+            ],
+            SymbolIcon::Restaurant => &[
+                "M5 2h1.5v6h1V2H9v6h1V2h1.5v7a3 3 0 0 1-2.25 2.9V22h-2v-10.1A3 3 0 0 1 5 9z M17 2c2 0 3 3 3 7v4h-2v9h-2V2z",
+            ],
+            SymbolIcon::Parking => {
+                &["M4 3h16v18H4z M9 7h4a3 3 0 0 1 0 6h-2v4H9z M11 9v2h2a1 1 0 0 0 0-2z"]
+            }
+            SymbolIcon::Shop => &["M5 8h14l-1 13H6z M9 8V6a3 3 0 0 1 6 0v2h-2V6a1 1 0 0 0-2 0v2z"],
+            SymbolIcon::Bank => {
+                &["M12 2 2 7v2h20V7z M4 10h3v8H4z M10.5 10h3v8h-3z M17 10h3v8h-3z M2 19h20v3H2z"]
+            }
+            SymbolIcon::Pharmacy => &["M9 3h6v6h6v6h-6v6H9v-6H3V9h6z"],
+            SymbolIcon::Worship => &["M11 2h2v3h3v2h-3v3l6 4v8H5v-8l6-4V7H8V5h3z"],
+            SymbolIcon::Bus => &["M5 3h14v14H5z M7 5v6h10V5z M6 18h4v3H6z M14 18h4v3h-4z"],
+            SymbolIcon::Toilets => &[
+                "M7 2a2 2 0 1 0 0 4a2 2 0 1 0 0-4z M5 7h4v14H5z M17 2a2 2 0 1 0 0 4a2 2 0 1 0 0-4z M14 7h6l-1.5 8H18v6h-2v-6h-.5z",
+            ],
+            SymbolIcon::Fuel => &["M4 3h10v18H4z M6 5v5h6V5z M15 8h2l2 2v9h-2v-8h-2z"],
+            SymbolIcon::Library => &["M3 4h8v16H3z M13 4h8v16h-8z"],
+            SymbolIcon::Bar => &["M5 3h14l-6 8v8h3v2H8v-2h3v-8z"],
+        }
+    }
+
+    /// The point of the icon which sits on the features location, for most icons this is the middle.
+    pub fn anchor(&self) -> Vec2 {
+        match self {
+            SymbolIcon::Pin => Vec2::new(12.0, 22.0),
+            _ => Vec2::new(12.0, 12.0),
+        }
+    }
+
+    /// Picks a built in icon from the OSM tags of a feature, falling back to a dot.
+    pub fn for_properties(properties: &serde_json::Value) -> SymbolIcon {
+        let tag = |key: &str| properties.get(key).and_then(|v| v.as_str()).unwrap_or("");
+        match tag("amenity") {
+            "school" | "kindergarten" | "college" | "university" => SymbolIcon::School,
+            "hospital" | "clinic" | "doctors" => SymbolIcon::Hospital,
+            "cafe" => SymbolIcon::Cafe,
+            "restaurant" | "fast_food" | "food_court" => SymbolIcon::Restaurant,
+            "parking" | "bicycle_parking" | "motorcycle_parking" => SymbolIcon::Parking,
+            "bank" | "atm" => SymbolIcon::Bank,
+            "pharmacy" => SymbolIcon::Pharmacy,
+            "place_of_worship" => SymbolIcon::Worship,
+            "bus_station" => SymbolIcon::Bus,
+            "toilets" => SymbolIcon::Toilets,
+            "fuel" => SymbolIcon::Fuel,
+            "library" => SymbolIcon::Library,
+            "bar" | "pub" | "biergarten" => SymbolIcon::Bar,
+            _ if !tag("shop").is_empty() => SymbolIcon::Shop,
+            _ if tag("highway") == "bus_stop" => SymbolIcon::Bus,
+            _ => SymbolIcon::Dot,
+        }
+    }
+}
+
+/// Caches the tessellated icon meshes, and the materials used to colour them, so the same
+/// handles can be reused every time the symbols are respawned.
+#[derive(Resource, Default)]
+pub struct SymbolCache {
+    meshes: HashMap<SymbolIcon, Handle<Mesh>>,
+    materials: HashMap<[u8; 4], Handle<ColorMaterial>>,
+}
+
+impl SymbolCache {
+    pub fn mesh(&mut self, icon: SymbolIcon, meshes: &mut Assets<Mesh>) -> Handle<Mesh> {
+        self.meshes
+            .entry(icon)
+            .or_insert_with(|| meshes.add(tessellate_icon(icon)))
+            .clone()
+    }
+
+    pub fn material(
+        &mut self,
+        color: Srgba,
+        materials: &mut Assets<ColorMaterial>,
+    ) -> Handle<ColorMaterial> {
+        self.materials
+            .entry(color.to_u8_array())
+            .or_insert_with(|| materials.add(ColorMaterial::from_color(color)))
+            .clone()
+    }
+}
+
+/// Turns the svg paths of an icon into a mesh. The mesh is one world unit per grid unit with
+/// the anchor at the origin and y pointing up, so it wants scaling by `SYMBOL_SIZE / 24`.
+fn tessellate_icon(icon: SymbolIcon) -> Mesh {
+    let mut builder = Path::builder();
+    let mut parser = PathParser::new();
+    for path in icon.paths() {
+        if let Err(e) = parser.parse(
+            &ParserOptions::DEFAULT,
+            &mut Source::new(path.chars()),
+            &mut builder,
+        ) {
+            warn!("Failed to parse the svg path for {:?}: {:?}", icon, e);
+        }
+    }
+    let path = builder.build();
+
+    let anchor = icon.anchor();
+    let mut geometry: VertexBuffers<[f32; 3], u32> = VertexBuffers::new();
+    if let Err(e) = FillTessellator::new().tessellate_path(
+        &path,
+        &FillOptions::default()
+            .with_fill_rule(FillRule::EvenOdd)
+            .with_tolerance(0.05),
+        &mut BuffersBuilder::new(&mut geometry, |vertex: FillVertex| {
+            let p = vertex.position();
+            [p.x - anchor.x, anchor.y - p.y, 0.0]
+        }),
+    ) {
+        warn!("Failed to tessellate {:?}: {:?}", icon, e);
+    }
+
+    Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::default(),
+    )
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, geometry.vertices)
+    .with_inserted_indices(Indices::U32(geometry.indices))
+}
+
+#[derive(Component)]
+pub struct SymbolMarker;
+
+/// Draws an icon for every point feature in the workspace, the icon comes from the workspace's
+/// symbol rules or otherwise from the feature's tags.
+#[allow(clippy::too_many_arguments)]
+pub fn respawn_symbols(
+    mut commands: Commands,
+    symbols_query: Query<Entity, With<SymbolMarker>>,
+    tile_map_manager: Res<TileMapResources>,
+    workspace: Res<Workspace>,
+    zoom_change: EventReader<ZoomChangedEvent>,
+    projection: Query<&Projection, With<MapViewerMarker>>,
+    mut cache: ResMut<SymbolCache>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    if zoom_change.is_empty() {
+        return;
+    }
+    let scale = match projection.single() {
+        Ok(Projection::Orthographic(proj)) => proj.scale,
+        _ => return,
+    };
+    for entity in symbols_query.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    let Some(ws) = &workspace.workspace else {
+        return;
+    };
+    if tile_map_manager.zoom_manager.zoom_level < 14 {
+        return;
+    }
+    let colors = ws.get_color_properties();
+    let default_color = Srgba::new(0.2, 0.2, 0.2, 0.9);
+
+    for request in workspace.get_rendered_requests() {
+        if request.get_processed_data().size() == 0 || !request.get_visible() {
+            continue;
+        }
+        for feature in request
+            .get_processed_data()
+            .locate_in_envelope_intersecting(&ws.envelope())
+            .filter(|feature| feature.is_point())
+        {
+            let icon = ws.get_symbol(&feature.properties);
+            let color = colors
+                .iter()
+                .find(|((key, value), _)| feature.properties.get(key) == Some(value))
+                .map(|(_, c)| *c)
+                .unwrap_or(default_color);
+            spawn_symbol(
+                &mut commands,
+                feature,
+                icon,
+                color,
+                scale,
+                tile_map_manager.clone(),
+                &mut cache,
+                &mut meshes,
+                &mut materials,
+            );
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_symbol(
+    commands: &mut Commands,
+    feature: &MapFeature,
+    icon: SymbolIcon,
+    color: Srgba,
+    scale: f32,
+    tile_map_resources: TileMapResources,
+    cache: &mut SymbolCache,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+) {
+    let Some(location) = feature
+        .get_in_world_space(tile_map_resources)
+        .first()
+        .copied()
+    else {
+        return;
+    };
+    let size = SYMBOL_SIZE / ICON_GRID * scale;
+    commands.spawn((
+        Mesh2d(cache.mesh(icon, meshes)),
+        MeshMaterial2d(cache.material(color, materials)),
+        Transform::from_translation(location.extend(SYMBOL_ELEVATION))
+            .with_scale(Vec3::new(size, size, 1.0)),
+        SymbolMarker,
+        RenderLayers::layer(1),
+    ));
+}
+
+/// Keeps the symbols the same size on screen as the camera zooms.
+pub fn scale_symbols(
+    mut symbols: Query<&mut Transform, With<SymbolMarker>>,
+    projection: Query<&Projection, (With<MapViewerMarker>, Changed<Projection>)>,
+) {
+    if let Ok(Projection::Orthographic(proj)) = projection.single() {
+        let size = SYMBOL_SIZE / ICON_GRID * proj.scale;
+        for mut transform in &mut symbols {
+            transform.scale = Vec3::new(size, size, 1.0);
+        }
+    }
+}
@@ -1,9 +1,13 @@
-
 use bevy::prelude::*;
 use bevy_map_viewer::{Coord, TileMapResources};
 use geo::BoundingRect;
 use rstar::{AABB, RTreeObject};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Roughly how many meters there are in a degree of latitude, close enough for turning
+/// distances into degrees.
+pub const METERS_PER_DEGREE: f64 = 111_320.0;
 
 #[derive(Component, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct MapFeature {
@@ -24,6 +28,91 @@ impl MapFeature {
         }
         new_points
     }
+
+    /// The feature as a GeoJSON feature, with the coordinates in `[long, lat]` order.
+    pub fn to_geojson(&self) -> serde_json::Value {
+        let coords: Vec<[f64; 2]> = self
+            .geometry
+            .exterior()
+            .0
+            .iter()
+            .map(|c| [c.y, c.x])
+            .collect();
+        let geometry = if self.is_point() {
+            json!({ "type": "Point", "coordinates": coords[0] })
+        } else if self.closed {
+            json!({ "type": "Polygon", "coordinates": [coords] })
+        } else {
+            json!({ "type": "LineString", "coordinates": coords })
+        };
+        json!({
+            "type": "Feature",
+            "id": self.id,
+            "geometry": geometry,
+            "properties": self.properties,
+        })
+    }
+
+    /// Nodes are stored as a polygon with a single coordinate, which `geo` closes to two. An
+    /// empty geometry is not a point.
+    pub fn is_point(&self) -> bool {
+        let exterior = &self.geometry.exterior().0;
+        !exterior.is_empty() && exterior.len() <= 2 && exterior.first() == exterior.last()
+    }
+
+    /// Checks if a world space position is on the feature. Areas are hit anywhere inside them,
+    /// lines and points when the position is within `tolerance` world units.
+    pub fn hit_test(
+        &self,
+        position: Vec2,
+        tolerance: f32,
+        tile_map_resources: TileMapResources,
+    ) -> bool {
+        let points = self.get_in_world_space(tile_map_resources);
+        if points.is_empty() {
+            return false;
+        }
+        if self.is_point() {
+            return points[0].distance(position) <= tolerance;
+        }
+        if self.closed && point_in_ring(position, &points) {
+            return true;
+        }
+        points
+            .windows(2)
+            .any(|w| distance_to_segment(position, w[0], w[1]) <= tolerance)
+    }
+}
+
+/// Even-odd ray cast, the ring may or may not repeat its first point.
+pub fn point_in_ring(position: Vec2, ring: &[Vec2]) -> bool {
+    let mut inside = false;
+    let mut j = ring.len() - 1;
+    for i in 0..ring.len() {
+        let (a, b) = (ring[i], ring[j]);
+        if (a.y > position.y) != (b.y > position.y)
+            && position.x < (b.x - a.x) * (position.y - a.y) / (b.y - a.y) + a.x
+        {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+/// The point on the segment from `a` to `b` which is nearest to `position`.
+pub fn closest_point_on_segment(position: Vec2, a: Vec2, b: Vec2) -> Vec2 {
+    let segment = b - a;
+    let length_squared = segment.length_squared();
+    if length_squared == 0.0 {
+        return a;
+    }
+    let t = ((position - a).dot(segment) / length_squared).clamp(0.0, 1.0);
+    a + segment * t
+}
+
+pub fn distance_to_segment(position: Vec2, a: Vec2, b: Vec2) -> f32 {
+    position.distance(closest_point_on_segment(position, a, b))
 }
 
 impl RTreeObject for MapFeature {
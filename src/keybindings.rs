@@ -0,0 +1,450 @@
+//! # Keybindings Module
+//!
+//! Keyboard shortcuts for the tools and panels, editable from the settings panel and saved to the
+//! config directory.
+//!
+//! ## Key Components
+//! - `Action`: Everything which can be bound to a key
+//! - `KeyBinding`: A key plus the modifiers which must be held with it
+//! - `Keybindings`: The current map from actions to keys, and conflict detection
+//! - `keybindings_ui`: The window for rebinding keys
+
+use std::{collections::HashMap, fs, path::PathBuf};
+
+use bevy::prelude::*;
+use bevy_egui::{EguiContexts, EguiPreUpdateSet, egui};
+use platform_dirs::AppDirs;
+use serde::{Deserialize, Serialize};
+
+pub struct KeybindingsPlugin;
+
+impl Plugin for KeybindingsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Keybindings::load()).add_systems(
+            Update,
+            (track_keyboard_focus, keybindings_ui)
+                .chain()
+                .after(EguiPreUpdateSet::InitContexts),
+        );
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Action {
+    SelectionTool,
+    MeasureTool,
+    FeaturePickerTool,
+    PinTool,
+    PointerTool,
+    SketchTool,
+    CycleSelectionType,
+    Delete,
+    Cancel,
+    Finish,
+    RemoveLastPoint,
+    ToggleLayers,
+    OpenChat,
+}
+
+impl Action {
+    pub const ALL: [Action; 13] = [
+        Action::SelectionTool,
+        Action::MeasureTool,
+        Action::FeaturePickerTool,
+        Action::PinTool,
+        Action::PointerTool,
+        Action::SketchTool,
+        Action::CycleSelectionType,
+        Action::Delete,
+        Action::Cancel,
+        Action::Finish,
+        Action::RemoveLastPoint,
+        Action::ToggleLayers,
+        Action::OpenChat,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Action::SelectionTool => "Workspace selection tool",
+            Action::MeasureTool => "Measure tool",
+            Action::FeaturePickerTool => "Feature picker tool",
+            Action::PinTool => "Pin tool",
+            Action::PointerTool => "Pointer",
+            Action::SketchTool => "Sketch tool",
+            Action::CycleSelectionType => "Cycle selection shape",
+            Action::Delete => "Delete selected pin or sketch",
+            Action::Cancel => "Cancel drawing",
+            Action::Finish => "Finish line, area or measurement",
+            Action::RemoveLastPoint => "Remove last measured point",
+            Action::ToggleLayers => "Show/hide layers panel",
+            Action::OpenChat => "Open chat",
+        }
+    }
+
+    fn default_binding(&self) -> KeyBinding {
+        KeyBinding::key(match self {
+            Action::SelectionTool => KeyCode::KeyS,
+            Action::MeasureTool => KeyCode::KeyM,
+            Action::FeaturePickerTool => KeyCode::KeyF,
+            Action::PinTool => KeyCode::KeyP,
+            Action::PointerTool => KeyCode::KeyV,
+            Action::SketchTool => KeyCode::KeyD,
+            Action::CycleSelectionType => KeyCode::KeyC,
+            Action::Delete => KeyCode::Delete,
+            Action::Cancel => KeyCode::Escape,
+            Action::Finish => KeyCode::Enter,
+            Action::RemoveLastPoint => KeyCode::Backspace,
+            Action::ToggleLayers => KeyCode::KeyL,
+            Action::OpenChat => KeyCode::Slash,
+        })
+    }
+}
+
+// The keys which can be bound. Stored by name, as `KeyCode` is not serializable here.
+const BINDABLE_KEYS: [KeyCode; 58] = [
+    KeyCode::KeyA,
+    KeyCode::KeyB,
+    KeyCode::KeyC,
+    KeyCode::KeyD,
+    KeyCode::KeyE,
+    KeyCode::KeyF,
+    KeyCode::KeyG,
+    KeyCode::KeyH,
+    KeyCode::KeyI,
+    KeyCode::KeyJ,
+    KeyCode::KeyK,
+    KeyCode::KeyL,
+    KeyCode::KeyM,
+    KeyCode::KeyN,
+    KeyCode::KeyO,
+    KeyCode::KeyP,
+    KeyCode::KeyQ,
+    KeyCode::KeyR,
+    KeyCode::KeyS,
+    KeyCode::KeyT,
+    KeyCode::KeyU,
+    KeyCode::KeyV,
+    KeyCode::KeyW,
+    KeyCode::KeyX,
+    KeyCode::KeyY,
+    KeyCode::KeyZ,
+    KeyCode::Digit0,
+    KeyCode::Digit1,
+    KeyCode::Digit2,
+    KeyCode::Digit3,
+    KeyCode::Digit4,
+    KeyCode::Digit5,
+    KeyCode::Digit6,
+    KeyCode::Digit7,
+    KeyCode::Digit8,
+    KeyCode::Digit9,
+    KeyCode::F1,
+    KeyCode::F2,
+    KeyCode::F3,
+    KeyCode::F4,
+    KeyCode::F5,
+    KeyCode::F6,
+    KeyCode::F7,
+    KeyCode::F8,
+    KeyCode::F9,
+    KeyCode::F10,
+    KeyCode::F11,
+    KeyCode::F12,
+    KeyCode::Escape,
+    KeyCode::Delete,
+    KeyCode::Backspace,
+    KeyCode::Tab,
+    KeyCode::Enter,
+    KeyCode::Space,
+    KeyCode::Slash,
+    KeyCode::Period,
+    KeyCode::Comma,
+    KeyCode::Backquote,
+];
+
+/// Shortcuts which are not configurable but still count when looking for conflicts.
+const RESERVED: [(&str, KeyBinding); 3] = [
+    (
+        "Undo",
+        KeyBinding {
+            key: KeyCode::KeyZ,
+            ctrl: true,
+            shift: false,
+            alt: false,
+        },
+    ),
+    (
+        "Redo",
+        KeyBinding {
+            key: KeyCode::KeyY,
+            ctrl: true,
+            shift: false,
+            alt: false,
+        },
+    ),
+    (
+        "Redo",
+        KeyBinding {
+            key: KeyCode::KeyZ,
+            ctrl: true,
+            shift: true,
+            alt: false,
+        },
+    ),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct KeyBinding {
+    pub key: KeyCode,
+    pub ctrl: bool,
+    pub shift: bool,
+    pub alt: bool,
+}
+
+impl KeyBinding {
+    pub fn key(key: KeyCode) -> Self {
+        KeyBinding {
+            key,
+            ctrl: false,
+            shift: false,
+            alt: false,
+        }
+    }
+
+    /// Whether the key went down this frame with exactly these modifiers held.
+    pub fn just_pressed(&self, keys: &ButtonInput<KeyCode>) -> bool {
+        keys.just_pressed(self.key)
+            && self.ctrl == keys.any_pressed(CTRL)
+            && self.shift == keys.any_pressed(SHIFT)
+            && self.alt == keys.any_pressed(ALT)
+    }
+
+    /// Something like `Ctrl+Shift+KeyZ`, also how bindings are written to disk.
+    pub fn name(&self) -> String {
+        let mut name = String::new();
+        for (held, modifier) in [
+            (self.ctrl, "Ctrl+"),
+            (self.shift, "Shift+"),
+            (self.alt, "Alt+"),
+        ] {
+            if held {
+                name.push_str(modifier);
+            }
+        }
+        name.push_str(&format!("{:?}", self.key));
+        name
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        let mut binding = KeyBinding::key(KeyCode::Escape);
+        let mut parts: Vec<&str> = name.split('+').collect();
+        let key = parts.pop()?;
+        for modifier in parts {
+            match modifier {
+                "Ctrl" => binding.ctrl = true,
+                "Shift" => binding.shift = true,
+                "Alt" => binding.alt = true,
+                _ => return None,
+            }
+        }
+        binding.key = BINDABLE_KEYS
+            .into_iter()
+            .find(|code| format!("{code:?}") == key)?;
+        Some(binding)
+    }
+}
+
+const CTRL: [KeyCode; 4] = [
+    KeyCode::ControlLeft,
+    KeyCode::ControlRight,
+    KeyCode::SuperLeft,
+    KeyCode::SuperRight,
+];
+const SHIFT: [KeyCode; 2] = [KeyCode::ShiftLeft, KeyCode::ShiftRight];
+const ALT: [KeyCode; 2] = [KeyCode::AltLeft, KeyCode::AltRight];
+
+#[derive(Resource)]
+pub struct Keybindings {
+    bindings: HashMap<Action, KeyBinding>,
+    /// Set while a text box has focus, so typing doesn't trigger shortcuts.
+    typing: bool,
+    /// The action waiting for a new key in the keybindings window.
+    capturing: Option<Action>,
+    pub window_open: bool,
+}
+
+impl Default for Keybindings {
+    fn default() -> Self {
+        Keybindings {
+            bindings: Action::ALL
+                .into_iter()
+                .map(|action| (action, action.default_binding()))
+                .collect(),
+            typing: false,
+            capturing: None,
+            window_open: false,
+        }
+    }
+}
+
+impl Keybindings {
+    fn path() -> Option<PathBuf> {
+        AppDirs::new(Some("Map-rs"), false).map(|dirs| dirs.config_dir.join("keybindings.json"))
+    }
+
+    /// The saved bindings on top of the defaults. Unknown actions and keys are ignored.
+    pub fn load() -> Self {
+        let mut keybindings = Keybindings::default();
+        let saved = Self::path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|data| serde_json::from_str::<HashMap<Action, String>>(&data).ok())
+            .unwrap_or_default();
+        for (action, name) in saved {
+            if let Some(binding) = KeyBinding::parse(&name) {
+                keybindings.bindings.insert(action, binding);
+            }
+        }
+        keybindings
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        let Some(path) = Self::path() else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let names: HashMap<Action, String> = self
+            .bindings
+            .iter()
+            .map(|(action, binding)| (*action, binding.name()))
+            .collect();
+        fs::write(path, serde_json::to_string_pretty(&names)?)
+    }
+
+    pub fn get(&self, action: Action) -> KeyBinding {
+        self.bindings
+            .get(&action)
+            .copied()
+            .unwrap_or_else(|| action.default_binding())
+    }
+
+    pub fn set(&mut self, action: Action, binding: KeyBinding) {
+        self.bindings.insert(action, binding);
+    }
+
+    /// Whether the shortcut for `action` was pressed this frame. Never while typing or rebinding.
+    pub fn just_pressed(&self, action: Action, keys: &ButtonInput<KeyCode>) -> bool {
+        !self.typing && self.capturing.is_none() && self.get(action).just_pressed(keys)
+    }
+
+    /// The names of the other actions and reserved shortcuts bound to the same keys as `action`.
+    pub fn conflicts(&self, action: Action) -> Vec<&'static str> {
+        let binding = self.get(action);
+        Action::ALL
+            .into_iter()
+            .filter(|other| *other != action && self.get(*other) == binding)
+            .map(|other| other.label())
+            .chain(
+                RESERVED
+                    .into_iter()
+                    .filter(|(_, reserved)| *reserved == binding)
+                    .map(|(name, _)| name),
+            )
+            .collect()
+    }
+}
+
+fn track_keyboard_focus(mut contexts: EguiContexts, mut keybindings: ResMut<Keybindings>) {
+    let typing = contexts.ctx_mut().wants_keyboard_input();
+    if keybindings.typing != typing {
+        keybindings.typing = typing;
+    }
+}
+
+fn keybindings_ui(
+    mut contexts: EguiContexts,
+    mut keybindings: ResMut<Keybindings>,
+    keys: Res<ButtonInput<KeyCode>>,
+) {
+    if !keybindings.window_open {
+        return;
+    }
+    // Waiting for a key: the first non modifier key pressed becomes the binding.
+    if let Some(action) = keybindings.capturing {
+        let pressed = keys
+            .get_just_pressed()
+            .find(|key| BINDABLE_KEYS.contains(key))
+            .copied();
+        if let Some(key) = pressed {
+            let binding = KeyBinding {
+                key,
+                ctrl: keys.any_pressed(CTRL),
+                shift: keys.any_pressed(SHIFT),
+                alt: keys.any_pressed(ALT),
+            };
+            keybindings.set(action, binding);
+            keybindings.capturing = None;
+            // The key shouldn't also trigger whatever it was just bound to.
+            keybindings.typing = true;
+            let _ = keybindings.save();
+        }
+    }
+
+    let mut open = true;
+    let mut changed = false;
+    egui::Window::new("Keybindings")
+        .open(&mut open)
+        .resizable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            egui::Grid::new("keybindings_grid")
+                .num_columns(3)
+                .striped(true)
+                .show(ui, |ui| {
+                    for action in Action::ALL {
+                        ui.label(action.label());
+                        let text = if keybindings.capturing == Some(action) {
+                            "Press a key...".to_string()
+                        } else {
+                            keybindings.get(action).name()
+                        };
+                        if ui.button(text).clicked() {
+                            keybindings.capturing = Some(action);
+                        }
+                        if ui
+                            .small_button("Reset")
+                            .on_hover_text(action.default_binding().name())
+                            .clicked()
+                        {
+                            keybindings.set(action, action.default_binding());
+                            changed = true;
+                        }
+                        ui.end_row();
+                        let conflicts = keybindings.conflicts(action);
+                        if !conflicts.is_empty() {
+                            ui.colored_label(
+                                egui::Color32::LIGHT_RED,
+                                format!("Also bound to {}", conflicts.join(", ")),
+                            );
+                            ui.end_row();
+                        }
+                    }
+                });
+            ui.separator();
+            ui.label("Ctrl+Z undoes, Ctrl+Y or Ctrl+Shift+Z redoes.");
+            if ui.button("Reset all").clicked() {
+                *keybindings = Keybindings {
+                    window_open: true,
+                    ..Default::default()
+                };
+                changed = true;
+            }
+        });
+    if !open {
+        keybindings.window_open = false;
+        keybindings.capturing = None;
+    }
+    if changed {
+        let _ = keybindings.save();
+    }
+}
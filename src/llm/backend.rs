@@ -0,0 +1,143 @@
+use std::sync::atomic::AtomicBool;
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+use super::{LlmResponse, Message};
+
+pub const DEFAULT_MODEL: &str = "deepseek/deepseek-chat-v3-0324:free";
+/// Used when the model's context length isn't known, small enough for most local models.
+pub const DEFAULT_CONTEXT_LENGTH: u32 = 8192;
+// Room left for the reply when `max_tokens` isn't set.
+const REPLY_RESERVE: u32 = 1024;
+
+/// The model and generation settings, chosen per workspace.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ModelParams {
+    pub model: String,
+    pub temperature: f32,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    /// From the model list when the model was picked there, otherwise set by hand.
+    #[serde(default)]
+    pub context_length: Option<u32>,
+    #[serde(default)]
+    pub pricing: Option<ModelPricing>,
+}
+
+impl Default for ModelParams {
+    fn default() -> Self {
+        ModelParams {
+            model: DEFAULT_MODEL.to_string(),
+            temperature: 0.0,
+            top_p: None,
+            max_tokens: None,
+            context_length: None,
+            pricing: None,
+        }
+    }
+}
+
+impl ModelParams {
+    /// Picks a model from the list, along with what is known about its limits and price.
+    pub fn set_model(&mut self, model: &ModelInfo) {
+        self.model = model.id.clone();
+        self.context_length = model.context_length.map(|c| c as u32);
+        self.pricing = model.pricing.clone();
+    }
+
+    pub fn context_length(&self) -> u32 {
+        self.context_length.unwrap_or(DEFAULT_CONTEXT_LENGTH)
+    }
+
+    /// How many tokens the messages sent may take up, leaving room for the reply.
+    pub fn prompt_budget(&self) -> u32 {
+        let reserve = self.max_tokens.unwrap_or(REPLY_RESERVE);
+        self.context_length().saturating_sub(reserve)
+    }
+}
+
+/// US dollars per token. OpenRouter sends these as strings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ModelPricing {
+    #[serde(deserialize_with = "price")]
+    pub prompt: f64,
+    #[serde(deserialize_with = "price")]
+    pub completion: f64,
+}
+
+impl ModelPricing {
+    pub fn cost(&self, prompt_tokens: i64, completion_tokens: i64) -> f64 {
+        self.prompt * prompt_tokens as f64 + self.completion * completion_tokens as f64
+    }
+}
+
+fn price<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Number(n) => n.as_f64().unwrap_or_default(),
+        Value::String(s) => s.parse().unwrap_or_default(),
+        _ => 0.0,
+    })
+}
+
+/// An entry of the endpoint's `/models` list. Local servers usually only fill in the id.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ModelInfo {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub context_length: Option<u64>,
+    #[serde(default)]
+    pub pricing: Option<ModelPricing>,
+}
+
+impl ModelInfo {
+    pub fn label(&self) -> &str {
+        if self.name.is_empty() {
+            &self.id
+        } else {
+            &self.name
+        }
+    }
+}
+
+/// Something the chat can be sent to. `OpenrouterClient` talks to any OpenAI compatible
+/// endpoint, such as OpenRouter or a local llama.cpp or Ollama server.
+pub trait ChatBackend: Send + Sync {
+    fn chat(&self, messages: &[Message], params: &ModelParams) -> Result<LlmResponse, ureq::Error>;
+
+    /// Sends the chat, calling `on_token` with each piece of the reply as it arrives, and
+    /// giving up once `cancel` is set. Backends that can't stream hand over the whole reply at
+    /// once.
+    fn chat_stream(
+        &self,
+        messages: &[Message],
+        params: &ModelParams,
+        on_token: &mut dyn FnMut(&str),
+        _cancel: &AtomicBool,
+    ) -> Result<LlmResponse, ureq::Error> {
+        let response = self.chat(messages, params)?;
+        if let Some(choice) = response
+            .choices
+            .first()
+            .filter(|c| !c.message.content.is_empty())
+        {
+            on_token(&choice.message.content);
+        }
+        Ok(response)
+    }
+
+    fn list_models(&self) -> Result<Vec<ModelInfo>, ureq::Error>;
+
+    /// Whether it has what it needs to send a message, such as an API key.
+    fn is_configured(&self) -> bool;
+
+    /// Roughly how many tokens every request takes before the conversation, for the system
+    /// prompt and tools.
+    fn overhead_tokens(&self) -> u32 {
+        0
+    }
+}
@@ -1,7 +1,21 @@
+use std::{
+    io::{BufRead, BufReader, Read},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError, channel},
+    },
+    time::{Duration, Instant},
+};
+
 use bevy::log;
-use serde_json::json;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use ureq::{Body, http::Response};
 
-use crate::llm::LlmResponse;
+use crate::llm::{
+    ChatBackend, LlmResponse, ModelInfo, ModelParams, SseEvent, SseParser, StreamAccumulator,
+    estimate_tokens, fit_to_context, tool_definitions,
+};
 
 // https://openrouter.ai/docs/api-reference/overview
 use super::{Message, OpenrouterClient};
@@ -9,7 +23,8 @@ use super::{Message, OpenrouterClient};
 // LLM Request Command Specification
 // ============================================================================
 //
-// Use "rq:" for data requests. Format:
+// Data is requested through tool calls, see `tools.rs` for the schemas. Models
+// that do not support tools fall back to "rq:" commands in the reply. Format:
 //     rq: <cmd> <params>
 //
 // Commands:
@@ -22,6 +37,7 @@ use super::{Message, OpenrouterClient};
 //   bb   : Features in bbox     ex: rq: bb {51.4,-0.1,51.6,-0.08}
 //   d    : Distance             ex: rq: d {51.5,-0.09} {51.6,-0.10}
 //   n    : Nearest feature      ex: rq: n {51.5,-0.09}
+//   sel  : Selected features    ex: rq: sel
 //
 // Rules:
 // - Points as {lat,lon}, radius as r<meters> (e.g., r200).
@@ -35,140 +51,494 @@ use super::{Message, OpenrouterClient};
 // ============================================================================
 
 // We should add a population density command
-// Command list updated to match actual implementations in commands.rs
 pub const LLM_PROMPT: &str = r#"
 You are a geo-analysis assistant.
 
 Your job is to answer user questions about geographic areas. Always follow this strict process:
 
-1. Use an `rq:` command to request data DO NOT ADD ANY OTHER TEXT TO THIS MESSAGE.
-2. Wait for JSON data to be returned.
+1. Call the provided tools to get data about the workspace. You can call several at once.
+2. Wait for the results.
 3. Interpret the data and give a clear, concise answer.
 4. Do NOT guess or assume anything without data.
-5. When words like here are used assume it means workspace and data can be gotten through commands
-
---- Commands ---
-
-Workspace-level commands (no parameters needed):
-- rq: i → General workspace info and stats including summery
-- rq: cnt → Count all features in workspace
-- rq: sm → Summarize features in workspace
+5. When words like here are used assume it means workspace and data can be gotten through the tools
 
-Location-based commands (need coordinates):
-- rq: nb {lat,lon} r{radius} → Nearby features within radius
-- rq: n {lat,lon} → Nearest feature to point
-- rq: d {lat1,lon1} {lat2,lon2} → Distance between two points
-
-Bounding box command:
-- rq: bb {minLat,minLon,maxLat,maxLon} → Features in bounding box
-
-Feature-specific commands (need feature ID):
-- rq: gt <id> → Get full details for a feature
-- rq: t <id> → Get tag metadata for a feature
+Points are latitude and longitude in degrees, distances and radii are in meters.
 
-Points must be in {lat,lon} format. Distances like r500 mean 500 meters.
+The tools marked Action change the map. Use them when the user asks to see, show, mark or load
+something, then say what you proposed. They are only applied once the user confirms them.
 
 --- Examples ---
 
 User: What's nearby at {51.5,-0.09}?
 
-Assistant: rq: nb {51.5,-0.09} r500
-
-{Wait wait for data}
-
-{Analyse data or request more}
+Assistant: (calls nearby_features with point {lat: 51.5, lon: -0.09} and radius 500)
 
 Assistant: There are 18 nearby features within 500 meters, including residential buildings, a park, and a school.
 
 ---
 
-User: What's the population density?
-
-Assistant: rq: sm
-
-{Wait wait for data}
+User: What happens if I add 200 houses here?
 
-{Analyse data or request more}
+Assistant: (calls summarize_features)
 
-Assistant: The population density is 15 people per 100 m² (or 15,000 per km²).
+Assistant: Currently, there are 500 households. Adding 200 would increase that by 40%. If average household size stays the same, population would increase from 1500 to ~2100.
 
 ---
 
-User: What happens if I add 200 houses here?
+Rules Recap:
+- Don’t guess — answer only after data is returned.
+- Be concise and directly answer the user's question.
 
-Assistant: rq: sm
+"#;
 
-{Wait wait for data}
+/// Appended to the prompt when the model cannot call tools.
+pub const RQ_PROMPT: &str = r#"
+Tools are not available. Instead request data by replying with a single line starting with `rq:`,
+the result is sent back to you as the next message.
 
-{Analyse data or request more}
+Workspace-level commands (no parameters needed):
+- rq: i → General workspace info and stats including summery
+- rq: cnt → Count all features in workspace
+- rq: sm → Summarize features in workspace
+- rq: f <filter> → Features matching a tag filter, ex: rq: f amenity=school or building:levels>4
+- rq: agg <key> [value] → Count, area and length of features grouped by a tag, ex: rq: agg highway
 
-Assistant: Currently, there are 500 households. Adding 200 would increase that by 40%. If average household size stays the same, population would increase from 1500 to ~2100.
+Location-based commands (need coordinates):
+- rq: nb {lat,lon} r{radius} → Nearby features within radius
+- rq: n {lat,lon} → Nearest feature to point
+- rq: d {lat1,lon1} {lat2,lon2} → Distance between two points
 
----
+Bounding box command:
+- rq: bb {minLat,minLon,maxLat,maxLon} → Features in bbox
 
-Rules Recap:
-- Only use `rq:` to request data DO NOT ADD ANY OTHER TEXT TO THIS MESSAGE.
-- Don’t guess — answer only after data is returned.
-- Be concise and directly answer the user's question.
+Feature-specific commands (need feature ID):
+- rq: gt <id> → Get full details for a feature
+- rq: t <id> → Get tag metadata for a feature
 
+Selection command (no parameters needed):
+- rq: sel → Get full details for the features the user has selected on the map
+
+Example: rq: nb {51.5,-0.09} r500
 "#;
 
-// This really needs to reflect how many tokens are left!
 impl OpenrouterClient {
-    fn build_messages_json(&self, mess: &Vec<Message>) -> serde_json::Value {
+    fn build_messages_json(&self, mess: &[Message], tools: bool) -> serde_json::Value {
+        let prompt = if tools {
+            self.prompt.clone()
+        } else {
+            format!("{}{}", self.prompt, RQ_PROMPT)
+        };
         let mut messages = vec![json!({
             "role": "system",
-            "content": self.prompt
+            "content": prompt
         })];
 
         for message in mess {
-            messages.push(json!({
+            if !tools {
+                // Without tool support the calls and results are sent as plain text.
+                let role = if message.role == "tool" {
+                    "user"
+                } else {
+                    &message.role
+                };
+                if message.content.is_empty() {
+                    continue;
+                }
+                messages.push(json!({
+                    "role": role,
+                    "content": message.content
+                }));
+                continue;
+            }
+            let mut json = json!({
                 "role": message.role,
                 "content": message.content
-            }));
+            });
+            if !message.tool_calls.is_empty() {
+                json["tool_calls"] = json!(message.tool_calls);
+            }
+            match &message.tool_call_id {
+                Some(id) => json["tool_call_id"] = json!(id),
+                // An `rq:` result from before the model supported tools.
+                None if message.role == "tool" => json["role"] = json!("user"),
+                None => {}
+            }
+            messages.push(json);
         }
 
         json!(messages)
     }
 
+    /// Sends the conversation with the workspace tools attached. If the model rejects them the
+    /// request is sent again without, and the model is told to use `rq:` commands instead.
     pub fn send_openrouter_chat(
         &self,
-        messages: &Vec<Message>,
+        messages: &[Message],
+        params: &ModelParams,
+    ) -> Result<LlmResponse, ureq::Error> {
+        let never = AtomicBool::new(false);
+        self.with_tool_fallback(|tools| {
+            let body = self.request_body(messages, params, tools);
+            let mut response = self.post(body, &never)?;
+            let res: LlmResponse = response.body_mut().read_json()?;
+            log::info!("Got the llm response!");
+            Ok(res)
+        })
+    }
+
+    /// Like `send_openrouter_chat`, but streamed, with `on_token` called for each piece of the
+    /// reply as it arrives. Stops reading once `cancel` is set.
+    pub fn send_openrouter_chat_stream(
+        &self,
+        messages: &[Message],
+        params: &ModelParams,
+        on_token: &mut dyn FnMut(&str),
+        cancel: &AtomicBool,
+    ) -> Result<LlmResponse, ureq::Error> {
+        self.with_tool_fallback(|tools| {
+            let mut body = self.request_body(messages, params, tools);
+            body["stream"] = json!(true);
+            body["stream_options"] = json!({ "include_usage": true });
+            let response = self.post(body, cancel)?;
+
+            let lines = read_lines(response.into_body().into_reader());
+            let mut parser = SseParser::default();
+            let mut accumulator = StreamAccumulator::default();
+            let mut last_line = Instant::now();
+            while !cancel.load(Ordering::Relaxed) {
+                let event = match lines.recv_timeout(CANCEL_POLL) {
+                    Ok(line) => {
+                        last_line = Instant::now();
+                        match line? {
+                            Some(line) => parser.push_line(&line),
+                            None => match parser.flush() {
+                                Some(event) => Some(event),
+                                None => break,
+                            },
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) if last_line.elapsed() > STREAM_TIMEOUT => {
+                        return Err(ureq::Error::Timeout(ureq::Timeout::RecvBody));
+                    }
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                match event {
+                    Some(SseEvent::Data(data)) => {
+                        let text = accumulator
+                            .add(&data)
+                            .map_err(|e| ureq::Error::Other(e.into()))?;
+                        if !text.is_empty() {
+                            on_token(&text);
+                        }
+                    }
+                    Some(SseEvent::Done) => break,
+                    None => {}
+                }
+            }
+            log::info!("Got the streamed llm response!");
+            Ok(accumulator.finish())
+        })
+    }
+
+    fn with_tool_fallback(
+        &self,
+        mut send: impl FnMut(bool) -> Result<LlmResponse, ureq::Error>,
     ) -> Result<LlmResponse, ureq::Error> {
-        let body = json!({
-            "temperature": 0.0,
-            "model": "deepseek/deepseek-chat-v3-0324:free",
-            "messages": self.build_messages_json(messages)
+        if !self.is_configured() {
+            return Err(ureq::Error::Other("No API key configured".into()));
+        }
+        match send(true) {
+            Err(ureq::Error::StatusCode(400 | 404)) => {
+                log::info!("Model does not support tools, falling back to rq: commands");
+                send(false)
+            }
+            result => result,
+        }
+    }
+
+    fn request_body(&self, messages: &[Message], params: &ModelParams, tools: bool) -> Value {
+        let budget = params
+            .prompt_budget()
+            .saturating_sub(self.overhead_tokens(tools));
+        let messages = fit_to_context(messages, budget);
+        let mut body = json!({
+            "temperature": params.temperature,
+            "model": params.model,
+            "messages": self.build_messages_json(&messages, tools)
         });
+        if let Some(top_p) = params.top_p {
+            body["top_p"] = json!(top_p);
+        }
+        if let Some(max_tokens) = params.max_tokens {
+            body["max_tokens"] = json!(max_tokens);
+        }
+        if tools {
+            body["tools"] = tool_definitions();
+        }
+        body
+    }
 
+    fn post(&self, body: Value, cancel: &AtomicBool) -> Result<Response<Body>, ureq::Error> {
         // We want to parse throught the error message. Rate limiting probably means that the tokens are all used up!
         // We can take "backup" keys so that it doesnt end it just switches keys.
-        let mut status = 429;
-        while status == 429 {
+        loop {
             let body_clone = body.clone(); // Clone the body for each request attempt
-            if let Ok(mut response) = self
-                .agent
-                .post(&self.url)
-                .header(
-                    "Authorization",
-                    format!("Bearer {}", self.token.as_ref().unwrap()),
-                )
+            match self
+                .authorize(self.agent.post(&self.url))
                 .send_json(body_clone)
             {
-                if response.status() == 200 {
-                    let res: LlmResponse = response.body_mut().read_json()?;
-                    log::info!("Got the llm response!");
-                    return Ok(res);
-                } else if response.status() == 429 {
+                Err(ureq::Error::StatusCode(429)) if !cancel.load(Ordering::Relaxed) => {
                     log::info!("Timeout!");
                     std::thread::sleep(std::time::Duration::from_secs(5));
-                } else {
-                    log::info!("Error");
-                    status = 0;
                 }
+                Err(e) => {
+                    log::info!("Error: {e}");
+                    return Err(e);
+                }
+                response => return response,
+            }
+        }
+    }
+
+    /// Roughly what the system prompt and tool definitions take up in every request.
+    pub fn overhead_tokens(&self, tools: bool) -> u32 {
+        if tools {
+            estimate_tokens(&self.prompt) + estimate_tokens(&tool_definitions().to_string())
+        } else {
+            estimate_tokens(&self.prompt) + estimate_tokens(RQ_PROMPT)
+        }
+    }
+
+    // Local servers are often run without a key.
+    fn authorize<B>(&self, request: ureq::RequestBuilder<B>) -> ureq::RequestBuilder<B> {
+        match self.token.as_ref().filter(|_| self.has_token()) {
+            Some(token) => request.header("Authorization", format!("Bearer {token}")),
+            None => request,
+        }
+    }
+
+    /// The `/models` endpoint next to the chat completions one.
+    fn models_url(&self) -> String {
+        let base = self
+            .url
+            .trim_end_matches('/')
+            .trim_end_matches("/chat/completions");
+        format!("{base}/models")
+    }
+}
+
+// A stalled server blocks a read until it sends something, so the stream is read on its own
+// thread and the request checks for a cancel in between.
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+// Long enough for slow models to start answering.
+const STREAM_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Reads `reader` line by line on another thread. `None` marks the end of the stream. The thread
+/// stops once the receiver is dropped and the next line arrives.
+fn read_lines(reader: impl Read + Send + 'static) -> Receiver<std::io::Result<Option<String>>> {
+    let (sender, receiver) = channel();
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        loop {
+            let mut line = String::new();
+            let result = match reader.read_line(&mut line) {
+                Ok(0) => Ok(None),
+                Ok(_) => Ok(Some(line)),
+                Err(e) => Err(e),
+            };
+            let more = matches!(result, Ok(Some(_)));
+            if sender.send(result).is_err() || !more {
+                break;
             }
         }
+    });
+    receiver
+}
+
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<ModelInfo>,
+}
+
+impl ChatBackend for OpenrouterClient {
+    fn chat(&self, messages: &[Message], params: &ModelParams) -> Result<LlmResponse, ureq::Error> {
+        self.send_openrouter_chat(messages, params)
+    }
+
+    fn chat_stream(
+        &self,
+        messages: &[Message],
+        params: &ModelParams,
+        on_token: &mut dyn FnMut(&str),
+        cancel: &AtomicBool,
+    ) -> Result<LlmResponse, ureq::Error> {
+        self.send_openrouter_chat_stream(messages, params, on_token, cancel)
+    }
+
+    fn list_models(&self) -> Result<Vec<ModelInfo>, ureq::Error> {
+        let mut response = self.authorize(self.agent.get(self.models_url())).call()?;
+        let list: ModelList = response.body_mut().read_json()?;
+        Ok(list.data)
+    }
+
+    fn overhead_tokens(&self) -> u32 {
+        OpenrouterClient::overhead_tokens(self, true)
+    }
+
+    fn is_configured(&self) -> bool {
+        // Only OpenRouter is known to need a key.
+        self.has_token() || !self.url.contains("openrouter.ai")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::{TcpListener, TcpStream},
+        sync::mpsc::Sender,
+        thread,
+    };
+
+    use super::*;
+
+    /// Reads one request, answers with `chunks` as a chunked event stream, then waits for `hold`
+    /// to be dropped before closing, like a server that stops sending.
+    fn stub_server(chunks: Vec<String>, hold: Option<Receiver<()>>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/api/v1/chat/completions",
+            listener.local_addr().unwrap()
+        );
+        let (sender, requests) = channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            answer(stream, chunks, hold, sender);
+        });
+        (url, requests)
+    }
+
+    fn answer(
+        mut stream: TcpStream,
+        chunks: Vec<String>,
+        hold: Option<Receiver<()>>,
+        requests: Sender<String>,
+    ) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                length = value.trim().parse().unwrap();
+            }
+            if line.trim().is_empty() {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        requests.send(String::from_utf8(body).unwrap()).unwrap();
+
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nTransfer-Encoding: chunked\r\n\r\n"
+        )
+        .unwrap();
+        for chunk in chunks {
+            write!(stream, "{:x}\r\n{chunk}\r\n", chunk.len()).unwrap();
+            stream.flush().unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        if let Some(hold) = hold {
+            let _ = hold.recv();
+        }
+        let _ = write!(stream, "0\r\n\r\n");
+    }
+
+    fn client(url: String) -> OpenrouterClient {
+        OpenrouterClient {
+            url,
+            ..Default::default()
+        }
+    }
+
+    fn user(text: &str) -> Vec<Message> {
+        vec![Message {
+            role: "user".to_string(),
+            content: text.to_string(),
+            ..Default::default()
+        }]
+    }
+
+    #[test]
+    fn streams_deltas_and_tool_calls_until_done() {
+        let chunks = [
+            ": OPENROUTER PROCESSING\n\n",
+            "data: {\"id\":\"gen-1\",\"model\":\"m\",\"choices\":[{\"delta\":{\"content\":\"Look\"}}]}\n",
+            "\ndata: {\"choices\":[{\"delta\":{\"content\":\"ing\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"id\":\"c1\",\"function\":{\"name\":\"nearby_features\",\"arguments\":\"{\\\"radius\\\":\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"200}\"}}]},\"finish_reason\":\"tool_calls\"}]}\n\n",
+            "data: [DONE]\n\n",
+            // Anything after `[DONE]` is ignored.
+            "data: {\"choices\":[{\"delta\":{\"content\":\"late\"}}]}\n\n",
+        ];
+        let (url, requests) = stub_server(chunks.map(str::to_string).to_vec(), None);
+
+        let mut tokens = Vec::new();
+        let response = client(url)
+            .send_openrouter_chat_stream(
+                &user("What is near?"),
+                &ModelParams::default(),
+                &mut |token| tokens.push(token.to_string()),
+                &AtomicBool::new(false),
+            )
+            .unwrap();
+
+        let request: Value = serde_json::from_str(&requests.recv().unwrap()).unwrap();
+        assert_eq!(request["stream"], json!(true));
+        assert!(request["tools"].is_array());
+        assert_eq!(tokens, ["Look", "ing"]);
+        let choice = &response.choices[0];
+        assert_eq!(choice.message.content, "Looking");
+        assert_eq!(choice.finish_reason, "tool_calls");
+        assert_eq!(
+            choice.message.tool_calls[0].function.name,
+            "nearby_features"
+        );
+        assert_eq!(
+            choice.message.tool_calls[0].function.arguments,
+            "{\"radius\":200}"
+        );
+    }
 
-        Err(ureq::Error::ConnectionFailed)
+    #[test]
+    fn cancel_stops_a_stalled_stream() {
+        let (hold, held) = channel();
+        let chunks = vec!["data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n".to_string()];
+        let (url, _requests) = stub_server(chunks, Some(held));
+
+        let cancel = AtomicBool::new(false);
+        let started = Instant::now();
+        let response = thread::scope(|scope| {
+            // The server sends nothing after the first chunk, so the cancel has to get through
+            // while the read is blocked.
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(300));
+                cancel.store(true, Ordering::Relaxed);
+            });
+            client(url).send_openrouter_chat_stream(
+                &user("Hello"),
+                &ModelParams::default(),
+                &mut |_| {},
+                &cancel,
+            )
+        })
+        .unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(response.choices[0].message.content, "Hi");
+        drop(hold);
     }
 }
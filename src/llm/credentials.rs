@@ -0,0 +1,70 @@
+use std::{fs, io, path::PathBuf};
+
+use platform_dirs::AppDirs;
+use serde::{Deserialize, Serialize};
+
+pub const OPENROUTER_URL: &str = "https://openrouter.ai/api/v1/chat/completions";
+
+/// The API key and endpoint for the chat, kept in the user's config directory rather than the
+/// workspace files so they are never shared along with a workspace.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LlmCredentials {
+    pub url: String,
+    #[serde(default)]
+    pub api_key: String,
+}
+
+impl Default for LlmCredentials {
+    fn default() -> Self {
+        LlmCredentials {
+            url: OPENROUTER_URL.to_string(),
+            api_key: String::new(),
+        }
+    }
+}
+
+impl LlmCredentials {
+    fn path() -> Option<PathBuf> {
+        AppDirs::new(Some("Map-rs"), false).map(|dirs| dirs.config_dir.join("llm.json"))
+    }
+
+    /// The saved credentials, or the defaults with no key if there are none or they can't be read.
+    pub fn load() -> Self {
+        Self::path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<(), io::Error> {
+        let Some(path) = Self::path() else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No config directory for this platform",
+            ));
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let data = serde_json::to_string_pretty(self)?;
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // Only the owner may read the key. On Windows the config directory is already private
+        // to the user.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+            // The mode only applies when the file is created.
+            if path.exists() {
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+            }
+        }
+        io::Write::write_all(&mut options.open(&path)?, data.as_bytes())
+    }
+
+    pub fn has_key(&self) -> bool {
+        !self.api_key.trim().is_empty()
+    }
+}
@@ -10,8 +10,13 @@
 //! - Generate automated reports and summaries of spatial analysis
 //!
 //! ## Sub-modules
+//! - `backend`: The `ChatBackend` trait, model parameters and model list entries
 //! - `client`: OpenRouter API client implementation
+//! - `credentials`: API key and endpoint, stored in the user's config directory
 //! - `openrouter_types`: Request/response data structures for API communication
+//! - `stream`: Server-sent events parsing for streamed replies
+//! - `tokens`: Token estimates, fitting the conversation to the context and usage totals
+//! - `tools`: JSON Schemas of the workspace queries the model can call
 //!
 //! ## Key Features
 //! - Support for multiple LLM providers through OpenRouter
@@ -24,13 +29,25 @@
 //! The LLM client can analyze map features, answer questions about geographic data,
 //! and provide insights based on spatial relationships and attribute data.
 
+mod backend;
 mod client;
+mod credentials;
 mod openrouter_types;
+mod stream;
+mod tokens;
+mod tools;
 
 use std::fmt::Display;
 
+pub use backend::{
+    ChatBackend, DEFAULT_CONTEXT_LENGTH, DEFAULT_MODEL, ModelInfo, ModelParams, ModelPricing,
+};
+pub use credentials::{LlmCredentials, OPENROUTER_URL};
 pub use openrouter_types::*;
 use serde::Serialize;
+pub use stream::{SseEvent, SseParser, StreamAccumulator};
+pub use tokens::{ChatUsage, estimate_tokens, fit_to_context, messages_tokens};
+pub use tools::tool_definitions;
 use ureq::Agent;
 
 use crate::llm::client::LLM_PROMPT;
@@ -69,6 +86,22 @@ impl OpenrouterClient {
         }
     }
 
+    /// A client using the saved credentials, if any.
+    pub fn load() -> Self {
+        OpenrouterClient::from_credentials(&LlmCredentials::load())
+    }
+
+    pub fn from_credentials(credentials: &LlmCredentials) -> Self {
+        let token = Some(credentials.api_key.clone()).filter(|_| credentials.has_key());
+        OpenrouterClient::new(&credentials.url, token)
+    }
+
+    pub fn has_token(&self) -> bool {
+        self.token
+            .as_ref()
+            .is_some_and(|token| !token.trim().is_empty())
+    }
+
     pub fn set_url(&mut self, url: impl Display) {
         self.url = url.to_string();
     }
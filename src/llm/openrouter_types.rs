@@ -1,8 +1,8 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", default)]
 pub struct LlmResponse {
     pub id: String,
     pub provider: String,
@@ -14,7 +14,7 @@ pub struct LlmResponse {
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", default)]
 pub struct Choice {
     pub logprobs: Value,
     #[serde(rename = "finish_reason")]
@@ -29,13 +29,65 @@ pub struct Choice {
 #[serde(rename_all = "camelCase")]
 pub struct Message {
     pub role: String,
+    // Null when the assistant only calls tools.
+    #[serde(default, deserialize_with = "null_as_empty")]
     pub content: String,
+    #[serde(default)]
     pub refusal: Value,
+    #[serde(default)]
     pub reasoning: Value,
+    #[serde(default, rename = "tool_calls", skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// Set on `tool` messages, the id of the call this is the result of.
+    #[serde(
+        default,
+        rename = "tool_call_id",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tool_call_id: Option<String>,
+}
+
+impl Message {
+    /// Whether it is part of the conversation the user sees, rather than tool calls and results.
+    pub fn is_chat(&self) -> bool {
+        matches!(self.role.as_str(), "user" | "assistant") && !self.content.trim().is_empty()
+    }
+
+    /// The text as shown to the user. Questions are sent with the map context in front.
+    pub fn display_text(&self) -> &str {
+        self.content
+            .split_once("USER QUERY: ")
+            .filter(|_| self.role == "user")
+            .map_or(&self.content, |(_, query)| query)
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON encoded arguments, as sent by the model.
+    #[serde(default)]
+    pub arguments: String,
+}
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+fn null_as_empty<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
 pub struct Usage {
     #[serde(rename = "prompt_tokens")]
     pub prompt_tokens: i64,
@@ -0,0 +1,284 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::{Choice, LlmResponse, Message, ToolCall, Usage};
+
+// https://openrouter.ai/docs/api-reference/streaming
+// Kept free of any IO so the parsing can be driven line by line from anywhere.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SseEvent {
+    Data(String),
+    /// The `[DONE]` sent after the last chunk.
+    Done,
+}
+
+/// Collects the lines of a server-sent events stream into events.
+#[derive(Default)]
+pub struct SseParser {
+    data: Vec<String>,
+}
+
+impl SseParser {
+    /// Feeds one line, without or with its line ending. Returns an event once the blank line
+    /// ending it arrives.
+    pub fn push_line(&mut self, line: &str) -> Option<SseEvent> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            return self.flush();
+        }
+        // Lines starting with a colon are comments, OpenRouter sends them as keep alives.
+        if let Some(data) = line.strip_prefix("data:") {
+            self.data
+                .push(data.strip_prefix(' ').unwrap_or(data).to_string());
+        }
+        None
+    }
+
+    /// The event still being collected, for a stream that ended without a final blank line.
+    pub fn flush(&mut self) -> Option<SseEvent> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let data = std::mem::take(&mut self.data).join("\n");
+        if data == "[DONE]" {
+            Some(SseEvent::Done)
+        } else {
+            Some(SseEvent::Data(data))
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct StreamChunk {
+    id: String,
+    provider: String,
+    model: String,
+    created: i64,
+    choices: Vec<StreamChoice>,
+    usage: Option<Usage>,
+    error: Option<Value>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct StreamChoice {
+    delta: Delta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Delta {
+    content: Option<String>,
+    tool_calls: Vec<ToolCallDelta>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ToolCallDelta {
+    index: usize,
+    id: Option<String>,
+    function: FunctionDelta,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct FunctionDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+/// Builds the full response out of the chunks of a streamed completion. Tool calls arrive in
+/// pieces keyed by their index, the arguments a few characters at a time.
+pub struct StreamAccumulator {
+    response: LlmResponse,
+}
+
+impl Default for StreamAccumulator {
+    fn default() -> Self {
+        let mut response = LlmResponse::default();
+        response.choices.push(Choice {
+            message: Message {
+                role: "assistant".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        StreamAccumulator { response }
+    }
+}
+
+impl StreamAccumulator {
+    /// Adds the data of one event, returning the text it added to the reply.
+    pub fn add(&mut self, data: &str) -> Result<String, String> {
+        let chunk: StreamChunk = serde_json::from_str(data).map_err(|e| e.to_string())?;
+        if let Some(error) = chunk.error {
+            return Err(error
+                .get("message")
+                .and_then(|m| m.as_str())
+                .map(str::to_string)
+                .unwrap_or_else(|| error.to_string()));
+        }
+        if !chunk.id.is_empty() {
+            self.response.id = chunk.id;
+            self.response.provider = chunk.provider;
+            self.response.model = chunk.model;
+            self.response.created = chunk.created;
+        }
+        if let Some(usage) = chunk.usage {
+            self.response.usage = usage;
+        }
+
+        let mut text = String::new();
+        let choice = &mut self.response.choices[0];
+        for delta in chunk.choices {
+            if let Some(reason) = delta.finish_reason {
+                choice.finish_reason = reason;
+            }
+            if let Some(content) = delta.delta.content {
+                text.push_str(&content);
+            }
+            for call in delta.delta.tool_calls {
+                let calls = &mut choice.message.tool_calls;
+                if calls.len() <= call.index {
+                    calls.resize_with(call.index + 1, || ToolCall {
+                        kind: "function".to_string(),
+                        ..Default::default()
+                    });
+                }
+                let existing = &mut calls[call.index];
+                if let Some(id) = call.id {
+                    existing.id = id;
+                }
+                if let Some(name) = call.function.name {
+                    existing.function.name.push_str(&name);
+                }
+                if let Some(arguments) = call.function.arguments {
+                    existing.function.arguments.push_str(&arguments);
+                }
+            }
+        }
+        choice.message.content.push_str(&text);
+        Ok(text)
+    }
+
+    pub fn finish(self) -> LlmResponse {
+        self.response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(lines: &[&str]) -> Vec<SseEvent> {
+        let mut parser = SseParser::default();
+        let mut events: Vec<SseEvent> = lines.iter().filter_map(|l| parser.push_line(l)).collect();
+        events.extend(parser.flush());
+        events
+    }
+
+    #[test]
+    fn events_end_at_blank_lines() {
+        assert_eq!(
+            events(&["data: {\"a\":1}\n", "\n", "data:{\"b\":2}\r\n", "\r\n"]),
+            [
+                SseEvent::Data("{\"a\":1}".to_string()),
+                SseEvent::Data("{\"b\":2}".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn data_lines_are_joined() {
+        assert_eq!(
+            events(&["data: one", "data: two", ""]),
+            [SseEvent::Data("one\ntwo".to_string())]
+        );
+    }
+
+    #[test]
+    fn comments_and_other_fields_are_skipped() {
+        assert_eq!(
+            events(&[
+                ": OPENROUTER PROCESSING",
+                "",
+                "event: message",
+                "id: 3",
+                "data: x",
+                ""
+            ]),
+            [SseEvent::Data("x".to_string())]
+        );
+    }
+
+    #[test]
+    fn done_and_unterminated_events() {
+        assert_eq!(
+            events(&["data: x", "", "data: [DONE]", ""]),
+            [SseEvent::Data("x".to_string()), SseEvent::Done]
+        );
+        assert_eq!(
+            events(&["data: last"]),
+            [SseEvent::Data("last".to_string())]
+        );
+        assert!(events(&["", "\n"]).is_empty());
+    }
+
+    #[test]
+    fn content_deltas_add_up() {
+        let mut accumulator = StreamAccumulator::default();
+        let first = r#"{"id":"gen-1","provider":"P","model":"m","created":7,"choices":[{"delta":{"role":"assistant","content":"Hel"}}]}"#;
+        assert_eq!(accumulator.add(first).unwrap(), "Hel");
+        let last =
+            r#"{"id":"gen-1","choices":[{"delta":{"content":"lo"},"finish_reason":"stop"}]}"#;
+        assert_eq!(accumulator.add(last).unwrap(), "lo");
+        let usage = r#"{"id":"gen-1","choices":[],"usage":{"prompt_tokens":3,"completion_tokens":2,"total_tokens":5}}"#;
+        assert_eq!(accumulator.add(usage).unwrap(), "");
+
+        let response = accumulator.finish();
+        assert_eq!(response.id, "gen-1");
+        assert_eq!(response.usage.total_tokens, 5);
+        let choice = &response.choices[0];
+        assert_eq!(choice.message.role, "assistant");
+        assert_eq!(choice.message.content, "Hello");
+        assert_eq!(choice.finish_reason, "stop");
+    }
+
+    #[test]
+    fn tool_call_fragments_are_joined_by_index() {
+        let mut accumulator = StreamAccumulator::default();
+        for chunk in [
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"id":"call_a","type":"function","function":{"name":"nearby_features","arguments":""}}]}}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"rad"}}]}}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":1,"id":"call_b","function":{"name":"count_features","arguments":"{}"}}]}}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"ius\":5}"}}]}}]}"#,
+            r#"{"choices":[{"delta":{},"finish_reason":"tool_calls"}]}"#,
+        ] {
+            assert_eq!(accumulator.add(chunk).unwrap(), "");
+        }
+        let response = accumulator.finish();
+        let calls = &response.choices[0].message.tool_calls;
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].id, "call_a");
+        assert_eq!(calls[0].kind, "function");
+        assert_eq!(calls[0].function.name, "nearby_features");
+        assert_eq!(calls[0].function.arguments, r#"{"radius":5}"#);
+        assert_eq!(calls[1].function.name, "count_features");
+        assert_eq!(response.choices[0].finish_reason, "tool_calls");
+    }
+
+    #[test]
+    fn errors_in_the_stream() {
+        let mut accumulator = StreamAccumulator::default();
+        assert_eq!(
+            accumulator
+                .add(r#"{"error":{"code":502,"message":"Provider returned error"}}"#)
+                .unwrap_err(),
+            "Provider returned error"
+        );
+        assert!(accumulator.add("not json").is_err());
+    }
+}
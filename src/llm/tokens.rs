@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Message, ModelPricing, Usage};
+
+// Earlier turns are summarised by their question and the start of the answer.
+const SUMMARY_CHARS: usize = 200;
+
+/// A rough token count, about four characters a token for English text and JSON. Good enough to
+/// decide what to leave out, the real count comes back in `LlmResponse.usage`.
+pub fn estimate_tokens(text: &str) -> u32 {
+    (text.chars().count() as u32).div_ceil(4)
+}
+
+pub fn message_tokens(message: &Message) -> u32 {
+    // Each message has a few tokens of framing around it.
+    let calls: u32 = message
+        .tool_calls
+        .iter()
+        .map(|call| {
+            estimate_tokens(&call.function.name) + estimate_tokens(&call.function.arguments)
+        })
+        .sum();
+    4 + estimate_tokens(&message.content) + calls
+}
+
+pub fn messages_tokens(messages: &[Message]) -> u32 {
+    messages.iter().map(message_tokens).sum()
+}
+
+/// The messages trimmed to fit in `budget` tokens. The oldest turns are replaced by a short
+/// summary of what was asked and answered. Turns are only cut where the user asks something, so
+/// tool calls always stay with their results, and the latest turn is always kept.
+pub fn fit_to_context(messages: &[Message], budget: u32) -> Vec<Message> {
+    if messages_tokens(messages) <= budget {
+        return messages.to_vec();
+    }
+    let turn_starts: Vec<usize> = messages
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| m.role == "user")
+        .map(|(i, _)| i)
+        .collect();
+    let cut = turn_starts
+        .iter()
+        .copied()
+        .find(|&start| messages_tokens(&messages[start..]) <= budget)
+        .or(turn_starts.last().copied())
+        .unwrap_or(0);
+
+    let room = budget.saturating_sub(messages_tokens(&messages[cut..]));
+    let mut kept: Vec<Message> = summarise(&messages[..cut], room).into_iter().collect();
+    kept.extend_from_slice(&messages[cut..]);
+    kept
+}
+
+/// One line per question and answer, dropping the oldest lines until it fits in `room` tokens.
+fn summarise(messages: &[Message], room: u32) -> Option<Message> {
+    let mut lines: Vec<String> = messages
+        .iter()
+        .filter(|m| m.is_chat())
+        .map(|message| {
+            let who = if message.role == "user" {
+                "User"
+            } else {
+                "Assistant"
+            };
+            let text = message
+                .display_text()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ");
+            let short: String = text.chars().take(SUMMARY_CHARS).collect();
+            let ellipsis = if short.len() < text.len() { "…" } else { "" };
+            format!("- {who}: {short}{ellipsis}")
+        })
+        .collect();
+    loop {
+        if lines.is_empty() {
+            return None;
+        }
+        let summary = Message {
+            role: "system".to_string(),
+            content: format!(
+                "Earlier in this conversation, left out to save space:\n{}",
+                lines.join("\n")
+            ),
+            ..Default::default()
+        };
+        if message_tokens(&summary) <= room {
+            return Some(summary);
+        }
+        lines.remove(0);
+    }
+}
+
+/// Tokens used and what they cost, added up over a workspace's conversation.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ChatUsage {
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    /// In US dollars, only counting replies from models with a known price.
+    pub cost: f64,
+    /// The size of the last request, how full the context is.
+    pub last_prompt_tokens: i64,
+}
+
+impl ChatUsage {
+    pub fn add(&mut self, usage: &Usage, pricing: Option<&ModelPricing>) {
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+        if let Some(pricing) = pricing {
+            self.cost += pricing.cost(usage.prompt_tokens, usage.completion_tokens);
+        }
+        if usage.prompt_tokens > 0 {
+            self.last_prompt_tokens = usage.prompt_tokens;
+        }
+    }
+
+    pub fn merge(&mut self, other: &ChatUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cost += other.cost;
+        if other.last_prompt_tokens > 0 {
+            self.last_prompt_tokens = other.last_prompt_tokens;
+        }
+    }
+
+    pub fn total_tokens(&self) -> i64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
@@ -0,0 +1,264 @@
+use serde_json::{Value, json};
+
+// Each tool matches one of the workspace queries in `workspace/commands.rs`, the names are what
+// `Workspace::run_command` dispatches on.
+
+fn point_schema(description: &str) -> Value {
+    json!({
+        "type": "object",
+        "description": description,
+        "properties": {
+            "lat": { "type": "number" },
+            "lon": { "type": "number" }
+        },
+        "required": ["lat", "lon"]
+    })
+}
+
+/// Adds the arguments choosing what is sent of each feature.
+fn formatted(mut properties: Value) -> Value {
+    properties["fields"] = json!({
+        "type": "array",
+        "items": { "type": "string" },
+        "description": "Tag keys to include, leave out for the main tags"
+    });
+    properties["geometry"] = json!({
+        "type": "boolean",
+        "description": "Include the simplified outline as [lat, lon] pairs"
+    });
+    properties["simplify"] = json!({
+        "type": "number",
+        "description": "Outline simplification tolerance in meters, 5 by default"
+    });
+    properties
+}
+
+/// Adds `offset` and `limit` to the properties of a tool that lists features.
+fn paged(properties: Value) -> Value {
+    let mut properties = formatted(properties);
+    properties["offset"] = json!({
+        "type": "integer",
+        "description": "How many results to skip, for the next page"
+    });
+    properties["limit"] = json!({
+        "type": "integer",
+        "description": "Most features to return, 25 by default"
+    });
+    properties
+}
+
+fn tool(name: &str, description: &str, properties: Value, required: &[&str]) -> Value {
+    json!({
+        "type": "function",
+        "function": {
+            "name": name,
+            "description": description,
+            "parameters": {
+                "type": "object",
+                "properties": properties,
+                "required": required
+            }
+        }
+    })
+}
+
+/// The workspace queries and map actions as OpenAI compatible `tools`. The actions are
+/// `MapAction`s, which wait for the user to confirm them.
+pub fn tool_definitions() -> Value {
+    json!([
+        tool(
+            "workspace_info",
+            "General workspace info and stats, including the area, selection and a summary of the features.",
+            json!({}),
+            &[],
+        ),
+        tool(
+            "count_features",
+            "Count every feature loaded in the workspace.",
+            json!({}),
+            &[],
+        ),
+        tool(
+            "summarize_features",
+            "Count the features in the workspace and how often each tag is used.",
+            json!({}),
+            &[],
+        ),
+        tool(
+            "aggregate_features",
+            "Group the features with a tag by its value, with the count, total and average area, total line length and density per km² of the selection for each group. For example key \"building\" for building areas, or \"highway\" for road length by type.",
+            json!({
+                "key": { "type": "string", "description": "Tag key to group by" },
+                "value": { "type": "string", "description": "Only count features with this value of the tag" },
+                "bbox": {
+                    "type": "object",
+                    "description": "Only count features in this bounding box",
+                    "properties": {
+                        "min_lat": { "type": "number" },
+                        "min_lon": { "type": "number" },
+                        "max_lat": { "type": "number" },
+                        "max_lon": { "type": "number" }
+                    },
+                    "required": ["min_lat", "min_lon", "max_lat", "max_lon"]
+                },
+                "sort_by": {
+                    "type": "string",
+                    "enum": ["count", "area", "length", "value"],
+                    "description": "Order of the groups, count by default"
+                },
+                "limit": { "type": "integer", "description": "Most groups to return, the rest are added up" }
+            }),
+            &["key"],
+        ),
+        tool(
+            "filter_features",
+            "Features in every layer matching a tag filter, optionally only inside a bbox, near a point or inside a polygon. Filters: key (has the tag), key=value, key!=value, key~regex, key!~regex, key<n, key<=n, key>n, key>=n, combined with and, or, not and brackets. Quote values with spaces. Example: amenity=school or (building and building:levels>4)",
+            paged(json!({
+                "expression": { "type": "string", "description": "The tag filter" },
+                "bbox": {
+                    "type": "object",
+                    "description": "Only features in this bounding box",
+                    "properties": {
+                        "min_lat": { "type": "number" },
+                        "min_lon": { "type": "number" },
+                        "max_lat": { "type": "number" },
+                        "max_lon": { "type": "number" }
+                    },
+                    "required": ["min_lat", "min_lon", "max_lat", "max_lon"]
+                },
+                "point": point_schema("Only features near this point, with radius"),
+                "radius": { "type": "number", "description": "Radius around point in meters" },
+                "polygon": {
+                    "type": "array",
+                    "description": "Only features inside this polygon",
+                    "items": point_schema("Vertex"),
+                    "minItems": 3
+                }
+            })),
+            &["expression"],
+        ),
+        tool(
+            "nearby_features",
+            "Features whose centre is within a radius of a point. Results have the id, main tags, centroid and area or length.",
+            paged(json!({
+                "point": point_schema("Centre of the search"),
+                "radius": { "type": "number", "description": "Radius in meters" }
+            })),
+            &["point", "radius"],
+        ),
+        tool(
+            "nearest_feature",
+            "The feature closest to a point.",
+            formatted(json!({ "point": point_schema("Point to search from") })),
+            &["point"],
+        ),
+        tool(
+            "distance",
+            "Distance in meters between two points.",
+            json!({
+                "from": point_schema("First point"),
+                "to": point_schema("Second point")
+            }),
+            &["from", "to"],
+        ),
+        tool(
+            "features_in_bbox",
+            "Features inside a bounding box. Results have the id, main tags, centroid and area or length.",
+            paged(json!({
+                "min_lat": { "type": "number" },
+                "min_lon": { "type": "number" },
+                "max_lat": { "type": "number" },
+                "max_lon": { "type": "number" }
+            })),
+            &["min_lat", "min_lon", "max_lat", "max_lon"],
+        ),
+        tool(
+            "features_in_polygon",
+            "Features whose centre is inside a polygon. Results have the id, main tags, centroid and area or length.",
+            paged(json!({
+                "points": {
+                    "type": "array",
+                    "description": "Polygon vertices in order",
+                    "items": point_schema("Vertex"),
+                    "minItems": 3
+                }
+            })),
+            &["points"],
+        ),
+        tool(
+            "feature_details",
+            "Full details of a feature by its id, with every tag.",
+            formatted(json!({ "id": { "type": "string" } })),
+            &["id"],
+        ),
+        tool(
+            "feature_tags",
+            "The tags of a feature by its id.",
+            json!({ "id": { "type": "string" } }),
+            &["id"],
+        ),
+        tool(
+            "selected_features",
+            "Full details of the features the user has selected on the map. Use this when the user says \"selected\", \"these\" or \"this\".",
+            paged(json!({})),
+            &[],
+        ),
+        tool(
+            "highlight_features",
+            "Action: select features on the map by id so they stand out. The user confirms it first.",
+            json!({
+                "ids": { "type": "array", "items": { "type": "string" } }
+            }),
+            &["ids"],
+        ),
+        tool(
+            "zoom_to_bbox",
+            "Action: move the map to show a bounding box. The user confirms it first.",
+            json!({
+                "min_lat": { "type": "number" },
+                "min_lon": { "type": "number" },
+                "max_lat": { "type": "number" },
+                "max_lon": { "type": "number" }
+            }),
+            &["min_lat", "min_lon", "max_lat", "max_lon"],
+        ),
+        tool(
+            "colour_features",
+            "Action: draw every feature with a tag value in a colour, for example amenity=school in red. The user confirms it first.",
+            json!({
+                "key": { "type": "string", "description": "Tag key" },
+                "value": { "type": "string", "description": "Tag value" },
+                "colour": { "type": "string", "description": "A colour name or #rrggbb" }
+            }),
+            &["key", "value", "colour"],
+        ),
+        tool(
+            "drop_pin",
+            "Action: add a pin with a note to the map. The user confirms it first.",
+            json!({
+                "point": point_schema("Where to put the pin"),
+                "title": { "type": "string" },
+                "note": { "type": "string" }
+            }),
+            &["point", "title"],
+        ),
+        tool(
+            "query_overpass",
+            "Action: load features with these tags from OpenStreetMap for the workspace area as a new layer, for when they aren't loaded yet. The user confirms it first.",
+            json!({
+                "tags": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "key": { "type": "string" },
+                            "value": { "type": "string", "description": "Leave out for any value" }
+                        },
+                        "required": ["key"]
+                    }
+                }
+            }),
+            &["tags"],
+        ),
+    ])
+}
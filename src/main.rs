@@ -14,6 +14,7 @@
 //! - WorkspacePlugin: Core workspace and data management
 //! - CameraSystemPlugin: Map navigation and camera controls
 //! - InteractionSystemPlugin: User input handling
+//! - KeybindingsPlugin: Configurable keyboard shortcuts
 //! - RenderPlugin: GeoJSON and geographic data rendering
 //! - SettingsPlugin: Application configuration
 //! - ToolsPlugin: Interactive map tools
@@ -36,6 +37,7 @@ use debug::DebugPlugin;
 use egui_extras::install_image_loaders;
 use geojson::RenderPlugin;
 use interaction::InteractionSystemPlugin;
+use keybindings::KeybindingsPlugin;
 use settings::SettingsPlugin;
 use tools::ToolsPlugin;
 use workspace::WorkspacePlugin;
@@ -44,6 +46,7 @@ pub mod camera;
 pub mod debug;
 pub mod geojson;
 pub mod interaction;
+pub mod keybindings;
 pub mod llm;
 pub mod overpass;
 pub mod settings;
@@ -81,6 +84,7 @@ fn main() {
         })
         .add_plugins(RenderPlugin)
         .add_plugins(SettingsPlugin)
+        .add_plugins(KeybindingsPlugin)
         .add_plugins(ToolsPlugin)
         .add_systems(Update, absorb_egui_inputs)
         .run();
@@ -0,0 +1,155 @@
+use bevy::math::Vec2;
+use bevy_map_viewer::Coord;
+use serde_json::Value;
+
+use crate::{geojson::point_in_ring, workspace::SelectionPolygon};
+
+// Overpass gives every relation an area with this offset added to its id.
+const RELATION_AREA_OFFSET: u64 = 3_600_000_000;
+
+/// An administrative boundary found by name, before its geometry has been fetched.
+#[derive(Clone, Debug)]
+pub struct BoundaryCandidate {
+    pub id: u64,
+    pub name: String,
+    pub admin_level: Option<String>,
+}
+
+impl BoundaryCandidate {
+    pub fn area_id(&self) -> u64 {
+        RELATION_AREA_OFFSET + self.id
+    }
+}
+
+/// Finds administrative boundary relations with exactly this name.
+pub fn build_boundary_search_query(name: &str) -> String {
+    let name = name.replace('\\', "\\\\").replace('"', "\\\"");
+    format!(
+        r#"[out:json][timeout:25];
+        relation["boundary"="administrative"]["name"="{name}"];
+        out tags;"#
+    )
+}
+
+pub fn build_boundary_geometry_query(id: u64) -> String {
+    format!("[out:json][timeout:60];\nrelation({id});\nout geom;")
+}
+
+pub fn parse_boundary_candidates(data: &str) -> Vec<BoundaryCandidate> {
+    let Ok(response) = serde_json::from_str::<Value>(data) else {
+        return Vec::new();
+    };
+    let Some(elements) = response.get("elements").and_then(|e| e.as_array()) else {
+        return Vec::new();
+    };
+    let mut candidates: Vec<BoundaryCandidate> = elements
+        .iter()
+        .filter_map(|element| {
+            let tags = element.get("tags")?;
+            Some(BoundaryCandidate {
+                id: element.get("id")?.as_u64()?,
+                name: tags.get("name")?.as_str()?.to_string(),
+                admin_level: tags
+                    .get("admin_level")
+                    .and_then(|l| l.as_str())
+                    .map(|l| l.to_string()),
+            })
+        })
+        .collect();
+    // Biggest areas (lowest admin level) first.
+    candidates.sort_by_key(|c| {
+        c.admin_level
+            .as_deref()
+            .and_then(|l| l.parse::<u32>().ok())
+            .unwrap_or(u32::MAX)
+    });
+    candidates
+}
+
+/// Turns the members of a relation fetched with `out geom` into polygons, with the inner rings
+/// as holes of the outer ring they sit in.
+pub fn parse_boundary_geometry(data: &str) -> Vec<SelectionPolygon> {
+    let Ok(response) = serde_json::from_str::<Value>(data) else {
+        return Vec::new();
+    };
+    let Some(members) = response
+        .get("elements")
+        .and_then(|e| e.as_array())
+        .and_then(|e| e.first())
+        .and_then(|relation| relation.get("members"))
+        .and_then(|m| m.as_array())
+    else {
+        return Vec::new();
+    };
+    let mut outer = Vec::new();
+    let mut inner = Vec::new();
+    for member in members {
+        if member.get("type").and_then(|t| t.as_str()) != Some("way") {
+            continue;
+        }
+        let Some(geometry) = member.get("geometry").and_then(|g| g.as_array()) else {
+            continue;
+        };
+        let line: Vec<Coord> = geometry
+            .iter()
+            .filter_map(|point| {
+                Some(Coord::new(
+                    point.get("lat")?.as_f64()? as f32,
+                    point.get("lon")?.as_f64()? as f32,
+                ))
+            })
+            .collect();
+        match member.get("role").and_then(|r| r.as_str()) {
+            Some("inner") => inner.push(line),
+            // Boundaries often leave the role empty for outer ways.
+            _ => outer.push(line),
+        }
+    }
+
+    let mut polygons: Vec<SelectionPolygon> = assemble_rings(outer)
+        .into_iter()
+        .map(|exterior| SelectionPolygon {
+            exterior,
+            holes: Vec::new(),
+        })
+        .collect();
+    for hole in assemble_rings(inner) {
+        let Some(first) = hole.first() else {
+            continue;
+        };
+        let first = first.to_vec2();
+        if let Some(polygon) = polygons.iter_mut().find(|polygon| {
+            let ring: Vec<Vec2> = polygon.exterior.iter().map(|c| c.to_vec2()).collect();
+            point_in_ring(first, &ring)
+        }) {
+            polygon.holes.push(hole);
+        }
+    }
+    polygons
+}
+
+/// Joins way segments end to end into closed rings. Segments which never close are dropped.
+pub fn assemble_rings(mut segments: Vec<Vec<Coord>>) -> Vec<Vec<Coord>> {
+    segments.retain(|s| s.len() > 1);
+    let mut rings = Vec::new();
+    while let Some(mut ring) = segments.pop() {
+        while ring.first() != ring.last() {
+            let end = *ring.last().unwrap();
+            let Some(i) = segments
+                .iter()
+                .position(|s| s.first() == Some(&end) || s.last() == Some(&end))
+            else {
+                break;
+            };
+            let mut next = segments.swap_remove(i);
+            if next.first() != Some(&end) {
+                next.reverse();
+            }
+            ring.extend(next.into_iter().skip(1));
+        }
+        if ring.len() > 3 && ring.first() == ring.last() {
+            rings.push(ring);
+        }
+    }
+    rings
+}
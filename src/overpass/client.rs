@@ -1,9 +1,14 @@
 use std::{io::Error, time::Duration};
 
-use bevy_map_viewer::DistanceType;
+use bevy_map_viewer::{Coord, DistanceType};
+use geo::Contains;
+use serde_json::{Value, json};
 use ureq::Agent;
 
-use crate::workspace::{Selection, SelectionType};
+use crate::{
+    geojson::get_data_from_string_osm,
+    workspace::{Selection, SelectionType},
+};
 
 use super::{OverpassClient, Settings};
 
@@ -114,69 +119,84 @@ impl OverpassClient {
     }
 }
 
-pub fn get_bounds(selection: Selection) -> String {
-    if selection.points.is_some() {
-        if selection.selection_type == SelectionType::POLYGON {
-            let points_string = selection
-                .points
-                .unwrap()
-                .iter()
-                .map(|point| format!("{} {}", point.lat, point.long))
-                .collect::<Vec<String>>()
-                .join(" ");
-            format!("poly:\"{points_string}\"")
-        } else {
-            String::default()
-        }
-    } else if selection.start.is_some() && selection.end.is_some() {
-        match selection.selection_type {
-            SelectionType::RECTANGLE => {
-                let start = selection.start.unwrap();
-                let end = selection.end.unwrap();
-                format!(
-                    "poly:\"{} {} {} {} {} {} {} {}\"",
-                    start.lat,
-                    start.long,
-                    start.lat,
-                    end.long,
-                    end.lat,
-                    end.long,
-                    end.lat,
-                    start.long
-                )
-            }
-            SelectionType::CIRCLE => {
-                let start = selection.start.unwrap();
-                let end = selection.end.unwrap();
+/// The Overpass filters covering the selection, one for each part of a `MULTIPOLYGON` since a
+/// poly filter only takes one ring. Empty when there is nothing to query.
+pub fn get_bounds(selection: Selection) -> Vec<String> {
+    if let Some(area_id) = selection.area_id {
+        return vec![format!("area:{area_id}")];
+    }
+    let poly = |points: &[Coord]| {
+        let points_string = points
+            .iter()
+            .map(|point| format!("{} {}", point.lat, point.long))
+            .collect::<Vec<String>>()
+            .join(" ");
+        format!("poly:\"{points_string}\"")
+    };
+    match selection.selection_type {
+        SelectionType::MULTIPOLYGON => selection
+            .polygons
+            .iter()
+            .map(|polygon| poly(&polygon.exterior))
+            .collect(),
+        SelectionType::POLYGON => selection.points.as_deref().map(poly).into_iter().collect(),
+        SelectionType::RECTANGLE => match (selection.start, selection.end) {
+            (Some(start), Some(end)) => vec![poly(&[
+                start,
+                Coord::new(start.lat, end.long),
+                end,
+                Coord::new(end.lat, start.long),
+            ])],
+            _ => Vec::new(),
+        },
+        SelectionType::CIRCLE => match (selection.start, selection.end) {
+            (Some(start), Some(end)) => {
                 let (mut dist, dist_type) = start.distance(&end);
                 match dist_type {
                     DistanceType::Km => dist *= 1000.0,
                     DistanceType::M => {}
                     DistanceType::CM => dist /= 100.0,
                 }
-                format!("around:{}, {}, {}", dist, start.lat, start.long)
-            }
-            // TODO: Add support to polygon.
-            SelectionType::POLYGON => {
-                if let Some(points) = &selection.points {
-                    let points_string = points
-                        .iter()
-                        .map(|point| format!("{} {}", point.lat, point.long))
-                        .collect::<Vec<String>>()
-                        .join(" ");
-                    format!("poly:\"{points_string}\"")
-                } else {
-                    String::new() // Return an empty string if no points are provided
-                }
+                vec![format!("around:{}, {}, {}", dist, start.lat, start.long)]
             }
+            _ => Vec::new(),
+        },
+        SelectionType::NONE => Vec::new(),
+    }
+}
 
-            _ => {
-                return String::new();
-            }
-        }
-    } else {
-        return String::new();
+/// Drops the elements of an Overpass response which lie wholly inside a hole of the selection.
+/// The poly filters only follow the outside of each part, so these come back too.
+pub fn drop_elements_in_holes(data: &[u8], selection: &Selection) -> Vec<u8> {
+    let holes: Vec<geo::Polygon> = selection
+        .to_multi_polygon()
+        .iter()
+        .flat_map(|polygon| polygon.interiors().iter())
+        .map(|hole| geo::Polygon::new(hole.clone(), vec![]))
+        .collect();
+    if holes.is_empty() {
+        return data.to_vec();
     }
+    let Ok(mut response) = serde_json::from_slice::<Value>(data) else {
+        return data.to_vec();
+    };
+    let Some(elements) = response.get_mut("elements").and_then(|e| e.as_array_mut()) else {
+        return data.to_vec();
+    };
+    elements.retain(|element| {
+        let wrapped = json!({ "elements": [element] }).to_string();
+        let features = get_data_from_string_osm(&wrapped).unwrap_or_default();
+        !features.iter().any(|feature| {
+            holes.iter().any(|hole| {
+                if feature.closed {
+                    hole.contains(&feature.geometry)
+                } else {
+                    hole.contains(feature.geometry.exterior())
+                }
+            })
+        })
+    });
+    serde_json::to_vec(&response).unwrap_or_else(|_| data.to_vec())
 }
 
 impl Default for OverpassClient {
@@ -213,50 +233,43 @@ impl OverpassClient {
     }
 }
 
-pub fn build_overpass_query_string(bounds: String, settings: Settings) -> Result<String, Error> {
+pub fn build_overpass_query_string(bounds: &[String], settings: Settings) -> Result<String, Error> {
+    build_tag_query_string(bounds, &settings.get_true_keys_with_category())
+}
+
+/// A query for the features with any of the `(key, value)` tags, where a value of `*` matches
+/// any value of the key. Each tag is looked for within every one of the `bounds` filters.
+pub fn build_tag_query_string(
+    bounds: &[String],
+    tags: &[(String, String)],
+) -> Result<String, Error> {
     let mut query = String::default();
     let opening = "[out:json];";
     let closing = "\nout body geom;";
 
-    for (category, key) in settings.get_true_keys_with_category() {
-        if key == "n/a" {
+    for (category, key) in tags {
+        if key == "n/a" || bounds.is_empty() {
             continue;
-        } else if key == "*" {
-            query.push_str(&format!(
-                r#"
-                    (
-                    way["{}"]({});
-                    node["{}"]({});
-                    relation["{}"]({});
-                    );
-                    "#,
-                category.to_lowercase(),
-                bounds,
-                category.to_lowercase(),
-                bounds,
-                category.to_lowercase(),
-                bounds,
-            ));
+        }
+        let filter = if key == "*" {
+            format!(r#"["{}"]"#, category.to_lowercase())
         } else {
+            format!(
+                r#"["{}"="{}"]"#,
+                category.to_lowercase(),
+                key.to_lowercase()
+            )
+        };
+        query.push_str("\n                    (");
+        for bound in bounds {
             query.push_str(&format!(
                 r#"
-                    (
-                    way["{}"="{}"]({});
-                    node["{}"="{}"]({});
-                    relation["{}"="{}"]({});
-                    );
-                    "#,
-                category.to_lowercase(),
-                key.to_lowercase(),
-                bounds,
-                category.to_lowercase(),
-                key.to_lowercase(),
-                bounds,
-                category.to_lowercase(),
-                key.to_lowercase(),
-                bounds,
+                    way{filter}({bound});
+                    node{filter}({bound});
+                    relation{filter}({bound});"#
             ));
         }
+        query.push_str("\n                    );\n                    ");
     }
 
     if !query.is_empty() {
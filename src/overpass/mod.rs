@@ -10,6 +10,7 @@
 //! - Provide efficient caching and data management for OSM data
 //! 
 //! ## Sub-modules
+//! - `boundary`: Administrative boundary search and relation geometry assembly
 //! - `client`: Overpass API client with query building and execution
 //! - `overpass_types`: Data structures for OSM features and query responses
 //! 
@@ -26,9 +27,11 @@
 //! - Attribute-based filtering and selection
 //! - Spatial relationship queries
 
+mod boundary;
 mod client;
 mod overpass_types;
 
+pub use boundary::*;
 pub use client::*;
 pub use overpass_types::*;
 use ureq::Agent;
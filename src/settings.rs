@@ -21,7 +21,12 @@
 //! - API configuration and credentials
 //! - Import/export of settings
 
-use bevy::prelude::*;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use bevy::{prelude::*, tasks::AsyncComputeTaskPool};
 #[allow(unused_imports)]
 use bevy_egui::{
     EguiContexts, EguiPlugin, EguiPreUpdateSet,
@@ -29,25 +34,369 @@ use bevy_egui::{
 };
 use bevy_map_viewer::ZoomChangedEvent;
 
-use crate::{settings::egui::color_picker::Alpha::Opaque, workspace::Workspace};
+use crate::{
+    keybindings::{Action, Keybindings},
+    llm::{ChatBackend, LlmCredentials, ModelInfo, ModelParams, OPENROUTER_URL, OpenrouterClient},
+    settings::egui::color_picker::Alpha::Opaque,
+    tools::ToolResources,
+    workspace::{
+        AttributeTable, FeatureSearch, History, HistoryChange, RequestType, StatisticsWindow,
+        Workspace, WorkspaceRequest,
+    },
+};
 
 pub struct SettingsPlugin;
 
 impl Plugin for SettingsPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(
-            Update,
-            ui_example_system.after(EguiPreUpdateSet::InitContexts),
-        ); // Ensure the system runs after EguiPreUpdateSet::InitContexts
+        app.insert_resource(SettingsPanel::default())
+            .insert_resource(LlmSettingsWindow::default())
+            .add_systems(Update, toggle_settings_panel)
+            .add_systems(
+                Update,
+                (ui_example_system, llm_settings_ui).after(EguiPreUpdateSet::InitContexts),
+            ); // Ensure the system runs after EguiPreUpdateSet::InitContexts
+    }
+}
+
+/// Whether the layers panel on the left is shown.
+#[derive(Resource)]
+pub struct SettingsPanel {
+    pub open: bool,
+}
+
+impl Default for SettingsPanel {
+    fn default() -> Self {
+        SettingsPanel { open: true }
+    }
+}
+
+/// The dialog for the chat's endpoint, API key and the open workspace's model. The endpoint and
+/// key are only written to the client and the config file when saved.
+#[derive(Resource, Default)]
+pub struct LlmSettingsWindow {
+    pub open: bool,
+    credentials: Option<LlmCredentials>,
+    show_key: bool,
+    status: Option<Result<String, String>>,
+    model_filter: String,
+    // Filled in by the background request to the endpoint's model list.
+    models: Arc<Mutex<ModelList>>,
+    // The model settings changed in the workspace but not written to disk yet, they are saved
+    // once a drag or typing ends.
+    unsaved_model: bool,
+}
+
+#[derive(Default)]
+struct ModelList {
+    loading: bool,
+    error: Option<String>,
+    models: Vec<ModelInfo>,
+}
+
+impl LlmSettingsWindow {
+    pub fn open(&mut self) {
+        self.open = true;
+        self.credentials = None;
+        self.status = None;
+    }
+
+    fn fetch_models(&self, backend: Arc<dyn ChatBackend>) {
+        let list = self.models.clone();
+        *list.lock().unwrap() = ModelList {
+            loading: true,
+            ..Default::default()
+        };
+        AsyncComputeTaskPool::get()
+            .spawn(async move {
+                let response = backend.list_models();
+                let mut list = list.lock().unwrap();
+                list.loading = false;
+                match response {
+                    Ok(mut models) => {
+                        models.sort_by(|a, b| a.id.cmp(&b.id));
+                        list.models = models;
+                    }
+                    Err(e) => list.error = Some(e.to_string()),
+                }
+            })
+            .detach();
+    }
+}
+
+fn llm_settings_ui(
+    mut contexts: EguiContexts,
+    mut window: ResMut<LlmSettingsWindow>,
+    mut workspace: ResMut<Workspace>,
+    mut tools: ResMut<ToolResources>,
+) {
+    if !window.open {
+        return;
+    }
+    let window = &mut *window;
+    if window.credentials.is_none() && workspace.llm_agent.is_configured() {
+        window.fetch_models(workspace.llm_agent.clone());
+    }
+    let credentials = window.credentials.get_or_insert_with(LlmCredentials::load);
+    let mut open = true;
+    let mut save = false;
+    let mut refresh = false;
+    let mut edit_finished = false;
+    egui::Window::new("AI Settings")
+        .open(&mut open)
+        .resizable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            egui::Grid::new("llm_settings_grid")
+                .num_columns(2)
+                .show(ui, |ui| {
+                    ui.label("Endpoint");
+                    ui.add(egui::TextEdit::singleline(&mut credentials.url).desired_width(300.0));
+                    ui.end_row();
+                    ui.label("API key");
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::TextEdit::singleline(&mut credentials.api_key)
+                                .password(!window.show_key)
+                                .desired_width(260.0),
+                        );
+                        ui.toggle_value(&mut window.show_key, "👁")
+                            .on_hover_text("Show key");
+                    });
+                    ui.end_row();
+                });
+            ui.label(
+                RichText::new(
+                    "Any OpenAI compatible endpoint works, such as a local llama.cpp or Ollama \
+                     server. The key is stored in your config folder, readable only by you.",
+                )
+                .weak(),
+            );
+            ui.horizontal(|ui| {
+                if ui.button("Save").clicked() {
+                    save = true;
+                }
+                if ui.button("Use OpenRouter").clicked() {
+                    credentials.url = OPENROUTER_URL.to_string();
+                }
+            });
+            match &window.status {
+                Some(Ok(message)) => {
+                    ui.colored_label(Color32::LIGHT_GREEN, message);
+                }
+                Some(Err(message)) => {
+                    ui.colored_label(Color32::LIGHT_RED, message);
+                }
+                None => {}
+            }
+
+            let Some(ws) = workspace.workspace.as_mut() else {
+                return;
+            };
+            ui.separator();
+            ui.label(RichText::new("Model for this workspace").strong());
+            let mut params = ws.get_model_params();
+            let list = window.models.lock().unwrap();
+            ui.horizontal(|ui| {
+                let filter = window.model_filter.to_lowercase();
+                egui::ComboBox::from_id_salt("llm_model")
+                    .selected_text(&params.model)
+                    .width(260.0)
+                    .show_ui(ui, |ui| {
+                        for model in list
+                            .models
+                            .iter()
+                            .filter(|m| m.id.to_lowercase().contains(&filter))
+                        {
+                            let mut hover = model.id.clone();
+                            if let Some(length) = model.context_length {
+                                hover.push_str(&format!("\nContext: {length} tokens"));
+                            }
+                            if let Some(pricing) = &model.pricing {
+                                hover.push_str(&format!(
+                                    "\n${:.2} / ${:.2} per million tokens in / out",
+                                    pricing.prompt * 1e6,
+                                    pricing.completion * 1e6
+                                ));
+                            }
+                            if ui
+                                .selectable_label(params.model == model.id, model.label())
+                                .on_hover_text(hover)
+                                .clicked()
+                            {
+                                params.set_model(model);
+                                edit_finished = true;
+                            }
+                        }
+                    });
+                if list.loading {
+                    ui.spinner();
+                } else if ui
+                    .small_button("⟳")
+                    .on_hover_text("Refresh models")
+                    .clicked()
+                {
+                    refresh = true;
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Filter");
+                ui.text_edit_singleline(&mut window.model_filter);
+            });
+            ui.horizontal(|ui| {
+                ui.label("Model id");
+                let response = ui
+                    .text_edit_singleline(&mut params.model)
+                    .on_hover_text("For models missing from the list");
+                if response.changed() {
+                    // The context length and pricing belong to the model that was picked before.
+                    match list.models.iter().find(|model| model.id == params.model) {
+                        Some(model) => params.set_model(model),
+                        None => {
+                            params.context_length = None;
+                            params.pricing = None;
+                        }
+                    }
+                }
+                edit_finished |= finished(&response);
+            });
+            if let Some(error) = &list.error {
+                ui.colored_label(Color32::LIGHT_RED, format!("Couldn't load models: {error}"));
+            }
+            drop(list);
+
+            let response =
+                ui.add(egui::Slider::new(&mut params.temperature, 0.0..=2.0).text("Temperature"));
+            edit_finished |= finished(&response);
+            let mut top_p = params.top_p.is_some();
+            ui.horizontal(|ui| {
+                edit_finished |= finished(&ui.checkbox(&mut top_p, "Top p"));
+                let mut value = params.top_p.unwrap_or(1.0);
+                let response = ui.add_enabled(top_p, egui::Slider::new(&mut value, 0.0..=1.0));
+                edit_finished |= finished(&response);
+                params.top_p = top_p.then_some(value);
+            });
+            let mut max_tokens = params.max_tokens.is_some();
+            ui.horizontal(|ui| {
+                edit_finished |= finished(&ui.checkbox(&mut max_tokens, "Max tokens"));
+                let mut value = params.max_tokens.unwrap_or(1024);
+                let response = ui.add_enabled(
+                    max_tokens,
+                    egui::DragValue::new(&mut value).range(1..=200_000),
+                );
+                edit_finished |= finished(&response);
+                params.max_tokens = max_tokens.then_some(value);
+            });
+            ui.horizontal(|ui| {
+                ui.label("Context length");
+                let mut value = params.context_length();
+                let response = ui
+                    .add(egui::DragValue::new(&mut value).range(512..=2_000_000))
+                    .on_hover_text("Older messages are summarised to fit");
+                if response.changed() {
+                    params.context_length = Some(value);
+                }
+                edit_finished |= finished(&response);
+            });
+            if ui.small_button("Reset to defaults").clicked() {
+                params = ModelParams::default();
+                edit_finished = true;
+            }
+
+            if params != ws.get_model_params() {
+                ws.set_model_params(params);
+                // Switching workspace saves the copy in the selection areas.
+                tools.selection_areas.replace(ws.clone());
+                window.unsaved_model = true;
+            }
+        });
+
+    if save {
+        credentials.url = credentials.url.trim().to_string();
+        credentials.api_key = credentials.api_key.trim().to_string();
+        workspace.llm_agent = Arc::new(OpenrouterClient::from_credentials(credentials));
+        window.status = Some(
+            credentials
+                .save()
+                .map(|_| "Saved".to_string())
+                .map_err(|e| format!("Failed to save: {e}")),
+        );
+        refresh = true;
+    }
+    if refresh {
+        window.fetch_models(workspace.llm_agent.clone());
+    }
+    if window.unsaved_model && (edit_finished || !open) {
+        let _ = workspace.save_workspace();
+        window.unsaved_model = false;
+    }
+    if !open {
+        window.open = false;
+    }
+}
+
+/// Whether an edit through this widget is done: a drag was let go, a text field lost focus or
+/// the value changed with a click or key.
+fn finished(response: &egui::Response) -> bool {
+    response.drag_stopped()
+        || response.lost_focus()
+        || (response.changed() && !response.dragged() && !response.has_focus())
+}
+
+/// A name for the layer which stays the same between frames: the tags it was queried for, or
+/// the start of its id when there are none.
+fn layer_name(request: &WorkspaceRequest) -> String {
+    let mut tags: Vec<&str> = Vec::new();
+    if let RequestType::OverpassTurboRequest(query) = request.get_request() {
+        for filter in query.split("way[").skip(1) {
+            let filter = filter.split(']').next().unwrap_or_default();
+            if !tags.contains(&filter) {
+                tags.push(filter);
+            }
+        }
+        if !tags.is_empty() {
+            return tags.join(", ").replace('"', "");
+        }
+    }
+    match request.get_request() {
+        RequestType::UserSketch => "Sketch".to_string(),
+        _ => request.get_id().chars().take(8).collect(),
     }
 }
 
-#[allow(dead_code)]
+/// The layers sorted by their name, since the workspace keeps them in no particular order.
+fn layers_by_name(mut requests: Vec<WorkspaceRequest>) -> Vec<WorkspaceRequest> {
+    requests.sort_by_cached_key(|request| (layer_name(request), request.get_id()));
+    requests
+}
+
+fn toggle_settings_panel(
+    keys: Res<ButtonInput<KeyCode>>,
+    keybindings: Res<Keybindings>,
+    mut panel: ResMut<SettingsPanel>,
+) {
+    if keybindings.just_pressed(Action::ToggleLayers, &keys) {
+        panel.open = !panel.open;
+    }
+}
+
+#[allow(dead_code, clippy::too_many_arguments)]
 fn ui_example_system(
     mut contexts: EguiContexts,
     mut overpass_settings: ResMut<Workspace>,
     mut zoom_event: EventWriter<ZoomChangedEvent>,
+    mut attribute_table: ResMut<AttributeTable>,
+    mut statistics: ResMut<StatisticsWindow>,
+    mut feature_search: ResMut<FeatureSearch>,
+    mut tools: ResMut<ToolResources>,
+    mut history: ResMut<History>,
+    mut keybindings: ResMut<Keybindings>,
+    mut llm_settings: ResMut<LlmSettingsWindow>,
+    panel: Res<SettingsPanel>,
+    mut label_drafts: Local<HashMap<String, String>>,
 ) {
+    if !panel.open {
+        return;
+    }
     let ctx = contexts.ctx_mut();
     let screen_rect = ctx.screen_rect();
 
@@ -55,6 +404,8 @@ fn ui_example_system(
     let tilebox_height = screen_rect.height() - 40.0;
 
     let tilebox_pos = egui::pos2(10.0, 30.0);
+    let mut remove_layer: Option<String> = None;
+    let mut jump_to: Option<usize> = None;
 
     egui::Area::new("layers".into())
         .fixed_pos(tilebox_pos)
@@ -145,7 +496,207 @@ fn ui_example_system(
                                 }
                             });
                         }
+
+                        ui.separator();
+                        let color = Color32::from_rgb(221, 221, 221);
+                        ui.collapsing(RichText::new("Labels").color(color), |ui| {
+                            let loaded_requests = overpass_settings.loaded_requests.clone();
+                            let mut changed = false;
+                            for request in layers_by_name(overpass_settings.get_requests()) {
+                                let mut style = request.get_style();
+                                let mut enabled = style.label_field.is_some();
+                                let mut edit_finished = false;
+                                ui.horizontal(|ui| {
+                                    let response = ui.checkbox(
+                                        &mut enabled,
+                                        RichText::new(layer_name(&request)).color(color),
+                                    );
+                                    edit_finished |= response.changed();
+                                    // The field is only applied once it loses focus, so it can be
+                                    // cleared while typing a new one.
+                                    let field =
+                                        label_drafts.entry(request.get_id()).or_insert_with(|| {
+                                            style.label_field.clone().unwrap_or_default()
+                                        });
+                                    let response = ui.add_enabled(
+                                        enabled,
+                                        egui::TextEdit::singleline(field)
+                                            .hint_text("name")
+                                            .desired_width(90.0),
+                                    );
+                                    if response.lost_focus() {
+                                        edit_finished = true;
+                                    } else if !response.has_focus() {
+                                        // Show the saved field again when not typing, e.g. after an undo.
+                                        label_drafts.remove(&request.get_id());
+                                    }
+                                });
+                                ui.horizontal(|ui| {
+                                    ui.label(RichText::new("Min zoom").color(color));
+                                    let response = ui.add(
+                                        egui::DragValue::new(&mut style.label_min_zoom)
+                                            .range(14..=20),
+                                    );
+                                    edit_finished |= finished(&response);
+                                });
+                                if edit_finished {
+                                    let field = label_drafts
+                                        .remove(&request.get_id())
+                                        .or(style.label_field.clone())
+                                        .filter(|field| !field.is_empty())
+                                        .unwrap_or_else(|| "name".to_string());
+                                    style.label_field = enabled.then_some(field);
+                                }
+                                if style != request.get_style() {
+                                    history.record_step(
+                                        "Change label style",
+                                        request.get_id(),
+                                        HistoryChange::Style {
+                                            request_id: request.get_id(),
+                                            before: request.get_style(),
+                                            after: style.clone(),
+                                        },
+                                    );
+                                    if let Some(request) =
+                                        loaded_requests.lock().unwrap().get_mut(&request.get_id())
+                                    {
+                                        request.set_style(style);
+                                    }
+                                }
+                                if edit_finished {
+                                    history.end_interaction();
+                                    changed = true;
+                                }
+                            }
+                            if changed {
+                                let _ = overpass_settings.save_requests();
+                                zoom_event.write(ZoomChangedEvent);
+                            }
+                        });
+
+                        ui.collapsing(RichText::new("Attribute tables").color(color), |ui| {
+                            for request in layers_by_name(overpass_settings.get_requests()) {
+                                ui.horizontal(|ui| {
+                                    ui.label(
+                                        RichText::new(format!(
+                                            "{} ({})",
+                                            layer_name(&request),
+                                            request.get_processed_data().size()
+                                        ))
+                                        .color(color),
+                                    );
+                                    if ui.button("Open").clicked() {
+                                        attribute_table.open(request.get_id());
+                                    }
+                                    if ui.button("Remove").clicked() {
+                                        remove_layer = Some(request.get_id());
+                                    }
+                                });
+                            }
+                            if ui
+                                .button("Statistics")
+                                .on_hover_text("Group the features by a tag")
+                                .clicked()
+                            {
+                                statistics.open();
+                            }
+                            if ui
+                                .button("Search")
+                                .on_hover_text("Find features by their tags")
+                                .clicked()
+                            {
+                                feature_search.open();
+                            }
+                        });
+
+                        ui.collapsing(RichText::new("Snapping").color(color), |ui| {
+                            let snapping = &mut tools.snapping;
+                            ui.checkbox(
+                                &mut snapping.enabled,
+                                RichText::new("Snap to features").color(color),
+                            );
+                            ui.add_enabled_ui(snapping.enabled, |ui| {
+                                ui.horizontal(|ui| {
+                                    ui.label(RichText::new("Tolerance").color(color));
+                                    ui.add(
+                                        egui::DragValue::new(&mut snapping.tolerance)
+                                            .range(2.0..=40.0)
+                                            .suffix(" px"),
+                                    );
+                                });
+                                ui.checkbox(
+                                    &mut snapping.vertices,
+                                    RichText::new("Vertices").color(color),
+                                );
+                                ui.checkbox(
+                                    &mut snapping.edges,
+                                    RichText::new("Edges").color(color),
+                                );
+                                ui.checkbox(
+                                    &mut snapping.centroids,
+                                    RichText::new("Centroids").color(color),
+                                );
+                                ui.label(RichText::new("Hold Alt to place freely.").color(color));
+                            });
+                        });
+
+                        ui.collapsing(RichText::new("History").color(color), |ui| {
+                            ui.horizontal(|ui| {
+                                if ui
+                                    .add_enabled(history.can_undo(), egui::Button::new("Undo"))
+                                    .on_hover_text("Ctrl+Z")
+                                    .clicked()
+                                {
+                                    jump_to = Some(history.entries().1 - 1);
+                                }
+                                if ui
+                                    .add_enabled(history.can_redo(), egui::Button::new("Redo"))
+                                    .on_hover_text("Ctrl+Y")
+                                    .clicked()
+                                {
+                                    jump_to = Some(history.entries().1 + 1);
+                                }
+                            });
+                            let (entries, applied) = history.entries();
+                            if entries.is_empty() {
+                                ui.label(RichText::new("Nothing to undo yet.").color(color));
+                            }
+                            egui::ScrollArea::vertical()
+                                .max_height(200.0)
+                                .show(ui, |ui| {
+                                    // Newest first. Clicking an entry goes back to just after it,
+                                    // the undone entries are greyed out.
+                                    for (i, entry) in entries.iter().enumerate().rev() {
+                                        let text = if i < applied {
+                                            RichText::new(&entry.label).color(color)
+                                        } else {
+                                            RichText::new(&entry.label).weak().italics()
+                                        };
+                                        if ui.selectable_label(i + 1 == applied, text).clicked() {
+                                            jump_to = Some(i + 1);
+                                        }
+                                    }
+                                });
+                        });
+
+                        ui.separator();
+                        if ui.button("Keybindings").clicked() {
+                            keybindings.window_open = true;
+                        }
+                        if ui.button("AI Settings").clicked() {
+                            llm_settings.open();
+                        }
                     });
                 });
         });
+
+    if let Some(request_id) = remove_layer {
+        history.remove_layer(&mut overpass_settings, request_id);
+        let _ = overpass_settings.save_workspace();
+        zoom_event.write(ZoomChangedEvent);
+    }
+    if let Some(applied) = jump_to {
+        history.jump_to(applied, &mut overpass_settings, &mut tools);
+        zoom_event.write(ZoomChangedEvent);
+    }
 }
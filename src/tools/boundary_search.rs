@@ -0,0 +1,219 @@
+//! Makes workspace selections out of existing shapes instead of drawing them: either a polygon
+//! feature which is already on the map, or an administrative boundary looked up by name.
+
+use std::sync::{Arc, Mutex};
+
+use bevy::{prelude::*, tasks::AsyncComputeTaskPool};
+use bevy_egui::{EguiContexts, EguiPreUpdateSet, egui};
+
+use crate::{
+    geojson::MapFeature,
+    overpass::{
+        BoundaryCandidate, build_boundary_geometry_query, build_boundary_search_query,
+        parse_boundary_candidates, parse_boundary_geometry,
+    },
+    workspace::{History, HistoryChange, Selection, Workspace, WorkspaceData},
+};
+
+use super::{FeatureSelection, ToolResources, feature_title};
+
+pub struct BoundarySearchPlugin;
+
+impl Plugin for BoundarySearchPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, add_found_boundary).add_systems(
+            Update,
+            boundary_search_ui.after(EguiPreUpdateSet::InitContexts),
+        );
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct BoundarySearch {
+    pub name: String,
+    // Filled in by the background Overpass requests.
+    state: Arc<Mutex<BoundaryState>>,
+}
+
+#[derive(Default)]
+struct BoundaryState {
+    loading: bool,
+    error: Option<String>,
+    candidates: Vec<BoundaryCandidate>,
+    // A fetched boundary waiting to be added as a selection area.
+    found: Option<(String, Selection)>,
+}
+
+impl BoundarySearch {
+    fn search(&self, workspace: &Workspace) {
+        let query = build_boundary_search_query(self.name.trim());
+        let overpass = workspace.overpass_agent.clone();
+        let state = self.state.clone();
+        *state.lock().unwrap() = BoundaryState {
+            loading: true,
+            ..Default::default()
+        };
+        AsyncComputeTaskPool::get()
+            .spawn(async move {
+                let response = overpass.send_overpass_query_string(query);
+                let mut state = state.lock().unwrap();
+                state.loading = false;
+                match response {
+                    Ok(data) => {
+                        state.candidates = parse_boundary_candidates(&data);
+                        if state.candidates.is_empty() {
+                            state.error = Some("No boundaries with that name.".to_string());
+                        }
+                    }
+                    Err(e) => state.error = Some(e.to_string()),
+                }
+            })
+            .detach();
+    }
+
+    fn fetch(&self, workspace: &Workspace, candidate: BoundaryCandidate) {
+        let query = build_boundary_geometry_query(candidate.id);
+        let overpass = workspace.overpass_agent.clone();
+        let state = self.state.clone();
+        {
+            let mut state = state.lock().unwrap();
+            state.loading = true;
+            state.error = None;
+        }
+        AsyncComputeTaskPool::get()
+            .spawn(async move {
+                let response = overpass.send_overpass_query_string(query);
+                let mut state = state.lock().unwrap();
+                state.loading = false;
+                match response {
+                    Ok(data) => {
+                        let polygons = parse_boundary_geometry(&data);
+                        if polygons.is_empty() {
+                            state.error = Some("The boundary has no closed outline.".to_string());
+                            return;
+                        }
+                        state.found = Some((
+                            candidate.name.clone(),
+                            Selection::from_polygons(polygons, Some(candidate.area_id())),
+                        ));
+                    }
+                    Err(e) => state.error = Some(e.to_string()),
+                }
+            })
+            .detach();
+    }
+}
+
+/// The polygon of a closed feature, or `None` for points and lines.
+pub fn feature_selection_polygon(feature: &MapFeature) -> Option<Selection> {
+    if feature.is_point() || !feature.closed {
+        return None;
+    }
+    Some(Selection::from_multi_polygon(&geo::MultiPolygon::new(
+        vec![feature.geometry.clone()],
+    )))
+}
+
+/// Adds a boundary as a new selection area once it has been fetched.
+fn add_found_boundary(mut tools: ResMut<ToolResources>, mut history: ResMut<History>) {
+    let Some((name, selection)) = tools.boundary_search.state.lock().unwrap().found.take() else {
+        return;
+    };
+    let area = WorkspaceData::new(name, selection);
+    history.record("New boundary selection", HistoryChange::area_added(&area));
+    tools.selection_areas.add(area);
+    tools.selection_areas.respawn = true;
+}
+
+fn boundary_search_ui(
+    mut contexts: EguiContexts,
+    mut tools: ResMut<ToolResources>,
+    workspace: Res<Workspace>,
+    selection: Res<FeatureSelection>,
+    mut history: ResMut<History>,
+) {
+    if !tools.selection_settings.enabled {
+        return;
+    }
+    let (loading, error, candidates) = {
+        let state = tools.boundary_search.state.lock().unwrap();
+        (state.loading, state.error.clone(), state.candidates.clone())
+    };
+    let from_feature = match selection.get_features().as_slice() {
+        [feature] => feature_selection_polygon(feature).map(|s| (feature_title(feature), s)),
+        _ => None,
+    };
+    let mut add_feature = false;
+
+    let search = &mut tools.boundary_search;
+    egui::Window::new("New workspace from")
+        .anchor(egui::Align2::LEFT_BOTTOM, [10.0, -10.0])
+        .default_width(260.0)
+        .show(contexts.ctx_mut(), |ui| {
+            add_feature = ui
+                .add_enabled(
+                    from_feature.is_some(),
+                    egui::Button::new("Selected feature"),
+                )
+                .on_disabled_hover_text("Select a single area feature, like a park, first.")
+                .clicked();
+            ui.separator();
+            ui.label("Administrative boundary");
+            ui.horizontal(|ui| {
+                let response = ui.text_edit_singleline(&mut search.name);
+                let submitted =
+                    response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                if (ui
+                    .add_enabled(!loading, egui::Button::new("Search"))
+                    .clicked()
+                    || submitted)
+                    && !loading
+                    && !search.name.trim().is_empty()
+                {
+                    search.search(&workspace);
+                }
+            });
+            if loading {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label("Querying Overpass...");
+                });
+            }
+            if let Some(error) = error {
+                ui.colored_label(egui::Color32::LIGHT_RED, error);
+            }
+            egui::ScrollArea::vertical()
+                .max_height(200.0)
+                .show(ui, |ui| {
+                    for candidate in candidates {
+                        let level = candidate
+                            .admin_level
+                            .as_deref()
+                            .map(|l| format!(" (level {l})"))
+                            .unwrap_or_default();
+                        if ui
+                            .add_enabled(
+                                !loading,
+                                egui::Button::new(format!("{}{level}", candidate.name)),
+                            )
+                            .clicked()
+                        {
+                            search.fetch(&workspace, candidate);
+                        }
+                    }
+                });
+        });
+
+    if !add_feature {
+        return;
+    }
+    if let Some((name, selection)) = from_feature {
+        let area = WorkspaceData::new(name, selection);
+        history.record(
+            "New selection from feature",
+            HistoryChange::area_added(&area),
+        );
+        tools.selection_areas.add(area);
+        tools.selection_areas.respawn = true;
+    }
+}
@@ -1,12 +1,399 @@
-/// The idea for this file will be that it selects all nearby things to a lat and long where you click, could be incorperated with the location where you can add a pin?
-
-
-/*
-(
-    node(around:1, 52.205, 0.119);
-    way(around:1, 52.205, 0.119);
-    relation(around:1, 52.205, 0.119);
-);
-(._;>;);
-out body;
-*/
+//! Queries Overpass for everything around a clicked point, so the user can see what is there and
+//! pull individual elements into the workspace without redrawing the selection.
+
+use std::{
+    collections::HashSet,
+    f32::consts::PI,
+    sync::{Arc, Mutex},
+};
+
+use bevy::{prelude::*, tasks::AsyncComputeTaskPool, window::PrimaryWindow};
+use bevy_egui::{EguiContexts, EguiPreUpdateSet, egui};
+use bevy_map_viewer::{
+    Coord, EguiBlockInputState, MapViewerMarker, TileMapResources, ZoomChangedEvent,
+};
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+use crate::{
+    geojson::{METERS_PER_DEGREE, MapFeature, get_data_from_string_osm},
+    workspace::{History, HistoryChange, RequestType, Workspace, WorkspaceRequest},
+};
+
+use super::{ToolResources, feature_title, features_in_area};
+
+pub struct FeaturePickerPlugin;
+
+impl Plugin for FeaturePickerPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (handle_feature_picker, render_feature_picker))
+            .add_systems(
+                Update,
+                feature_picker_results_ui.after(EguiPreUpdateSet::InitContexts),
+            );
+    }
+}
+
+#[derive(Clone)]
+pub struct FeaturePicker {
+    pub enabled: bool,
+    /// How far around the click to search, in meters.
+    pub radius: f32,
+    location: Option<Coord>,
+    // Filled in by the background Overpass request.
+    state: Arc<Mutex<PickerState>>,
+    selected: HashSet<String>,
+}
+
+impl Default for FeaturePicker {
+    fn default() -> Self {
+        FeaturePicker {
+            enabled: false,
+            radius: 25.0,
+            location: None,
+            state: Arc::new(Mutex::new(PickerState::default())),
+            selected: HashSet::new(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct PickerState {
+    loading: bool,
+    error: Option<String>,
+    results: Vec<PickedFeature>,
+}
+
+#[derive(Clone)]
+struct PickedFeature {
+    feature: MapFeature,
+    // The raw Overpass element, kept so it can be saved as a layer. `None` when the feature came
+    // from a layer which is already loaded.
+    element: Option<Value>,
+}
+
+impl FeaturePicker {
+    pub fn disable(&mut self) {
+        self.enabled = false;
+        self.location = None;
+        self.selected.clear();
+        // A fresh state so a request still in flight can't fill the list back in.
+        self.state = Arc::new(Mutex::new(PickerState::default()));
+    }
+
+    /// The search area as a ring of coordinates.
+    fn search_area(&self) -> Vec<Coord> {
+        let Some(location) = self.location else {
+            return Vec::new();
+        };
+        let lat_radius = self.radius / METERS_PER_DEGREE as f32;
+        let long_radius = lat_radius / location.lat.to_radians().cos().max(0.01);
+        (0..24)
+            .map(|i| {
+                let angle = i as f32 / 24.0 * 2.0 * PI;
+                Coord::new(
+                    location.lat + lat_radius * angle.sin(),
+                    location.long + long_radius * angle.cos(),
+                )
+            })
+            .collect()
+    }
+}
+
+pub fn build_around_query(location: Coord, radius: f32) -> String {
+    format!(
+        r#"[out:json];
+        (
+        node(around:{radius},{lat},{long});
+        way(around:{radius},{lat},{long});
+        relation(around:{radius},{lat},{long});
+        );
+        out body geom;"#,
+        radius = radius,
+        lat = location.lat,
+        long = location.long,
+    )
+}
+
+/// A query for exactly these Overpass elements, so reloading the layer doesn't pull in whatever
+/// else is near the click.
+pub fn build_id_query(elements: &[Value]) -> String {
+    let statements: String = ["node", "way", "relation"]
+        .iter()
+        .filter_map(|kind| {
+            let ids: Vec<String> = elements
+                .iter()
+                .filter(|element| element.get("type").and_then(|t| t.as_str()) == Some(kind))
+                .filter_map(|element| element.get("id").and_then(|id| id.as_u64()))
+                .map(|id| id.to_string())
+                .collect();
+            (!ids.is_empty()).then(|| format!("\n        {kind}(id:{});", ids.join(",")))
+        })
+        .collect();
+    format!(
+        r#"[out:json];
+        ({statements}
+        );
+        out body geom;"#
+    )
+}
+
+/// Each Overpass element together with the map feature it turns into. Elements which don't
+/// become a feature, like the untagged nodes of ways, are dropped.
+fn split_elements(data: &str) -> Vec<PickedFeature> {
+    let Ok(response) = serde_json::from_str::<Value>(data) else {
+        return Vec::new();
+    };
+    let Some(elements) = response.get("elements").and_then(|e| e.as_array()) else {
+        return Vec::new();
+    };
+    elements
+        .iter()
+        .filter_map(|element| {
+            let wrapped = json!({ "elements": [element] }).to_string();
+            let feature = get_data_from_string_osm(&wrapped)
+                .ok()?
+                .into_iter()
+                .next()?;
+            Some(PickedFeature {
+                feature,
+                element: Some(element.clone()),
+            })
+        })
+        .collect()
+}
+
+pub fn handle_feature_picker(
+    mut tools: ResMut<ToolResources>,
+    workspace: Res<Workspace>,
+    camera: Query<(&Camera, &GlobalTransform), With<MapViewerMarker>>,
+    q_windows: Query<&Window, With<PrimaryWindow>>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    tile_map_manager: Res<TileMapResources>,
+    state: Res<EguiBlockInputState>,
+) {
+    if !tools.feature_picker.enabled
+        || !buttons.just_pressed(MouseButton::Left)
+        || state.block_input
+    {
+        return;
+    }
+    let (camera, camera_transform) = match camera.single() {
+        Ok(result) => result,
+        Err(_) => return,
+    };
+    let Some(position) = q_windows.single().ok().and_then(|w| w.cursor_position()) else {
+        return;
+    };
+    let Ok(world) = camera.viewport_to_world_2d(camera_transform, position) else {
+        return;
+    };
+    let picker = &mut tools.feature_picker;
+    if picker.state.lock().unwrap().loading {
+        return;
+    }
+    let location = tile_map_manager.point_to_coord(world);
+    picker.location = Some(location);
+    picker.selected.clear();
+
+    // Show what is already loaded straight away, the Overpass results are merged in when they
+    // arrive.
+    let loaded: Vec<PickedFeature> = features_in_area(&workspace, &picker.search_area())
+        .into_iter()
+        .map(|feature| PickedFeature {
+            feature,
+            element: None,
+        })
+        .collect();
+    *picker.state.lock().unwrap() = PickerState {
+        loading: true,
+        error: None,
+        results: loaded,
+    };
+
+    let query = build_around_query(location, picker.radius);
+    let overpass = workspace.overpass_agent.clone();
+    let picker_state = picker.state.clone();
+    AsyncComputeTaskPool::get()
+        .spawn(async move {
+            let response = overpass.send_overpass_query_string(query);
+            let mut state = picker_state.lock().unwrap();
+            state.loading = false;
+            match response {
+                Ok(data) => {
+                    for picked in split_elements(&data) {
+                        if state
+                            .results
+                            .iter()
+                            .any(|r| r.feature.id == picked.feature.id)
+                        {
+                            continue;
+                        }
+                        state.results.push(picked);
+                    }
+                }
+                Err(e) => state.error = Some(e.to_string()),
+            }
+        })
+        .detach();
+}
+
+/// Outlines the search circle around the last click.
+fn render_feature_picker(
+    mut gizmos: Gizmos,
+    tools: Res<ToolResources>,
+    tile_map_manager: Res<TileMapResources>,
+) {
+    if !tools.feature_picker.enabled {
+        return;
+    }
+    let mut ring: Vec<Vec2> = tools
+        .feature_picker
+        .search_area()
+        .iter()
+        .map(|c| c.to_game_coords(tile_map_manager.clone()))
+        .collect();
+    if let Some(first) = ring.first().copied() {
+        ring.push(first);
+        gizmos.linestrip_2d(ring, Color::srgba(0.0, 0.6, 1.0, 1.0));
+    }
+}
+
+/// Lists what was found around the click, with a button to add the chosen elements as a layer.
+fn feature_picker_results_ui(
+    mut contexts: EguiContexts,
+    mut tools: ResMut<ToolResources>,
+    mut workspace: ResMut<Workspace>,
+    mut zoom_event: EventWriter<ZoomChangedEvent>,
+    mut history: ResMut<History>,
+) {
+    if !tools.feature_picker.enabled {
+        return;
+    }
+    let picker = &mut tools.feature_picker;
+    let results: Vec<PickedFeature> = picker.state.lock().unwrap().results.clone();
+    let (loading, error) = {
+        let state = picker.state.lock().unwrap();
+        (state.loading, state.error.clone())
+    };
+    let mut add = false;
+
+    egui::Window::new("Feature picker")
+        .anchor(egui::Align2::RIGHT_BOTTOM, [-10.0, -10.0])
+        .default_width(300.0)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Radius");
+                ui.add(
+                    egui::DragValue::new(&mut picker.radius)
+                        .range(1.0..=500.0)
+                        .suffix(" m"),
+                );
+            });
+            if picker.location.is_none() {
+                ui.label("Click on the map to see what is there.");
+                return;
+            }
+            if loading {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label("Querying Overpass...");
+                });
+            }
+            if let Some(error) = error {
+                ui.colored_label(egui::Color32::LIGHT_RED, error);
+            }
+            ui.separator();
+            egui::ScrollArea::vertical()
+                .max_height(300.0)
+                .show(ui, |ui| {
+                    for picked in results.iter() {
+                        let id = picked.feature.id.clone();
+                        ui.horizontal(|ui| {
+                            if picked.element.is_none() {
+                                ui.add_enabled(false, egui::Checkbox::new(&mut true, ""))
+                                    .on_disabled_hover_text("Already in the workspace");
+                            } else {
+                                let mut checked = picker.selected.contains(&id);
+                                if ui.checkbox(&mut checked, "").changed() {
+                                    if checked {
+                                        picker.selected.insert(id.clone());
+                                    } else {
+                                        picker.selected.remove(&id);
+                                    }
+                                }
+                            }
+                            egui::CollapsingHeader::new(feature_title(&picked.feature))
+                                .id_salt(&id)
+                                .show(ui, |ui| {
+                                    egui::Grid::new(("picker_tags", &id)).show(ui, |ui| {
+                                        if let Some(tags) = picked.feature.properties.as_object() {
+                                            for (key, value) in tags {
+                                                ui.label(key);
+                                                ui.label(
+                                                    value.as_str().unwrap_or(&value.to_string()),
+                                                );
+                                                ui.end_row();
+                                            }
+                                        }
+                                    });
+                                });
+                        });
+                    }
+                });
+            ui.separator();
+            add = ui
+                .add_enabled(
+                    !picker.selected.is_empty() && workspace.workspace.is_some(),
+                    egui::Button::new(format!("Add {} to workspace", picker.selected.len())),
+                )
+                .clicked();
+        });
+
+    if !add {
+        return;
+    }
+    let elements: Vec<Value> = results
+        .iter()
+        .filter(|picked| picker.selected.contains(&picked.feature.id))
+        .filter_map(|picked| picked.element.clone())
+        .collect();
+    let mut request = WorkspaceRequest::new(
+        Uuid::new_v4().to_string(),
+        1,
+        RequestType::OverpassTurboRequest(build_id_query(&elements)),
+        json!({ "elements": elements }).to_string().into_bytes(),
+    );
+    request.process_request();
+    // The added elements now count as loaded, so they can't be added twice.
+    for picked in picker.state.lock().unwrap().results.iter_mut() {
+        if picker.selected.contains(&picked.feature.id) {
+            picked.element = None;
+        }
+    }
+    picker.selected.clear();
+
+    let Some(ws) = workspace.workspace.as_mut() else {
+        return;
+    };
+    ws.add_request(request.get_id());
+    // Switching workspace saves the copy in the selection areas.
+    tools.selection_areas.replace(ws.clone());
+    history.record(
+        "Add picked features",
+        HistoryChange::Layer {
+            workspace_id: ws.get_id(),
+            request_id: request.get_id(),
+            request: None,
+            added: true,
+        },
+    );
+    workspace
+        .loaded_requests
+        .lock()
+        .unwrap()
+        .insert(request.get_id(), request);
+    let _ = workspace.save_workspace();
+    let _ = workspace.save_requests();
+    zoom_event.write(ZoomChangedEvent);
+}
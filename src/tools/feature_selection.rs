@@ -0,0 +1,528 @@
+use std::{collections::HashSet, fs::File, io::Write};
+
+use bevy::{prelude::*, window::PrimaryWindow};
+use bevy_egui::{EguiContexts, EguiPreUpdateSet, egui};
+use bevy_map_viewer::{Coord, EguiBlockInputState, MapViewerMarker, TileMapResources};
+use geo::{Area, Intersects};
+use rstar::AABB;
+
+use crate::{
+    geojson::{MapFeature, SYMBOL_SIZE},
+    keybindings::{Action, Keybindings},
+    workspace::Workspace,
+};
+
+use super::ToolResources;
+
+/// A plugin which keeps track of the features picked with the pointer tool and highlights them.
+/// Clicking picks the features under the cursor, dragging draws a box and holding alt while
+/// dragging draws a lasso. Holding shift adds to the current selection instead of replacing it.
+/// The selected features are filled in `SELECTED_COLOR` and can be exported as GeoJSON.
+pub struct FeatureSelectionPlugin;
+
+impl Plugin for FeatureSelectionPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(FeatureSelection::default())
+            .add_event::<FeatureClickedEvent>()
+            .add_systems(Update, handle_feature_selection)
+            .add_systems(
+                Update,
+                (feature_picker_ui, selection_ui).after(EguiPreUpdateSet::InitContexts),
+            )
+            .add_systems(PostUpdate, render_feature_highlight);
+    }
+}
+
+// How far the cursor has to move, in pixels, before a click turns into a box selection.
+const DRAG_THRESHOLD: f32 = 4.0;
+// How close, in pixels, a click has to be to a line or point to hit it.
+const HIT_TOLERANCE: f32 = 6.0;
+/// The fill of selected areas and lines, the outline is drawn on top in full opacity.
+pub const SELECTED_COLOR: Srgba = Srgba::new(1.0, 0.6, 0.0, 0.6);
+// Tags shown next to a feature's name when picking between overlapping features.
+const DESCRIPTIVE_TAGS: [&str; 8] = [
+    "amenity", "shop", "highway", "building", "landuse", "leisure", "natural", "boundary",
+];
+
+/// Sent when the pointer tool clicks on the map, with every feature under the cursor.
+#[derive(Event, Clone)]
+pub struct FeatureClickedEvent {
+    pub features: Vec<MapFeature>,
+}
+
+/// The set of features the user has selected on the map. This is what the export, styling and
+/// the LLM work on when they are asked about "the selected" features.
+#[derive(Resource, Default, Clone)]
+pub struct FeatureSelection {
+    features: Vec<MapFeature>,
+    /// The feature whose info window is under the cursor, this is drawn in a different colour.
+    pub hovered: Option<String>,
+    drag: Option<SelectionDrag>,
+    picker: Option<OverlapPicker>,
+}
+
+/// The popup shown when a click lands on several overlapping features.
+#[derive(Clone)]
+struct OverlapPicker {
+    features: Vec<MapFeature>,
+    // Screen position of the click, the popup opens here.
+    position: Vec2,
+    hovered: Option<usize>,
+}
+
+#[derive(Clone)]
+struct SelectionDrag {
+    // Where the press started on screen, used to tell a click from a drag.
+    start: Vec2,
+    // The cursor path in world space, a box only uses the first and last points.
+    points: Vec<Vec2>,
+    lasso: bool,
+    dragging: bool,
+}
+
+impl FeatureSelection {
+    pub fn get_features(&self) -> Vec<MapFeature> {
+        self.features.clone()
+    }
+
+    pub fn get_ids(&self) -> Vec<String> {
+        self.features.iter().map(|f| f.id.clone()).collect()
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.features.iter().any(|f| f.id == id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.features.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+
+    pub fn add(&mut self, feature: MapFeature) {
+        if !self.contains(&feature.id) {
+            self.features.push(feature);
+        }
+    }
+
+    /// Adds the feature, or removes it if it is already selected.
+    pub fn toggle(&mut self, feature: MapFeature) {
+        if self.contains(&feature.id) {
+            self.features.retain(|f| f.id != feature.id);
+        } else {
+            self.features.push(feature);
+        }
+    }
+
+    pub fn set(&mut self, features: Vec<MapFeature>) {
+        self.features.clear();
+        for feature in features {
+            self.add(feature);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.features.clear();
+    }
+
+    /// Writes the selected features out as a GeoJSON feature collection.
+    pub fn export_geojson(&self, name: &str) -> Result<(), std::io::Error> {
+        let collection = serde_json::json!({
+            "type": "FeatureCollection",
+            "features": self.features.iter().map(MapFeature::to_geojson).collect::<Vec<_>>(),
+        });
+        let mut file = File::create(format!("SELECTED_{name}.geojson"))?;
+        file.write_all(serde_json::to_string_pretty(&collection)?.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Handles clicking, box and lasso selection for the pointer tool.
+#[allow(clippy::too_many_arguments)]
+pub fn handle_feature_selection(
+    tools: Res<ToolResources>,
+    mut selection: ResMut<FeatureSelection>,
+    workspace: Res<Workspace>,
+    camera: Query<(&Camera, &GlobalTransform), With<MapViewerMarker>>,
+    q_windows: Query<&Window, With<PrimaryWindow>>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    tile_map_manager: Res<TileMapResources>,
+    state: Res<EguiBlockInputState>,
+    mut clicked: EventWriter<FeatureClickedEvent>,
+    projection: Query<&Projection, With<MapViewerMarker>>,
+    keybindings: Res<Keybindings>,
+) {
+    if !tools.pointer {
+        selection.drag = None;
+        selection.picker = None;
+        return;
+    }
+    let scale = match projection.single() {
+        Ok(Projection::Orthographic(proj)) => proj.scale,
+        _ => return,
+    };
+    let (camera, camera_transform) = match camera.single() {
+        Ok(result) => result,
+        Err(_) => return,
+    };
+    let Some(cursor) = q_windows.single().ok().and_then(|w| w.cursor_position()) else {
+        return;
+    };
+    let Ok(world) = camera.viewport_to_world_2d(camera_transform, cursor) else {
+        return;
+    };
+
+    if keybindings.just_pressed(Action::Cancel, &keys) && !state.block_input {
+        if selection.picker.is_some() {
+            selection.picker = None;
+        } else {
+            selection.clear();
+        }
+    }
+
+    if buttons.just_pressed(MouseButton::Left) && !state.block_input {
+        selection.picker = None;
+        selection.drag = Some(SelectionDrag {
+            start: cursor,
+            points: vec![world],
+            lasso: keys.any_pressed([KeyCode::AltLeft, KeyCode::AltRight]),
+            dragging: false,
+        });
+    }
+
+    if let Some(drag) = selection
+        .drag
+        .as_mut()
+        .filter(|_| buttons.pressed(MouseButton::Left))
+    {
+        if drag.start.distance(cursor) > DRAG_THRESHOLD {
+            drag.dragging = true;
+        }
+        if drag.dragging && drag.points.last() != Some(&world) {
+            if drag.lasso || drag.points.len() == 1 {
+                drag.points.push(world);
+            } else {
+                drag.points[1] = world;
+            }
+        }
+    }
+
+    if !buttons.just_released(MouseButton::Left) {
+        return;
+    }
+    let Some(drag) = selection.drag.take() else {
+        return;
+    };
+    let additive = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+
+    if !drag.dragging {
+        let mut features = features_at(&workspace, &tile_map_manager, world, scale);
+        if features.len() > 1 && !additive {
+            selection.picker = Some(OverlapPicker {
+                features,
+                position: cursor,
+                hovered: None,
+            });
+            return;
+        }
+        // Shift clicking only ever toggles the topmost feature.
+        features.truncate(1);
+        match features.first() {
+            Some(feature) if additive => selection.toggle(feature.clone()),
+            Some(_) => selection.set(features.clone()),
+            None if !additive => selection.clear(),
+            None => {}
+        }
+        if !features.is_empty() {
+            clicked.write(FeatureClickedEvent { features });
+        }
+        return;
+    }
+
+    let area: Vec<Coord> = if drag.lasso {
+        drag.points
+            .iter()
+            .map(|p| tile_map_manager.point_to_coord(*p))
+            .collect()
+    } else {
+        let a = drag.points[0];
+        let b = *drag.points.last().unwrap();
+        [a, Vec2::new(b.x, a.y), b, Vec2::new(a.x, b.y)]
+            .iter()
+            .map(|p| tile_map_manager.point_to_coord(*p))
+            .collect()
+    };
+    if area.len() < 3 {
+        return;
+    }
+    let features = features_in_area(&workspace, &area);
+    if !additive {
+        selection.clear();
+    }
+    for feature in features {
+        selection.add(feature);
+    }
+}
+
+/// Every visible feature under a world space position, topmost first. Points sit above lines
+/// and lines above areas, then higher layers win and smaller areas beat the ones around them.
+pub fn features_at(
+    workspace: &Workspace,
+    tile_map_manager: &TileMapResources,
+    position: Vec2,
+    scale: f32,
+) -> Vec<MapFeature> {
+    let tolerance = HIT_TOLERANCE * scale;
+    // Symbols are drawn much bigger than the tolerance so the whole icon should be clickable.
+    let symbol_tolerance = tolerance.max(SYMBOL_SIZE * scale / 2.0);
+    let min = tile_map_manager.point_to_coord(position - Vec2::splat(symbol_tolerance));
+    let max = tile_map_manager.point_to_coord(position + Vec2::splat(symbol_tolerance));
+    let envelope: AABB<[f64; 2]> = AABB::from_corners(
+        [min.lat as f64, min.long as f64],
+        [max.lat as f64, max.long as f64],
+    );
+
+    let mut hits: Vec<((u8, u32, f64), MapFeature)> = Vec::new();
+    for request in workspace.get_rendered_requests() {
+        if !request.get_visible() {
+            continue;
+        }
+        for feature in request
+            .get_processed_data()
+            .locate_in_envelope_intersecting(&envelope)
+        {
+            if hits.iter().any(|(_, hit)| hit.id == feature.id) {
+                continue;
+            }
+            let feature_tolerance = if feature.is_point() {
+                symbol_tolerance
+            } else {
+                tolerance
+            };
+            if !feature.hit_test(position, feature_tolerance, tile_map_manager.clone()) {
+                continue;
+            }
+            let kind = if feature.is_point() {
+                0
+            } else if !feature.closed {
+                1
+            } else {
+                2
+            };
+            let area = if feature.closed {
+                feature.geometry.unsigned_area()
+            } else {
+                0.0
+            };
+            hits.push((
+                (kind, u32::MAX - request.get_layer(), area),
+                feature.clone(),
+            ));
+        }
+    }
+    hits.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0).then(a.1.cmp(&b.1)).then(a.2.total_cmp(&b.2)));
+    hits.into_iter().map(|(_, feature)| feature).collect()
+}
+
+/// A short name for a feature, used where there is no room for all of its tags.
+pub fn feature_title(feature: &MapFeature) -> String {
+    let tag = DESCRIPTIVE_TAGS.iter().find_map(|key| {
+        feature
+            .properties
+            .get(*key)
+            .and_then(|v| v.as_str())
+            .map(|v| format!("{key}={v}"))
+    });
+    match (feature.properties.get("name").and_then(|v| v.as_str()), tag) {
+        (Some(name), Some(tag)) => format!("{name} ({tag})"),
+        (Some(name), None) => name.to_string(),
+        (None, Some(tag)) => format!("{tag} {}", feature.id),
+        (None, None) => feature.id.clone(),
+    }
+}
+
+/// Lists the overlapping features under a click so the user can pick the one they meant.
+fn feature_picker_ui(
+    mut contexts: EguiContexts,
+    mut selection: ResMut<FeatureSelection>,
+    mut clicked: EventWriter<FeatureClickedEvent>,
+) {
+    let Some(picker) = selection.picker.clone() else {
+        return;
+    };
+    let mut hovered = None;
+    let mut picked = None;
+    egui::Area::new("feature_picker".into())
+        .fixed_pos(egui::pos2(picker.position.x, picker.position.y))
+        .show(contexts.ctx_mut(), |ui| {
+            egui::Frame::popup(ui.style()).show(ui, |ui| {
+                ui.label(format!("{} features here", picker.features.len()));
+                ui.separator();
+                egui::ScrollArea::vertical()
+                    .max_height(300.0)
+                    .show(ui, |ui| {
+                        for (i, feature) in picker.features.iter().enumerate() {
+                            let response = ui.selectable_label(false, feature_title(feature));
+                            if response.hovered() {
+                                hovered = Some(i);
+                            }
+                            if response.clicked() {
+                                picked = Some(feature.clone());
+                            }
+                        }
+                    });
+            });
+        });
+
+    if let Some(feature) = picked {
+        selection.picker = None;
+        selection.set(vec![feature.clone()]);
+        clicked.write(FeatureClickedEvent {
+            features: vec![feature],
+        });
+    } else if let Some(current) = selection
+        .picker
+        .as_mut()
+        .filter(|current| current.hovered != hovered)
+    {
+        current.hovered = hovered;
+    }
+}
+
+/// How many features are selected, with buttons to export or clear them. Shown while the
+/// pointer tool is selected and something is.
+fn selection_ui(
+    mut contexts: EguiContexts,
+    tools: Res<ToolResources>,
+    mut selection: ResMut<FeatureSelection>,
+    workspace: Res<Workspace>,
+) {
+    if !tools.pointer || selection.is_empty() {
+        return;
+    }
+    let mut export = false;
+    let mut clear = false;
+    egui::Window::new("Selected features")
+        .anchor(egui::Align2::LEFT_BOTTOM, [10.0, -10.0])
+        .resizable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label(format!("{} selected", selection.len()));
+            ui.horizontal(|ui| {
+                export = ui.button("Export GeoJSON").clicked();
+                clear = ui.button("Clear").clicked();
+            });
+        });
+    if export {
+        let name = workspace
+            .workspace
+            .as_ref()
+            .map(|ws| ws.get_id())
+            .unwrap_or_else(|| "features".to_string());
+        if let Err(e) = selection.export_geojson(&name) {
+            error!("Failed to export the selection: {}", e);
+        }
+    }
+    if clear {
+        selection.clear();
+    }
+}
+
+/// Every feature on the current workspace's visible layers which touches the area, once per id.
+pub fn features_in_area(workspace: &Workspace, area: &[Coord]) -> Vec<MapFeature> {
+    let polygon = geo::Polygon::new(
+        geo::LineString(
+            area.iter()
+                .map(|c| geo::Coord {
+                    x: c.lat as f64,
+                    y: c.long as f64,
+                })
+                .collect(),
+        ),
+        vec![],
+    );
+    let Some(bbox) = geo::BoundingRect::bounding_rect(&polygon) else {
+        return Vec::new();
+    };
+    let envelope: AABB<[f64; 2]> =
+        AABB::from_corners([bbox.min().x, bbox.min().y], [bbox.max().x, bbox.max().y]);
+
+    let mut seen = HashSet::new();
+    let mut features: Vec<MapFeature> = Vec::new();
+    for request in workspace.get_requests() {
+        if !request.get_visible() {
+            continue;
+        }
+        features.extend(
+            request
+                .get_processed_data()
+                .locate_in_envelope_intersecting(&envelope)
+                .filter(|feature| {
+                    // Lines are stored as polygons, so test against their outline instead.
+                    if feature.closed {
+                        feature.geometry.intersects(&polygon)
+                    } else {
+                        feature.geometry.exterior().intersects(&polygon)
+                    }
+                })
+                .filter(|feature| seen.insert(feature.id.clone()))
+                .cloned(),
+        );
+    }
+    features
+}
+
+/// Outlines the selected features, and the box or lasso while it is being dragged.
+fn render_feature_highlight(
+    mut gizmos: Gizmos,
+    selection: Res<FeatureSelection>,
+    tile_map_manager: Res<TileMapResources>,
+    projection: Query<&Projection, With<MapViewerMarker>>,
+) {
+    let scale = match projection.single() {
+        Ok(Projection::Orthographic(proj)) => proj.scale,
+        _ => return,
+    };
+    let selected_color = Color::from(SELECTED_COLOR.with_alpha(1.0));
+    let hovered_color = Color::srgba(0.0, 0.9, 1.0, 1.0);
+
+    for feature in selection.features.iter() {
+        let color = if selection.hovered.as_ref() == Some(&feature.id) {
+            hovered_color
+        } else {
+            selected_color
+        };
+        let points = feature.get_in_world_space(tile_map_manager.clone());
+        if feature.is_point() {
+            gizmos.circle_2d(points[0], 10.0 * scale, color);
+        } else {
+            gizmos.linestrip_2d(points, color);
+        }
+    }
+
+    let picker_hovered = selection
+        .picker
+        .as_ref()
+        .and_then(|picker| picker.features.get(picker.hovered?));
+    if let Some(feature) = picker_hovered {
+        let points = feature.get_in_world_space(tile_map_manager.clone());
+        if feature.is_point() {
+            gizmos.circle_2d(points[0], 10.0 * scale, hovered_color);
+        } else {
+            gizmos.linestrip_2d(points, hovered_color);
+        }
+    }
+
+    if let Some(drag) = selection.drag.as_ref().filter(|d| d.dragging) {
+        let drag_color = Color::srgba(1.0, 0.6, 0.0, 0.8);
+        if drag.lasso {
+            let mut points = drag.points.clone();
+            points.push(drag.points[0]);
+            gizmos.linestrip_2d(points, drag_color);
+        } else if let (Some(a), Some(b)) = (drag.points.first(), drag.points.last()) {
+            gizmos.rect_2d((*a + *b) / 2.0, (*b - *a).abs(), drag_color);
+        }
+    }
+}
@@ -1,7 +1,15 @@
 use std::f32::consts::PI;
 
 use bevy::{prelude::*, render::view::RenderLayers, window::PrimaryWindow};
+use bevy_egui::{EguiContexts, EguiPreUpdateSet, egui};
 use bevy_map_viewer::{Coord, EguiBlockInputState, MapViewerMarker, TileMapResources};
+use geo::{Distance, Geodesic, GeodesicArea};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    keybindings::{Action, Keybindings},
+    workspace::{SavedMeasurement, Workspace},
+};
 
 use super::ToolResources;
 
@@ -9,86 +17,224 @@ pub struct MeasurePlugin;
 
 impl Plugin for MeasurePlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, (render_measure, handle_measure));
+        app.add_systems(Update, (render_measure, handle_measure))
+            .add_systems(Update, measure_ui.after(EguiPreUpdateSet::InitContexts));
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MeasureMode {
+    /// A polyline, measuring each segment and the total length.
+    #[default]
+    Path,
+    /// A closed polygon, measuring its area and perimeter.
+    Area,
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub enum MeasureUnits {
+    #[default]
+    Metric,
+    Imperial,
+}
+
+impl MeasureUnits {
+    pub fn format_length(&self, meters: f64) -> String {
+        match self {
+            MeasureUnits::Metric if meters < 1000.0 => format!("{meters:.1} m"),
+            MeasureUnits::Metric => format!("{:.3} km", meters / 1000.0),
+            MeasureUnits::Imperial => {
+                let feet = meters * 3.28084;
+                if feet < 528.0 {
+                    format!("{feet:.0} ft")
+                } else {
+                    format!("{:.3} mi", feet / 5280.0)
+                }
+            }
+        }
+    }
+
+    pub fn format_area(&self, square_meters: f64) -> String {
+        match self {
+            MeasureUnits::Metric if square_meters < 10_000.0 => format!("{square_meters:.0} m²"),
+            MeasureUnits::Metric if square_meters < 1_000_000.0 => {
+                format!("{:.2} ha", square_meters / 10_000.0)
+            }
+            MeasureUnits::Metric => format!("{:.3} km²", square_meters / 1_000_000.0),
+            MeasureUnits::Imperial => {
+                let acres = square_meters / 4046.856;
+                if acres < 1.0 {
+                    format!("{:.0} ft²", square_meters * 10.7639)
+                } else if acres < 640.0 {
+                    format!("{acres:.2} ac")
+                } else {
+                    format!("{:.3} mi²", acres / 640.0)
+                }
+            }
+        }
     }
 }
 
 #[derive(Component, Clone, Default)]
 pub struct Measure {
-    start: Option<Coord>,
-    end: Option<Coord>,
+    points: Vec<Coord>,
+    // Where the next point would go, drawn as a preview segment until the measurement is finished.
+    cursor: Option<Coord>,
+    finished: bool,
+    pub mode: MeasureMode,
+    pub units: MeasureUnits,
     pub enabled: bool,
     pub respawn: bool,
 }
 
 impl Measure {
     pub fn get_in_world_space(&self, tile_map_resources: TileMapResources) -> Vec<Vec2> {
-        let mut new_points = Vec::new();
-        if self.start.is_some() {
-            new_points.push(
-                self.start
-                    .unwrap()
-                    .to_game_coords(tile_map_resources.clone()),
-            );
+        self.points
+            .iter()
+            .map(|point| point.to_game_coords(tile_map_resources.clone()))
+            .collect()
+    }
+
+    pub fn get_points(&self) -> Vec<Coord> {
+        self.points.clone()
+    }
+
+    /// Replaces the current measurement, used to bring back a saved one.
+    pub fn set_points(&mut self, points: Vec<Coord>, mode: MeasureMode) {
+        self.points = points;
+        self.mode = mode;
+        self.finished = true;
+        self.respawn = true;
+    }
+
+    pub fn undo(&mut self) {
+        self.points.pop();
+        self.finished = false;
+        self.respawn = true;
+    }
+
+    pub fn clear(&mut self) {
+        self.points.clear();
+        self.cursor = None;
+        self.finished = false;
+        self.respawn = true;
+    }
+
+    /// The points to draw, including the preview point under the cursor.
+    fn drawn_points(&self) -> Vec<Coord> {
+        let mut points = self.points.clone();
+        if let (Some(cursor), false) = (self.cursor, self.finished) {
+            points.push(cursor);
+        }
+        points
+    }
+
+    /// Geodesic length of each segment in meters, in area mode this includes the closing segment.
+    pub fn segment_lengths(&self) -> Vec<f64> {
+        let mut points = self.points.clone();
+        if self.mode == MeasureMode::Area && points.len() > 2 {
+            points.push(points[0]);
         }
-        if self.end.is_some() {
-            new_points.push(self.end.unwrap().to_game_coords(tile_map_resources.clone()));
+        points
+            .windows(2)
+            .map(|w| Geodesic.distance(geo_point(&w[0]), geo_point(&w[1])))
+            .collect()
+    }
+
+    pub fn total_length(&self) -> f64 {
+        self.segment_lengths().iter().sum()
+    }
+
+    /// Geodesic area on the WGS84 ellipsoid in square meters, or `None` for paths.
+    pub fn area(&self) -> Option<f64> {
+        if self.mode != MeasureMode::Area || self.points.len() < 3 {
+            return None;
         }
-        new_points
+        let polygon = geo::Polygon::new(
+            geo::LineString::from(self.points.iter().map(geo_point).collect::<Vec<_>>()),
+            vec![],
+        );
+        Some(polygon.geodesic_area_unsigned())
     }
 
     pub fn disable(&mut self) {
         *self = Measure {
-            start: None,
-            end: None,
+            mode: self.mode,
+            units: self.units,
             enabled: false,
             respawn: true,
+            ..default()
         }
     }
 }
 
+// geo's geodesic algorithms expect x to be the longitude.
+fn geo_point(coord: &Coord) -> geo::Point {
+    geo::Point::new(coord.long as f64, coord.lat as f64)
+}
+
+/// Left click adds a point, right click or backspace removes the last one, enter finishes the
+/// measurement and escape clears it. The keys are the rebindable ones from `Keybindings`, which
+/// ignores them while a text box has focus.
+#[allow(clippy::too_many_arguments)]
 pub fn handle_measure(
     mut measure: ResMut<ToolResources>,
     camera: Query<(&Camera, &GlobalTransform), With<MapViewerMarker>>,
     q_windows: Query<&Window, With<PrimaryWindow>>,
     buttons: Res<ButtonInput<MouseButton>>,
+    keys: Res<ButtonInput<KeyCode>>,
     tile_map_manager: Res<TileMapResources>,
     state: Res<EguiBlockInputState>,
+    keybindings: Res<Keybindings>,
 ) {
     let (camera, camera_transform) = match camera.single() {
         Ok(result) => result,
         Err(_) => return,
     };
-    if measure.measure.enabled {
-        if let Some(position) = q_windows
-            .single()
-            .expect("Couldnt get the cursor position")
-            .cursor_position()
-        {
-            let pos = tile_map_manager.point_to_coord(
-                camera
-                    .viewport_to_world_2d(camera_transform, position)
-                    .unwrap(),
-            );
-            if buttons.just_pressed(MouseButton::Left) && !state.block_input {
-                let start = Coord::new(pos.lat, pos.long);
-                measure.measure.start = Some(start);
-            }
-            if buttons.pressed(MouseButton::Left)
-                && measure.measure.end != Some(Coord::new(pos.lat, pos.long))
-            {
-                measure.measure.end = Some(Coord::new(pos.lat, pos.long));
-            }
-            if buttons.just_released(MouseButton::Left) {
-                measure.measure.end = Some(Coord::new(pos.lat, pos.long));
-            }
-            if buttons.pressed(MouseButton::Right) {
-                measure.measure.start = None;
-                measure.measure.end = None;
-            }
-            measure.measure.respawn = true;
+    if !measure.measure.enabled {
+        return;
+    }
+    let Some(position) = q_windows
+        .single()
+        .expect("Couldnt get the cursor position")
+        .cursor_position()
+    else {
+        return;
+    };
+    let Ok(world) = camera.viewport_to_world_2d(camera_transform, position) else {
+        return;
+    };
+    let pos = measure.snapping.snap(world, &tile_map_manager);
+    let measure = &mut measure.measure;
+
+    if measure.cursor != Some(pos) {
+        measure.cursor = Some(pos);
+        if !measure.finished && !measure.points.is_empty() {
+            measure.respawn = true;
         }
     }
+    if state.block_input {
+        return;
+    }
+    if buttons.just_pressed(MouseButton::Left) {
+        if measure.finished {
+            measure.clear();
+        }
+        measure.points.push(pos);
+        measure.respawn = true;
+    }
+    if buttons.just_pressed(MouseButton::Right)
+        || keybindings.just_pressed(Action::RemoveLastPoint, &keys)
+    {
+        measure.undo();
+    }
+    if keybindings.just_pressed(Action::Finish, &keys) {
+        measure.finished = true;
+        measure.respawn = true;
+    }
+    if keybindings.just_pressed(Action::Cancel, &keys) {
+        measure.clear();
+    }
 }
 
 #[derive(Component)]
@@ -97,175 +243,248 @@ pub struct MeasureMarker;
 #[derive(Component)]
 pub struct MeasureTextMarker;
 
-#[derive(Component)]
-struct MeasureTextTranslation;
-
-#[derive(Component)]
-struct MeasureText;
-
 // Find a way to reduce this by say using a ParamSet
 #[allow(clippy::too_many_arguments)]
 fn render_measure(
     mut commands: Commands,
-    mut measure_query: Query<(Entity, &MeasureMarker)>,
-    mut text_trans: Query<&mut Transform, (With<Text2d>, With<MeasureTextTranslation>)>,
-    mut measure_length: Query<&mut TextSpan, With<MeasureText>>,
+    measure_query: Query<Entity, With<MeasureMarker>>,
     mut tool_res: ResMut<ToolResources>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
     asset_server: Res<AssetServer>,
-    text_query: Query<(Entity, &MeasureTextTranslation)>,
     tile_map_manager: Res<TileMapResources>,
     projection: Query<&Projection, With<MapViewerMarker>>,
 ) {
-    if tool_res.measure.respawn {
-        tool_res.measure.respawn = false;
-        let projection = match projection.single() {
-            Ok(result) => result,
-            Err(_) => return,
-        };
+    if !tool_res.measure.respawn {
+        return;
+    }
+    tool_res.measure.respawn = false;
+    let projection = match projection.single() {
+        Ok(Projection::Orthographic(proj)) => proj,
+        _ => return,
+    };
 
-        let fill_color = Srgba {
-            red: 0.75,
-            green: 0.,
-            blue: 0.,
-            alpha: 1.,
-        };
-        let projection = match projection {
-            Projection::Orthographic(proj) => proj,
-            _ => return,
-        };
-        let line_width = 3.5 * projection.scale;
-        let elevation = 100.0;
+    for entity in measure_query.iter() {
+        commands.entity(entity).despawn();
+    }
 
-        if let Ok((entity, _)) = measure_query.single_mut() {
-            commands.entity(entity).despawn();
-        }
+    let measure = &tool_res.measure;
+    let line_color = Color::srgba(0.75, 0., 0., 1.);
+    let closing_color = Color::srgba(0.75, 0., 0., 0.5);
+    let line_width = 3.5 * projection.scale;
+    let elevation = 100.0;
+    let font = asset_server.load("fonts/BagnardSans.otf");
+    let text_font = TextFont {
+        font: font.clone(),
+        font_size: 15.0,
+        ..default()
+    };
 
-        if let Ok((entity, _text)) = text_query.single() {
-            if tool_res.measure.start.is_none() && tool_res.measure.end.is_none() {
-                commands.entity(entity).despawn();
-            } else {
-                for mut transform in &mut text_trans {
-                    let points: Vec<Vec2> = vec![
-                        tool_res
-                            .measure
-                            .start
-                            .unwrap()
-                            .to_game_coords(tile_map_manager.clone()),
-                        tool_res
-                            .measure
-                            .end
-                            .unwrap()
-                            .to_game_coords(tile_map_manager.clone()),
-                    ];
-                    let direction = points[1] - points[0];
-
-                    let mut angle = direction.y.atan2(direction.x);
-                    if !(-1.6..=1.5).contains(&angle) {
-                        angle -= PI;
-                    }
+    let coords = measure.drawn_points();
+    let points: Vec<Vec2> = coords
+        .iter()
+        .map(|point| point.to_game_coords(tile_map_manager.clone()))
+        .collect();
+    let mut segments: Vec<(Vec2, Vec2, Color, f64)> = points
+        .windows(2)
+        .zip(coords.windows(2))
+        .map(|(p, c)| {
+            let length = Geodesic.distance(geo_point(&c[0]), geo_point(&c[1]));
+            (p[0], p[1], line_color, length)
+        })
+        .collect();
+    if measure.mode == MeasureMode::Area && points.len() > 2 {
+        let (first, last) = (points[0], points[points.len() - 1]);
+        let length = Geodesic.distance(geo_point(&coords[0]), geo_point(&coords[coords.len() - 1]));
+        segments.push((last, first, closing_color, length));
+    }
 
-                    let midpoint = Vec3::new(
-                        (points[0].x + points[1].x) / 2.0, // x midpoint
-                        (points[0].y + points[1].y) / 2.0, // y midpoint
-                        elevation + 20.0, // Adjusted elevation to ensure text is above the red line
-                    );
-
-                    let distance = tool_res
-                        .measure
-                        .start
-                        .unwrap()
-                        .distance(&tool_res.measure.end.unwrap());
-                    for mut span in &mut measure_length {
-                        **span = format!("{:.3} {:?}", distance.0, distance.1);
-                    }
+    for (start, end, color, length) in segments.iter() {
+        let direction = *end - *start;
+        let midpoint = (*start + *end) / 2.0;
+        let mut angle = direction.y.atan2(direction.x);
+        commands.spawn((
+            Mesh2d(meshes.add(Rectangle::new(direction.length(), line_width))),
+            Transform::from_translation(midpoint.extend(elevation))
+                .with_rotation(Quat::from_rotation_z(angle)),
+            MeshMaterial2d(materials.add(*color)),
+            MeasureMarker,
+            RenderLayers::layer(1),
+        ));
 
-                    transform.translation = midpoint;
-                    transform.scale = Vec3::new(projection.scale, projection.scale, 1.0);
-                    transform.rotation = Quat::from_rotation_z(angle);
-                }
-            }
-        } else if tool_res.measure.start.is_some() && tool_res.measure.end.is_some() {
-            let points: Vec<Vec2> = vec![
-                tool_res
-                    .measure
-                    .start
-                    .unwrap()
-                    .to_game_coords(tile_map_manager.clone()),
-                tool_res
-                    .measure
-                    .end
-                    .unwrap()
-                    .to_game_coords(tile_map_manager.clone()),
-            ];
-            let direction = points[1] - points[0];
-
-            let mut angle = direction.y.atan2(direction.x);
-            if !(-1.6..=1.5).contains(&angle) {
-                angle -= PI;
-            }
+        // Keep the text upright.
+        if !(-PI / 2.0..=PI / 2.0).contains(&angle) {
+            angle -= PI;
+        }
+        commands.spawn((
+            Text2d::new(measure.units.format_length(*length)),
+            text_font.clone(),
+            TextColor(Color::BLACK),
+            Transform::from_translation(midpoint.extend(elevation + 10.0))
+                .with_rotation(Quat::from_rotation_z(angle))
+                .with_scale(Vec3::new(projection.scale, projection.scale, 1.0)),
+            MeasureMarker,
+            MeasureTextMarker,
+            RenderLayers::layer(1),
+        ));
+    }
 
-            let midpoint = Vec3::new(
-                (points[0].x + points[1].x) / 2.0, // x midpoint
-                (points[0].y + points[1].y) / 2.0, // y midpoint
-                elevation + 10.0,
+    // The running total sits by the last point.
+    if segments.len() > 1 || measure.mode == MeasureMode::Area {
+        let Some(last) = points.last() else {
+            return;
+        };
+        let total: f64 = segments.iter().map(|s| s.3).sum();
+        let mut text = format!("Total {}", measure.units.format_length(total));
+        if measure.mode == MeasureMode::Area && coords.len() > 2 {
+            let polygon = geo::Polygon::new(
+                geo::LineString::from(coords.iter().map(geo_point).collect::<Vec<_>>()),
+                vec![],
+            );
+            text = format!(
+                "{}\nPerimeter {}",
+                measure.units.format_area(polygon.geodesic_area_unsigned()),
+                measure.units.format_length(total)
             );
+        }
+        commands.spawn((
+            Text2d::new(text),
+            text_font,
+            TextColor(Color::BLACK),
+            Transform::from_translation(
+                (*last + Vec2::new(0.0, 20.0 * projection.scale)).extend(elevation + 10.0),
+            )
+            .with_scale(Vec3::new(projection.scale, projection.scale, 1.0)),
+            MeasureMarker,
+            MeasureTextMarker,
+            RenderLayers::layer(1),
+        ));
+    }
+}
 
-            let font = asset_server.load("fonts/BagnardSans.otf");
-            let text_font = TextFont {
-                font: font.clone(),
-                font_size: 15.0 * projection.scale,
-                ..default()
-            };
+/// The measure panel, with the per segment lengths, totals, unit switch and saved measurements.
+fn measure_ui(
+    mut contexts: EguiContexts,
+    mut tools: ResMut<ToolResources>,
+    mut workspace: ResMut<Workspace>,
+    mut name: Local<String>,
+) {
+    if !tools.measure.enabled {
+        return;
+    }
+    let measure = &mut tools.measure;
+    let mut save = false;
+    egui::Window::new("Measure")
+        .anchor(egui::Align2::RIGHT_BOTTOM, [-10.0, -10.0])
+        .resizable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                let mode = measure.mode;
+                ui.selectable_value(&mut measure.mode, MeasureMode::Path, "Path");
+                ui.selectable_value(&mut measure.mode, MeasureMode::Area, "Area");
+                ui.separator();
+                let units = measure.units;
+                ui.selectable_value(&mut measure.units, MeasureUnits::Metric, "Metric");
+                ui.selectable_value(&mut measure.units, MeasureUnits::Imperial, "Imperial");
+                if mode != measure.mode || units != measure.units {
+                    measure.respawn = true;
+                }
+            });
+            ui.separator();
 
-            for mut span in &mut measure_length {
-                **span = format!("{:.2} km", points[0].distance(points[1]) / 1000.0);
+            let lengths = measure.segment_lengths();
+            if lengths.is_empty() {
+                ui.label("Click on the map to add points.");
+            }
+            egui::ScrollArea::vertical()
+                .max_height(150.0)
+                .show(ui, |ui| {
+                    for (i, length) in lengths.iter().enumerate() {
+                        ui.label(format!(
+                            "Segment {}: {}",
+                            i + 1,
+                            measure.units.format_length(*length)
+                        ));
+                    }
+                });
+            match measure.area() {
+                Some(area) => {
+                    ui.strong(format!("Area: {}", measure.units.format_area(area)));
+                    ui.strong(format!(
+                        "Perimeter: {}",
+                        measure.units.format_length(measure.total_length())
+                    ));
+                }
+                None => {
+                    ui.strong(format!(
+                        "Total: {}",
+                        measure.units.format_length(measure.total_length())
+                    ));
+                }
             }
 
-            commands
-                .spawn((
-                    Text2d::new(""),
-                    text_font,
-                    Transform::from_translation(midpoint)
-                        .with_rotation(Quat::from_rotation_z(angle)),
-                    MeasureTextTranslation,
-                ))
-                .with_child((TextSpan::default(), TextColor(Color::BLACK), MeasureText));
-        }
+            ui.horizontal(|ui| {
+                if ui.button("Undo point").clicked() {
+                    measure.undo();
+                }
+                if ui.button("Clear").clicked() {
+                    measure.clear();
+                }
+            });
 
-        if tool_res.measure.start.is_some() && tool_res.measure.end.is_some() {
-            let points: Vec<Vec2> = vec![
-                tool_res
-                    .measure
-                    .start
-                    .unwrap()
-                    .to_game_coords(tile_map_manager.clone()),
-                tool_res
-                    .measure
-                    .end
-                    .unwrap()
-                    .to_game_coords(tile_map_manager.clone()),
-            ];
-            let direction = points[1] - points[0];
-
-            let angle = direction.y.atan2(direction.x);
-
-            let midpoint = Vec3::new(
-                (points[0].x + points[1].x) / 2.0, // x midpoint
-                (points[0].y + points[1].y) / 2.0, // y midpoint
-                elevation,
-            );
+            let Some(ws) = workspace.workspace.as_mut() else {
+                return;
+            };
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::TextEdit::singleline(&mut *name)
+                        .hint_text("Name")
+                        .desired_width(100.0),
+                );
+                if ui
+                    .add_enabled(measure.points.len() > 1, egui::Button::new("Save"))
+                    .clicked()
+                {
+                    let title = if name.is_empty() {
+                        format!("Measurement {}", ws.get_measurements().len() + 1)
+                    } else {
+                        std::mem::take(&mut *name)
+                    };
+                    ws.add_measurement(SavedMeasurement {
+                        name: title,
+                        mode: measure.mode,
+                        points: measure.get_points(),
+                        length: measure.total_length(),
+                        area: measure.area(),
+                    });
+                    save = true;
+                }
+            });
 
-            let length = points[0].distance(points[1]);
+            for (i, saved) in ws.get_measurements().iter().enumerate() {
+                ui.horizontal(|ui| {
+                    let value = match saved.area {
+                        Some(area) => measure.units.format_area(area),
+                        None => measure.units.format_length(saved.length),
+                    };
+                    ui.label(format!("{}: {}", saved.name, value));
+                    if ui.small_button("Show").clicked() {
+                        measure.set_points(saved.points.clone(), saved.mode);
+                    }
+                    if ui.small_button("Delete").clicked() {
+                        ws.remove_measurement(i);
+                        save = true;
+                    }
+                });
+            }
+        });
 
-            commands.spawn((
-                Mesh2d(meshes.add(Rectangle::new(length, line_width))),
-                Transform::from_translation(midpoint).with_rotation(Quat::from_rotation_z(angle)),
-                MeshMaterial2d(materials.add(Color::from(fill_color))),
-                MeasureMarker,
-                RenderLayers::layer(1),
-            ));
+    if save {
+        // Switching workspace saves the copy in the selection areas.
+        if let Some(ws) = &workspace.workspace {
+            tools.selection_areas.replace(ws.clone());
         }
+        let _ = workspace.save_workspace();
     }
 }
@@ -10,8 +10,14 @@
 //! - Facilitate workspace area selection and boundary definition
 //! 
 //! ## Sub-modules
+//! - `boundary_search`: Workspace selections from features and administrative boundaries
+//! - `feature_picker`: Click-to-query of nearby OSM features via Overpass
+//! - `feature_selection`: Picking, box and lasso selection of map features
 //! - `measure`: Distance and area measurement tools
 //! - `pin`: Map marker and annotation placement tools
+//! - `selection_ops`: Buffer, union, intersection and subtraction of selections
+//! - `sketch`: Drawing of user authored points, lines, areas and text notes
+//! - `snapping`: Cursor snapping to feature vertices, edges and centroids
 //! - `tool`: Base tool trait and common tool functionality
 //! - `ui`: User interface components for tool interaction
 //! - `work_space_selector`: Area selection tools for workspace definition
@@ -29,14 +35,26 @@
 //! - Selection tools (rectangle, polygon, circle)
 //! - Drawing tools (freehand, shapes)
 
+mod boundary_search;
+mod feature_picker;
+mod feature_selection;
 mod measure;
 mod pin;
+mod selection_ops;
+mod sketch;
+mod snapping;
 mod tool;
 mod ui;
 mod work_space_selector;
 
+pub use boundary_search::*;
+pub use feature_picker::*;
+pub use feature_selection::*;
 pub use measure::*;
 pub use pin::*;
+pub use selection_ops::*;
+pub use sketch::*;
+pub use snapping::*;
 pub use tool::*;
 pub use ui::*;
 pub use work_space_selector::*;
@@ -1,6 +1,17 @@
 use bevy::{prelude::*, render::view::RenderLayers, window::PrimaryWindow};
-use bevy_map_viewer::{Coord, EguiBlockInputState, MapViewerMarker, TileMapResources};
-use rstar::{AABB, RTree, RTreeObject};
+use bevy_egui::{EguiContexts, EguiPreUpdateSet, egui};
+use bevy_map_viewer::{
+    Coord, EguiBlockInputState, MapViewerMarker, TileMapResources, ZoomChangedEvent,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    geojson::{SymbolCache, SymbolIcon},
+    keybindings::{Action, Keybindings},
+    workspace::{History, Workspace},
+};
 
 use super::ToolResources;
 
@@ -8,130 +19,415 @@ pub struct PinPlugin;
 
 impl Plugin for PinPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, (render_pins, handle_pin));
+        app.add_systems(Update, (render_pins, handle_pin))
+            .add_systems(Update, pin_ui.after(EguiPreUpdateSet::InitContexts));
     }
 }
 
-#[derive(Clone)]
+// Size of the pin on screen in pixels, the icons are drawn on a 24 unit grid.
+const PIN_SIZE: f32 = 30.0;
+const PIN_ELEVATION: f32 = 500.0;
+
+/// The pin tool state. The pins themselves are saved in the current workspace.
+#[derive(Clone, Default)]
 pub struct Pins {
-    pins: RTree<Pin>,
     pub enabled: bool,
     pub respawn: bool,
+    /// The id of the pin open in the editor window.
+    pub editing: Option<String>,
+    // The pin being dragged and where it was grabbed relative to its location.
+    drag: Option<(String, Vec2)>,
+    moved: bool,
 }
 
-impl Pins {
-    pub fn add_pin(&mut self, pin: Pin) {
-        self.pins.insert(pin);
-    }
-}
-
-impl Default for Pins {
-    fn default() -> Self {
-        Pins {
-            pins: RTree::new(),
-            enabled: false,
-            respawn: false,
-        }
-    }
-}
-
-#[derive(Component, Clone)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Pin {
+    pub id: String,
     pub location: Coord,
-    // Add the ability to change the icon. Do that in a similar way to the settings for the overpass query.
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub note: String,
+    #[serde(default = "default_pin_icon")]
+    pub icon: SymbolIcon,
+    #[serde(default = "default_pin_color")]
+    pub color: Srgba,
 }
 
-impl RTreeObject for Pin {
-    type Envelope = AABB<[f32; 2]>;
+fn default_pin_icon() -> SymbolIcon {
+    SymbolIcon::Pin
+}
 
-    fn envelope(&self) -> Self::Envelope {
-        AABB::from_point([self.location.lat, self.location.long])
+fn default_pin_color() -> Srgba {
+    Srgba {
+        red: 0.,
+        green: 0.,
+        blue: 1.,
+        alpha: 0.75,
     }
 }
 
 impl Pin {
+    pub fn new(location: Coord, title: String) -> Self {
+        Pin {
+            id: Uuid::new_v4().to_string(),
+            location,
+            title,
+            note: String::new(),
+            icon: default_pin_icon(),
+            color: default_pin_color(),
+        }
+    }
+
     pub fn get_in_world_space(&self, tile_map_resources: TileMapResources) -> Vec2 {
         self.location.to_game_coords(tile_map_resources)
     }
+
+    /// The middle of the drawn icon, pins sit on their tip so the head is above the location.
+    fn icon_center(&self, tile_map_resources: TileMapResources, scale: f32) -> Vec2 {
+        let location = self.get_in_world_space(tile_map_resources);
+        if self.icon == SymbolIcon::Pin {
+            location + Vec2::new(0.0, PIN_SIZE / 2.0 * scale)
+        } else {
+            location
+        }
+    }
+
+    /// The pin as a GeoJSON point feature.
+    pub fn to_geojson(&self) -> serde_json::Value {
+        json!({
+            "type": "Feature",
+            "id": self.id,
+            "geometry": {
+                "type": "Point",
+                "coordinates": [self.location.long, self.location.lat],
+            },
+            "properties": {
+                "title": self.title,
+                "note": self.note,
+                "icon": self.icon,
+                "color": self.color.to_hex(),
+            },
+        })
+    }
 }
 
+/// Clicking empty space drops a new pin, clicking a pin opens it in the editor and dragging
+/// a pin moves it.
+#[allow(clippy::too_many_arguments)]
 pub fn handle_pin(
-    mut pin: ResMut<ToolResources>,
+    mut tools: ResMut<ToolResources>,
+    mut workspace: ResMut<Workspace>,
     camera: Query<(&Camera, &GlobalTransform), With<MapViewerMarker>>,
     q_windows: Query<&Window, With<PrimaryWindow>>,
     buttons: Res<ButtonInput<MouseButton>>,
     tile_map_manager: Res<TileMapResources>,
     state: Res<EguiBlockInputState>,
+    projection: Query<&Projection, With<MapViewerMarker>>,
+    mut history: ResMut<History>,
 ) {
+    if !tools.pins.enabled {
+        return;
+    }
     let (camera, camera_transform) = match camera.single() {
         Ok(result) => result,
         Err(_) => return,
     };
-    if pin.pins.enabled && !state.block_input {
-        if let Some(position) = q_windows
-            .single()
-            .expect("Cant get cursor position")
-            .cursor_position()
-        {
-            if buttons.just_pressed(MouseButton::Left) {
-                let pos = tile_map_manager.point_to_coord(
-                    camera
-                        .viewport_to_world_2d(camera_transform, position)
-                        .unwrap(),
+    let scale = match projection.single() {
+        Ok(Projection::Orthographic(proj)) => proj.scale,
+        _ => return,
+    };
+    let Some(position) = q_windows
+        .single()
+        .expect("Cant get cursor position")
+        .cursor_position()
+    else {
+        return;
+    };
+    let Ok(world) = camera.viewport_to_world_2d(camera_transform, position) else {
+        return;
+    };
+    let Some(ws) = workspace.workspace.as_mut() else {
+        return;
+    };
+
+    if buttons.just_pressed(MouseButton::Left) && !state.block_input {
+        let hit = ws.get_pins().into_iter().rev().find(|pin| {
+            pin.icon_center(tile_map_manager.clone(), scale)
+                .distance(world)
+                <= PIN_SIZE / 2.0 * scale
+        });
+        match hit {
+            Some(pin) => {
+                let offset = world - pin.get_in_world_space(tile_map_manager.clone());
+                tools.pins.editing = Some(pin.id.clone());
+                tools.pins.drag = Some((pin.id, offset));
+                tools.pins.moved = false;
+            }
+            None => {
+                let pos = tools.snapping.snap(world, &tile_map_manager);
+                let pin = Pin::new(
+                    Coord::new(pos.lat, pos.long),
+                    format!("Pin {}", ws.get_pins().len() + 1),
                 );
-                pin.pins.add_pin(Pin {
-                    location: Coord::new(pos.lat, pos.long),
-                });
-                pin.pins.respawn = true;
+                tools.pins.editing = Some(pin.id.clone());
+                let before = ws.get_pins();
+                ws.add_pin(pin);
+                history.record_pins("Add pin", before, ws);
+                tools.selection_areas.replace(ws.clone());
+                let _ = workspace.save_workspace();
             }
         }
+        tools.pins.respawn = true;
+        return;
+    }
+
+    let Some((id, offset)) = tools.pins.drag.clone() else {
+        return;
+    };
+    if buttons.pressed(MouseButton::Left) {
+        // A snapped pin goes exactly on the target, not where the grab offset would put it.
+        let pos = match tools.snapping.target {
+            Some(target) => target.coord,
+            None => tile_map_manager.point_to_coord(world - offset),
+        };
+        if let Some(mut pin) = ws.get_pin(&id).filter(|pin| pin.location != pos) {
+            pin.location = pos;
+            let before = ws.get_pins();
+            ws.update_pin(pin);
+            history.record_pins_step("Move pin", &id, before, ws);
+            tools.pins.moved = true;
+            tools.pins.respawn = true;
+        }
+    } else {
+        tools.pins.drag = None;
+        history.end_interaction();
+        if tools.pins.moved {
+            // Switching workspace saves the copy in the selection areas.
+            tools.selection_areas.replace(ws.clone());
+            let _ = workspace.save_workspace();
+        }
     }
 }
 
+#[derive(Component)]
+pub struct PinMarker;
+
+#[allow(clippy::too_many_arguments)]
 fn render_pins(
     mut commands: Commands,
-    selections_query: Query<(Entity, &Transform, &Pin)>,
+    pins_query: Query<Entity, With<PinMarker>>,
     tile_map_manager: Res<TileMapResources>,
-    pin: Res<ToolResources>,
+    workspace: Res<Workspace>,
+    mut cache: ResMut<SymbolCache>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
+    asset_server: Res<AssetServer>,
     camera: Query<&Projection, With<MapViewerMarker>>,
+    mut tools: ResMut<ToolResources>,
+    mut zoom_events: EventReader<ZoomChangedEvent>,
 ) {
+    // The pins are scaled to the zoom, so they only change when it does or they are edited.
+    let zoomed = zoom_events.read().count() > 0;
+    if !tools.pins.respawn && !zoomed {
+        return;
+    }
+    tools.pins.respawn = false;
     let projection = match camera.single() {
         Ok(camera) => camera,
         Err(_) => return,
     };
-    for (entity, _, _) in selections_query.iter() {
+    for entity in pins_query.iter() {
         commands.entity(entity).despawn();
     }
 
-    let fill_color = Srgba {
-        red: 0.,
-        green: 0.,
-        blue: 1.,
-        alpha: 0.75,
-    };
-
     let projection = match projection {
         Projection::Orthographic(proj) => proj,
         _ => return,
     };
-    let width = 7.5 * projection.scale;
-    let elevation = 500.0;
+    let Some(ws) = &workspace.workspace else {
+        return;
+    };
+    // The icons are drawn on a 24 unit grid, so this keeps the pin the same size on screen.
+    let size = PIN_SIZE / 24.0 * projection.scale;
+    let font = asset_server.load("fonts/BagnardSans.otf");
 
-    for pin in pin.pins.pins.iter() {
+    for pin in ws.get_pins().iter() {
         let loc = pin.get_in_world_space(tile_map_manager.clone());
         commands.spawn((
-            Mesh2d(meshes.add(Circle::new(width))),
-            Transform::from_translation(Vec3::new(loc.x, loc.y, elevation)),
-            MeshMaterial2d(materials.add(Color::from(fill_color))),
-            pin.clone(),
+            Mesh2d(cache.mesh(pin.icon, &mut meshes)),
+            Transform::from_translation(Vec3::new(loc.x, loc.y, PIN_ELEVATION))
+                .with_scale(Vec3::new(size, size, 1.0)),
+            MeshMaterial2d(cache.material(pin.color, &mut materials)),
+            PinMarker,
+            RenderLayers::layer(1),
+        ));
+        if pin.title.is_empty() {
+            continue;
+        }
+        commands.spawn((
+            Text2d::new(pin.title.clone()),
+            TextFont {
+                font: font.clone(),
+                font_size: 13.0,
+                ..default()
+            },
+            TextColor(Color::BLACK),
+            Transform::from_translation(
+                (loc - Vec2::new(0.0, 10.0 * projection.scale)).extend(PIN_ELEVATION + 1.0),
+            )
+            .with_scale(Vec3::new(projection.scale, projection.scale, 1.0)),
+            PinMarker,
             RenderLayers::layer(1),
         ));
     }
 }
 
-const _PIN_ICON: [&str; 2] = [
-    "M12 2a8 8 0 0 0-2.565 15.572L12 21.992l2.565-4.42A8 8 0 0 0 12 2zm1.626 13.77-.391.11L12 18.008l-1.235-2.128-.391-.11a6 6 0 1 1 3.252 0z",
-    "M12 7a3 3 0 1 0 3 3 3 3 0 0 0-3-3zm0 4a1 1 0 1 1 1-1 1 1 0 0 1-1 1z",
-];
+/// The pin list, shown while the pin tool is selected, and the editor for the selected pin.
+#[allow(clippy::too_many_arguments)]
+fn pin_ui(
+    mut contexts: EguiContexts,
+    mut tools: ResMut<ToolResources>,
+    mut workspace: ResMut<Workspace>,
+    mut tile_map_res: ResMut<TileMapResources>,
+    mut camera: Query<&mut Transform, With<MapViewerMarker>>,
+    mut zoom_event: EventWriter<ZoomChangedEvent>,
+    mut history: ResMut<History>,
+    keys: Res<ButtonInput<KeyCode>>,
+    keybindings: Res<Keybindings>,
+) {
+    let Some(ws) = workspace.workspace.as_mut() else {
+        return;
+    };
+    let ctx = contexts.ctx_mut();
+    let mut save = false;
+    let mut go_to: Option<Coord> = None;
+    let mut export = false;
+
+    if tools.pins.enabled {
+        egui::Window::new("Pins")
+            .anchor(egui::Align2::RIGHT_BOTTOM, [-10.0, -10.0])
+            .resizable(false)
+            .show(ctx, |ui| {
+                let pins = ws.get_pins();
+                if pins.is_empty() {
+                    ui.label("Click on the map to drop a pin.");
+                }
+                egui::ScrollArea::vertical()
+                    .max_height(250.0)
+                    .show(ui, |ui| {
+                        for pin in pins.iter() {
+                            ui.horizontal(|ui| {
+                                let selected = tools.pins.editing.as_ref() == Some(&pin.id);
+                                if ui
+                                    .selectable_label(
+                                        selected,
+                                        format!("{:?} {}", pin.icon, pin.title),
+                                    )
+                                    .clicked()
+                                {
+                                    tools.pins.editing = Some(pin.id.clone());
+                                }
+                                if ui.small_button("Go to").clicked() {
+                                    go_to = Some(pin.location);
+                                }
+                            });
+                        }
+                    });
+                ui.separator();
+                export = ui
+                    .add_enabled(!pins.is_empty(), egui::Button::new("Export GeoJSON"))
+                    .on_hover_text("Export the pins and the sketch")
+                    .clicked();
+            });
+    }
+
+    let editing = tools.pins.editing.clone().and_then(|id| ws.get_pin(&id));
+    if let Some(mut pin) = editing {
+        let mut open = true;
+        let mut delete = tools.pins.enabled && keybindings.just_pressed(Action::Delete, &keys);
+        egui::Window::new("Pin")
+            .id(egui::Id::new("pin_editor"))
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label("Title");
+                save |= ui.text_edit_singleline(&mut pin.title).lost_focus();
+                ui.label("Note");
+                save |= ui
+                    .add(egui::TextEdit::multiline(&mut pin.note).desired_rows(4))
+                    .lost_focus();
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_id_salt("pin_icon")
+                        .selected_text(format!("{:?}", pin.icon))
+                        .show_ui(ui, |ui| {
+                            for option in SymbolIcon::ALL {
+                                save |= ui
+                                    .selectable_value(&mut pin.icon, option, format!("{option:?}"))
+                                    .clicked();
+                            }
+                        });
+                    let mut color = egui::Color32::from_rgba_unmultiplied(
+                        (pin.color.red * 255.0) as u8,
+                        (pin.color.green * 255.0) as u8,
+                        (pin.color.blue * 255.0) as u8,
+                        (pin.color.alpha * 255.0) as u8,
+                    );
+                    if ui.color_edit_button_srgba(&mut color).changed() {
+                        pin.color = Srgba::rgba_u8(color.r(), color.g(), color.b(), color.a());
+                        save = true;
+                    }
+                });
+                ui.horizontal(|ui| {
+                    if ui.button("Go to").clicked() {
+                        go_to = Some(pin.location);
+                    }
+                    if ui.button("Delete").clicked() {
+                        delete = true;
+                    }
+                });
+            });
+
+        let before = ws.get_pins();
+        if delete {
+            ws.remove_pin(&pin.id);
+            history.record_pins("Delete pin", before, ws);
+            tools.selection_areas.replace(ws.clone());
+            tools.pins.editing = None;
+            tools.pins.respawn = true;
+            save = true;
+        } else {
+            // Keep the edits while typing, they are written to disk once the field loses focus.
+            if Some(&pin) != ws.get_pin(&pin.id).as_ref() {
+                let id = pin.id.clone();
+                ws.update_pin(pin);
+                history.record_pins_step("Edit pin", &id, before, ws);
+                tools.selection_areas.replace(ws.clone());
+                tools.pins.respawn = true;
+            }
+            if !open {
+                tools.pins.editing = None;
+                save = true;
+            }
+            if save {
+                history.end_interaction();
+            }
+        }
+    }
+
+    if save {
+        let _ = workspace.save_workspace();
+    }
+    if let Some(Err(e)) = export.then(|| workspace.export_geojson()) {
+        error!("Failed to export pins: {}", e);
+    }
+
+    let Some(location) = go_to else {
+        return;
+    };
+    if let Ok(mut camera_transform) = camera.single_mut() {
+        tile_map_res.location_manager.location = location;
+        camera_transform.translation = location.to_game_coords(tile_map_res.clone()).extend(0.0);
+        zoom_event.write(ZoomChangedEvent);
+    }
+}
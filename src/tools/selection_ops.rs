@@ -0,0 +1,342 @@
+//! Buffer, union, intersection and subtraction of workspace selections. Each result is added as a
+//! new workspace area, the inputs are left as they are.
+
+use std::f64::consts::TAU;
+
+use bevy::prelude::*;
+use bevy_egui::{EguiContexts, EguiPreUpdateSet, egui};
+use geo::{
+    BooleanOps, LineString, MapCoords, MultiPolygon, Polygon,
+    orient::{Direction, Orient},
+    unary_union,
+};
+
+use crate::{
+    geojson::{METERS_PER_DEGREE, MapFeature},
+    workspace::{History, HistoryChange, Selection, WorkspaceData},
+};
+
+use super::{FeatureSelection, ToolResources, feature_title};
+
+pub struct SelectionOpsPlugin;
+
+impl Plugin for SelectionOpsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            selection_ops_ui.after(EguiPreUpdateSet::InitContexts),
+        );
+    }
+}
+
+// How many sides the rounded corners of a buffer get.
+const BUFFER_SEGMENTS: usize = 16;
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub enum SelectionOperation {
+    #[default]
+    Union,
+    Intersection,
+    Subtract,
+    Buffer,
+}
+
+impl SelectionOperation {
+    const ALL: [SelectionOperation; 4] = [
+        SelectionOperation::Union,
+        SelectionOperation::Intersection,
+        SelectionOperation::Subtract,
+        SelectionOperation::Buffer,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            SelectionOperation::Union => "Union",
+            SelectionOperation::Intersection => "Intersection",
+            SelectionOperation::Subtract => "Subtract",
+            SelectionOperation::Buffer => "Buffer",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SelectionOps {
+    pub operation: SelectionOperation,
+    // Workspace ids.
+    first: Option<String>,
+    second: Option<String>,
+    /// Buffer distance in meters, negative shrinks the area.
+    pub distance: f64,
+    buffer_feature: bool,
+    error: Option<String>,
+}
+
+impl Default for SelectionOps {
+    fn default() -> Self {
+        SelectionOps {
+            operation: SelectionOperation::Union,
+            first: None,
+            second: None,
+            distance: 100.0,
+            buffer_feature: false,
+            error: None,
+        }
+    }
+}
+
+/// An equirectangular projection to meters around a point, close enough at city scale.
+struct LocalProjection {
+    origin: geo::Coord,
+    cos: f64,
+}
+
+impl LocalProjection {
+    fn new(origin: geo::Coord) -> Self {
+        LocalProjection {
+            origin,
+            cos: origin.x.to_radians().cos().max(0.01),
+        }
+    }
+
+    fn to_local(&self, c: geo::Coord) -> geo::Coord {
+        geo::Coord {
+            x: (c.y - self.origin.y) * self.cos * METERS_PER_DEGREE,
+            y: (c.x - self.origin.x) * METERS_PER_DEGREE,
+        }
+    }
+
+    fn to_map(&self, c: geo::Coord) -> geo::Coord {
+        geo::Coord {
+            x: c.y / METERS_PER_DEGREE + self.origin.x,
+            y: c.x / (self.cos * METERS_PER_DEGREE) + self.origin.y,
+        }
+    }
+}
+
+/// Grows an area by `meters`, or shrinks it when negative.
+pub fn buffer_area(area: &MultiPolygon, meters: f64) -> MultiPolygon {
+    buffer_geometry(area, &[], meters)
+}
+
+/// Buffers a map feature: areas grow, lines and points become a corridor or circle around them.
+pub fn buffer_feature(feature: &MapFeature, meters: f64) -> MultiPolygon {
+    if feature.closed && !feature.is_point() {
+        return buffer_area(&MultiPolygon::new(vec![feature.geometry.clone()]), meters);
+    }
+    buffer_geometry(
+        &MultiPolygon::new(vec![]),
+        &[feature.geometry.exterior().clone()],
+        meters.abs(),
+    )
+}
+
+fn buffer_geometry(area: &MultiPolygon, lines: &[LineString], meters: f64) -> MultiPolygon {
+    let Some(origin) = area
+        .iter()
+        .flat_map(|p| p.exterior().0.iter())
+        .chain(lines.iter().flat_map(|l| l.0.iter()))
+        .next()
+        .copied()
+    else {
+        return MultiPolygon::new(vec![]);
+    };
+    let projection = LocalProjection::new(origin);
+    let area = area.map_coords(|c| projection.to_local(c));
+    let lines: Vec<LineString> = lines
+        .iter()
+        .map(|l| l.map_coords(|c| projection.to_local(c)))
+        .collect();
+
+    // The band the outline sweeps out, added to the area to grow it or cut away to shrink it.
+    let rings = area
+        .iter()
+        .flat_map(|p| std::iter::once(p.exterior()).chain(p.interiors()))
+        .chain(lines.iter());
+    let band = unary_union(&stroke(rings, meters.abs()));
+    let result = if meters >= 0.0 {
+        area.union(&band)
+    } else {
+        area.difference(&band)
+    };
+    result.map_coords(|c| projection.to_map(c))
+}
+
+/// A circle around every vertex and a rectangle along every segment, all wound the same way.
+fn stroke<'a>(lines: impl Iterator<Item = &'a LineString>, distance: f64) -> Vec<Polygon> {
+    let mut pieces = Vec::new();
+    if distance <= 0.0 {
+        return pieces;
+    }
+    for line in lines {
+        for point in line.0.iter() {
+            let circle: Vec<geo::Coord> = (0..BUFFER_SEGMENTS)
+                .map(|i| {
+                    let angle = i as f64 / BUFFER_SEGMENTS as f64 * TAU;
+                    geo::Coord {
+                        x: point.x + distance * angle.cos(),
+                        y: point.y + distance * angle.sin(),
+                    }
+                })
+                .collect();
+            pieces.push(Polygon::new(LineString::from(circle), vec![]));
+        }
+        for segment in line.lines() {
+            let delta = segment.delta();
+            let length = delta.x.hypot(delta.y);
+            if length == 0.0 {
+                continue;
+            }
+            let normal = geo::Coord {
+                x: -delta.y / length * distance,
+                y: delta.x / length * distance,
+            };
+            pieces.push(Polygon::new(
+                LineString::from(vec![
+                    segment.start + normal,
+                    segment.end + normal,
+                    segment.end - normal,
+                    segment.start - normal,
+                ]),
+                vec![],
+            ));
+        }
+    }
+    pieces
+        .into_iter()
+        .map(|piece| piece.orient(Direction::Default))
+        .collect()
+}
+
+fn selection_ops_ui(
+    mut contexts: EguiContexts,
+    mut tools: ResMut<ToolResources>,
+    feature_selection: Res<FeatureSelection>,
+    mut history: ResMut<History>,
+) {
+    if !tools.selection_settings.enabled {
+        return;
+    }
+    let areas: Vec<WorkspaceData> = tools.selection_areas.areas.iter().cloned().collect();
+    let selected_feature = match feature_selection.get_features().as_slice() {
+        [feature] => Some(feature.clone()),
+        _ => None,
+    };
+    let name_of = |id: &Option<String>| {
+        areas
+            .iter()
+            .find(|area| Some(area.get_id()) == *id)
+            .map(|area| area.get_name())
+            .unwrap_or_else(|| "Choose...".to_string())
+    };
+    let mut apply = false;
+
+    let ops = &mut tools.selection_ops;
+    egui::Window::new("Selection operations")
+        .default_open(false)
+        .default_width(260.0)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                for operation in SelectionOperation::ALL {
+                    ui.selectable_value(&mut ops.operation, operation, operation.label());
+                }
+            });
+            ui.separator();
+            let buffering = ops.operation == SelectionOperation::Buffer;
+            if buffering {
+                ui.add_enabled(
+                    selected_feature.is_some(),
+                    egui::Checkbox::new(&mut ops.buffer_feature, "Buffer the selected feature"),
+                );
+                if selected_feature.is_none() {
+                    ops.buffer_feature = false;
+                }
+            }
+            if !(buffering && ops.buffer_feature) {
+                egui::ComboBox::from_label(if buffering { "Area" } else { "First" })
+                    .selected_text(name_of(&ops.first))
+                    .show_ui(ui, |ui| {
+                        for area in areas.iter() {
+                            ui.selectable_value(
+                                &mut ops.first,
+                                Some(area.get_id()),
+                                area.get_name(),
+                            );
+                        }
+                    });
+            }
+            if buffering {
+                ui.horizontal(|ui| {
+                    ui.label("Distance");
+                    ui.add(
+                        egui::DragValue::new(&mut ops.distance)
+                            .speed(5.0)
+                            .suffix(" m"),
+                    );
+                });
+            } else {
+                egui::ComboBox::from_label(match ops.operation {
+                    SelectionOperation::Subtract => "Minus",
+                    _ => "Second",
+                })
+                .selected_text(name_of(&ops.second))
+                .show_ui(ui, |ui| {
+                    for area in areas.iter() {
+                        ui.selectable_value(&mut ops.second, Some(area.get_id()), area.get_name());
+                    }
+                });
+            }
+            if let Some(error) = &ops.error {
+                ui.colored_label(egui::Color32::LIGHT_RED, error);
+            }
+            apply = ui.button("Create workspace").clicked();
+        });
+
+    if !apply {
+        return;
+    }
+    let find = |id: &Option<String>| areas.iter().find(|area| Some(area.get_id()) == *id);
+    let ops = tools.selection_ops.clone();
+    let result = match (ops.operation, find(&ops.first), find(&ops.second)) {
+        (SelectionOperation::Buffer, _, _) if ops.buffer_feature => {
+            selected_feature.map(|feature| {
+                (
+                    format!("{} buffer {} m", feature_title(&feature), ops.distance),
+                    buffer_feature(&feature, ops.distance),
+                )
+            })
+        }
+        (SelectionOperation::Buffer, Some(area), _) => Some((
+            format!("{} buffer {} m", area.get_name(), ops.distance),
+            buffer_area(&area.get_selection().to_multi_polygon(), ops.distance),
+        )),
+        (operation, Some(first), Some(second)) => {
+            let a = first.get_selection().to_multi_polygon();
+            let b = second.get_selection().to_multi_polygon();
+            let (word, shape) = match operation {
+                SelectionOperation::Intersection => ("intersect", a.intersection(&b)),
+                SelectionOperation::Subtract => ("minus", a.difference(&b)),
+                _ => ("union", a.union(&b)),
+            };
+            Some((
+                format!("{} {word} {}", first.get_name(), second.get_name()),
+                shape,
+            ))
+        }
+        _ => None,
+    };
+
+    let ops = &mut tools.selection_ops;
+    let Some((name, shape)) = result else {
+        ops.error = Some("Choose the areas to use first.".to_string());
+        return;
+    };
+    if shape.0.is_empty() {
+        ops.error = Some("The result is empty.".to_string());
+        return;
+    }
+    ops.error = None;
+    let area = WorkspaceData::new(name, Selection::from_multi_polygon(&shape));
+    history.record(ops.operation.label(), HistoryChange::area_added(&area));
+    tools.selection_areas.add(area);
+    tools.selection_areas.respawn = true;
+}
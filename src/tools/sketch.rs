@@ -0,0 +1,410 @@
+//! Drawing of user authored points, lines, areas and text notes, used to mark things like
+//! proposed developments. They are kept as `MapFeature`s in a "user sketch" layer of the
+//! workspace, which is saved with the other layers and can be exported as GeoJSON.
+
+use bevy::{prelude::*, window::PrimaryWindow};
+use bevy_egui::{EguiContexts, EguiPreUpdateSet, egui};
+use bevy_map_viewer::{
+    Coord, EguiBlockInputState, MapViewerMarker, TileMapResources, ZoomChangedEvent,
+};
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+use crate::{
+    geojson::MapFeature,
+    keybindings::{Action, Keybindings},
+    workspace::{History, HistoryChange, Workspace},
+};
+
+use super::ToolResources;
+
+pub struct SketchPlugin;
+
+impl Plugin for SketchPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, handle_sketch)
+            .add_systems(Update, sketch_ui.after(EguiPreUpdateSet::InitContexts))
+            .add_systems(PostUpdate, render_sketch);
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub enum SketchKind {
+    #[default]
+    Point,
+    Line,
+    Area,
+    Text,
+}
+
+impl SketchKind {
+    const ALL: [SketchKind; 4] = [
+        SketchKind::Point,
+        SketchKind::Line,
+        SketchKind::Area,
+        SketchKind::Text,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            SketchKind::Point => "Point",
+            SketchKind::Line => "Line",
+            SketchKind::Area => "Area",
+            SketchKind::Text => "Text",
+        }
+    }
+
+    /// The value of the `sketch` tag, so sketches can be coloured and filtered by kind.
+    fn tag(&self) -> &'static str {
+        match self {
+            SketchKind::Point => "point",
+            SketchKind::Line => "line",
+            SketchKind::Area => "area",
+            SketchKind::Text => "text",
+        }
+    }
+
+    /// How many points it takes before the shape can be finished.
+    fn min_points(&self) -> usize {
+        match self {
+            SketchKind::Line => 2,
+            SketchKind::Area => 3,
+            SketchKind::Point | SketchKind::Text => 1,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Sketch {
+    pub enabled: bool,
+    pub kind: SketchKind,
+    // The line or area being drawn.
+    points: Vec<Coord>,
+    cursor: Option<Coord>,
+    /// The id of the sketch feature open in the editor.
+    pub editing: Option<String>,
+    // The name of the property being added in the editor.
+    new_property: String,
+    // The feature in the editor with typing that isn't committed to the layer yet.
+    draft: Option<MapFeature>,
+}
+
+impl Sketch {
+    pub fn disable(&mut self) {
+        self.enabled = false;
+        self.points.clear();
+        self.cursor = None;
+    }
+}
+
+fn sketch_feature(kind: SketchKind, points: &[Coord], number: usize) -> MapFeature {
+    let mut coords: Vec<geo::Coord> = points
+        .iter()
+        .map(|c| geo::Coord {
+            x: c.lat as f64,
+            y: c.long as f64,
+        })
+        .collect();
+    let closed = kind == SketchKind::Area;
+    if closed {
+        coords.push(coords[0]);
+    }
+    let name = match kind {
+        SketchKind::Text => "Note".to_string(),
+        kind => format!("{} {number}", kind.label()),
+    };
+    MapFeature {
+        id: Uuid::new_v4().to_string(),
+        properties: json!({ "sketch": kind.tag(), "name": name }),
+        closed,
+        geometry: geo::Polygon::new(geo::LineString(coords), vec![]),
+    }
+}
+
+/// Writes the sketch features back and saves them, recording the change so it can be undone.
+fn update_sketch(
+    workspace: &mut Workspace,
+    history: &mut History,
+    label: &str,
+    features: Vec<MapFeature>,
+) {
+    let Some(workspace_id) = workspace.workspace.as_ref().map(|ws| ws.get_id()) else {
+        return;
+    };
+    history.record(
+        label,
+        HistoryChange::Sketch {
+            workspace_id,
+            before: workspace.get_sketch_features(),
+            after: features.clone(),
+        },
+    );
+    workspace.set_sketch_features(features);
+    let _ = workspace.save_requests();
+}
+
+/// Left click places points, right click takes the last one back and Enter finishes a line or
+/// area. Points and text notes are made with a single click.
+#[allow(clippy::too_many_arguments)]
+fn handle_sketch(
+    mut tools: ResMut<ToolResources>,
+    mut workspace: ResMut<Workspace>,
+    mut history: ResMut<History>,
+    camera: Query<(&Camera, &GlobalTransform), With<MapViewerMarker>>,
+    q_windows: Query<&Window, With<PrimaryWindow>>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    keybindings: Res<Keybindings>,
+    tile_map_manager: Res<TileMapResources>,
+    state: Res<EguiBlockInputState>,
+    mut zoom_event: EventWriter<ZoomChangedEvent>,
+) {
+    if !tools.sketch.enabled || workspace.workspace.is_none() {
+        return;
+    }
+    let Ok((camera, camera_transform)) = camera.single() else {
+        return;
+    };
+    let Some(world) = q_windows
+        .single()
+        .ok()
+        .and_then(|w| w.cursor_position())
+        .and_then(|p| camera.viewport_to_world_2d(camera_transform, p).ok())
+    else {
+        return;
+    };
+    let pos = tools.snapping.snap(world, &tile_map_manager);
+    let sketch = &mut tools.sketch;
+    sketch.cursor = Some(pos);
+
+    if keybindings.just_pressed(Action::Cancel, &keys) {
+        sketch.points.clear();
+    }
+    if !state.block_input && buttons.just_pressed(MouseButton::Right) {
+        sketch.points.pop();
+    }
+    if !state.block_input && buttons.just_pressed(MouseButton::Left) {
+        sketch.points.push(pos);
+    }
+    let finish = match sketch.kind {
+        SketchKind::Point | SketchKind::Text => !sketch.points.is_empty(),
+        kind => {
+            keybindings.just_pressed(Action::Finish, &keys)
+                && sketch.points.len() >= kind.min_points()
+        }
+    };
+    if !finish {
+        return;
+    }
+
+    let mut features = workspace.get_sketch_features();
+    let feature = sketch_feature(sketch.kind, &sketch.points, features.len() + 1);
+    sketch.points.clear();
+    sketch.editing = Some(feature.id.clone());
+    let label = format!("Sketch {}", sketch.kind.label().to_lowercase());
+    features.push(feature);
+    update_sketch(&mut workspace, &mut history, &label, features);
+    zoom_event.write(ZoomChangedEvent);
+}
+
+/// The shape being drawn, with a preview segment to the cursor.
+fn render_sketch(
+    mut gizmos: Gizmos,
+    tools: Res<ToolResources>,
+    tile_map_manager: Res<TileMapResources>,
+    projection: Query<&Projection, With<MapViewerMarker>>,
+) {
+    let sketch = &tools.sketch;
+    if !sketch.enabled || sketch.points.is_empty() {
+        return;
+    }
+    let scale = match projection.single() {
+        Ok(Projection::Orthographic(proj)) => proj.scale,
+        _ => 1.0,
+    };
+    let color = Color::srgba(0.1, 0.4, 1.0, 1.0);
+    let mut points: Vec<Vec2> = sketch
+        .points
+        .iter()
+        .chain(sketch.cursor.iter())
+        .map(|c| c.to_game_coords(tile_map_manager.clone()))
+        .collect();
+    for point in points.iter().take(sketch.points.len()) {
+        gizmos.circle_2d(*point, 4.0 * scale, color);
+    }
+    if sketch.kind == SketchKind::Area && points.len() > 2 {
+        points.push(points[0]);
+    }
+    gizmos.linestrip_2d(points, color);
+}
+
+fn sketch_ui(
+    mut contexts: EguiContexts,
+    mut tools: ResMut<ToolResources>,
+    mut workspace: ResMut<Workspace>,
+    mut history: ResMut<History>,
+    mut zoom_event: EventWriter<ZoomChangedEvent>,
+    keys: Res<ButtonInput<KeyCode>>,
+    keybindings: Res<Keybindings>,
+) {
+    if workspace.workspace.is_none() {
+        return;
+    }
+    let ctx = contexts.ctx_mut();
+    let mut features = workspace.get_sketch_features();
+    let before = features.clone();
+    let sketch = &mut tools.sketch;
+    let mut label = "Edit sketch";
+
+    if sketch.enabled {
+        egui::Window::new("Sketch")
+            .anchor(egui::Align2::RIGHT_BOTTOM, [-10.0, -10.0])
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    for kind in SketchKind::ALL {
+                        if ui
+                            .selectable_value(&mut sketch.kind, kind, kind.label())
+                            .changed()
+                        {
+                            sketch.points.clear();
+                        }
+                    }
+                });
+                ui.label(match sketch.kind {
+                    SketchKind::Point => "Click to place a point.",
+                    SketchKind::Text => "Click to place a note, then type its text.",
+                    SketchKind::Line | SketchKind::Area => {
+                        "Click to add points, right click to undo one, Enter to finish."
+                    }
+                });
+                ui.separator();
+                egui::ScrollArea::vertical()
+                    .max_height(200.0)
+                    .show(ui, |ui| {
+                        for feature in features.iter() {
+                            let name = feature
+                                .properties
+                                .get("name")
+                                .and_then(|n| n.as_str())
+                                .unwrap_or("Unnamed");
+                            let selected = sketch.editing.as_ref() == Some(&feature.id);
+                            if ui.selectable_label(selected, name).clicked() {
+                                sketch.editing = Some(feature.id.clone());
+                            }
+                        }
+                    });
+                ui.separator();
+                let export = ui
+                    .add_enabled(!features.is_empty(), egui::Button::new("Export GeoJSON"))
+                    .on_hover_text("Export the sketch and the pins")
+                    .clicked();
+                if let Some(Err(e)) = export.then(|| workspace.export_geojson()) {
+                    error!("Failed to export sketch: {}", e);
+                }
+            });
+    }
+
+    // The property editor for the selected sketch feature.
+    let index = sketch
+        .editing
+        .as_ref()
+        .and_then(|id| features.iter().position(|f| &f.id == id));
+    if let Some(index) = index {
+        let mut open = true;
+        let mut delete = sketch.enabled
+            && !ctx.wants_keyboard_input()
+            && keybindings.just_pressed(Action::Delete, &keys);
+        // Typing goes into a copy, which is written to the layer once the field loses focus or
+        // Enter is pressed, so the layer isn't rebuilt on every key.
+        let mut feature = sketch
+            .draft
+            .take()
+            .filter(|draft| draft.id == features[index].id)
+            .unwrap_or_else(|| features[index].clone());
+        let mut commit = false;
+        let is_text = feature.properties.get("sketch") == Some(&json!("text"));
+        egui::Window::new("Sketch feature")
+            .id(egui::Id::new("sketch_editor"))
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                let mut removed = None;
+                egui::Grid::new("sketch_properties")
+                    .num_columns(3)
+                    .show(ui, |ui| {
+                        let Some(properties) = feature.properties.as_object_mut() else {
+                            return;
+                        };
+                        for (key, value) in properties.iter_mut() {
+                            ui.label(key);
+                            let mut text = match &*value {
+                                Value::String(s) => s.clone(),
+                                other => other.to_string(),
+                            };
+                            let edit = if key == "name" && is_text {
+                                egui::TextEdit::multiline(&mut text).desired_rows(3)
+                            } else {
+                                egui::TextEdit::singleline(&mut text)
+                            };
+                            let response = ui.add(edit);
+                            if response.changed() {
+                                *value = Value::String(text);
+                            }
+                            commit |= response.lost_focus();
+                            // The kind tag is what the sketch is, so it stays.
+                            if key != "sketch" && ui.small_button("🗑").clicked() {
+                                removed = Some(key.clone());
+                            }
+                            ui.end_row();
+                        }
+                    });
+                if let (Some(key), Some(properties)) = (removed, feature.properties.as_object_mut())
+                {
+                    properties.remove(&key);
+                    commit = true;
+                }
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::TextEdit::singleline(&mut sketch.new_property)
+                            .hint_text("new property")
+                            .desired_width(120.0),
+                    );
+                    let key = sketch.new_property.trim().to_string();
+                    if ui
+                        .add_enabled(!key.is_empty(), egui::Button::new("Add"))
+                        .clicked()
+                    {
+                        if let Some(properties) = feature.properties.as_object_mut() {
+                            properties
+                                .entry(key)
+                                .or_insert(Value::String(String::new()));
+                        }
+                        sketch.new_property.clear();
+                        commit = true;
+                    }
+                });
+                ui.separator();
+                if ui.button("Delete").clicked() {
+                    delete = true;
+                }
+            });
+        if delete {
+            features.remove(index);
+            label = "Delete sketch";
+            sketch.editing = None;
+        } else if commit || !open {
+            features[index] = feature;
+        } else if feature != features[index] {
+            sketch.draft = Some(feature);
+        }
+        if !open {
+            sketch.editing = None;
+        }
+    }
+
+    if features != before {
+        update_sketch(&mut workspace, &mut history, label, features);
+        zoom_event.write(ZoomChangedEvent);
+    }
+}
@@ -0,0 +1,228 @@
+//! Snaps the cursor of the drawing tools to the vertices, edges and centroids of the features in
+//! the workspace layers. Holding Alt places points freely.
+
+use bevy::{prelude::*, window::PrimaryWindow};
+use bevy_map_viewer::{Coord, MapViewerMarker, TileMapResources};
+use geo::Centroid;
+use rstar::AABB;
+
+use crate::{geojson::closest_point_on_segment, workspace::Workspace};
+
+use super::ToolResources;
+
+pub struct SnappingPlugin;
+
+impl Plugin for SnappingPlugin {
+    fn build(&self, app: &mut App) {
+        // Before the tools read the cursor in Update.
+        app.add_systems(PreUpdate, update_snap)
+            .add_systems(PostUpdate, render_snap_indicator);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SnapKind {
+    Vertex,
+    Edge,
+    Centroid,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SnapTarget {
+    pub kind: SnapKind,
+    pub coord: Coord,
+    world: Vec2,
+}
+
+#[derive(Clone)]
+pub struct Snapping {
+    pub enabled: bool,
+    /// How close the cursor has to be to snap, in pixels.
+    pub tolerance: f32,
+    pub vertices: bool,
+    pub edges: bool,
+    pub centroids: bool,
+    /// What the cursor is snapped to this frame, if anything.
+    pub target: Option<SnapTarget>,
+}
+
+impl Default for Snapping {
+    fn default() -> Self {
+        Snapping {
+            enabled: true,
+            tolerance: 10.0,
+            vertices: true,
+            edges: true,
+            centroids: true,
+            target: None,
+        }
+    }
+}
+
+impl Snapping {
+    /// The snapped coordinate when there is a target, otherwise the coordinate under `world`.
+    pub fn snap(&self, world: Vec2, tile_map_manager: &TileMapResources) -> Coord {
+        self.target
+            .map(|target| target.coord)
+            .unwrap_or_else(|| tile_map_manager.point_to_coord(world))
+    }
+}
+
+fn update_snap(
+    mut tools: ResMut<ToolResources>,
+    workspace: Res<Workspace>,
+    camera: Query<(&Camera, &GlobalTransform, &Projection), With<MapViewerMarker>>,
+    q_windows: Query<&Window, With<PrimaryWindow>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    tile_map_manager: Res<TileMapResources>,
+) {
+    let drawing = tools.selection_settings.enabled
+        || tools.measure.enabled
+        || tools.pins.enabled
+        || tools.sketch.enabled;
+    let snapping = &tools.snapping;
+    if !drawing || !snapping.enabled || keys.any_pressed([KeyCode::AltLeft, KeyCode::AltRight]) {
+        if tools.snapping.target.is_some() {
+            tools.snapping.target = None;
+        }
+        return;
+    }
+    let Ok((camera, camera_transform, projection)) = camera.single() else {
+        return;
+    };
+    let scale = match projection {
+        Projection::Orthographic(proj) => proj.scale,
+        _ => 1.0,
+    };
+    let Some(world) = q_windows
+        .single()
+        .ok()
+        .and_then(|w| w.cursor_position())
+        .and_then(|p| camera.viewport_to_world_2d(camera_transform, p).ok())
+    else {
+        tools.snapping.target = None;
+        return;
+    };
+    let target = find_snap(
+        &workspace,
+        &tile_map_manager,
+        world,
+        snapping.tolerance * scale,
+        snapping,
+    );
+    tools.snapping.target = target;
+}
+
+/// The closest snap point within `tolerance` world units. Vertices win over centroids, and both
+/// win over edges, so corners are easy to hit even where edges meet.
+fn find_snap(
+    workspace: &Workspace,
+    tile_map_manager: &TileMapResources,
+    world: Vec2,
+    tolerance: f32,
+    snapping: &Snapping,
+) -> Option<SnapTarget> {
+    let ws = workspace.workspace.as_ref()?;
+    let min = tile_map_manager.point_to_coord(world - Vec2::splat(tolerance));
+    let max = tile_map_manager.point_to_coord(world + Vec2::splat(tolerance));
+    let envelope: AABB<[f64; 2]> = AABB::from_corners(
+        [min.lat as f64, min.long as f64],
+        [max.lat as f64, max.long as f64],
+    );
+
+    let to_world = |c: &geo::Coord| {
+        let coord = Coord::new(c.x as f32, c.y as f32);
+        (coord, coord.to_game_coords(tile_map_manager.clone()))
+    };
+    // The best (distance, target) of each kind.
+    let mut best: [Option<(f32, SnapTarget)>; 3] = [None, None, None];
+    let mut offer = |slot: usize, kind: SnapKind, coord: Coord, point: Vec2| {
+        let distance = point.distance(world);
+        if distance > tolerance || best[slot].is_some_and(|(d, _)| d <= distance) {
+            return;
+        }
+        best[slot] = Some((
+            distance,
+            SnapTarget {
+                kind,
+                coord,
+                world: point,
+            },
+        ));
+    };
+
+    let loaded_requests = workspace.loaded_requests.lock().unwrap();
+    for id in ws.get_requests() {
+        let Some(request) = loaded_requests.get(&id).filter(|r| r.get_visible()) else {
+            continue;
+        };
+        for feature in request.features_in(&envelope) {
+            let rings = std::iter::once(feature.geometry.exterior())
+                .chain(feature.geometry.interiors().iter());
+            for ring in rings {
+                let points: Vec<(Coord, Vec2)> = ring.0.iter().map(to_world).collect();
+                if snapping.vertices {
+                    for (coord, point) in points.iter() {
+                        offer(0, SnapKind::Vertex, *coord, *point);
+                    }
+                }
+                if snapping.edges {
+                    for pair in points.windows(2) {
+                        let point = closest_point_on_segment(world, pair[0].1, pair[1].1);
+                        offer(
+                            2,
+                            SnapKind::Edge,
+                            tile_map_manager.point_to_coord(point),
+                            point,
+                        );
+                    }
+                }
+            }
+            let centroid = feature
+                .geometry
+                .centroid()
+                .filter(|_| snapping.centroids && feature.closed && !feature.is_point());
+            if let Some(centroid) = centroid {
+                let (coord, point) = to_world(&centroid.0);
+                offer(1, SnapKind::Centroid, coord, point);
+            }
+        }
+    }
+    best.into_iter().flatten().next().map(|(_, target)| target)
+}
+
+/// Marks the snap target: a square on vertices, a diamond on edges and a circle on centroids.
+fn render_snap_indicator(
+    mut gizmos: Gizmos,
+    tools: Res<ToolResources>,
+    projection: Query<&Projection, With<MapViewerMarker>>,
+) {
+    let Some(target) = tools.snapping.target else {
+        return;
+    };
+    let scale = match projection.single() {
+        Ok(Projection::Orthographic(proj)) => proj.scale,
+        _ => 1.0,
+    };
+    let size = 10.0 * scale;
+    let color = Color::srgba(1.0, 0.0, 1.0, 1.0);
+    match target.kind {
+        SnapKind::Vertex => {
+            gizmos.rect_2d(
+                Isometry2d::from_translation(target.world),
+                Vec2::splat(size),
+                color,
+            );
+        }
+        SnapKind::Edge => {
+            gizmos.rect_2d(
+                Isometry2d::new(target.world, Rot2::degrees(45.0)),
+                Vec2::splat(size),
+                color,
+            );
+        }
+        SnapKind::Centroid => {
+            gizmos.circle_2d(target.world, size / 2.0, color);
+        }
+    }
+}
@@ -1,4 +1,8 @@
-use crate::{EguiBlockInputState, workspace::SelectionAreas};
+use crate::{
+    EguiBlockInputState,
+    keybindings::{Action, Keybindings},
+    workspace::SelectionAreas,
+};
 use bevy::{
     app::*,
     ecs::{
@@ -9,7 +13,10 @@ use bevy::{
 };
 
 use super::{
-    Measure, MeasurePlugin, PinPlugin, Pins, SelectionPlugin, SelectionSettings, ToolbarUiPlugin,
+    BoundarySearch, BoundarySearchPlugin, FeaturePicker, FeaturePickerPlugin,
+    FeatureSelectionPlugin, Measure, MeasurePlugin, PinPlugin, Pins, SelectionOps,
+    SelectionOpsPlugin, SelectionPlugin, SelectionSettings, Sketch, SketchPlugin, Snapping,
+    SnappingPlugin, ToolbarUiPlugin,
 };
 
 // Collected res for the tools. When you add a new tool, add it here.
@@ -19,6 +26,11 @@ pub struct ToolResources {
     pub selection_settings: SelectionSettings,
     pub measure: Measure,
     pub pins: Pins,
+    pub feature_picker: FeaturePicker,
+    pub boundary_search: BoundarySearch,
+    pub selection_ops: SelectionOps,
+    pub snapping: Snapping,
+    pub sketch: Sketch,
     pub pointer: bool,
 }
 
@@ -29,6 +41,11 @@ impl Default for ToolResources {
             selection_settings: SelectionSettings::default(),
             measure: Measure::default(),
             pins: Pins::default(),
+            feature_picker: FeaturePicker::default(),
+            boundary_search: BoundarySearch::default(),
+            selection_ops: SelectionOps::default(),
+            snapping: Snapping::default(),
+            sketch: Sketch::default(),
             pointer: true,
         }
     }
@@ -46,30 +63,56 @@ impl ToolResources {
                 self.selection_settings.enabled = true;
                 self.measure.disable();
                 self.pins.enabled = false;
+                self.feature_picker.disable();
+                self.sketch.disable();
                 self.pointer = false;
             }
             "measure" => {
                 self.selection_settings.enabled = false;
                 self.measure.enabled = true;
                 self.pins.enabled = false;
+                self.feature_picker.disable();
+                self.sketch.disable();
                 self.pointer = false;
             }
             "pins" => {
                 self.selection_settings.enabled = false;
                 self.measure.disable();
                 self.pins.enabled = true;
+                self.feature_picker.disable();
+                self.sketch.disable();
+                self.pointer = false;
+            }
+            "feature_picker" => {
+                self.selection_settings.enabled = false;
+                self.measure.disable();
+                self.pins.enabled = false;
+                self.feature_picker.enabled = true;
+                self.sketch.disable();
+                self.pointer = false;
+            }
+            "sketch" => {
+                self.selection_settings.enabled = false;
+                self.measure.disable();
+                self.pins.enabled = false;
+                self.feature_picker.disable();
+                self.sketch.enabled = true;
                 self.pointer = false;
             }
             "pointer" => {
                 self.selection_settings.enabled = false;
                 self.measure.disable();
                 self.pins.enabled = false;
+                self.feature_picker.disable();
+                self.sketch.disable();
                 self.pointer = true;
             }
             _ => {
                 self.selection_settings.enabled = false;
                 self.measure.disable();
                 self.pins.enabled = false;
+                self.feature_picker.disable();
+                self.sketch.disable();
                 self.pointer = false;
             }
         }
@@ -82,32 +125,57 @@ pub struct ToolsPlugin;
 impl Plugin for ToolsPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(ToolResources::default())
-            .add_plugins((SelectionPlugin, MeasurePlugin, PinPlugin, ToolbarUiPlugin))
+            .add_plugins((
+                SelectionPlugin,
+                MeasurePlugin,
+                PinPlugin,
+                FeatureSelectionPlugin,
+                FeaturePickerPlugin,
+                BoundarySearchPlugin,
+                SelectionOpsPlugin,
+                SnappingPlugin,
+                SketchPlugin,
+                ToolbarUiPlugin,
+            ))
             .add_systems(Update, handle_tool_keybinds);
     }
 }
 
-/// This is incontrol of key binds for the tools.
+/// This is incontrol of key binds for the tools, the keys themselves come from `Keybindings`.
 fn handle_tool_keybinds(
     mut tools: ResMut<ToolResources>,
     keys: Res<ButtonInput<KeyCode>>,
+    keybindings: Res<Keybindings>,
     state: Res<EguiBlockInputState>,
 ) {
-    if !state.block_input {
-        if keys.just_pressed(KeyCode::KeyS) {
-            if tools.selection_settings.enabled {
-                tools.selection_settings.tool_type.iterate();
-            }
-            tools.select_tool("workspace");
-        }
-        if keys.just_pressed(KeyCode::KeyM) {
-            tools.select_tool("measure");
-        }
-        if keys.just_pressed(KeyCode::KeyP) {
-            tools.select_tool("pins");
-        }
-        if keys.just_pressed(KeyCode::KeyP) {
-            tools.select_tool("pointer");
+    if state.block_input {
+        return;
+    }
+    if keybindings.just_pressed(Action::SelectionTool, &keys) {
+        if tools.selection_settings.enabled {
+            tools.selection_settings.tool_type.iterate();
         }
+        tools.select_tool("workspace");
+    }
+    if keybindings.just_pressed(Action::CycleSelectionType, &keys)
+        && tools.selection_settings.enabled
+    {
+        tools.selection_settings.tool_type.iterate();
+        tools.respawn();
+    }
+    if keybindings.just_pressed(Action::MeasureTool, &keys) {
+        tools.select_tool("measure");
+    }
+    if keybindings.just_pressed(Action::FeaturePickerTool, &keys) {
+        tools.select_tool("feature_picker");
+    }
+    if keybindings.just_pressed(Action::PinTool, &keys) {
+        tools.select_tool("pins");
+    }
+    if keybindings.just_pressed(Action::PointerTool, &keys) {
+        tools.select_tool("pointer");
+    }
+    if keybindings.just_pressed(Action::SketchTool, &keys) {
+        tools.select_tool("sketch");
     }
 }
@@ -22,7 +22,7 @@ impl Plugin for ToolbarUiPlugin {
 fn tool_ui(mut tools: ResMut<ToolResources>, mut contexts: EguiContexts) {
     let ctx = contexts.ctx_mut();
 
-    let toolbar_width = 195.0;
+    let toolbar_width = 245.0;
     let toolbar_height = 40.0;
 
     let screen_rect = ctx.screen_rect();
@@ -38,6 +38,8 @@ fn tool_ui(mut tools: ResMut<ToolResources>, mut contexts: EguiContexts) {
     let polygon_select = egui::include_image!("../../assets/buttons/polygon-pt.svg");
     let rectangle_select = egui::include_image!("../../assets/buttons/rectangle-pt.svg");
     let arrow_select = egui::include_image!("../../assets/buttons/arrow.svg");
+    let picker_icon = egui::include_image!("../../assets/buttons/crosshair.svg");
+    let sketch_icon = egui::include_image!("../../assets/buttons/pencil.svg");
 
     egui::Area::new("toolbar".into())
         .fixed_pos(toolbar_pos)
@@ -92,6 +94,7 @@ fn tool_ui(mut tools: ResMut<ToolResources>, mut contexts: EguiContexts) {
                                         SelectionType::RECTANGLE => rectangle_select,
                                         SelectionType::POLYGON => polygon_select,
                                         SelectionType::NONE => circle_select,
+                                        SelectionType::MULTIPOLYGON => polygon_select,
                                     },
                                 ),
                             )
@@ -120,6 +123,26 @@ fn tool_ui(mut tools: ResMut<ToolResources>, mut contexts: EguiContexts) {
                         {
                             tools.select_tool("pins");
                         }
+                        if ui
+                            .add_sized(
+                                [64.0, 30.0],
+                                image_button_selected(tools.feature_picker.enabled, picker_icon),
+                            )
+                            .on_hover_text("Feature picker")
+                            .clicked()
+                        {
+                            tools.select_tool("feature_picker");
+                        }
+                        if ui
+                            .add_sized(
+                                [64.0, 30.0],
+                                image_button_selected(tools.sketch.enabled, sketch_icon),
+                            )
+                            .on_hover_text("Sketch")
+                            .clicked()
+                        {
+                            tools.select_tool("sketch");
+                        }
                     });
                 });
         });
@@ -1,9 +1,18 @@
 use bevy::{prelude::*, window::PrimaryWindow};
-use bevy_map_viewer::{Coord, EguiBlockInputState, MapViewerMarker, TileMapResources};
+use bevy_egui::{EguiContexts, EguiPreUpdateSet, egui};
+use bevy_map_viewer::{
+    Coord, EguiBlockInputState, MapViewerMarker, TileMapResources, ZoomChangedEvent,
+};
+use uuid::Uuid;
 
 use crate::{
     camera::PostProcessSettings,
-    workspace::{Selection, SelectionType, Workspace, WorkspaceData},
+    keybindings::{Action, Keybindings},
+    overpass::get_bounds,
+    workspace::{
+        History, HistoryChange, RequestType, Selection, SelectionType, Workspace, WorkspaceData,
+        WorkspaceRequest,
+    },
 };
 
 use super::ToolResources;
@@ -14,7 +23,11 @@ pub struct SelectionPlugin;
 
 impl Plugin for SelectionPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, handle_selection)
+        app.add_systems(Update, (handle_selection, handle_selection_edit))
+            .add_systems(
+                Update,
+                selection_edit_ui.after(EguiPreUpdateSet::InitContexts),
+            )
             .add_systems(PostUpdate, (render_selection_box, render_darkening_overlay));
     }
 }
@@ -22,6 +35,7 @@ impl Plugin for SelectionPlugin {
 pub struct SelectionSettings {
     pub tool_type: SelectionType,
     pub enabled: bool,
+    pub editor: SelectionEditor,
 }
 
 impl Default for SelectionSettings {
@@ -29,12 +43,60 @@ impl Default for SelectionSettings {
         Self {
             tool_type: SelectionType::CIRCLE,
             enabled: false,
+            editor: SelectionEditor::default(),
         }
     }
 }
 
+/// State for reshaping the current workspace's selection by dragging its handles.
+#[derive(Default)]
+pub struct SelectionEditor {
+    pub enabled: bool,
+    drag: Option<EditHandle>,
+    // The selection as it was when editing started, its bounds are what the layers were fetched with.
+    original: Option<Selection>,
+    // Set when editing finished with a changed shape, until the user answers the re-run prompt.
+    confirm_rerun: Option<Selection>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum EditHandle {
+    // A rectangle corner, polygon vertex, or the edge of a circle.
+    Vertex(usize),
+    // Between polygon vertex `i` and the next one, dragging it inserts a vertex.
+    Midpoint(usize),
+    // Moves the whole circle.
+    Center,
+}
+
+// How close the cursor has to be to grab a handle, in pixels.
+const HANDLE_SIZE: f32 = 8.0;
+
+impl SelectionEditor {
+    pub fn start(&mut self, selection: Selection) {
+        self.enabled = true;
+        self.drag = None;
+        self.original = Some(selection);
+    }
+
+    /// Stops editing. Returns true when the shape was changed.
+    pub fn finish(&mut self, current: Option<Selection>) -> bool {
+        self.enabled = false;
+        self.drag = None;
+        let Some(original) = self.original.take() else {
+            return false;
+        };
+        if current.as_ref() == Some(&original) {
+            return false;
+        }
+        self.confirm_rerun = Some(original);
+        true
+    }
+}
+
 /// Handles user input for creating and modifying selections on the map.
 /// Supports rectangle, polygon, and circle selection types.
+#[allow(clippy::too_many_arguments)]
 pub fn handle_selection(
     mut tools: ResMut<ToolResources>,
     camera: Query<(&Camera, &GlobalTransform), With<MapViewerMarker>>,
@@ -44,21 +106,28 @@ pub fn handle_selection(
     state: Res<EguiBlockInputState>,
     keys: Res<ButtonInput<KeyCode>>,
     mut workspace: ResMut<Workspace>,
+    mut history: ResMut<History>,
+    keybindings: Res<Keybindings>,
 ) {
     let (camera, camera_transform) = match camera.single() {
         Ok(result) => result,
         Err(_) => return,
     };
+    // The clicks belong to the handles while editing.
+    if tools.selection_settings.editor.enabled {
+        return;
+    }
     if tools.selection_settings.enabled {
         if let Some(position) = q_windows
             .single()
             .expect("Couldn't get cursor position")
             .cursor_position()
         {
-            let pos = res_manager.point_to_coord(
+            let pos = tools.snapping.snap(
                 camera
                     .viewport_to_world_2d(camera_transform, position)
                     .unwrap(),
+                &res_manager,
             );
 
             let mut name = String::new();
@@ -116,24 +185,27 @@ pub fn handle_selection(
                         let workspace_data = WorkspaceData::new(name.clone(), selection);
                         let serded = serde_json::to_string(&workspace_data).unwrap();
                         info!("Serialized workspace: {}", serded);
+                        history.record("New selection", HistoryChange::area_added(&workspace_data));
                         tools.selection_areas.add(workspace_data);
                         let _ = workspace.save_workspace();
                     }
                     tools.selection_areas.respawn = true;
                 }
             }
-            if buttons.pressed(MouseButton::Right) {
+            if buttons.pressed(MouseButton::Right)
+                || keybindings.just_pressed(Action::Cancel, &keys)
+            {
                 tools.selection_areas.unfinished_selection = None;
                 tools.selection_areas.respawn = true;
                 let _ = workspace.save_workspace();
             }
-            if keys.just_pressed(KeyCode::Enter)
+            if keybindings.just_pressed(Action::Finish, &keys)
                 && tools.selection_settings.tool_type == SelectionType::POLYGON
             {
                 if let Some(selection) = tools.selection_areas.unfinished_selection.take() {
-                    tools
-                        .selection_areas
-                        .add(WorkspaceData::new(name, selection));
+                    let workspace_data = WorkspaceData::new(name, selection);
+                    history.record("New selection", HistoryChange::area_added(&workspace_data));
+                    tools.selection_areas.add(workspace_data);
                     let _ = workspace.save_workspace();
                 }
             }
@@ -141,6 +213,314 @@ pub fn handle_selection(
     }
 }
 
+/// The grabbable points of a selection, in world space.
+fn edit_handles(selection: &Selection, res_manager: &TileMapResources) -> Vec<(EditHandle, Vec2)> {
+    let world = |c: Coord| c.to_game_coords(res_manager.clone());
+    match selection.selection_type {
+        SelectionType::RECTANGLE => rectangle_corners(selection)
+            .into_iter()
+            .enumerate()
+            .map(|(i, c)| (EditHandle::Vertex(i), world(c)))
+            .collect(),
+        SelectionType::CIRCLE => match (selection.start, selection.end) {
+            (Some(start), Some(end)) => vec![
+                (EditHandle::Center, world(start)),
+                (EditHandle::Vertex(0), world(end)),
+            ],
+            _ => Vec::new(),
+        },
+        SelectionType::POLYGON => {
+            let points = selection.points.clone().unwrap_or_default();
+            let mut handles: Vec<(EditHandle, Vec2)> = points
+                .iter()
+                .enumerate()
+                .map(|(i, c)| (EditHandle::Vertex(i), world(*c)))
+                .collect();
+            for i in 0..points.len() {
+                let next = points[(i + 1) % points.len()];
+                handles.push((
+                    EditHandle::Midpoint(i),
+                    (world(points[i]) + world(next)) / 2.0,
+                ));
+            }
+            handles
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Corners going around the rectangle, so the opposite of corner `i` is `(i + 2) % 4`.
+fn rectangle_corners(selection: &Selection) -> Vec<Coord> {
+    let (Some(start), Some(end)) = (selection.start, selection.end) else {
+        return Vec::new();
+    };
+    vec![
+        start,
+        Coord::new(start.lat, end.long),
+        end,
+        Coord::new(end.lat, start.long),
+    ]
+}
+
+/// Moves `handle` of the selection to `pos`, returning the handle which is under the cursor
+/// afterwards. A dragged rectangle corner becomes `end`, so it is corner 2 from then on.
+fn apply_edit(selection: &mut Selection, handle: EditHandle, pos: Coord) -> EditHandle {
+    match (&selection.selection_type, handle) {
+        (SelectionType::RECTANGLE, EditHandle::Vertex(i)) => {
+            let corners = rectangle_corners(selection);
+            if corners.len() == 4 {
+                selection.start = Some(corners[(i + 2) % 4]);
+                selection.end = Some(pos);
+                return EditHandle::Vertex(2);
+            }
+        }
+        (SelectionType::CIRCLE, EditHandle::Center) => {
+            if let (Some(start), Some(end)) = (selection.start, selection.end) {
+                selection.end = Some(Coord::new(
+                    end.lat + pos.lat - start.lat,
+                    end.long + pos.long - start.long,
+                ));
+                selection.start = Some(pos);
+            }
+        }
+        (SelectionType::CIRCLE, EditHandle::Vertex(_)) => selection.end = Some(pos),
+        (SelectionType::POLYGON, EditHandle::Vertex(i)) => {
+            if let Some(point) = selection.points.as_mut().and_then(|p| p.get_mut(i)) {
+                *point = pos;
+            }
+        }
+        _ => {}
+    }
+    handle
+}
+
+/// Writes the edited selection to the current workspace and the matching selection area. A
+/// `step` of a drag is undone together with the rest of the drag.
+fn update_selection(
+    tools: &mut ToolResources,
+    workspace: &mut Workspace,
+    history: &mut History,
+    mut selection: Selection,
+    step: bool,
+) {
+    let Some(ws) = workspace.workspace.as_mut() else {
+        return;
+    };
+    // The shape no longer matches the boundary it came from.
+    selection.area_id = None;
+    let change = HistoryChange::Selection {
+        workspace_id: ws.get_id(),
+        before: ws.get_selection(),
+        after: selection.clone(),
+    };
+    if step {
+        history.record_step("Edit selection", ws.get_id(), change);
+    } else {
+        history.record("Edit selection", change);
+    }
+    ws.set_selection(selection);
+    tools.selection_areas.replace(ws.clone());
+    tools.selection_areas.respawn = true;
+}
+
+/// Drags the handles of the current workspace's selection. Right clicking a polygon vertex
+/// removes it.
+#[allow(clippy::too_many_arguments)]
+pub fn handle_selection_edit(
+    mut tools: ResMut<ToolResources>,
+    camera: Query<(&Camera, &GlobalTransform, &Projection), With<MapViewerMarker>>,
+    q_windows: Query<&Window, With<PrimaryWindow>>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    res_manager: Res<TileMapResources>,
+    state: Res<EguiBlockInputState>,
+    mut workspace: ResMut<Workspace>,
+    mut history: ResMut<History>,
+) {
+    if !tools.selection_settings.editor.enabled {
+        return;
+    }
+    let Some(mut selection) = workspace.workspace.as_ref().map(|ws| ws.get_selection()) else {
+        return;
+    };
+    let Ok((camera, camera_transform, projection)) = camera.single() else {
+        return;
+    };
+    let scale = match projection {
+        Projection::Orthographic(proj) => proj.scale,
+        _ => 1.0,
+    };
+    let Some(world) = q_windows
+        .single()
+        .ok()
+        .and_then(|w| w.cursor_position())
+        .and_then(|p| camera.viewport_to_world_2d(camera_transform, p).ok())
+    else {
+        return;
+    };
+    let pos = tools.snapping.snap(world, &res_manager);
+
+    if buttons.just_released(MouseButton::Left)
+        && tools.selection_settings.editor.drag.take().is_some()
+    {
+        history.end_interaction();
+        let _ = workspace.save_workspace();
+        return;
+    }
+    if let Some(handle) = tools.selection_settings.editor.drag {
+        let before = selection.clone();
+        tools.selection_settings.editor.drag = Some(apply_edit(&mut selection, handle, pos));
+        if selection != before {
+            update_selection(&mut tools, &mut workspace, &mut history, selection, true);
+        }
+        return;
+    }
+    if state.block_input {
+        return;
+    }
+
+    let Some((handle, _)) = edit_handles(&selection, &res_manager)
+        .into_iter()
+        .filter(|(_, point)| point.distance(world) <= HANDLE_SIZE * scale)
+        .min_by(|(_, a), (_, b)| a.distance(world).total_cmp(&b.distance(world)))
+    else {
+        return;
+    };
+    if buttons.just_pressed(MouseButton::Left) {
+        let handle = match handle {
+            EditHandle::Midpoint(i) => {
+                if let Some(points) = selection.points.as_mut() {
+                    points.insert(i + 1, pos);
+                }
+                // The new vertex is dragged right away, undoing the drag removes it again.
+                update_selection(&mut tools, &mut workspace, &mut history, selection, true);
+                EditHandle::Vertex(i + 1)
+            }
+            handle => handle,
+        };
+        tools.selection_settings.editor.drag = Some(handle);
+    } else if buttons.just_pressed(MouseButton::Right) {
+        let EditHandle::Vertex(i) = handle else {
+            return;
+        };
+        let Some(points) = selection.points.as_mut().filter(|p| p.len() > 3) else {
+            return;
+        };
+        points.remove(i);
+        update_selection(&mut tools, &mut workspace, &mut history, selection, false);
+        let _ = workspace.save_workspace();
+    }
+}
+
+/// The edit button for the current selection, and the prompt to re-run its layers once the
+/// shape has changed.
+fn selection_edit_ui(
+    mut contexts: EguiContexts,
+    mut tools: ResMut<ToolResources>,
+    mut workspace: ResMut<Workspace>,
+    mut history: ResMut<History>,
+    mut zoom_event: EventWriter<ZoomChangedEvent>,
+) {
+    let current = workspace.workspace.as_ref().map(|ws| ws.get_selection());
+    let settings = &mut tools.selection_settings;
+    let editor = &mut settings.editor;
+    // Leaving the tool finishes the edit.
+    if editor.enabled && !settings.enabled {
+        editor.finish(current.clone());
+    }
+    let ctx = contexts.ctx_mut();
+
+    // Multipolygons come from features and boundaries, they have no handles.
+    let editable = current
+        .as_ref()
+        .is_some_and(|s| s.selection_type != SelectionType::MULTIPOLYGON);
+    if settings.enabled && editable {
+        egui::Window::new("Edit selection")
+            .anchor(egui::Align2::RIGHT_BOTTOM, [-10.0, -10.0])
+            .resizable(false)
+            .show(ctx, |ui| {
+                if editor.enabled {
+                    ui.label("Drag the handles to reshape the selection.");
+                    if current.as_ref().map(|s| &s.selection_type) == Some(&SelectionType::POLYGON)
+                    {
+                        ui.label(
+                            "Drag a midpoint to add a vertex, right click a vertex to remove it.",
+                        );
+                    }
+                    if ui.button("Done").clicked() {
+                        editor.finish(current.clone());
+                    }
+                } else if ui.button("Edit shape").clicked() {
+                    editor.start(current.clone().unwrap_or_default());
+                }
+            });
+    }
+
+    let Some(original) = editor.confirm_rerun.clone() else {
+        return;
+    };
+    let mut answer: Option<bool> = None;
+    egui::Window::new("Selection changed")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+        .show(ctx, |ui| {
+            ui.label("Re-run the workspace's Overpass layers for the new bounds?");
+            ui.horizontal(|ui| {
+                if ui.button("Re-run").clicked() {
+                    answer = Some(true);
+                }
+                if ui.button("Keep current data").clicked() {
+                    answer = Some(false);
+                }
+            });
+        });
+    let Some(rerun) = answer else {
+        return;
+    };
+    editor.confirm_rerun = None;
+    if rerun {
+        rerun_layers(&mut workspace, &mut history, &original);
+        zoom_event.write(ZoomChangedEvent);
+    }
+}
+
+/// Queues the Overpass layers which were fetched for the `original` bounds again with the
+/// current bounds, replacing the old layers. Layers with other queries are left alone.
+fn rerun_layers(workspace: &mut Workspace, history: &mut History, original: &Selection) {
+    let Some(selection) = workspace.workspace.as_ref().map(|ws| ws.get_selection()) else {
+        return;
+    };
+    let old_bounds = get_bounds(original.clone());
+    let new_bounds = get_bounds(selection);
+    // Only single shapes have handles, so both have the same number of filters.
+    if old_bounds.is_empty() || old_bounds.len() != new_bounds.len() || old_bounds == new_bounds {
+        return;
+    }
+    for request in workspace.get_requests() {
+        let RequestType::OverpassTurboRequest(query) = request.get_request() else {
+            continue;
+        };
+        if !old_bounds.iter().all(|bound| query.contains(bound)) {
+            continue;
+        }
+        let query = old_bounds
+            .iter()
+            .zip(&new_bounds)
+            .fold(query, |query, (old, new)| query.replace(old, new));
+        let mut new_request = WorkspaceRequest::new(
+            Uuid::new_v4().to_string(),
+            request.get_layer(),
+            RequestType::OverpassTurboRequest(query),
+            Vec::new(),
+        );
+        new_request.set_style(request.get_style());
+        workspace.worker.queue_request(new_request);
+        history.remove_layer(workspace, request.get_id());
+    }
+    let _ = workspace.save_workspace();
+    let _ = workspace.save_requests();
+}
+
 #[derive(Component)]
 pub struct SelectionMarker;
 
@@ -154,7 +534,33 @@ fn render_selection_box(
     mut gizmos: Gizmos,
     tools: ResMut<ToolResources>,
     res_manager: ResMut<TileMapResources>,
+    workspace: Res<Workspace>,
+    projection: Query<&Projection, With<MapViewerMarker>>,
 ) {
+    if tools.selection_settings.editor.enabled {
+        let scale = match projection.single() {
+            Ok(Projection::Orthographic(proj)) => proj.scale,
+            _ => 1.0,
+        };
+        if let Some(ws) = workspace.workspace.as_ref() {
+            for (handle, point) in edit_handles(&ws.get_selection(), &res_manager) {
+                if let EditHandle::Midpoint(_) = handle {
+                    gizmos.circle_2d(
+                        point,
+                        HANDLE_SIZE * scale / 2.0,
+                        Color::srgba(1.0, 1.0, 1.0, 0.6),
+                    );
+                } else {
+                    gizmos.rect_2d(
+                        Isometry2d::from_translation(point),
+                        Vec2::splat(HANDLE_SIZE * scale),
+                        Color::srgba(1.0, 1.0, 1.0, 1.0),
+                    );
+                }
+            }
+        }
+    }
+
     let mut intersection_candidates = tools
         .selection_areas
         .areas
@@ -240,6 +646,21 @@ fn render_selection_box(
                 // Draw circle
                 gizmos.circle_2d(center, radius, stroke_color);
             }
+            SelectionType::MULTIPOLYGON => {
+                let selection = feature.get_selection();
+                for polygon in selection.polygons.iter() {
+                    for ring in std::iter::once(&polygon.exterior).chain(polygon.holes.iter()) {
+                        let mut ring: Vec<Vec2> = ring
+                            .iter()
+                            .map(|c| c.to_game_coords(res_manager.clone()))
+                            .collect();
+                        if let Some(first) = ring.first().copied() {
+                            ring.push(first);
+                            gizmos.linestrip_2d(ring, stroke_color);
+                        }
+                    }
+                }
+            }
             _ => {}
         }
     }
@@ -400,3 +821,25 @@ fn render_darkening_overlay(
         //}
         */
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dragging_a_rectangle_corner_keeps_the_opposite_one() {
+        let mut selection = Selection {
+            selection_type: SelectionType::RECTANGLE,
+            start: Some(Coord::new(52.0, 5.0)),
+            end: Some(Coord::new(53.0, 6.0)),
+            ..Default::default()
+        };
+        // Corner 1 is (52, 6), so (53, 5) stays put however far it is dragged.
+        let mut handle = EditHandle::Vertex(1);
+        for pos in [Coord::new(51.5, 6.5), Coord::new(51.0, 7.0)] {
+            handle = apply_edit(&mut selection, handle, pos);
+        }
+        assert_eq!(selection.start, Some(Coord::new(53.0, 5.0)));
+        assert_eq!(selection.end, Some(Coord::new(51.0, 7.0)));
+    }
+}
@@ -0,0 +1,541 @@
+use bevy::{
+    color::{Srgba, palettes::css},
+    prelude::*,
+    window::PrimaryWindow,
+};
+use bevy_egui::{EguiContexts, egui};
+use bevy_map_viewer::{Coord, MapViewerMarker, TileMapResources, ZoomChangedEvent};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{
+    geojson::MapFeature,
+    overpass::{build_tag_query_string, get_bounds},
+    tools::{FeatureSelection, Pin, ToolResources},
+};
+
+use super::{
+    History, HistoryChange, RequestType, Workspace, WorkspaceRequest,
+    commands::{arg_coord, value_bbox},
+};
+
+/// Something the assistant can do to the map. The LLM proposes these through the action tools,
+/// they are only applied once the user confirms them, and each can be undone.
+#[derive(Clone, Debug)]
+pub enum MapAction {
+    Highlight {
+        ids: Vec<String>,
+    },
+    /// `[min_lat, min_lon, max_lat, max_lon]`
+    ZoomTo {
+        bbox: [f64; 4],
+    },
+    Colour {
+        key: String,
+        value: String,
+        colour: Srgba,
+    },
+    DropPin {
+        location: Coord,
+        title: String,
+        note: String,
+    },
+    /// Loads the features with any of these `(key, value)` tags in the workspace area as a new
+    /// layer, `*` matches any value.
+    QueryOverpass {
+        tags: Vec<(String, String)>,
+    },
+}
+
+impl MapAction {
+    /// The action a tool call asks for, or `None` if the tool is a query rather than an action.
+    pub fn from_tool(name: &str, args: &Value) -> Option<Result<MapAction, String>> {
+        let text = |key: &str| {
+            args.get(key)
+                .and_then(|v| v.as_str())
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+        };
+        let action = match name {
+            "highlight_features" => {
+                let ids: Vec<String> = args
+                    .get("ids")
+                    .and_then(|ids| ids.as_array())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|id| match id {
+                        Value::String(id) => Some(id.clone()),
+                        Value::Number(id) => Some(id.to_string()),
+                        _ => None,
+                    })
+                    .collect();
+                if ids.is_empty() {
+                    Err("No feature ids to highlight".to_string())
+                } else {
+                    Ok(MapAction::Highlight { ids })
+                }
+            }
+            "zoom_to_bbox" => value_bbox(args)
+                .map(|bbox| MapAction::ZoomTo { bbox })
+                .ok_or_else(|| "Missing or invalid bbox".to_string()),
+            "colour_features" => match (text("key"), text("value"), text("colour")) {
+                (Some(key), Some(value), Some(colour)) => parse_colour(&colour)
+                    .map(|colour| MapAction::Colour { key, value, colour })
+                    .ok_or_else(|| format!("Unknown colour {colour}, use a name or #rrggbb")),
+                _ => Err("Needs a key, value and colour".to_string()),
+            },
+            "drop_pin" => arg_coord(args, "point")
+                .map(|location| MapAction::DropPin {
+                    location,
+                    title: text("title").unwrap_or_else(|| "Pin".to_string()),
+                    note: text("note").unwrap_or_default(),
+                })
+                .ok_or_else(|| "Missing or invalid point".to_string()),
+            "query_overpass" => {
+                let tags: Vec<(String, String)> = args
+                    .get("tags")
+                    .and_then(|tags| tags.as_array())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|tag| {
+                        let key = tag.get("key")?.as_str()?.trim();
+                        let value = tag.get("value").and_then(|v| v.as_str()).unwrap_or("*");
+                        (!key.is_empty()).then(|| (key.to_string(), value.trim().to_string()))
+                    })
+                    .collect();
+                if tags.is_empty() {
+                    Err("No tags to query".to_string())
+                } else {
+                    Ok(MapAction::QueryOverpass { tags })
+                }
+            }
+            _ => return None,
+        };
+        Some(action)
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            MapAction::Highlight { ids } => format!("Highlight {} features", ids.len()),
+            MapAction::ZoomTo { bbox } => format!(
+                "Zoom to {:.4}, {:.4} - {:.4}, {:.4}",
+                bbox[0], bbox[1], bbox[2], bbox[3]
+            ),
+            MapAction::Colour { key, value, colour } => {
+                format!("Colour {key}={value} {}", colour.to_hex())
+            }
+            MapAction::DropPin {
+                location, title, ..
+            } => format!(
+                "Drop pin \"{title}\" at {:.5}, {:.5}",
+                location.lat, location.long
+            ),
+            MapAction::QueryOverpass { tags } => format!(
+                "Load {} in the workspace area",
+                tags.iter()
+                    .map(|(key, value)| format!("{key}={value}"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+fn parse_colour(colour: &str) -> Option<Srgba> {
+    if colour.starts_with('#') {
+        return Srgba::hex(colour).ok();
+    }
+    Some(match colour.to_lowercase().as_str() {
+        "red" => css::RED,
+        "green" => css::GREEN,
+        "blue" => css::BLUE,
+        "yellow" => css::YELLOW,
+        "orange" => css::ORANGE,
+        "purple" => css::PURPLE,
+        "pink" => css::PINK,
+        "brown" => css::BROWN,
+        "black" => css::BLACK,
+        "white" => css::WHITE,
+        "grey" | "gray" => css::GRAY,
+        "cyan" => css::AQUA,
+        "magenta" => css::FUCHSIA,
+        hex => return Srgba::hex(hex).ok(),
+    })
+}
+
+/// Where the camera is and how far out it is zoomed.
+#[derive(Clone, Debug)]
+pub struct MapView {
+    pub location: Coord,
+    pub scale: f32,
+}
+
+/// A change History can't make itself because it needs the camera or the feature selection.
+/// `apply_map_requests` carries it out.
+#[derive(Clone, Debug)]
+pub enum MapRequest {
+    View(MapView),
+    Highlight(Vec<String>),
+}
+
+/// Actions proposed by the assistant, waiting for the user to apply or dismiss them.
+#[derive(Resource, Default)]
+pub struct MapActions {
+    // The workspace each was proposed in, they only make sense there.
+    pending: Vec<(String, MapAction)>,
+}
+
+impl MapActions {
+    pub fn propose(&mut self, workspace_id: &str, actions: Vec<MapAction>) {
+        self.pending.extend(
+            actions
+                .into_iter()
+                .map(|action| (workspace_id.to_string(), action)),
+        );
+    }
+}
+
+impl Workspace {
+    pub fn features_by_ids(&self, ids: &[String]) -> Vec<MapFeature> {
+        self.get_requests()
+            .iter()
+            .flat_map(|request| request.processed_data.iter())
+            .filter(|feature| ids.contains(&feature.id))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Lists the proposed actions for the open workspace, applying the ones the user confirms.
+#[allow(clippy::too_many_arguments)]
+pub fn map_actions_ui(
+    mut contexts: EguiContexts,
+    mut actions: ResMut<MapActions>,
+    mut workspace: ResMut<Workspace>,
+    mut tools: ResMut<ToolResources>,
+    mut history: ResMut<History>,
+    selection: Res<FeatureSelection>,
+    tile_map_res: Res<TileMapResources>,
+    camera: Query<(&Transform, &Projection), With<MapViewerMarker>>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    mut zoom_event: EventWriter<ZoomChangedEvent>,
+) {
+    let Some(workspace_id) = workspace.workspace.as_ref().map(|ws| ws.get_id()) else {
+        return;
+    };
+    let proposed: Vec<usize> = (0..actions.pending.len())
+        .filter(|&i| actions.pending[i].0 == workspace_id)
+        .collect();
+    if proposed.is_empty() {
+        return;
+    }
+
+    let mut apply = Vec::new();
+    let mut dismiss = Vec::new();
+    egui::Window::new("Proposed map changes")
+        .anchor(egui::Align2::RIGHT_BOTTOM, [-10.0, -10.0])
+        .resizable(false)
+        .collapsible(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label("The assistant would like to:");
+            for &i in &proposed {
+                let action = &actions.pending[i].1;
+                ui.horizontal(|ui| {
+                    if let MapAction::Colour { colour, .. } = action {
+                        let [r, g, b, _] = colour.to_u8_array();
+                        ui.colored_label(egui::Color32::from_rgb(r, g, b), "⏺");
+                    }
+                    ui.label(action.describe());
+                    if ui.button("Apply").clicked() {
+                        apply.push(i);
+                    }
+                    if ui.button("Dismiss").clicked() {
+                        dismiss.push(i);
+                    }
+                });
+            }
+            if proposed.len() > 1 {
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui.button("Apply all").clicked() {
+                        apply = proposed.clone();
+                    }
+                    if ui.button("Dismiss all").clicked() {
+                        dismiss = proposed.clone();
+                    }
+                });
+            }
+        });
+    if apply.is_empty() && dismiss.is_empty() {
+        return;
+    }
+
+    let current_view = camera.single().ok().map(|(transform, projection)| MapView {
+        location: tile_map_res.point_to_coord(transform.translation.truncate()),
+        scale: match projection {
+            Projection::Orthographic(projection) => projection.scale,
+            _ => 1.0,
+        },
+    });
+    let window_size = window
+        .single()
+        .map_or(Vec2::new(1280.0, 720.0), |w| w.size());
+    for &i in &apply {
+        let action = actions.pending[i].1.clone();
+        match action {
+            MapAction::Highlight { ids } => {
+                history.record(
+                    "Highlight features",
+                    HistoryChange::Highlight {
+                        before: selection.get_ids(),
+                        after: ids.clone(),
+                    },
+                );
+                history.request_map_change(MapRequest::Highlight(ids));
+            }
+            MapAction::ZoomTo { bbox } => {
+                let view = fit_view(bbox, &tile_map_res, window_size);
+                if let Some(before) = current_view.clone() {
+                    history.record(
+                        "Zoom",
+                        HistoryChange::View {
+                            before,
+                            after: view.clone(),
+                        },
+                    );
+                }
+                history.request_map_change(MapRequest::View(view));
+            }
+            MapAction::Colour { key, value, colour } => {
+                let Some(ws) = workspace.workspace.as_mut() else {
+                    continue;
+                };
+                let key = (key, Value::String(value));
+                history.record(
+                    "Change colour",
+                    HistoryChange::Colour {
+                        workspace_id: ws.get_id(),
+                        key: key.clone(),
+                        before: ws.properties.get(&key).copied(),
+                        after: Some(colour),
+                    },
+                );
+                ws.properties.insert(key, colour);
+                tools.selection_areas.replace(ws.clone());
+                let _ = workspace.save_workspace();
+            }
+            MapAction::DropPin {
+                location,
+                title,
+                note,
+            } => {
+                let Some(ws) = workspace.workspace.as_mut() else {
+                    continue;
+                };
+                let before = ws.pins.clone();
+                let mut pin = Pin::new(location, title);
+                pin.note = note;
+                ws.pins.push(pin);
+                history.record_pins("Add pin", before, ws);
+                tools.selection_areas.replace(ws.clone());
+                tools.pins.respawn = true;
+                let _ = workspace.save_workspace();
+            }
+            MapAction::QueryOverpass { tags } => {
+                let Some(ws) = workspace.workspace.as_ref() else {
+                    continue;
+                };
+                // Recorded in the history as an added layer once the worker picks it up.
+                match build_tag_query_string(&get_bounds(ws.get_selection()), &tags) {
+                    Ok(query) => workspace.worker.queue_request(WorkspaceRequest::new(
+                        Uuid::new_v4().to_string(),
+                        1,
+                        RequestType::OverpassTurboRequest(query),
+                        Vec::new(),
+                    )),
+                    Err(e) => warn!("Could not build the query: {e}"),
+                }
+            }
+        }
+    }
+    zoom_event.write(ZoomChangedEvent);
+
+    let mut done: Vec<usize> = apply.into_iter().chain(dismiss).collect();
+    done.sort_unstable();
+    done.dedup();
+    for i in done.into_iter().rev() {
+        actions.pending.remove(i);
+    }
+}
+
+/// The view that fits `bbox` in the window, with a little margin.
+fn fit_view(bbox: [f64; 4], tile_map_res: &TileMapResources, window_size: Vec2) -> MapView {
+    let [min_lat, min_lon, max_lat, max_lon] = bbox;
+    let min = Coord::new(min_lat as f32, min_lon as f32).to_game_coords(tile_map_res.clone());
+    let max = Coord::new(max_lat as f32, max_lon as f32).to_game_coords(tile_map_res.clone());
+    let size = (max - min).abs();
+    let scale = (size.x / window_size.x).max(size.y / window_size.y) * 1.2;
+    MapView {
+        location: Coord::new(
+            ((min_lat + max_lat) / 2.0) as f32,
+            ((min_lon + max_lon) / 2.0) as f32,
+        ),
+        scale: scale.max(0.01),
+    }
+}
+
+/// Moves the camera or changes the highlighted features for confirmed actions and their undo.
+pub fn apply_map_requests(
+    mut history: ResMut<History>,
+    workspace: Res<Workspace>,
+    mut selection: ResMut<FeatureSelection>,
+    mut tile_map_res: ResMut<TileMapResources>,
+    mut camera: Query<(&mut Transform, &mut Projection), With<MapViewerMarker>>,
+    mut zoom_event: EventWriter<ZoomChangedEvent>,
+) {
+    let requests = history.take_map_requests();
+    if requests.is_empty() {
+        return;
+    }
+    for request in requests {
+        match request {
+            MapRequest::View(view) => {
+                let Ok((mut transform, mut projection)) = camera.single_mut() else {
+                    continue;
+                };
+                tile_map_res.location_manager.location = view.location;
+                transform.translation = view
+                    .location
+                    .to_game_coords(tile_map_res.clone())
+                    .extend(0.0);
+                if let Projection::Orthographic(projection) = &mut *projection {
+                    projection.scale = view.scale;
+                }
+            }
+            MapRequest::Highlight(ids) => selection.set(workspace.features_by_ids(&ids)),
+        }
+    }
+    zoom_event.write(ZoomChangedEvent);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn colours_by_name_and_hex() {
+        assert_eq!(parse_colour("Red"), Some(css::RED));
+        assert_eq!(parse_colour("gray"), parse_colour("grey"));
+        assert_eq!(parse_colour("#ff8800"), Some(Srgba::rgb_u8(255, 136, 0)));
+        assert_eq!(parse_colour("00ff00"), Some(Srgba::rgb_u8(0, 255, 0)));
+        assert_eq!(parse_colour("#f80"), Some(Srgba::rgb_u8(255, 136, 0)));
+        assert_eq!(parse_colour("chartreuse-ish"), None);
+        assert_eq!(parse_colour("#12345"), None);
+    }
+
+    #[test]
+    fn queries_are_not_actions() {
+        assert!(MapAction::from_tool("nearby_features", &json!({})).is_none());
+        assert!(MapAction::from_tool("workspace_info", &json!({})).is_none());
+    }
+
+    #[test]
+    fn highlight_takes_string_and_number_ids() {
+        let action = MapAction::from_tool("highlight_features", &json!({ "ids": ["way/1", 2] }));
+        let Some(Ok(MapAction::Highlight { ids })) = action else {
+            panic!("{action:?}");
+        };
+        assert_eq!(ids, ["way/1", "2"]);
+        assert!(matches!(
+            MapAction::from_tool("highlight_features", &json!({ "ids": [] })),
+            Some(Err(_))
+        ));
+    }
+
+    #[test]
+    fn zoom_needs_the_whole_bbox() {
+        let bbox = json!({ "min_lat": 52.0, "min_lon": "4.5", "max_lat": 52.1, "max_lon": 4.6 });
+        let Some(Ok(MapAction::ZoomTo { bbox })) = MapAction::from_tool("zoom_to_bbox", &bbox)
+        else {
+            panic!("zoom_to_bbox wasn't parsed");
+        };
+        assert_eq!(bbox, [52.0, 4.5, 52.1, 4.6]);
+        assert!(matches!(
+            MapAction::from_tool("zoom_to_bbox", &json!({ "min_lat": 52.0 })),
+            Some(Err(_))
+        ));
+    }
+
+    #[test]
+    fn colour_needs_a_known_colour() {
+        let args = json!({ "key": "amenity", "value": "school", "colour": "orange" });
+        let Some(Ok(MapAction::Colour { key, value, colour })) =
+            MapAction::from_tool("colour_features", &args)
+        else {
+            panic!("colour_features wasn't parsed");
+        };
+        assert_eq!(
+            (key.as_str(), value.as_str(), colour),
+            ("amenity", "school", css::ORANGE)
+        );
+
+        let args = json!({ "key": "amenity", "value": "school", "colour": "sparkly" });
+        assert!(matches!(
+            MapAction::from_tool("colour_features", &args),
+            Some(Err(_))
+        ));
+        let args = json!({ "key": "amenity", "value": " ", "colour": "red" });
+        assert!(matches!(
+            MapAction::from_tool("colour_features", &args),
+            Some(Err(_))
+        ));
+    }
+
+    #[test]
+    fn drop_pin_defaults_the_title() {
+        let args = json!({ "point": { "lat": 52.37, "lon": 4.89 } });
+        let Some(Ok(MapAction::DropPin {
+            location,
+            title,
+            note,
+        })) = MapAction::from_tool("drop_pin", &args)
+        else {
+            panic!("drop_pin wasn't parsed");
+        };
+        assert_eq!((location.lat, location.long), (52.37, 4.89));
+        assert_eq!(title, "Pin");
+        assert_eq!(note, "");
+        assert!(matches!(
+            MapAction::from_tool("drop_pin", &json!({ "point": { "lat": 52.0 } })),
+            Some(Err(_))
+        ));
+    }
+
+    #[test]
+    fn query_overpass_skips_empty_keys() {
+        let args = json!({ "tags": [
+            { "key": "amenity", "value": "school" },
+            { "key": "shop" },
+            { "key": " ", "value": "x" },
+        ] });
+        let Some(Ok(MapAction::QueryOverpass { tags })) =
+            MapAction::from_tool("query_overpass", &args)
+        else {
+            panic!("query_overpass wasn't parsed");
+        };
+        assert_eq!(
+            tags,
+            [
+                ("amenity".to_string(), "school".to_string()),
+                ("shop".to_string(), "*".to_string())
+            ]
+        );
+        assert!(matches!(
+            MapAction::from_tool("query_overpass", &json!({ "tags": [] })),
+            Some(Err(_))
+        ));
+    }
+}
@@ -0,0 +1,274 @@
+use std::{cmp::Ordering, collections::HashSet};
+
+use bevy::prelude::*;
+use bevy_egui::{EguiContexts, egui};
+use bevy_map_viewer::{Coord, MapViewerMarker, TileMapResources, ZoomChangedEvent};
+use egui_extras::{Column, TableBuilder};
+use geo::Centroid;
+use serde_json::Value;
+
+use crate::{geojson::MapFeature, tools::FeatureSelection};
+
+use super::Workspace;
+
+/// State for the spreadsheet style view of a layer's features.
+/// Set `layer` to a request id to open the table for that layer.
+#[derive(Resource, Default)]
+pub struct AttributeTable {
+    pub layer: Option<String>,
+    filter: String,
+    // Column name and if it is ascending.
+    sort: Option<(String, bool)>,
+    hidden_columns: HashSet<String>,
+    // The features are copied out of the RTree once when the layer is opened, not every frame.
+    loaded_layer: Option<String>,
+    rows: Vec<MapFeature>,
+    columns: Vec<String>,
+    // Indices into `rows` which pass the filter, in sort order. `None` when the layer, filter,
+    // sort or shown columns changed and they have to be worked out again.
+    visible: Option<Vec<usize>>,
+}
+
+impl AttributeTable {
+    pub fn open(&mut self, layer: String) {
+        self.layer = Some(layer);
+    }
+
+    fn load(&mut self, workspace: &Workspace) {
+        self.rows.clear();
+        self.columns.clear();
+        self.visible = None;
+        self.loaded_layer = self.layer.clone();
+        let Some(layer) = &self.layer else {
+            return;
+        };
+        let Some(request) = workspace
+            .loaded_requests
+            .lock()
+            .unwrap()
+            .get(layer)
+            .cloned()
+        else {
+            return;
+        };
+        let mut columns: HashSet<String> = HashSet::new();
+        for feature in request.get_processed_data().iter() {
+            if let Some(object) = feature.properties.as_object() {
+                columns.extend(object.keys().cloned());
+            }
+            self.rows.push(feature.clone());
+        }
+        self.columns = columns.into_iter().collect();
+        self.columns.sort();
+        // Put the usual identifying tag first.
+        if let Some(i) = self.columns.iter().position(|c| c == "name") {
+            let name = self.columns.remove(i);
+            self.columns.insert(0, name);
+        }
+    }
+
+    /// The indices of the rows which pass the filter, in sort order.
+    fn visible_rows(&self) -> Vec<usize> {
+        let filter = self.filter.to_lowercase();
+        let mut rows: Vec<usize> = (0..self.rows.len())
+            .filter(|i| {
+                let feature = &self.rows[*i];
+                filter.is_empty()
+                    || feature.id.to_lowercase().contains(&filter)
+                    || self
+                        .columns
+                        .iter()
+                        .filter(|c| !self.hidden_columns.contains(*c))
+                        .any(|c| cell_text(feature, c).to_lowercase().contains(&filter))
+            })
+            .collect();
+        if let Some((column, ascending)) = &self.sort {
+            let cells: Vec<String> = self.rows.iter().map(|f| cell_text(f, column)).collect();
+            rows.sort_by(|a, b| {
+                let ordering = compare_cells(&cells[*a], &cells[*b]);
+                if *ascending {
+                    ordering
+                } else {
+                    ordering.reverse()
+                }
+            });
+        }
+        rows
+    }
+}
+
+pub fn attribute_table_ui(
+    mut contexts: EguiContexts,
+    mut table: ResMut<AttributeTable>,
+    workspace: Res<Workspace>,
+    mut tile_map_res: ResMut<TileMapResources>,
+    mut camera: Query<&mut Transform, With<MapViewerMarker>>,
+    mut selection: ResMut<FeatureSelection>,
+    mut zoom_event: EventWriter<ZoomChangedEvent>,
+) {
+    if table.layer.is_none() {
+        return;
+    }
+    if table.loaded_layer != table.layer {
+        table.load(&workspace);
+    }
+
+    let mut open = true;
+    let mut zoom_to: Option<MapFeature> = None;
+    let mut new_sort = None;
+    egui::Window::new("Attribute table")
+        .open(&mut open)
+        .default_size([700.0, 400.0])
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Filter");
+                if ui.text_edit_singleline(&mut table.filter).changed() {
+                    table.visible = None;
+                }
+                ui.menu_button("Columns", |ui| {
+                    let columns = table.columns.clone();
+                    for column in columns {
+                        let mut shown = !table.hidden_columns.contains(&column);
+                        if ui.checkbox(&mut shown, &column).changed() {
+                            if shown {
+                                table.hidden_columns.remove(&column);
+                            } else {
+                                table.hidden_columns.insert(column);
+                            }
+                            // The filter only looks at the shown columns.
+                            table.visible = None;
+                        }
+                    }
+                });
+                if ui.button("Reload").clicked() {
+                    table.loaded_layer = None;
+                }
+            });
+
+            let columns: Vec<String> = table
+                .columns
+                .iter()
+                .filter(|c| !table.hidden_columns.contains(*c))
+                .cloned()
+                .collect();
+            if table.visible.is_none() {
+                table.visible = Some(table.visible_rows());
+            }
+            let table = &*table;
+            let rows = table.visible.as_deref().unwrap_or_default();
+            ui.label(format!("{} of {} features", rows.len(), table.rows.len()));
+            ui.separator();
+
+            let mut sort = table.sort.clone();
+            egui::ScrollArea::horizontal().show(ui, |ui| {
+                TableBuilder::new(ui)
+                    .striped(true)
+                    .resizable(true)
+                    .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
+                    .column(Column::auto())
+                    .columns(
+                        Column::initial(120.0).at_least(40.0).clip(true),
+                        columns.len(),
+                    )
+                    .min_scrolled_height(0.0)
+                    .header(20.0, |mut header| {
+                        header.col(|ui| {
+                            ui.strong("id");
+                        });
+                        for column in columns.iter() {
+                            header.col(|ui| {
+                                let arrow = match &sort {
+                                    Some((c, true)) if c == column => " ⏶",
+                                    Some((c, false)) if c == column => " ⏷",
+                                    _ => "",
+                                };
+                                if ui
+                                    .button(
+                                        egui::RichText::new(format!("{column}{arrow}")).strong(),
+                                    )
+                                    .clicked()
+                                {
+                                    // Ascending, then descending, then back to unsorted.
+                                    sort = match &sort {
+                                        Some((c, true)) if c == column => {
+                                            Some((column.clone(), false))
+                                        }
+                                        Some((c, false)) if c == column => None,
+                                        _ => Some((column.clone(), true)),
+                                    };
+                                }
+                            });
+                        }
+                    })
+                    .body(|body| {
+                        body.rows(18.0, rows.len(), |mut row| {
+                            let feature = &table.rows[rows[row.index()]];
+                            row.col(|ui| {
+                                if ui
+                                    .link(&feature.id)
+                                    .on_hover_text("Zoom to feature")
+                                    .clicked()
+                                {
+                                    zoom_to = Some(feature.clone());
+                                }
+                            });
+                            for column in columns.iter() {
+                                row.col(|ui| {
+                                    ui.label(cell_text(feature, column));
+                                });
+                            }
+                        });
+                    });
+            });
+            if sort != table.sort {
+                new_sort = Some(sort);
+            }
+        });
+
+    if let Some(sort) = new_sort {
+        table.sort = sort;
+        table.visible = None;
+    }
+
+    if !open {
+        table.layer = None;
+        table.loaded_layer = None;
+        table.rows.clear();
+    }
+
+    let Some(feature) = zoom_to else {
+        return;
+    };
+    let Some(centroid) = feature.geometry.centroid() else {
+        return;
+    };
+    let coord = Coord::new(centroid.x() as f32, centroid.y() as f32);
+    if let Ok(mut camera_transform) = camera.single_mut() {
+        tile_map_res.location_manager.location = coord;
+        camera_transform.translation = coord.to_game_coords(tile_map_res.clone()).extend(0.0);
+    }
+    selection.set(vec![feature]);
+    zoom_event.write(ZoomChangedEvent);
+}
+
+fn cell_text(feature: &MapFeature, column: &str) -> String {
+    match feature.properties.get(column) {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Null) | None => String::new(),
+        Some(value) => value.to_string(),
+    }
+}
+
+/// Numbers sort by value, everything else alphabetically, and empty cells go last.
+fn compare_cells(a: &str, b: &str) -> Ordering {
+    match (a.is_empty(), b.is_empty()) {
+        (true, true) => return Ordering::Equal,
+        (true, false) => return Ordering::Greater,
+        (false, true) => return Ordering::Less,
+        _ => {}
+    }
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.total_cmp(&b),
+        _ => a.to_lowercase().cmp(&b.to_lowercase()),
+    }
+}
@@ -1,10 +1,15 @@
-use crate::{geojson::MapFeature, workspace::Workspace};
+use super::{
+    feature_json::{FeatureFormat, compact_feature},
+    filter::{Filter, SpatialFilter},
+    statistics::StatSort,
+};
+use crate::{geojson::MapFeature, tools::FeatureSelection, workspace::Workspace};
 use bevy_map_viewer::Coord;
 use geo::Centroid;
 use rstar::AABB;
+use serde_json::{Value, json};
 use std::fmt::Display;
 
-// TODO: Make the geojson simplifyer to parse to the llm.
 impl Workspace {
     // i    : General info/stats.
     pub fn get_info(&self) -> String {
@@ -13,14 +18,10 @@ impl Workspace {
             let area = workspace.get_area();
             let selection = &workspace.selection;
 
-            let mut matches = Vec::new();
-            for request in self.get_requests() {
-                let mut m: Vec<MapFeature> = request.processed_data.iter().cloned().collect();
-                matches.append(&mut m);
-            }
+            let summary = self.summarize_features();
 
             return format!(
-                "Features: {count}, Area: {area:?}, Selection: {selection:?}, Summery of features: {matches:?}"
+                "Features: {count}, Area: {area:?}, Selection: {selection:?}, Summery of features: {summary}"
             );
         }
         format!("Features: {count}")
@@ -66,12 +67,7 @@ impl Workspace {
                 .processed_data
                 .iter()
                 .filter(|feature| {
-                    let c = feature.geometry.centroid().unwrap();
-                    let coord_c = Coord {
-                        lat: c.y() as f32,
-                        long: c.x() as f32,
-                    };
-                    point.distance_haversine(&coord_c) <= radius
+                    feature_coord(feature).is_some_and(|c| point.distance_haversine(&c) <= radius)
                 })
                 .cloned()
                 .collect();
@@ -106,7 +102,8 @@ impl Workspace {
         max_lat: f64,
         max_lon: f64,
     ) -> Vec<MapFeature> {
-        let envelope = AABB::from_corners([min_lon, min_lat], [max_lon, max_lat]);
+        // Features keep the latitude in x, so the envelope does too.
+        let envelope = AABB::from_corners([min_lat, min_lon], [max_lat, max_lon]);
         let mut matches = Vec::new();
 
         for request in self.get_requests() {
@@ -133,10 +130,8 @@ impl Workspace {
 
         for request in self.get_requests() {
             for feature in request.get_processed_data() {
-                let c = feature.geometry.centroid().unwrap();
-                let coord_c = Coord {
-                    lat: c.y() as f32,
-                    long: c.x() as f32,
+                let Some(coord_c) = feature_coord(&feature) else {
+                    continue;
                 };
                 let dist: f64 = point.distance_haversine(&coord_c);
                 if dist < min_dist {
@@ -176,13 +171,7 @@ impl Workspace {
                 .processed_data
                 .iter()
                 .filter(|feature| {
-                    point_in_polygon(
-                        &Coord {
-                            lat: feature.geometry.centroid().unwrap().y() as f32,
-                            long: feature.geometry.centroid().unwrap().x() as f32,
-                        },
-                        polygon,
-                    )
+                    feature_coord(feature).is_some_and(|c| point_in_polygon(&c, polygon))
                 })
                 .cloned()
                 .collect();
@@ -192,6 +181,378 @@ impl Workspace {
     }
 }
 
+/// The centre of a feature. Features keep the latitude in x and the longitude in y.
+fn feature_coord(feature: &MapFeature) -> Option<Coord> {
+    let c = feature.geometry.centroid()?;
+    Some(Coord {
+        lat: c.x() as f32,
+        long: c.y() as f32,
+    })
+}
+
+impl Workspace {
+    /// Runs one of the tools from `llm::tool_definitions` and returns the result for the model.
+    /// Bad arguments are reported back as text so the model can correct them. Lists of features
+    /// come a page at a time and every result is capped so one call can't fill the context.
+    pub fn run_command(&self, name: &str, args: &Value, selection: &FeatureSelection) -> String {
+        cap_result(self.run_command_uncapped(name, args, selection))
+    }
+
+    fn run_command_uncapped(
+        &self,
+        name: &str,
+        args: &Value,
+        selection: &FeatureSelection,
+    ) -> String {
+        match name {
+            "workspace_info" => self.get_info(),
+            "count_features" => format!("Feature count: {}", self.count_workspace()),
+            "summarize_features" => self.summarize_features(),
+            "aggregate_features" => match args.get("key").and_then(|k| k.as_str()) {
+                Some(key) => {
+                    let bbox = args.get("bbox").and_then(value_bbox);
+                    let value = args.get("value").and_then(|v| v.as_str());
+                    let mut aggregation = self.aggregate(key, value, bbox);
+                    let sort = args
+                        .get("sort_by")
+                        .and_then(|s| s.as_str())
+                        .unwrap_or("count");
+                    aggregation.sort(StatSort::parse(sort));
+                    let limit = arg_f64(args, "limit").map_or(GROUP_LIMIT, |l| l.max(1.0) as usize);
+                    aggregation.to_json(limit).to_string()
+                }
+                None => "Missing tag key to group by".to_string(),
+            },
+            "filter_features" => {
+                let Some(expression) = args.get("expression").and_then(|e| e.as_str()) else {
+                    return "Missing filter expression".to_string();
+                };
+                let filter = match Filter::parse(expression) {
+                    Ok(filter) => filter,
+                    Err(e) => return format!("Invalid filter: {e}"),
+                };
+                let points: Option<Vec<Coord>> = args
+                    .get("polygon")
+                    .and_then(|p| p.as_array())
+                    .map(|p| p.iter().filter_map(value_coord).collect());
+                let area = if let Some(bbox) = args.get("bbox").and_then(value_bbox) {
+                    Some(SpatialFilter::Bbox(bbox))
+                } else if let (Some(point), Some(radius)) =
+                    (arg_coord(args, "point"), arg_f64(args, "radius"))
+                {
+                    Some(SpatialFilter::Near(point, radius))
+                } else {
+                    points.filter(|p| p.len() >= 3).map(SpatialFilter::Polygon)
+                };
+                feature_list(&self.filter_features(&filter, area.as_ref()), args)
+            }
+            "nearby_features" => match (arg_coord(args, "point"), arg_f64(args, "radius")) {
+                (Some(point), Some(radius)) => {
+                    feature_list(&self.nearby_point(point, radius), args)
+                }
+                _ => "Missing or invalid point or radius".to_string(),
+            },
+            "nearest_feature" => match arg_coord(args, "point") {
+                Some(point) => match self.nearest_feature(point) {
+                    Some(feature) => {
+                        compact_feature(&feature, &FeatureFormat::from_args(args, false))
+                            .to_string()
+                    }
+                    None => "No features found".to_string(),
+                },
+                None => "Missing or invalid point".to_string(),
+            },
+            "distance" => match (arg_coord(args, "from"), arg_coord(args, "to")) {
+                (Some(a), Some(b)) => {
+                    format!("Distance: {:.2} meters", self.distance_between(a, b))
+                }
+                _ => "Missing or invalid points".to_string(),
+            },
+            "features_in_bbox" => {
+                let bbox = ["min_lat", "min_lon", "max_lat", "max_lon"].map(|k| arg_f64(args, k));
+                match bbox {
+                    [Some(min_lat), Some(min_lon), Some(max_lat), Some(max_lon)] => {
+                        let features = self.features_in_bbox(min_lat, min_lon, max_lat, max_lon);
+                        feature_list(&features, args)
+                    }
+                    _ => "Missing or invalid bbox".to_string(),
+                }
+            }
+            "features_in_polygon" => {
+                let points: Option<Vec<Coord>> = args
+                    .get("points")
+                    .and_then(|p| p.as_array())
+                    .map(|p| p.iter().filter_map(value_coord).collect());
+                match points.filter(|p| p.len() >= 3) {
+                    Some(points) => feature_list(&self.features_in_polygon(&points), args),
+                    None => "The polygon needs at least 3 valid points".to_string(),
+                }
+            }
+            "feature_details" => match arg_id(args) {
+                Some(id) => match self.get_feature_by_id(&id) {
+                    Some(feature) => {
+                        compact_feature(&feature, &FeatureFormat::from_args(args, true)).to_string()
+                    }
+                    None => format!("Feature {} not found", id),
+                },
+                None => "Missing feature id".to_string(),
+            },
+            "feature_tags" => match arg_id(args) {
+                Some(id) => match self.get_feature_tags(&id) {
+                    Some(tags) => format!("Tags for feature {}: {}", id, tags),
+                    None => format!("Feature {} not found or has no tags", id),
+                },
+                None => "Missing feature id".to_string(),
+            },
+            "selected_features" => {
+                if selection.is_empty() {
+                    "No features are selected".to_string()
+                } else {
+                    feature_list(&selection.get_features(), args)
+                }
+            }
+            _ => format!("Unknown tool: {name}"),
+        }
+    }
+}
+
+const FEATURE_PAGE_SIZE: usize = 25;
+const GROUP_LIMIT: usize = 30;
+// About 2000 tokens.
+const MAX_RESULT_CHARS: usize = 8000;
+
+/// The page of `features` asked for with the `offset` and `limit` arguments as JSON, each one
+/// turned into a value by `to_json`. The page is kept under the result cap so the JSON is never
+/// cut off, and `next_offset` is set when some were left out so the model can ask for the rest.
+fn feature_page(
+    features: &[MapFeature],
+    args: &Value,
+    to_json: impl Fn(&MapFeature) -> Value,
+) -> String {
+    let offset = arg_f64(args, "offset")
+        .map_or(0, |o| o.max(0.0) as usize)
+        .min(features.len());
+    let limit = arg_f64(args, "limit").map_or(FEATURE_PAGE_SIZE, |l| l.max(1.0) as usize);
+    let max_chars = MAX_RESULT_CHARS - 100;
+    let mut page = Vec::new();
+    let mut chars = 0;
+    for feature in features.iter().skip(offset).take(limit) {
+        let value = to_json(feature);
+        let len = value.to_string().len() + 1;
+        // Always send at least one, even if it is large.
+        if !page.is_empty() && chars + len > max_chars {
+            break;
+        }
+        chars += len;
+        page.push(value);
+    }
+    let end = offset + page.len();
+    let mut result = json!({
+        "total": features.len(),
+        "offset": offset,
+        "features": page,
+    });
+    if end < features.len() {
+        result["next_offset"] = json!(end);
+    }
+    result.to_string()
+}
+
+/// A page of features in the compact format, see `compact_feature`.
+fn feature_list(features: &[MapFeature], args: &Value) -> String {
+    let format = FeatureFormat::from_args(args, false);
+    feature_page(features, args, |f| compact_feature(f, &format))
+}
+
+fn cap_result(mut result: String) -> String {
+    if result.len() <= MAX_RESULT_CHARS {
+        return result;
+    }
+    let mut end = MAX_RESULT_CHARS;
+    while !result.is_char_boundary(end) {
+        end -= 1;
+    }
+    let cut = result.len() - end;
+    result.truncate(end);
+    result.push_str(&format!(
+        "... ({cut} more characters left out, narrow the query or ask for a smaller page)"
+    ));
+    result
+}
+
+/// Finds an `rq:` command anywhere in a reply from a model without tool support, and turns it
+/// into a tool name and arguments for `Workspace::run_command`.
+pub fn parse_rq_command(text: &str) -> Option<Result<(&'static str, Value), String>> {
+    let line = text
+        .lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("rq:"))?;
+    let parts = split_command(&line[3..]);
+    let Some((cmd, params)) = parts.split_first() else {
+        return Some(Err("Missing command after rq:".to_string()));
+    };
+    let params: Vec<&str> = params.iter().map(String::as_str).collect();
+    let command = match (cmd.as_str(), params.as_slice()) {
+        ("i", _) => Ok(("workspace_info", json!({}))),
+        ("cnt", _) => Ok(("count_features", json!({}))),
+        ("sm", _) => Ok(("summarize_features", json!({}))),
+        ("sel", _) => Ok(("selected_features", json!({}))),
+        ("agg", [key]) => Ok(("aggregate_features", json!({ "key": key }))),
+        ("agg", [key, value, ..]) => {
+            Ok(("aggregate_features", json!({ "key": key, "value": value })))
+        }
+        ("agg", _) => Err("Missing tag key. Use: rq: agg <key> [value]".to_string()),
+        ("f", []) => Err("Missing filter. Use: rq: f <expression>".to_string()),
+        ("f", expression) => Ok((
+            "filter_features",
+            json!({ "expression": expression.join(" ") }),
+        )),
+        ("nb", [point, radius, ..]) => parse_coord(point)
+            .and_then(|point| Ok((point, parse_radius(radius)?)))
+            .map(|(point, radius)| {
+                (
+                    "nearby_features",
+                    json!({ "point": point, "radius": radius }),
+                )
+            }),
+        ("nb", _) => Err("Missing parameters. Use: rq: nb {lat,lon} r<meters>".to_string()),
+        ("n", [point, ..]) => {
+            parse_coord(point).map(|point| ("nearest_feature", json!({ "point": point })))
+        }
+        ("n", _) => Err("Missing coordinate. Use: rq: n {lat,lon}".to_string()),
+        ("d", [from, to, ..]) => parse_coord(from)
+            .and_then(|from| Ok((from, parse_coord(to)?)))
+            .map(|(from, to)| ("distance", json!({ "from": from, "to": to }))),
+        ("d", _) => Err("Missing coordinates. Use: rq: d {lat1,lon1} {lat2,lon2}".to_string()),
+        ("bb", [bbox, ..]) => parse_bbox(bbox).map(|(min_lat, min_lon, max_lat, max_lon)| {
+            (
+                "features_in_bbox",
+                json!({
+                    "min_lat": min_lat,
+                    "min_lon": min_lon,
+                    "max_lat": max_lat,
+                    "max_lon": max_lon
+                }),
+            )
+        }),
+        ("bb", _) => Err("Missing bbox. Use: rq: bb {min_lat,min_lon,max_lat,max_lon}".to_string()),
+        ("gt", [id, ..]) => Ok(("feature_details", json!({ "id": id }))),
+        ("gt", _) => Err("Missing feature ID. Use: rq: gt <feature_id>".to_string()),
+        ("t", [id, ..]) => Ok(("feature_tags", json!({ "id": id }))),
+        ("t", _) => Err("Missing feature ID. Use: rq: t <feature_id>".to_string()),
+        (cmd, _) => Err(format!(
+            "Unknown command: {cmd}. Available commands: i, cnt, nb, sm, agg, f, gt, t, bb, d, n, sel"
+        )),
+    };
+    Some(command)
+}
+
+// Splits on whitespace, except inside braces so `{51.5, -0.09}` stays one part.
+fn split_command(command: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    for c in command.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            c if c.is_whitespace() && depth <= 0 => {
+                if !current.is_empty() {
+                    parts.push(std::mem::take(&mut current));
+                }
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            _ => {}
+        }
+        current.push(c);
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+    parts
+}
+
+pub(super) fn arg_f64(args: &Value, key: &str) -> Option<f64> {
+    let value = args.get(key)?;
+    // Some models send numbers as strings.
+    value
+        .as_f64()
+        .or_else(|| value.as_str().and_then(|s| s.trim().parse().ok()))
+}
+
+fn arg_id(args: &Value) -> Option<String> {
+    match args.get("id")? {
+        Value::String(id) => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+pub(super) fn arg_coord(args: &Value, key: &str) -> Option<Coord> {
+    value_coord(args.get(key)?)
+}
+
+pub(super) fn value_bbox(value: &Value) -> Option<[f64; 4]> {
+    match ["min_lat", "min_lon", "max_lat", "max_lon"].map(|k| arg_f64(value, k)) {
+        [Some(min_lat), Some(min_lon), Some(max_lat), Some(max_lon)] => {
+            Some([min_lat, min_lon, max_lat, max_lon])
+        }
+        _ => None,
+    }
+}
+
+fn value_coord(value: &Value) -> Option<Coord> {
+    Some(Coord {
+        lat: arg_f64(value, "lat")? as f32,
+        long: arg_f64(value, "lon")? as f32,
+    })
+}
+
+fn parse_coord(coord_str: &str) -> Result<Value, String> {
+    let coord_str = coord_str.trim_start_matches('{').trim_end_matches('}');
+    let parts: Vec<&str> = coord_str.split(',').collect();
+
+    if parts.len() != 2 {
+        return Err("Coordinate must have exactly 2 parts, as {lat,lon}".to_string());
+    }
+
+    let lat = parts[0]
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| "Invalid latitude")?;
+    let lon = parts[1]
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| "Invalid longitude")?;
+
+    Ok(json!({ "lat": lat, "lon": lon }))
+}
+
+fn parse_radius(radius_str: &str) -> Result<f64, String> {
+    radius_str
+        .strip_prefix('r')
+        .ok_or("Radius must start with 'r', as r<meters>")?
+        .parse::<f64>()
+        .map_err(|_| "Invalid radius value".to_string())
+}
+
+fn parse_bbox(bbox_str: &str) -> Result<(f64, f64, f64, f64), String> {
+    let bbox_str = bbox_str.trim_start_matches('{').trim_end_matches('}');
+    let parts: Vec<f64> = bbox_str
+        .split(',')
+        .map(|p| p.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| "Invalid bbox value")?;
+
+    match parts[..] {
+        [min_lat, min_lon, max_lat, max_lon] => Ok((min_lat, min_lon, max_lat, max_lon)),
+        _ => {
+            Err("Bbox must have exactly 4 parts, as {min_lat,min_lon,max_lat,max_lon}".to_string())
+        }
+    }
+}
+
 pub trait HaversineDistance {
     fn distance_haversine(&self, other: &Self) -> f64;
 }
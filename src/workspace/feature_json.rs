@@ -0,0 +1,140 @@
+use geo::{Centroid, Geodesic, GeodesicArea, Length, MapCoords, Simplify};
+use serde_json::{Map, Value, json};
+
+use crate::geojson::{METERS_PER_DEGREE, MapFeature};
+
+// Tags that say what a feature is, sent when the model doesn't ask for particular ones.
+const KEY_TAGS: &[&str] = &[
+    "name",
+    "amenity",
+    "shop",
+    "building",
+    "building:levels",
+    "highway",
+    "railway",
+    "waterway",
+    "landuse",
+    "leisure",
+    "natural",
+    "tourism",
+    "office",
+    "historic",
+    "man_made",
+    "place",
+    "public_transport",
+    "addr:housenumber",
+    "addr:street",
+];
+const DEFAULT_TOLERANCE: f64 = 5.0;
+
+/// What goes into a feature sent to the model. The `fields`, `geometry` and `simplify` tool
+/// arguments choose it.
+pub struct FeatureFormat {
+    /// Tag keys to include, `None` for the defaults.
+    pub fields: Option<Vec<String>>,
+    /// Whether the defaults are every tag rather than only the `KEY_TAGS`.
+    pub all_tags: bool,
+    pub geometry: bool,
+    /// Simplification tolerance for the geometry, in meters.
+    pub tolerance: f64,
+}
+
+impl FeatureFormat {
+    pub fn from_args(args: &Value, all_tags: bool) -> Self {
+        let fields = args.get("fields").and_then(|f| f.as_array()).map(|f| {
+            f.iter()
+                .filter_map(|k| k.as_str().map(str::to_string))
+                .collect()
+        });
+        let geometry = match args.get("geometry") {
+            Some(Value::Bool(b)) => *b,
+            Some(Value::String(s)) => s == "true",
+            _ => false,
+        };
+        let tolerance = args
+            .get("simplify")
+            .and_then(|s| s.as_f64())
+            .filter(|s| *s >= 0.0)
+            .unwrap_or(DEFAULT_TOLERANCE);
+        FeatureFormat {
+            fields,
+            all_tags,
+            geometry,
+            tolerance,
+        }
+    }
+}
+
+/// A feature as a small JSON object: its id, what kind of shape it is, the chosen tags, centroid,
+/// area or length, and the simplified outline when asked for. Coordinates are `[lat, lon]`.
+pub fn compact_feature(feature: &MapFeature, format: &FeatureFormat) -> Value {
+    let mut object = Map::new();
+    object.insert("id".to_string(), json!(feature.id));
+
+    let kind = if feature.is_point() {
+        "point"
+    } else if feature.closed {
+        "area"
+    } else {
+        "line"
+    };
+    object.insert("kind".to_string(), json!(kind));
+
+    let props = feature.properties.as_object();
+    let mut tags = Map::new();
+    let mut other_tags = 0;
+    for (key, value) in props.into_iter().flatten() {
+        let wanted = match &format.fields {
+            Some(fields) => fields.iter().any(|f| f == key),
+            None => format.all_tags || KEY_TAGS.contains(&key.as_str()),
+        };
+        if wanted {
+            tags.insert(key.clone(), value.clone());
+        } else {
+            other_tags += 1;
+        }
+    }
+    object.insert("tags".to_string(), Value::Object(tags));
+    // Lets the model know there is more to ask for with `fields` or `feature_tags`.
+    if other_tags > 0 && format.fields.is_none() {
+        object.insert("other_tags".to_string(), json!(other_tags));
+    }
+
+    // Features keep the latitude in x, geo's geodesic algorithms expect the longitude there.
+    let geometry = feature
+        .geometry
+        .map_coords(|c| geo::Coord { x: c.y, y: c.x });
+    if let Some(centroid) = geometry.centroid() {
+        object.insert(
+            "centroid".to_string(),
+            json!([round(centroid.y(), 1e6), round(centroid.x(), 1e6)]),
+        );
+    }
+    match kind {
+        "area" => {
+            let area = geometry.geodesic_area_unsigned();
+            object.insert("area_m2".to_string(), json!(area.round()));
+        }
+        "line" => {
+            let length = Geodesic.length(geometry.exterior());
+            object.insert("length_m".to_string(), json!(round(length, 10.0)));
+        }
+        _ => {}
+    }
+
+    if format.geometry && kind != "point" {
+        let epsilon = format.tolerance / METERS_PER_DEGREE;
+        let outline: Vec<Value> = geometry
+            .exterior()
+            .simplify(&epsilon)
+            .coords()
+            .map(|c| json!([round(c.y, 1e6), round(c.x, 1e6)]))
+            .collect();
+        object.insert("geometry".to_string(), Value::Array(outline));
+    }
+    Value::Object(object)
+}
+
+fn round(value: f64, scale: f64) -> f64 {
+    (value * scale).round() / scale
+}
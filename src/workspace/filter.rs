@@ -0,0 +1,526 @@
+use bevy::prelude::*;
+use bevy_egui::{EguiContexts, egui};
+use bevy_map_viewer::Coord;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{geojson::MapFeature, tools::FeatureSelection};
+
+use super::Workspace;
+
+// A small expression language over feature tags, for example
+// `amenity=school`, `building and building:levels>4` or `(shop~"^(bakery|butcher)$" or amenity=cafe) and !name`.
+//
+// - `key` or `key=*`        the tag exists
+// - `key=value`, `key!=value`  equal or not, numbers compare by value
+// - `key~regex`, `key!~regex`  the value matches or not
+// - `key<4`, `<=`, `>`, `>=`   numeric comparison, features without a number never match
+// - `and`/`&&`, `or`/`||`, `not`/`!` and brackets, `and` binds tighter than `or`
+//
+// Values with spaces or symbols go in double or single quotes. The key `id` matches the feature
+// id when the feature has no tag with that name.
+
+#[derive(Debug, Clone)]
+pub enum Filter {
+    Has(String),
+    Equals(String, String),
+    Matches(String, Regex),
+    Compare(String, Comparison, f64),
+    Not(Box<Filter>),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Filter {
+    pub fn parse(text: &str) -> Result<Filter, String> {
+        let tokens = tokenize(text)?;
+        if tokens.is_empty() {
+            return Err("The filter is empty".to_string());
+        }
+        let mut parser = Parser { tokens, pos: 0 };
+        let filter = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(filter),
+            Some(token) => Err(format!("Unexpected {}", token.describe())),
+        }
+    }
+
+    pub fn matches(&self, feature: &MapFeature) -> bool {
+        match self {
+            Filter::Has(key) => tag(feature, key).is_some(),
+            Filter::Equals(key, value) => tag(feature, key).is_some_and(|tag| {
+                match (tag.parse::<f64>(), value.parse::<f64>()) {
+                    (Ok(a), Ok(b)) => a == b,
+                    _ => tag == *value,
+                }
+            }),
+            Filter::Matches(key, regex) => {
+                tag(feature, key).is_some_and(|tag| regex.is_match(&tag))
+            }
+            Filter::Compare(key, comparison, number) => tag(feature, key)
+                .and_then(|tag| leading_number(&tag))
+                .is_some_and(|value| match comparison {
+                    Comparison::Less => value < *number,
+                    Comparison::LessOrEqual => value <= *number,
+                    Comparison::Greater => value > *number,
+                    Comparison::GreaterOrEqual => value >= *number,
+                }),
+            Filter::Not(filter) => !filter.matches(feature),
+            Filter::And(filters) => filters.iter().all(|f| f.matches(feature)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(feature)),
+        }
+    }
+}
+
+fn tag(feature: &MapFeature, key: &str) -> Option<String> {
+    match feature.properties.get(key) {
+        Some(Value::String(s)) => Some(s.clone()),
+        Some(Value::Null) => None,
+        Some(value) => Some(value.to_string()),
+        None if key == "id" => Some(feature.id.clone()),
+        None => None,
+    }
+}
+
+// Tags like `height=12 m` or `maxspeed=30 mph` carry a unit after the number.
+fn leading_number(text: &str) -> Option<f64> {
+    let text = text.trim();
+    let end = text
+        .char_indices()
+        .find(|(i, c)| !(c.is_ascii_digit() || *c == '.' || (*i == 0 && (*c == '-' || *c == '+'))))
+        .map_or(text.len(), |(i, _)| i);
+    text[..end].parse().ok()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Op(&'static str),
+    Word(String),
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Open => "'('".to_string(),
+            Token::Close => "')'".to_string(),
+            Token::And => "'and'".to_string(),
+            Token::Or => "'or'".to_string(),
+            Token::Not => "'not'".to_string(),
+            Token::Op(op) => format!("'{op}'"),
+            Token::Word(word) => format!("'{word}'"),
+        }
+    }
+}
+
+// Longest first so `<=` isn't read as `<` then `=`.
+const OPERATORS: [&str; 8] = ["!=", "!~", "<=", ">=", "=", "~", "<", ">"];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' || c == ')' {
+            tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            i += 1;
+        } else if rest == "&&" || rest == "||" {
+            tokens.push(if c == '&' { Token::And } else { Token::Or });
+            i += 2;
+        } else if c == '&' || c == '|' {
+            return Err("Use && or ||".to_string());
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Op(op));
+            i += op.len();
+        } else if c == '!' {
+            tokens.push(Token::Not);
+            i += 1;
+        } else if c == '"' || c == '\'' {
+            let end = chars[i + 1..]
+                .iter()
+                .position(|&q| q == c)
+                .ok_or("Missing closing quote")?;
+            tokens.push(Token::Word(chars[i + 1..i + 1 + end].iter().collect()));
+            i += end + 2;
+        } else {
+            let start = i;
+            while i < chars.len() && !chars[i].is_whitespace() && !"()=!<>~&|\"'".contains(chars[i])
+            {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            if word.is_empty() {
+                return Err(format!("Unexpected '{c}'"));
+            }
+            tokens.push(match word.to_lowercase().as_str() {
+                "and" => Token::And,
+                "or" => Token::Or,
+                "not" => Token::Not,
+                _ => Token::Word(word),
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let found = self.tokens.get(self.pos) == Some(token);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Filter, String> {
+        let mut filters = vec![self.and()?];
+        while self.eat(&Token::Or) {
+            filters.push(self.and()?);
+        }
+        Ok(if filters.len() == 1 {
+            filters.remove(0)
+        } else {
+            Filter::Or(filters)
+        })
+    }
+
+    fn and(&mut self) -> Result<Filter, String> {
+        let mut filters = vec![self.not()?];
+        while self.eat(&Token::And) {
+            filters.push(self.not()?);
+        }
+        Ok(if filters.len() == 1 {
+            filters.remove(0)
+        } else {
+            Filter::And(filters)
+        })
+    }
+
+    fn not(&mut self) -> Result<Filter, String> {
+        if self.eat(&Token::Not) {
+            return Ok(Filter::Not(Box::new(self.not()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Filter, String> {
+        match self.next() {
+            Some(Token::Open) => {
+                let filter = self.or()?;
+                if !self.eat(&Token::Close) {
+                    return Err("Missing ')'".to_string());
+                }
+                Ok(filter)
+            }
+            Some(Token::Word(key)) => self.condition(key),
+            Some(token) => Err(format!("Expected a tag key, found {}", token.describe())),
+            None => Err("Expected a tag key at the end".to_string()),
+        }
+    }
+
+    fn condition(&mut self, key: String) -> Result<Filter, String> {
+        let Some(Token::Op(op)) = self.tokens.get(self.pos).cloned() else {
+            return Ok(Filter::Has(key));
+        };
+        self.pos += 1;
+        let value = match self.next() {
+            Some(Token::Word(value)) => value,
+            _ => return Err(format!("Expected a value after '{key}{op}'")),
+        };
+        let number = || {
+            value
+                .parse::<f64>()
+                .map_err(|_| format!("'{value}' is not a number, needed for '{op}'"))
+        };
+        let regex = || Regex::new(&value).map_err(|e| format!("Invalid regex '{value}': {e}"));
+        Ok(match op {
+            "=" if value == "*" => Filter::Has(key),
+            "!=" if value == "*" => Filter::Not(Box::new(Filter::Has(key))),
+            "=" => Filter::Equals(key, value),
+            "!=" => Filter::Not(Box::new(Filter::Equals(key, value))),
+            "~" => Filter::Matches(key, regex()?),
+            "!~" => Filter::Not(Box::new(Filter::Matches(key, regex()?))),
+            "<" => Filter::Compare(key, Comparison::Less, number()?),
+            "<=" => Filter::Compare(key, Comparison::LessOrEqual, number()?),
+            ">" => Filter::Compare(key, Comparison::Greater, number()?),
+            _ => Filter::Compare(key, Comparison::GreaterOrEqual, number()?),
+        })
+    }
+}
+
+/// Where to look for features, on top of what the filter asks for.
+#[derive(Debug, Clone)]
+pub enum SpatialFilter {
+    /// `[min_lat, min_lon, max_lat, max_lon]`
+    Bbox([f64; 4]),
+    /// Within a radius in meters of a point.
+    Near(Coord, f64),
+    Polygon(Vec<Coord>),
+}
+
+impl Workspace {
+    /// The features in every layer of the workspace matching `filter`, and if given, `area`.
+    pub fn filter_features(
+        &self,
+        filter: &Filter,
+        area: Option<&SpatialFilter>,
+    ) -> Vec<MapFeature> {
+        let candidates = match area {
+            Some(SpatialFilter::Bbox([min_lat, min_lon, max_lat, max_lon])) => {
+                self.features_in_bbox(*min_lat, *min_lon, *max_lat, *max_lon)
+            }
+            Some(SpatialFilter::Near(point, radius)) => self.nearby_point(*point, *radius),
+            Some(SpatialFilter::Polygon(points)) => self.features_in_polygon(points),
+            None => self
+                .get_requests()
+                .iter()
+                .flat_map(|request| request.processed_data.iter().cloned())
+                .collect(),
+        };
+        candidates
+            .into_iter()
+            .filter(|feature| filter.matches(feature))
+            .collect()
+    }
+}
+
+// Only this many results are listed in the window, all of them can still be selected.
+const LISTED_RESULTS: usize = 200;
+
+/// State for the feature search window, which runs a `Filter` over the workspace.
+#[derive(Resource, Default)]
+pub struct FeatureSearch {
+    pub open: bool,
+    pub query: String,
+    error: Option<String>,
+    results: Vec<MapFeature>,
+    searched: bool,
+}
+
+impl FeatureSearch {
+    pub fn open(&mut self) {
+        self.open = true;
+    }
+
+    fn run(&mut self, workspace: &Workspace) {
+        self.searched = true;
+        match Filter::parse(&self.query) {
+            Ok(filter) => {
+                self.error = None;
+                self.results = workspace.filter_features(&filter, None);
+            }
+            Err(e) => {
+                self.error = Some(e);
+                self.results.clear();
+            }
+        }
+    }
+}
+
+pub fn feature_search_ui(
+    mut contexts: EguiContexts,
+    mut search: ResMut<FeatureSearch>,
+    workspace: Res<Workspace>,
+    mut selection: ResMut<FeatureSelection>,
+) {
+    if !search.open {
+        return;
+    }
+    let mut open = search.open;
+    let search = &mut *search;
+    egui::Window::new("Search features")
+        .open(&mut open)
+        .default_size([360.0, 320.0])
+        .show(contexts.ctx_mut(), |ui| {
+            let mut run = false;
+            ui.horizontal(|ui| {
+                let response = ui.add(
+                    egui::TextEdit::singleline(&mut search.query)
+                        .hint_text("amenity=school and name~\"^St\"")
+                        .desired_width(260.0),
+                );
+                run |= response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                run |= ui.button("Search").clicked();
+            })
+            .response
+            .on_hover_text(
+                "key, key=value, key!=value, key~regex, key>number, combined with and, or, not and brackets",
+            );
+            if run {
+                search.run(&workspace);
+            }
+
+            if let Some(error) = &search.error {
+                ui.colored_label(egui::Color32::LIGHT_RED, error);
+                return;
+            }
+            if !search.searched {
+                return;
+            }
+            ui.horizontal(|ui| {
+                ui.label(format!("{} features", search.results.len()));
+                if ui
+                    .add_enabled(!search.results.is_empty(), egui::Button::new("Select all"))
+                    .clicked()
+                {
+                    selection.set(search.results.clone());
+                }
+            });
+            ui.separator();
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for feature in search.results.iter().take(LISTED_RESULTS) {
+                    let name = feature
+                        .properties
+                        .get("name")
+                        .and_then(|n| n.as_str())
+                        .unwrap_or_default();
+                    if ui
+                        .selectable_label(
+                            selection.contains(&feature.id),
+                            format!("{} {}", feature.id, name),
+                        )
+                        .clicked()
+                    {
+                        selection.set(vec![feature.clone()]);
+                    }
+                }
+                if search.results.len() > LISTED_RESULTS {
+                    ui.label(format!(
+                        "and {} more",
+                        search.results.len() - LISTED_RESULTS
+                    ));
+                }
+            });
+        });
+    search.open = open;
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn feature(properties: Value) -> MapFeature {
+        MapFeature {
+            id: "node/1".to_string(),
+            properties,
+            closed: false,
+            geometry: geo::Polygon::new(geo::LineString::from(vec![(52.0, 5.0)]), vec![]),
+        }
+    }
+
+    fn matches(filter: &str, properties: Value) -> bool {
+        Filter::parse(filter).unwrap().matches(&feature(properties))
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let filter = "amenity=cafe or shop=bakery and name";
+        assert!(matches(filter, json!({ "amenity": "cafe" })));
+        assert!(!matches(filter, json!({ "shop": "bakery" })));
+        assert!(matches(
+            filter,
+            json!({ "shop": "bakery", "name": "Bread" })
+        ));
+        assert!(!matches(
+            "(amenity=cafe or shop=bakery) and name",
+            json!({ "amenity": "cafe" })
+        ));
+        assert!(matches(
+            "amenity=cafe || shop && name",
+            json!({ "amenity": "cafe" })
+        ));
+    }
+
+    #[test]
+    fn quoted_values() {
+        assert!(matches(
+            "name=\"De Gouden Leeuw\"",
+            json!({ "name": "De Gouden Leeuw" })
+        ));
+        assert!(matches("name='a && b'", json!({ "name": "a && b" })));
+        assert!(matches(
+            "shop~\"^(bakery|butcher)$\"",
+            json!({ "shop": "butcher" })
+        ));
+        assert_eq!(
+            Filter::parse("name=\"open").unwrap_err(),
+            "Missing closing quote"
+        );
+    }
+
+    #[test]
+    fn not() {
+        assert!(matches("!name", json!({ "amenity": "cafe" })));
+        assert!(!matches("not name", json!({ "name": "Cafe" })));
+        assert!(matches("!!name", json!({ "name": "Cafe" })));
+        assert!(matches("!(a=1 or b=2)", json!({ "a": "3" })));
+        assert!(matches("a!=1", json!({ "a": "2" })));
+        assert!(matches("name!=*", json!({})));
+    }
+
+    #[test]
+    fn comparisons() {
+        assert!(matches(
+            "building:levels>4",
+            json!({ "building:levels": "5" })
+        ));
+        assert!(!matches("height<=10", json!({ "height": "12 m" })));
+        assert!(matches("height<=10", json!({ "height": "9.5 m" })));
+        assert!(!matches("height>1", json!({ "height": "tall" })));
+        assert!(matches("levels=3", json!({ "levels": "3.0" })));
+        assert!(matches("id=node/1", json!({})));
+        assert!(Filter::parse("height>tall").is_err());
+    }
+
+    #[test]
+    fn single_ampersand_or_pipe_is_an_error() {
+        for text in [
+            "amenity=school & building",
+            "a | b",
+            "&",
+            "|",
+            "a &",
+            "a&&b|c",
+        ] {
+            assert_eq!(Filter::parse(text).unwrap_err(), "Use && or ||", "{text}");
+        }
+    }
+
+    #[test]
+    fn malformed_filters_are_errors() {
+        for text in [
+            "", "   ", "(a", "a)", "a and", "or a", "a=", "=b", "a b", "a~(", "!",
+        ] {
+            assert!(Filter::parse(text).is_err(), "{text}");
+        }
+    }
+}
@@ -0,0 +1,604 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+use bevy::{color::Srgba, prelude::*};
+use bevy_egui::EguiContexts;
+use bevy_map_viewer::ZoomChangedEvent;
+use serde_json::Value;
+
+use crate::{
+    geojson::MapFeature,
+    tools::{Pin, ToolResources},
+};
+
+use super::{
+    LayerStyle, Selection, Workspace, WorkspaceData, WorkspaceRequest,
+    actions::{MapRequest, MapView},
+};
+
+// Steps of one interaction this close together become one entry, so a drag or dragging a colour
+// picker is undone in one go.
+const COALESCE_WINDOW: Duration = Duration::from_secs(1);
+const MAX_ENTRIES: usize = 100;
+
+/// One undoable change. Each holds the state on both sides so it can be applied either way.
+#[derive(Clone, Debug)]
+pub enum HistoryChange {
+    /// A new selection area. `saved` is whether it had been written to disk when it was undone,
+    /// so redoing writes it again.
+    AreaAdded {
+        area: Box<WorkspaceData>,
+        saved: bool,
+    },
+    /// A workspace's selection was reshaped.
+    Selection {
+        workspace_id: String,
+        before: Selection,
+        after: Selection,
+    },
+    /// Any pin being added, moved, edited or deleted.
+    Pins {
+        workspace_id: String,
+        before: Vec<Pin>,
+        after: Vec<Pin>,
+    },
+    /// The colour of a tag value in `item_info`, `None` when no colour was set.
+    Colour {
+        workspace_id: String,
+        key: (String, Value),
+        before: Option<Srgba>,
+        after: Option<Srgba>,
+    },
+    Style {
+        request_id: String,
+        before: LayerStyle,
+        after: LayerStyle,
+    },
+    /// A layer being added to or removed from a workspace. The request is kept here while the
+    /// layer is gone so it can be put back.
+    Layer {
+        workspace_id: String,
+        request_id: String,
+        request: Option<WorkspaceRequest>,
+        added: bool,
+    },
+    /// Features drawn, edited or deleted in the user sketch layer.
+    Sketch {
+        workspace_id: String,
+        before: Vec<MapFeature>,
+        after: Vec<MapFeature>,
+    },
+    /// The ids of the selected features, changed by an assistant action.
+    Highlight {
+        before: Vec<String>,
+        after: Vec<String>,
+    },
+    /// The camera moved by an assistant action.
+    View { before: MapView, after: MapView },
+}
+
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    pub label: String,
+    change: HistoryChange,
+    time: Instant,
+    // Hash of the interaction later steps can be merged into, `None` once it has ended.
+    interaction: Option<u64>,
+}
+
+#[derive(Resource, Default)]
+pub struct History {
+    undo: Vec<HistoryEntry>,
+    redo: Vec<HistoryEntry>,
+    // Waiting for `apply_map_requests`, which has the camera and feature selection. Applying
+    // several actions or undoing several entries at once queues more than one.
+    map_requests: Vec<MapRequest>,
+}
+
+impl History {
+    pub fn record(&mut self, label: impl Into<String>, change: HistoryChange) {
+        self.push(label.into(), change, None);
+    }
+
+    /// Records one step of an ongoing interaction, like a drag. Steps with the same label and
+    /// `interaction` key are merged into one entry until `end_interaction` is called or no step
+    /// comes for a while.
+    pub fn record_step(
+        &mut self,
+        label: impl Into<String>,
+        interaction: impl Hash,
+        change: HistoryChange,
+    ) {
+        let label = label.into();
+        let mut hasher = DefaultHasher::new();
+        interaction.hash(&mut hasher);
+        let interaction = hasher.finish();
+        // After an undo the last entry is done with, the step starts a new one.
+        let ongoing = self.undo.last_mut().filter(|last| {
+            self.redo.is_empty()
+                && last.label == label
+                && last.interaction == Some(interaction)
+                && last.time.elapsed() < COALESCE_WINDOW
+        });
+        if let Some(last) = ongoing
+            && last.change.merge(&change)
+        {
+            last.time = Instant::now();
+            return;
+        }
+        self.push(label, change, Some(interaction));
+    }
+
+    /// Stops later steps from being merged into the last entry.
+    pub fn end_interaction(&mut self) {
+        if let Some(last) = self.undo.last_mut() {
+            last.interaction = None;
+        }
+    }
+
+    fn push(&mut self, label: String, change: HistoryChange, interaction: Option<u64>) {
+        self.redo.clear();
+        self.undo.push(HistoryEntry {
+            label,
+            change,
+            time: Instant::now(),
+            interaction,
+        });
+        if self.undo.len() > MAX_ENTRIES {
+            self.undo.remove(0);
+        }
+    }
+
+    /// Records a change to the pins of `ws`, if there was one.
+    pub fn record_pins(&mut self, label: impl Into<String>, before: Vec<Pin>, ws: &WorkspaceData) {
+        if let Some(change) = pins_change(before, ws) {
+            self.record(label, change);
+        }
+    }
+
+    /// Records a step of moving or editing the pin with `pin_id`, see `record_step`.
+    pub fn record_pins_step(
+        &mut self,
+        label: impl Into<String>,
+        pin_id: &str,
+        before: Vec<Pin>,
+        ws: &WorkspaceData,
+    ) {
+        if let Some(change) = pins_change(before, ws) {
+            self.record_step(label, pin_id, change);
+        }
+    }
+
+    /// Takes a layer out of the current workspace, keeping it so the removal can be undone.
+    pub fn remove_layer(&mut self, workspace: &mut Workspace, request_id: String) {
+        let Some(ws) = workspace.workspace.as_mut() else {
+            return;
+        };
+        ws.remove_request(request_id.clone());
+        let request = workspace
+            .loaded_requests
+            .lock()
+            .unwrap()
+            .remove(&request_id);
+        self.record(
+            "Remove layer",
+            HistoryChange::Layer {
+                workspace_id: ws.get_id(),
+                request_id,
+                request,
+                added: false,
+            },
+        );
+    }
+
+    pub fn request_map_change(&mut self, request: MapRequest) {
+        self.map_requests.push(request);
+    }
+
+    /// The map changes waiting to be made, oldest first.
+    pub fn take_map_requests(&mut self) -> Vec<MapRequest> {
+        std::mem::take(&mut self.map_requests)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Every entry oldest first, and how many of them are applied. The rest can be redone.
+    pub fn entries(&self) -> (Vec<&HistoryEntry>, usize) {
+        let entries = self.undo.iter().chain(self.redo.iter().rev()).collect();
+        (entries, self.undo.len())
+    }
+
+    pub fn undo(&mut self, workspace: &mut Workspace, tools: &mut ToolResources) -> bool {
+        let Some(mut entry) = self.undo.pop() else {
+            return false;
+        };
+        entry.change.apply(false, workspace, tools);
+        if let Some(request) = entry.change.map_request(false) {
+            self.map_requests.push(request);
+        }
+        self.redo.push(entry);
+        tools.respawn();
+        true
+    }
+
+    pub fn redo(&mut self, workspace: &mut Workspace, tools: &mut ToolResources) -> bool {
+        let Some(mut entry) = self.redo.pop() else {
+            return false;
+        };
+        entry.change.apply(true, workspace, tools);
+        if let Some(request) = entry.change.map_request(true) {
+            self.map_requests.push(request);
+        }
+        self.undo.push(entry);
+        tools.respawn();
+        true
+    }
+
+    /// Undoes or redoes until exactly `applied` entries are applied.
+    pub fn jump_to(
+        &mut self,
+        applied: usize,
+        workspace: &mut Workspace,
+        tools: &mut ToolResources,
+    ) {
+        while self.undo.len() > applied && self.undo(workspace, tools) {}
+        while self.undo.len() < applied && self.redo(workspace, tools) {}
+    }
+}
+
+impl HistoryChange {
+    pub fn area_added(area: &WorkspaceData) -> Self {
+        HistoryChange::AreaAdded {
+            area: Box::new(area.clone()),
+            saved: false,
+        }
+    }
+
+    /// Folds a later change to the same thing into this one.
+    fn merge(&mut self, newer: &HistoryChange) -> bool {
+        match (self, newer) {
+            (
+                HistoryChange::Selection {
+                    workspace_id,
+                    after,
+                    ..
+                },
+                HistoryChange::Selection {
+                    workspace_id: id,
+                    after: newer,
+                    ..
+                },
+            ) if workspace_id == id => *after = newer.clone(),
+            (
+                HistoryChange::Pins {
+                    workspace_id,
+                    after,
+                    ..
+                },
+                HistoryChange::Pins {
+                    workspace_id: id,
+                    after: newer,
+                    ..
+                },
+            ) if workspace_id == id => *after = newer.clone(),
+            (
+                HistoryChange::Colour {
+                    workspace_id,
+                    key,
+                    after,
+                    ..
+                },
+                HistoryChange::Colour {
+                    workspace_id: id,
+                    key: newer_key,
+                    after: newer,
+                    ..
+                },
+            ) if workspace_id == id && key == newer_key => *after = *newer,
+            (
+                HistoryChange::Style {
+                    request_id, after, ..
+                },
+                HistoryChange::Style {
+                    request_id: id,
+                    after: newer,
+                    ..
+                },
+            ) if request_id == id => *after = newer.clone(),
+            (
+                HistoryChange::Sketch {
+                    workspace_id,
+                    after,
+                    ..
+                },
+                HistoryChange::Sketch {
+                    workspace_id: id,
+                    after: newer,
+                    ..
+                },
+            ) if workspace_id == id => *after = newer.clone(),
+            _ => return false,
+        }
+        true
+    }
+
+    /// The camera or selection change for the changes `apply` can't make itself.
+    fn map_request(&self, forward: bool) -> Option<MapRequest> {
+        match self {
+            HistoryChange::Highlight { before, after } => Some(MapRequest::Highlight(
+                if forward { after } else { before }.clone(),
+            )),
+            HistoryChange::View { before, after } => Some(MapRequest::View(
+                if forward { after } else { before }.clone(),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Puts the state back to after the change when `forward`, otherwise to before it.
+    fn apply(&mut self, forward: bool, workspace: &mut Workspace, tools: &mut ToolResources) {
+        match self {
+            HistoryChange::AreaAdded { area, saved } => {
+                if forward {
+                    tools.selection_areas.add((**area).clone());
+                    if *saved {
+                        let _ = area.save();
+                        workspace
+                            .loaded_workspace
+                            .insert(area.get_id(), (**area).clone());
+                    }
+                } else {
+                    let id = area.get_id();
+                    let open = workspace.workspace.take_if(|ws| ws.id == id);
+                    let loaded = workspace.loaded_workspace.remove(&id);
+                    // Keep what was done to the area since, the redo puts that back.
+                    let latest = open
+                        .as_ref()
+                        .or_else(|| {
+                            let areas = &tools.selection_areas.areas;
+                            areas.iter().find(|candidate| candidate.id == id)
+                        })
+                        .or(loaded.as_ref());
+                    if let Some(latest) = latest {
+                        **area = latest.clone();
+                    }
+                    tools.selection_areas.remove(&id);
+                    // It is only written to disk once it has been opened.
+                    *saved = open.is_some() || loaded.is_some();
+                    if *saved {
+                        let _ = std::fs::remove_file(format!("WS_{id}.json"));
+                    }
+                }
+            }
+            HistoryChange::Selection {
+                workspace_id,
+                before,
+                after,
+            } => {
+                let selection = if forward { after } else { before }.clone();
+                edit_workspace(workspace, tools, workspace_id, |ws| {
+                    ws.set_selection(selection)
+                });
+            }
+            HistoryChange::Pins {
+                workspace_id,
+                before,
+                after,
+            } => {
+                let pins = if forward { after } else { before }.clone();
+                edit_workspace(workspace, tools, workspace_id, |ws| ws.pins = pins);
+            }
+            HistoryChange::Colour {
+                workspace_id,
+                key,
+                before,
+                after,
+            } => {
+                let colour = if forward { *after } else { *before };
+                edit_workspace(workspace, tools, workspace_id, |ws| match colour {
+                    Some(colour) => {
+                        ws.properties.insert(key.clone(), colour);
+                    }
+                    None => {
+                        ws.properties.remove(key);
+                    }
+                });
+            }
+            HistoryChange::Style {
+                request_id,
+                before,
+                after,
+            } => {
+                let style = if forward { after } else { before }.clone();
+                if let Some(request) = workspace
+                    .loaded_requests
+                    .lock()
+                    .unwrap()
+                    .get_mut(request_id)
+                {
+                    request.set_style(style);
+                }
+                let _ = workspace.save_requests();
+            }
+            HistoryChange::Layer {
+                workspace_id,
+                request_id,
+                request,
+                added,
+            } => {
+                if forward == *added {
+                    if let Some(request) = request.take() {
+                        workspace
+                            .loaded_requests
+                            .lock()
+                            .unwrap()
+                            .insert(request_id.clone(), request);
+                    }
+                    edit_workspace(workspace, tools, workspace_id, |ws| {
+                        ws.add_request(request_id.clone())
+                    });
+                    let _ = workspace.save_requests();
+                } else {
+                    *request = workspace.loaded_requests.lock().unwrap().remove(request_id);
+                    edit_workspace(workspace, tools, workspace_id, |ws| {
+                        ws.remove_request(request_id.clone())
+                    });
+                }
+            }
+            HistoryChange::Sketch {
+                workspace_id,
+                before,
+                after,
+            } => {
+                // The sketch layer only exists for the open workspace.
+                if workspace
+                    .workspace
+                    .as_ref()
+                    .is_some_and(|ws| ws.id == *workspace_id)
+                {
+                    let features = if forward { after } else { before }.clone();
+                    workspace.set_sketch_features(features);
+                    let _ = workspace.save_requests();
+                }
+            }
+            // Made through `map_request`.
+            HistoryChange::Highlight { .. } | HistoryChange::View { .. } => {}
+        }
+    }
+}
+
+fn pins_change(before: Vec<Pin>, ws: &WorkspaceData) -> Option<HistoryChange> {
+    (before != ws.pins).then(|| HistoryChange::Pins {
+        workspace_id: ws.get_id(),
+        before,
+        after: ws.pins.clone(),
+    })
+}
+
+/// Changes the workspace with this id, whether it is the open one or only a selection area.
+fn edit_workspace(
+    workspace: &mut Workspace,
+    tools: &mut ToolResources,
+    id: &str,
+    edit: impl FnOnce(&mut WorkspaceData),
+) {
+    if let Some(ws) = workspace.workspace.as_mut().filter(|ws| ws.id == id) {
+        edit(ws);
+        tools.selection_areas.replace(ws.clone());
+        let _ = workspace.save_workspace();
+        return;
+    }
+    let area = tools
+        .selection_areas
+        .areas
+        .iter()
+        .find(|area| area.id == id)
+        .cloned();
+    if let Some(mut area) = area {
+        edit(&mut area);
+        tools.selection_areas.replace(area);
+    }
+}
+
+/// Ctrl+Z to undo, Ctrl+Y or Ctrl+Shift+Z to redo. Left to egui while a text box has focus so
+/// it can undo the typing.
+pub fn handle_history_keys(
+    mut contexts: EguiContexts,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut history: ResMut<History>,
+    mut workspace: ResMut<Workspace>,
+    mut tools: ResMut<ToolResources>,
+    mut zoom_event: EventWriter<ZoomChangedEvent>,
+) {
+    if contexts.ctx_mut().wants_keyboard_input() {
+        return;
+    }
+    let ctrl = keys.any_pressed([
+        KeyCode::ControlLeft,
+        KeyCode::ControlRight,
+        KeyCode::SuperLeft,
+        KeyCode::SuperRight,
+    ]);
+    let shift = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    if !ctrl {
+        return;
+    }
+    let changed = if keys.just_pressed(KeyCode::KeyY) || (shift && keys.just_pressed(KeyCode::KeyZ))
+    {
+        history.redo(&mut workspace, &mut tools)
+    } else if keys.just_pressed(KeyCode::KeyZ) {
+        history.undo(&mut workspace, &mut tools)
+    } else {
+        false
+    };
+    if changed {
+        zoom_event.write(ZoomChangedEvent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn highlight(ids: &[&str]) -> HistoryChange {
+        HistoryChange::Highlight {
+            before: Vec::new(),
+            after: ids.iter().map(|id| id.to_string()).collect(),
+        }
+    }
+
+    fn pins(workspace_id: &str) -> HistoryChange {
+        HistoryChange::Pins {
+            workspace_id: workspace_id.to_string(),
+            before: Vec::new(),
+            after: Vec::new(),
+        }
+    }
+
+    fn labels(history: &History) -> Vec<String> {
+        history
+            .entries()
+            .0
+            .iter()
+            .map(|e| e.label.clone())
+            .collect()
+    }
+
+    #[test]
+    fn record_never_merges() {
+        let mut history = History::default();
+        history.record("Move pin", pins("ws"));
+        history.record("Move pin", pins("ws"));
+        assert_eq!(labels(&history), ["Move pin", "Move pin"]);
+    }
+
+    #[test]
+    fn steps_merge_per_interaction() {
+        let mut history = History::default();
+        history.record_step("Move pin", "a", pins("ws"));
+        history.record_step("Move pin", "a", pins("ws"));
+        assert_eq!(history.entries().0.len(), 1);
+        // Another pin is another interaction, even with the same label.
+        history.record_step("Move pin", "b", pins("ws"));
+        assert_eq!(history.entries().0.len(), 2);
+        history.end_interaction();
+        history.record_step("Move pin", "b", pins("ws"));
+        assert_eq!(history.entries().0.len(), 3);
+    }
+
+    #[test]
+    fn steps_only_merge_changes_that_can_be() {
+        let mut history = History::default();
+        history.record_step("Highlight", "a", highlight(&["node/1"]));
+        history.record_step("Highlight", "a", highlight(&["node/2"]));
+        assert_eq!(history.entries().0.len(), 2);
+    }
+}
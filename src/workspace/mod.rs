@@ -11,8 +11,14 @@
 //! - Enable collaborative workspace sharing and management
 //!
 //! ## Sub-modules
+//! - `actions`: Map actions proposed by the assistant, applied once the user confirms them
+//! - `attribute_table`: Spreadsheet style view of a layer's features and tags
 //! - `commands`: Workspace operation commands and state management
+//! - `feature_json`: Compact JSON of features for LLM command results
+//! - `filter`: Tag filter expressions and the feature search window
+//! - `history`: Undo and redo of selection, pin, style and layer edits
 //! - `renderer`: Workspace-specific rendering and visualization
+//! - `statistics`: Features grouped by tag value with counts, areas, lengths and densities
 //! - `ui`: User interface components for workspace interaction
 //! - `worker`: Background task processing and data pipeline management
 //! - `workspace_types`: Core data structures and plugin implementation
@@ -36,27 +42,43 @@ use std::{
     sync::{Arc, Mutex},
 };
 
+pub use actions::{MapAction, MapActions};
+pub use attribute_table::AttributeTable;
 use bevy::{color::Srgba, ecs::resource::Resource};
+use bevy_map_viewer::Coord;
+pub use filter::FeatureSearch;
+pub use history::{History, HistoryChange};
 use rstar::RTree;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+pub use statistics::StatisticsWindow;
 use worker::WorkspaceWorker;
 pub use workspace_types::*;
 
 use crate::{
-    geojson::MapFeature,
-    llm::{Message, OpenrouterClient},
+    geojson::{MapFeature, SymbolIcon},
+    llm::{ChatBackend, ChatUsage, Message, ModelParams, OpenrouterClient},
     overpass::OverpassClient,
+    tools::{MeasureMode, Pin},
 };
 
+mod actions;
+mod attribute_table;
 mod commands;
+mod feature_json;
+mod filter;
+mod history;
 mod renderer;
+mod statistics;
 mod ui;
 mod worker;
 mod workspace_types;
 
 pub struct WorkspacePlugin;
 
+// The parsed features of the user sketch layer, with the layer's id.
+type SketchCache = Arc<Mutex<Option<(String, Vec<MapFeature>)>>>;
+
 #[derive(Resource, Clone)]
 pub struct Workspace {
     pub workspace: Option<WorkspaceData>,
@@ -65,10 +87,11 @@ pub struct Workspace {
     // This is highly inneficient, but it works for now.. We should have it so it only loads the id (which is the file name) and then loads the data when needed.
     pub loaded_workspace: HashMap<String, WorkspaceData>,
     pub worker: WorkspaceWorker,
+    sketch_cache: SketchCache,
 
     // Request Clients:
     pub overpass_agent: OverpassClient,
-    pub llm_agent: OpenrouterClient,
+    pub llm_agent: Arc<dyn ChatBackend>,
     // Add llm requests agent!
 }
 
@@ -79,8 +102,9 @@ impl Default for Workspace {
             loaded_requests: Arc::new(Mutex::new(HashMap::new())),
             worker: WorkspaceWorker::new(4),
             loaded_workspace: HashMap::new(),
+            sketch_cache: Arc::new(Mutex::new(None)),
             overpass_agent: OverpassClient::new("https://overpass-api.de/api/interpreter"),
-            llm_agent: OpenrouterClient::new("https://openrouter.ai/api/v1/chat/completions", None),
+            llm_agent: Arc::new(OpenrouterClient::load()),
         }
     }
 }
@@ -99,7 +123,35 @@ pub struct WorkspaceData {
     last_modified: i64,
     requests: HashSet<String>,
     properties: HashMap<(String, Value), Srgba>,
+    #[serde(default)]
+    symbols: Vec<SymbolRule>,
+    #[serde(default)]
+    measurements: Vec<SavedMeasurement>,
+    #[serde(default)]
+    pins: Vec<Pin>,
     messages: Vec<Message>,
+    #[serde(default)]
+    model: ModelParams,
+    #[serde(default)]
+    usage: ChatUsage,
+}
+
+/// Picks the icon for point features with a matching tag, these take priority over the built in icons.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SymbolRule {
+    pub key: String,
+    pub value: Value,
+    pub icon: SymbolIcon,
+}
+
+/// A measurement the user has kept, lengths are in meters and areas in square meters.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SavedMeasurement {
+    pub name: String,
+    pub mode: MeasureMode,
+    pub points: Vec<Coord>,
+    pub length: f64,
+    pub area: Option<f64>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -112,4 +164,6 @@ pub struct WorkspaceRequest {
     #[serde(skip)]
     processed_data: RTree<MapFeature>,
     last_query_date: i64, // When the OSM data was fetched
+    #[serde(default)]
+    style: LayerStyle,
 }
@@ -0,0 +1,415 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use bevy::prelude::*;
+use bevy_egui::{EguiContexts, egui};
+use egui_extras::{Column, TableBuilder};
+use geo::{Geodesic, GeodesicArea, Length, MapCoords};
+use rstar::AABB;
+use serde_json::{Value, json};
+
+use crate::geojson::MapFeature;
+
+use super::Workspace;
+
+/// Counts and measures of the features sharing one value of a tag.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GroupStats {
+    pub value: String,
+    pub count: usize,
+    /// Total area of the closed features in the group, in square meters.
+    pub area_m2: f64,
+    /// How many of the features are areas, for the average.
+    pub areas: usize,
+    /// Total length of the open lines in the group, in meters.
+    pub length_m: f64,
+}
+
+impl GroupStats {
+    fn add(&mut self, feature: &MapFeature) {
+        self.count += 1;
+        if feature.is_point() {
+            return;
+        }
+        // Features keep the latitude in x, geo's geodesic algorithms expect the longitude there.
+        let geometry = feature
+            .geometry
+            .map_coords(|c| geo::Coord { x: c.y, y: c.x });
+        if feature.closed {
+            self.area_m2 += geometry.geodesic_area_unsigned();
+            self.areas += 1;
+        } else {
+            self.length_m += Geodesic.length(geometry.exterior());
+        }
+    }
+
+    pub fn average_area(&self) -> Option<f64> {
+        (self.areas > 0).then(|| self.area_m2 / self.areas as f64)
+    }
+
+    fn to_json(&self, area_km2: Option<f64>) -> Value {
+        let mut json = json!({ "value": self.value, "count": self.count });
+        if self.areas > 0 {
+            json["area_m2"] = json!(self.area_m2.round());
+            json["avg_area_m2"] = json!(self.average_area().unwrap_or_default().round());
+        }
+        if self.length_m > 0.0 {
+            json["length_m"] = json!(self.length_m.round());
+        }
+        if let Some(area_km2) = area_km2 {
+            json["per_km2"] = json!((self.count as f64 / area_km2 * 100.0).round() / 100.0);
+        }
+        json
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum StatSort {
+    #[default]
+    Count,
+    Area,
+    Length,
+    Value,
+}
+
+impl StatSort {
+    pub const ALL: [StatSort; 4] = [
+        StatSort::Count,
+        StatSort::Area,
+        StatSort::Length,
+        StatSort::Value,
+    ];
+
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "area" => StatSort::Area,
+            "length" => StatSort::Length,
+            "value" => StatSort::Value,
+            _ => StatSort::Count,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            StatSort::Count => "Count",
+            StatSort::Area => "Area",
+            StatSort::Length => "Length",
+            StatSort::Value => "Value",
+        }
+    }
+
+    fn compare(&self, a: &GroupStats, b: &GroupStats) -> Ordering {
+        match self {
+            StatSort::Count => b.count.cmp(&a.count),
+            StatSort::Area => b.area_m2.total_cmp(&a.area_m2),
+            StatSort::Length => b.length_m.total_cmp(&a.length_m),
+            StatSort::Value => a.value.cmp(&b.value),
+        }
+    }
+}
+
+/// The workspace's features grouped by the values of one tag.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Aggregation {
+    pub key: String,
+    pub groups: Vec<GroupStats>,
+    /// Every feature with the tag, whatever its value.
+    pub total: GroupStats,
+    /// Features looked at which don't have the tag.
+    pub missing: usize,
+    /// Area of the bbox looked in, or else the workspace selection, for densities. `None` if it
+    /// has no area.
+    pub area_km2: Option<f64>,
+}
+
+impl Aggregation {
+    pub fn sort(&mut self, sort: StatSort) {
+        self.groups.sort_by(|a, b| sort.compare(a, b));
+    }
+
+    /// The aggregation for the model, at most `limit` groups with the rest added up.
+    pub fn to_json(&self, limit: usize) -> Value {
+        let mut json = json!({
+            "key": self.key,
+            "total": self.total.to_json(self.area_km2),
+            "missing_key": self.missing,
+            "group_count": self.groups.len(),
+            "groups": self
+                .groups
+                .iter()
+                .take(limit)
+                .map(|g| g.to_json(self.area_km2))
+                .collect::<Vec<_>>(),
+        });
+        if self.groups.len() > limit {
+            let mut other = GroupStats {
+                value: format!("({} other values)", self.groups.len() - limit),
+                ..Default::default()
+            };
+            for group in &self.groups[limit..] {
+                other.count += group.count;
+                other.area_m2 += group.area_m2;
+                other.areas += group.areas;
+                other.length_m += group.length_m;
+            }
+            json["other"] = other.to_json(self.area_km2);
+        }
+        if let Some(area_km2) = self.area_km2 {
+            json["area_km2"] = json!((area_km2 * 1000.0).round() / 1000.0);
+        }
+        json
+    }
+}
+
+impl Workspace {
+    /// Groups the features with the tag `key` by its value. With `value` only that value is
+    /// counted, and with `bbox`, as `[min_lat, min_lon, max_lat, max_lon]`, only features
+    /// touching it, found through each layer's RTree.
+    pub fn aggregate(&self, key: &str, value: Option<&str>, bbox: Option<[f64; 4]>) -> Aggregation {
+        let mut groups: HashMap<String, GroupStats> = HashMap::new();
+        let mut total = GroupStats {
+            value: "all".to_string(),
+            ..Default::default()
+        };
+        let mut missing = 0;
+        for request in self.get_requests() {
+            let data = &request.processed_data;
+            let features: Box<dyn Iterator<Item = &MapFeature>> = match bbox {
+                // Features keep the latitude in x, so the envelope does too.
+                Some([min_lat, min_lon, max_lat, max_lon]) => {
+                    Box::new(data.locate_in_envelope_intersecting(&AABB::from_corners(
+                        [min_lat, min_lon],
+                        [max_lat, max_lon],
+                    )))
+                }
+                None => Box::new(data.iter()),
+            };
+            for feature in features {
+                let Some(tag) = feature.properties.get(key).map(tag_text) else {
+                    missing += 1;
+                    continue;
+                };
+                if value.is_some_and(|v| v != tag) {
+                    continue;
+                }
+                total.add(feature);
+                groups
+                    .entry(tag.clone())
+                    .or_insert_with(|| GroupStats {
+                        value: tag,
+                        ..Default::default()
+                    })
+                    .add(feature);
+            }
+        }
+        let mut aggregation = Aggregation {
+            key: key.to_string(),
+            groups: groups.into_values().collect(),
+            total,
+            missing,
+            area_km2: match bbox {
+                Some(bbox) => bbox_area_km2(bbox),
+                None => self.selection_area_km2(),
+            },
+        };
+        aggregation.sort(StatSort::Count);
+        aggregation
+    }
+
+    /// Geodesic area of the workspace selection in square kilometers.
+    pub fn selection_area_km2(&self) -> Option<f64> {
+        let selection = self.workspace.as_ref()?.get_selection();
+        let area: f64 = selection
+            .to_multi_polygon()
+            .map_coords(|c| geo::Coord { x: c.y, y: c.x })
+            .geodesic_area_unsigned();
+        (area > 0.0).then_some(area / 1_000_000.0)
+    }
+
+    /// The tag keys used in the workspace, most used first.
+    pub fn tag_keys(&self) -> Vec<String> {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for request in self.get_requests() {
+            for feature in request.processed_data.iter() {
+                for key in feature.properties.as_object().into_iter().flatten() {
+                    *counts.entry(key.0.clone()).or_default() += 1;
+                }
+            }
+        }
+        let mut keys: Vec<(String, usize)> = counts.into_iter().collect();
+        keys.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        keys.into_iter().map(|(key, _)| key).collect()
+    }
+}
+
+/// Geodesic area of a `[min_lat, min_lon, max_lat, max_lon]` box in square kilometers.
+fn bbox_area_km2([min_lat, min_lon, max_lat, max_lon]: [f64; 4]) -> Option<f64> {
+    let area = geo::Rect::new(
+        geo::Coord {
+            x: min_lon,
+            y: min_lat,
+        },
+        geo::Coord {
+            x: max_lon,
+            y: max_lat,
+        },
+    )
+    .to_polygon()
+    .geodesic_area_unsigned();
+    (area > 0.0).then_some(area / 1_000_000.0)
+}
+
+fn tag_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// State for the statistics window, which groups the workspace's features by a tag.
+#[derive(Resource, Default)]
+pub struct StatisticsWindow {
+    pub open: bool,
+    key: String,
+    sort: StatSort,
+    keys: Vec<String>,
+    result: Option<Aggregation>,
+}
+
+impl StatisticsWindow {
+    pub fn open(&mut self) {
+        self.open = true;
+        // Loaded again when the window opens, the layers may have changed since.
+        self.keys.clear();
+        self.result = None;
+    }
+}
+
+pub fn statistics_ui(
+    mut contexts: EguiContexts,
+    mut window: ResMut<StatisticsWindow>,
+    workspace: Res<Workspace>,
+) {
+    if !window.open {
+        return;
+    }
+    if window.keys.is_empty() {
+        window.keys = workspace.tag_keys();
+        if window.key.is_empty() {
+            window.key = ["building", "highway", "amenity"]
+                .into_iter()
+                .find(|k| window.keys.iter().any(|key| key == k))
+                .or(window.keys.first().map(String::as_str))
+                .unwrap_or_default()
+                .to_string();
+        }
+    }
+
+    let mut open = window.open;
+    let window = &mut *window;
+    egui::Window::new("Statistics")
+        .open(&mut open)
+        .default_size([460.0, 360.0])
+        .show(contexts.ctx_mut(), |ui| {
+            let mut changed = window.result.is_none();
+            ui.horizontal(|ui| {
+                ui.label("Group by");
+                egui::ComboBox::from_id_salt("statistics_key")
+                    .selected_text(&window.key)
+                    .show_ui(ui, |ui| {
+                        egui::ScrollArea::vertical()
+                            .max_height(300.0)
+                            .show(ui, |ui| {
+                                for key in &window.keys {
+                                    changed |= ui
+                                        .selectable_value(&mut window.key, key.clone(), key)
+                                        .changed();
+                                }
+                            });
+                    });
+                ui.label("Sort by");
+                egui::ComboBox::from_id_salt("statistics_sort")
+                    .selected_text(window.sort.label())
+                    .show_ui(ui, |ui| {
+                        for sort in StatSort::ALL {
+                            changed |= ui
+                                .selectable_value(&mut window.sort, sort, sort.label())
+                                .changed();
+                        }
+                    });
+                if ui.button("Refresh").clicked() {
+                    window.keys.clear();
+                    changed = true;
+                }
+            });
+            if changed && !window.key.is_empty() {
+                let mut result = workspace.aggregate(&window.key, None, None);
+                result.sort(window.sort);
+                window.result = Some(result);
+            }
+
+            let Some(result) = &window.result else {
+                ui.label("No features loaded.");
+                return;
+            };
+            ui.label(format!(
+                "{} features in {} groups, {} without the tag",
+                result.total.count,
+                result.groups.len(),
+                result.missing
+            ));
+            if let Some(area_km2) = result.area_km2 {
+                ui.label(format!(
+                    "Selection {:.2} km², {:.1} features per km²",
+                    area_km2,
+                    result.total.count as f64 / area_km2
+                ));
+            }
+            ui.separator();
+
+            TableBuilder::new(ui)
+                .striped(true)
+                .resizable(true)
+                .column(Column::initial(140.0).at_least(60.0).clip(true))
+                .columns(Column::auto().at_least(60.0), 5)
+                .min_scrolled_height(0.0)
+                .header(20.0, |mut header| {
+                    for title in [
+                        "Value",
+                        "Count",
+                        "Area m²",
+                        "Avg area m²",
+                        "Length m",
+                        "per km²",
+                    ] {
+                        header.col(|ui| {
+                            ui.strong(title);
+                        });
+                    }
+                })
+                .body(|body| {
+                    body.rows(18.0, result.groups.len(), |mut row| {
+                        let group = &result.groups[row.index()];
+                        let cells = [
+                            group.value.clone(),
+                            group.count.to_string(),
+                            format!("{:.0}", group.area_m2),
+                            group
+                                .average_area()
+                                .map(|a| format!("{a:.0}"))
+                                .unwrap_or_default(),
+                            format!("{:.0}", group.length_m),
+                            result
+                                .area_km2
+                                .map(|a| format!("{:.2}", group.count as f64 / a))
+                                .unwrap_or_default(),
+                        ];
+                        for cell in cells {
+                            row.col(|ui| {
+                                ui.label(cell);
+                            });
+                        }
+                    });
+                });
+        });
+    window.open = open;
+}
@@ -1,25 +1,29 @@
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
 
 use bevy::prelude::*;
 use bevy_egui::{
     EguiContexts,
     egui::{self, Align2, Checkbox, CornerRadius, RichText},
 };
-use bevy_map_viewer::{
-    Coord, EguiBlockInputState, MapViewerMarker, TileMapResources, ZoomChangedEvent, game_to_coord,
-};
-use rstar::{AABB, Envelope};
+use bevy_map_viewer::{Coord, MapViewerMarker, TileMapResources, ZoomChangedEvent};
+use rstar::{AABB, Envelope, RTreeObject};
 use uuid::Uuid;
 
 use crate::{
-    geojson::MapFeature,
+    geojson::SymbolIcon,
+    keybindings::{Action, Keybindings},
+    llm::{ChatUsage, Message, messages_tokens},
     overpass::{build_overpass_query_string, get_bounds},
-    tools::ToolResources,
-    workspace::{SelectionType, Workspace, WorkspaceData},
+    settings::LlmSettingsWindow,
+    tools::{FeatureClickedEvent, FeatureSelection, ToolResources},
+    workspace::{MapAction, SelectionType, Workspace, WorkspaceData},
 };
 
-use super::{RequestType, WorkspaceRequest};
+use super::{History, HistoryChange, RequestType, WorkspaceRequest};
 
 // This should go into workspace so it can be saved.
 #[derive(Resource)]
@@ -46,14 +50,27 @@ impl Clone for ChatState {
 #[derive(Default, Clone)]
 pub struct ChatStateInner {
     pub input_text: String,
-    pub chat_history: Vec<ChatMessage>,
     pub is_processing: bool,
-}
-
-#[derive(Clone)]
-pub struct ChatMessage {
-    pub content: String,
-    pub is_user: bool,
+    /// The workspace the reply being worked on belongs to.
+    pub workspace_id: Option<String>,
+    /// The reply as it streams in. It moves into the workspace's messages once complete.
+    pub streaming: String,
+    /// Messages from the background request, waiting for `collect_chat_messages` to add them to
+    /// the workspace.
+    pub new_messages: Vec<Message>,
+    /// Tokens used by the background request, added to the workspace along with the messages.
+    pub new_usage: ChatUsage,
+    /// Map actions the assistant proposed, moved to `MapActions` for the user to confirm.
+    pub new_actions: Vec<MapAction>,
+    /// Shown under the conversation until the next question, for errors and stopped replies.
+    pub notice: Option<String>,
+    /// Collapsed to its title bar until it is opened again.
+    pub hidden: bool,
+    /// Focus the input box on the next frame, set by the open chat shortcut.
+    pub focus_input: bool,
+    /// Set to stop the reply being streamed in. Each message gets a new one so a cancelled
+    /// request that is still winding down can't pick up the next message's flag.
+    pub cancel: Arc<AtomicBool>,
 }
 
 pub fn workspace_actions_ui(
@@ -155,7 +172,7 @@ pub fn workspace_actions_ui(
 
                                                 // Make request to the overpass server
                                                 let q = build_overpass_query_string(
-                                                    get_bounds(selection.clone()),
+                                                    &get_bounds(selection.clone()),
                                                     workspace_res.overpass_agent.settings.clone(),
                                                 );
                                                 if let Ok(query) = q {
@@ -253,71 +270,37 @@ pub struct PersistentInfoWindows {
     pub windows: HashMap<String, serde_json::Value>,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn item_info(
-    windows: Query<&Window>,
-    camera: Query<(&Camera, &GlobalTransform), With<Camera2d>>,
-    mouse_button: Res<ButtonInput<MouseButton>>,
     mut contexts: EguiContexts,
     mut persistent_info_windows: ResMut<PersistentInfoWindows>,
-    res_manager: Res<TileMapResources>,
-    state: Res<EguiBlockInputState>,
-    tools: Res<ToolResources>,
+    mut clicked: EventReader<FeatureClickedEvent>,
+    mut selection: ResMut<FeatureSelection>,
     mut workspace: ResMut<Workspace>,
     mut zoom_change: EventWriter<ZoomChangedEvent>,
+    mut history: ResMut<History>,
+    mut tools: ResMut<ToolResources>,
 ) {
-    if mouse_button.just_pressed(MouseButton::Left) && !state.block_input && tools.pointer {
-        let (camera, camera_transform) = camera.iter().next().expect("Couldnt get camera");
-        let Ok(window) = windows.single() else {
-            return;
-        };
-        if let Some(cursor_pos) = window.cursor_position() {
-            let world_position = camera
-                .viewport_to_world_2d(camera_transform, cursor_pos)
-                .unwrap();
-            let position = game_to_coord(
-                world_position.x,
-                world_position.y,
-                res_manager.chunk_manager.refrence_long_lat,
-                res_manager.chunk_manager.displacement,
-                14,
-                res_manager.zoom_manager.tile_quality,
-            );
-            let envelope: AABB<[f64; 2]> = AABB::from_corners(
-                [position.lat as f64, position.long as f64],
-                [position.lat as f64, position.long as f64],
-            );
-
-            let mut features: Vec<MapFeature> = Vec::new();
-            for i in workspace.get_rendered_requests() {
-                if i.get_processed_data().size() == 0 {
-                    continue;
-                }
-                features.extend(
-                    i.get_processed_data()
-                        .locate_in_envelope_intersecting(&envelope)
-                        .cloned()
-                        .collect::<Vec<_>>(),
-                );
-            }
-
-            for feature in features {
-                let feat = feature.clone();
-                if persistent_info_windows
-                    .windows
-                    .contains_key(&feat.id.to_string())
-                {
-                    continue;
-                }
-                persistent_info_windows
-                    .windows
-                    .insert(feat.id.to_string(), feat.properties);
+    for event in clicked.read() {
+        for feature in event.features.iter() {
+            let feat = feature.clone();
+            if persistent_info_windows
+                .windows
+                .contains_key(&feat.id.to_string())
+            {
+                continue;
             }
+            persistent_info_windows
+                .windows
+                .insert(feat.id.to_string(), feat.properties);
         }
     }
 
     let mut windows_to_remove = Vec::new();
+    let mut hovered = None;
+    let mut edited = false;
     for (id, window_state) in persistent_info_windows.windows.iter() {
-        egui::Window::new(id.clone()).show(contexts.ctx_mut(), |ui| {
+        let response = egui::Window::new(id.clone()).show(contexts.ctx_mut(), |ui| {
             egui::Grid::new("grid").show(ui, |ui| {
                 if let Some(object) = window_state.as_object() {
                     for (key, value) in object {
@@ -326,47 +309,98 @@ pub fn item_info(
 
                             ui.label(format!("{value}"));
 
-                            if let Some(color) = workspace
+                            if let Some(existing) = workspace
                                 .workspace
-                                .as_mut()
+                                .as_ref()
                                 .unwrap()
                                 .properties
                                 .get(&(key.clone(), value.clone()))
+                                .copied()
                             {
                                 let mut color = egui::Color32::from_rgb(
-                                    (color.red * 255.0) as u8,
-                                    (color.green * 255.0) as u8,
-                                    (color.blue * 255.0) as u8,
+                                    (existing.red * 255.0) as u8,
+                                    (existing.green * 255.0) as u8,
+                                    (existing.blue * 255.0) as u8,
                                 );
                                 if ui.color_edit_button_srgba(&mut color).changed() {
-                                    // Update the workspace properties with the new color
-                                    workspace.workspace.as_mut().unwrap().properties.insert(
-                                        (key.clone(), value.clone()),
-                                        Srgba {
-                                            red: color.r() as f32 / 255.0,
-                                            green: color.g() as f32 / 255.0,
-                                            blue: color.b() as f32 / 255.0,
-                                            alpha: color.a() as f32 / 255.0,
+                                    let ws = workspace.workspace.as_mut().unwrap();
+                                    let after = Srgba {
+                                        red: color.r() as f32 / 255.0,
+                                        green: color.g() as f32 / 255.0,
+                                        blue: color.b() as f32 / 255.0,
+                                        alpha: color.a() as f32 / 255.0,
+                                    };
+                                    history.record_step(
+                                        "Change colour",
+                                        (ws.get_id(), key, value.to_string()),
+                                        HistoryChange::Colour {
+                                            workspace_id: ws.get_id(),
+                                            key: (key.clone(), value.clone()),
+                                            before: Some(existing),
+                                            after: Some(after),
                                         },
                                     );
+                                    // Update the workspace properties with the new color
+                                    ws.properties.insert((key.clone(), value.clone()), after);
                                     zoom_change.write(ZoomChangedEvent);
+                                    edited = true;
                                 }
                             } else {
                                 let mut color = egui::Color32::WHITE; // Default color
                                 if ui.color_edit_button_srgba(&mut color).changed() {
-                                    // Update the workspace properties with the new color
-                                    workspace.workspace.as_mut().unwrap().properties.insert(
-                                        (key.clone(), value.clone()),
-                                        Srgba {
-                                            red: color.r() as f32 / 255.0,
-                                            green: color.g() as f32 / 255.0,
-                                            blue: color.b() as f32 / 255.0,
-                                            alpha: color.a() as f32 / 255.0,
+                                    let ws = workspace.workspace.as_mut().unwrap();
+                                    let after = Srgba {
+                                        red: color.r() as f32 / 255.0,
+                                        green: color.g() as f32 / 255.0,
+                                        blue: color.b() as f32 / 255.0,
+                                        alpha: color.a() as f32 / 255.0,
+                                    };
+                                    history.record_step(
+                                        "Change colour",
+                                        (ws.get_id(), key, value.to_string()),
+                                        HistoryChange::Colour {
+                                            workspace_id: ws.get_id(),
+                                            key: (key.clone(), value.clone()),
+                                            before: None,
+                                            after: Some(after),
                                         },
                                     );
+                                    // Update the workspace properties with the new color
+                                    ws.properties.insert((key.clone(), value.clone()), after);
                                     zoom_change.write(ZoomChangedEvent);
+                                    edited = true;
                                 }
                             }
+
+                            let ws = workspace.workspace.as_mut().unwrap();
+                            let mut icon = ws
+                                .get_symbol_rules()
+                                .into_iter()
+                                .find(|rule| &rule.key == key && &rule.value == value)
+                                .map(|rule| rule.icon);
+                            egui::ComboBox::from_id_salt((id, key))
+                                .width(70.0)
+                                .selected_text(
+                                    icon.map_or("Icon".to_string(), |i| format!("{i:?}")),
+                                )
+                                .show_ui(ui, |ui| {
+                                    let mut changed =
+                                        ui.selectable_value(&mut icon, None, "Default").clicked();
+                                    for option in SymbolIcon::ALL {
+                                        changed |= ui
+                                            .selectable_value(
+                                                &mut icon,
+                                                Some(option),
+                                                format!("{option:?}"),
+                                            )
+                                            .clicked();
+                                    }
+                                    if changed {
+                                        ws.set_symbol(key.clone(), value.clone(), icon);
+                                        zoom_change.write(ZoomChangedEvent);
+                                        edited = true;
+                                    }
+                                });
                         });
                         ui.end_row();
                     }
@@ -377,10 +411,21 @@ pub fn item_info(
                 windows_to_remove.push(id.clone());
             }
         });
+        // Highlight the feature on the map while its window is hovered.
+        if response.is_some_and(|r| r.response.contains_pointer()) {
+            hovered = Some(id.clone());
+        }
+    }
+    if selection.hovered != hovered {
+        selection.hovered = hovered;
     }
     for id in windows_to_remove {
         persistent_info_windows.windows.remove(&id);
     }
+    // Switching workspace saves the copy in the selection areas.
+    if let Some(ws) = workspace.workspace.as_ref().filter(|_| edited) {
+        tools.selection_areas.replace(ws.clone());
+    }
 }
 
 fn center(selection: &super::Selection, tile_map_res: &TileMapResources) -> Vec2 {
@@ -420,6 +465,10 @@ fn center(selection: &super::Selection, tile_map_res: &TileMapResources) -> Vec2
             .start
             .unwrap()
             .to_game_coords(tile_map_res.clone()),
+        SelectionType::MULTIPOLYGON => {
+            let center = selection.envelope().center();
+            Coord::new(center[0] as f32, center[1] as f32).to_game_coords(tile_map_res.clone())
+        }
         _ => Vec2::new(0.0, 0.0),
     }
 }
@@ -428,10 +477,21 @@ pub fn chat_box_ui(
     mut contexts: EguiContexts,
     chat_state: Res<ChatState>,
     mut workspace: ResMut<Workspace>,
+    keys: Res<ButtonInput<KeyCode>>,
+    keybindings: Res<Keybindings>,
+    mut llm_settings: ResMut<LlmSettingsWindow>,
+    mut tools: ResMut<ToolResources>,
 ) {
-    if workspace.workspace.is_none() {
+    let Some(workspace_id) = workspace.workspace.as_ref().map(|ws| ws.get_id()) else {
         return;
+    };
+    if keybindings.just_pressed(Action::OpenChat, &keys) {
+        let mut inner = chat_state.inner.lock().unwrap();
+        inner.hidden = false;
+        inner.focus_input = true;
     }
+    let hidden = chat_state.inner.lock().is_ok_and(|inner| inner.hidden);
+    let mut clear = false;
     let ctx = contexts.ctx_mut();
     let screen_rect = ctx.screen_rect();
 
@@ -440,7 +500,11 @@ pub fn chat_box_ui(
     } else {
         200.0
     }; // Same width as workspace_analysis_ui
-    let chat_height = screen_rect.height() - 50.0;
+    let chat_height = if hidden {
+        20.0
+    } else {
+        screen_rect.height() - 50.0
+    };
     let chat_pos = egui::pos2(
         screen_rect.width() - chat_width - 10.0,
         40.0, // Position under item_info with some spacing
@@ -473,57 +537,144 @@ pub fn chat_box_ui(
                             ui.with_layout(
                                 egui::Layout::right_to_left(egui::Align::Center),
                                 |ui| {
-                                    if ui.small_button("🗑").on_hover_text("Clear chat").clicked()
+                                    let (icon, hover) = if hidden {
+                                        ("⏶", "Show chat")
+                                    } else {
+                                        ("⏷", "Hide chat")
+                                    };
+                                    if ui.small_button(icon).on_hover_text(hover).clicked() {
+                                        chat_state.inner.lock().unwrap().hidden = !hidden;
+                                    }
+                                    let model = workspace
+                                        .workspace
+                                        .as_ref()
+                                        .map(|ws| ws.get_model_params().model)
+                                        .unwrap_or_default();
+                                    if ui
+                                        .small_button("⚙")
+                                        .on_hover_text(format!("AI settings\nModel: {model}"))
+                                        .clicked()
+                                    {
+                                        llm_settings.open();
+                                    }
+                                    if ui
+                                        .small_button("🗑")
+                                        .on_hover_text("Clear history")
+                                        .clicked()
+                                    {
+                                        clear = true;
+                                    }
+                                    if ui
+                                        .small_button("⬇")
+                                        .on_hover_text("Export conversation as Markdown")
+                                        .clicked()
                                     {
-                                        if let Ok(mut inner) = chat_state.inner.lock() {
-                                            inner.chat_history.clear();
+                                        if let Some(Err(e)) = workspace
+                                            .workspace
+                                            .as_ref()
+                                            .map(|ws| ws.export_conversation())
+                                        {
+                                            error!("Failed to export conversation: {}", e);
                                         }
                                     }
                                 },
                             );
                         });
 
+                        if hidden {
+                            return;
+                        }
                         ui.separator();
 
                         egui::ScrollArea::vertical()
                             .auto_shrink([false, false])
                             .stick_to_bottom(true)
-                            .max_height(chat_height - 80.0) // Leave space for input
+                            .max_height(chat_height - 100.0) // Leave space for input
                             .show(ui, |ui| {
                                 ui.set_width(chat_width - 20.0);
 
-                                if let Ok(inner) = chat_state.inner.lock() {
-                                    if inner.chat_history.is_empty() {
-                                        ui.vertical_centered(|ui| {
-                                            ui.add_space(20.0);
-                                            ui.label(
-                                                RichText::new("Ask me about the map data!")
-                                                    .color(egui::Color32::GRAY)
-                                                    .italics(),
-                                            );
-                                        });
-                                    } else {
-                                        for message in &inner.chat_history {
-                                            render_chat_message(ui, message, chat_width - 30.0);
-                                            ui.add_space(8.0);
-                                        }
-                                    } // Show loading indicator if processing
-                                    if inner.is_processing {
-                                        ui.horizontal(|ui| {
-                                            ui.add_space(10.0);
-                                            ui.spinner();
-                                            ui.label(
-                                                RichText::new("AI is thinking...")
-                                                    .color(egui::Color32::GRAY),
-                                            );
-                                        });
+                                let messages: Vec<&Message> = workspace
+                                    .workspace
+                                    .iter()
+                                    .flat_map(|ws| ws.get_messages())
+                                    .filter(|m| m.is_chat())
+                                    .collect();
+                                let mut inner = chat_state.inner.lock().unwrap();
+                                // A reply for another workspace isn't shown here.
+                                let processing = inner.is_processing
+                                    && inner.workspace_id.as_deref() == Some(workspace_id.as_str());
+                                if messages.is_empty() && !processing {
+                                    ui.vertical_centered(|ui| {
+                                        ui.add_space(20.0);
+                                        ui.label(
+                                            RichText::new("Ask me about the map data!")
+                                                .color(egui::Color32::GRAY)
+                                                .italics(),
+                                        );
+                                    });
+                                }
+                                for message in messages {
+                                    render_chat_message(
+                                        ui,
+                                        message.display_text(),
+                                        message.role == "user",
+                                        chat_width - 30.0,
+                                    );
+                                    ui.add_space(8.0);
+                                }
+                                if processing && !inner.streaming.is_empty() {
+                                    render_chat_message(
+                                        ui,
+                                        &inner.streaming,
+                                        false,
+                                        chat_width - 30.0,
+                                    );
+                                    ui.add_space(8.0);
+                                }
+                                // Show loading indicator if processing
+                                if processing {
+                                    let mut stop = false;
+                                    ui.horizontal(|ui| {
+                                        ui.add_space(10.0);
+                                        ui.spinner();
+                                        ui.label(
+                                            RichText::new("AI is thinking...")
+                                                .color(egui::Color32::GRAY),
+                                        );
+                                        stop = ui
+                                            .small_button("⏹ Stop")
+                                            .on_hover_text("Stop the response")
+                                            .clicked();
+                                    });
+                                    if stop {
+                                        inner.cancel.store(true, Ordering::Relaxed);
+                                        inner.is_processing = false;
+                                        inner.streaming.clear();
+                                        inner.notice = Some("Response stopped.".to_string());
                                     }
                                 }
+                                if let Some(notice) = &inner.notice {
+                                    ui.label(
+                                        RichText::new(notice).color(egui::Color32::GRAY).italics(),
+                                    );
+                                }
                             });
 
                         ui.add_space(5.0);
                         ui.separator();
 
+                        if !workspace.llm_agent.is_configured() {
+                            ui.label(
+                                RichText::new("No API key configured.")
+                                    .color(egui::Color32::GRAY)
+                                    .italics(),
+                            );
+                            if ui.button("Set API key").clicked() {
+                                llm_settings.open();
+                            }
+                            return;
+                        }
+
                         // Input area
                         ui.horizontal(|ui| {
                             if let Ok(mut inner) = chat_state.inner.lock() {
@@ -532,6 +683,10 @@ pub fn chat_box_ui(
                                     .hint_text("Ask about the map data...");
 
                                 let response = ui.add(text_edit);
+                                if inner.focus_input {
+                                    inner.focus_input = false;
+                                    response.request_focus();
+                                }
 
                                 let send_clicked =
                                     ui.button("📤").on_hover_text("Send message").clicked();
@@ -546,30 +701,41 @@ pub fn chat_box_ui(
                                     let user_message = inner.input_text.trim().to_string();
                                     inner.input_text.clear();
                                     inner.is_processing = true;
-
-                                    // Add user message to history
-                                    inner.chat_history.push(ChatMessage {
-                                        content: user_message.clone(),
-                                        is_user: true,
-                                    });
+                                    inner.cancel = Arc::new(AtomicBool::new(false));
+                                    inner.workspace_id = Some(workspace_id.clone());
+                                    inner.streaming.clear();
+                                    inner.notice = None;
 
                                     // Drop the lock before calling send_chat_message_background
                                     drop(inner);
                                     send_chat_message_background(
-                                        &chat_state,
                                         &mut workspace,
+                                        &mut tools,
                                         user_message,
                                     );
                                 }
                             }
                         });
+
+                        if let Some(ws) = workspace.workspace.as_ref() {
+                            usage_label(ui, ws, workspace.llm_agent.overhead_tokens());
+                        }
                     });
                 });
         });
+
+    if clear {
+        if let Some(ws) = workspace.workspace.as_mut() {
+            ws.clear_history();
+            tools.selection_areas.replace(ws.clone());
+        }
+        let _ = workspace.save_workspace();
+        chat_state.inner.lock().unwrap().notice = None;
+    }
 }
 
-fn render_chat_message(ui: &mut egui::Ui, message: &ChatMessage, max_width: f32) {
-    let bg_color = if message.is_user {
+fn render_chat_message(ui: &mut egui::Ui, text: &str, is_user: bool, max_width: f32) {
+    let bg_color = if is_user {
         egui::Color32::from_rgba_premultiplied(70, 130, 180, 200) // Steel blue for user
     } else {
         egui::Color32::from_rgba_premultiplied(60, 60, 60, 200) // Dark gray for AI
@@ -579,7 +745,7 @@ fn render_chat_message(ui: &mut egui::Ui, message: &ChatMessage, max_width: f32)
 
     ui.horizontal(|ui| {
         ui.add_space(10.0);
-        if !message.is_user {
+        if !is_user {
             ui.add_space(20.0);
         }
 
@@ -592,8 +758,8 @@ fn render_chat_message(ui: &mut egui::Ui, message: &ChatMessage, max_width: f32)
 
                 // Message header
                 ui.horizontal(|ui| {
-                    let icon = if message.is_user { "👤" } else { "🤖" };
-                    let sender = if message.is_user { "You" } else { "AI" };
+                    let icon = if is_user { "👤" } else { "🤖" };
+                    let sender = if is_user { "You" } else { "AI" };
                     ui.label(
                         RichText::new(format!("{icon} {sender}"))
                             .small()
@@ -601,22 +767,68 @@ fn render_chat_message(ui: &mut egui::Ui, message: &ChatMessage, max_width: f32)
                     );
                 });
 
-                ui.add(egui::Label::new(RichText::new(&message.content).color(text_color)).wrap());
+                ui.add(egui::Label::new(RichText::new(text).color(text_color)).wrap());
             });
 
-        if message.is_user {
+        if is_user {
             ui.add_space(10.0);
         }
     });
 }
 
+/// How full the model's context is and what the conversation has used so far.
+fn usage_label(ui: &mut egui::Ui, ws: &WorkspaceData, overhead: u32) {
+    let params = ws.get_model_params();
+    let usage = ws.get_usage();
+    let context = overhead + messages_tokens(ws.get_messages());
+    let mut text = format!(
+        "Context ~{} / {}",
+        short_count(context as i64),
+        short_count(params.context_length() as i64)
+    );
+    if usage.total_tokens() > 0 {
+        text.push_str(&format!(" · Used {}", short_count(usage.total_tokens())));
+    }
+    if usage.cost > 0.0 {
+        text.push_str(&format!(" · ${:.4}", usage.cost));
+    }
+    let mut hover = format!(
+        "Prompt tokens: {}\nCompletion tokens: {}",
+        usage.prompt_tokens, usage.completion_tokens
+    );
+    if usage.last_prompt_tokens > 0 {
+        hover.push_str(&format!(
+            "\nLast request: {} tokens",
+            usage.last_prompt_tokens
+        ));
+    }
+    if params.pricing.is_none() {
+        hover
+            .push_str("\nThe model's price isn't known, pick it from the model list to see costs.");
+    }
+    let colour = if context > params.prompt_budget() {
+        hover.push_str("\nOlder messages are summarised to fit.");
+        egui::Color32::from_rgb(230, 160, 60)
+    } else {
+        egui::Color32::GRAY
+    };
+    ui.label(RichText::new(text).small().color(colour))
+        .on_hover_text(hover);
+}
+
+fn short_count(count: i64) -> String {
+    if count >= 1000 {
+        format!("{:.1}k", count as f64 / 1000.0)
+    } else {
+        count.to_string()
+    }
+}
+
 fn send_chat_message_background(
-    chat_state: &ChatState,
     workspace: &mut Workspace,
+    tools: &mut ToolResources,
     user_message: String,
 ) {
-    workspace.llm_agent.set_token("!!! YOUR TOKEN HERE !!!");
-
     // Build context information about the current workspace and selection
     let mut context_info = String::new();
 
@@ -669,6 +881,14 @@ fn send_chat_message_background(
                     }
                 }
             }
+            crate::workspace::SelectionType::MULTIPOLYGON => {
+                let holes: usize = selection.polygons.iter().map(|p| p.holes.len()).sum();
+                context_info.push_str(&format!(
+                    "Multipolygon with {} parts and {} holes\n",
+                    selection.polygons.len(),
+                    holes
+                ));
+            }
             _ => {}
         }
 
@@ -687,9 +907,11 @@ fn send_chat_message_background(
     } else {
         format!("CONTEXT:\n{context_info}\nUSER QUERY: {user_message}")
     };
-    if let Some(workspace) = workspace.workspace.as_mut() {
-        workspace.add_message("user", &enhanced_message);
+    if let Some(ws) = workspace.workspace.as_mut() {
+        ws.add_message("user", &enhanced_message);
+        tools.selection_areas.replace(ws.clone());
     }
+    let _ = workspace.save_workspace();
 
     // Attempt to get response from LLM
 
@@ -700,11 +922,4 @@ fn send_chat_message_background(
         Vec::new(),
     );
     workspace.worker.queue_request(request);
-
-    // Clean up old messages
-    if let Ok(mut inner) = chat_state.inner.lock() {
-        while inner.chat_history.len() > 50 {
-            inner.chat_history.remove(0);
-        }
-    }
 }
@@ -1,13 +1,20 @@
-use crate::tools::ToolResources;
-use crate::workspace::ui::{ChatMessage, ChatState};
-use crate::workspace::{RequestType, WorkspaceRequest};
+use crate::llm::ChatUsage;
+use crate::overpass::{drop_elements_in_holes, get_bounds};
+use crate::tools::{FeatureSelection, ToolResources};
+use crate::workspace::commands::parse_rq_command;
+use crate::workspace::ui::ChatState;
+use crate::workspace::{
+    History, HistoryChange, MapAction, MapActions, RequestType, WorkspaceRequest,
+};
 use bevy::prelude::*;
 use bevy::tasks::{AsyncComputeTaskPool, Task};
-use bevy_map_viewer::Coord;
 use bevy_tasks::futures_lite::future;
-use std::sync::{Arc, Mutex};
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
 
-use super::Workspace;
+use super::{Workspace, WorkspaceData};
 #[derive(Default, Clone)]
 pub struct WorkspaceWorker {
     /// A thread-safe queue of pending Overpass requests.
@@ -39,12 +46,15 @@ pub fn load_workspaces(mut workspace: ResMut<Workspace>, mut tools: ResMut<ToolR
     for (_, data) in workspace.loaded_workspace.clone().into_iter() {
         tools.selection_areas.areas.insert(data);
     }
+    tools.pins.respawn = true;
 }
 
 pub fn process_requests(
     mut commands: Commands,
     mut workspace: ResMut<Workspace>,
     chat_state: ResMut<ChatState>,
+    feature_selection: Res<FeatureSelection>,
+    mut history: ResMut<History>,
 ) {
     let task_pool = AsyncComputeTaskPool::get();
     let pending_requests = workspace.worker.pending_requests.clone();
@@ -75,6 +85,18 @@ pub fn process_requests(
             if let Some(workspace) = workspace.workspace.as_mut() {
                 info!("Request id: {}", request.id);
                 workspace.add_request(request.id.clone());
+                // Chat replies and weather lookups are not map layers.
+                if matches!(request.request, RequestType::OverpassTurboRequest(_)) {
+                    history.record(
+                        "Add layer",
+                        HistoryChange::Layer {
+                            workspace_id: workspace.get_id(),
+                            request_id: request.id.clone(),
+                            request: None,
+                            added: true,
+                        },
+                    );
+                }
             } else {
                 info!("No workspace found");
                 return;
@@ -82,6 +104,7 @@ pub fn process_requests(
             let loaded_requests = workspace.loaded_requests.clone();
             let workspace_clone = workspace.clone();
             let cs = chat_state.clone();
+            let selection = feature_selection.clone();
             let task = task_pool.spawn(async move {
                 let mut result = Vec::new();
                 match request.get_request() {
@@ -93,15 +116,35 @@ pub fn process_requests(
                             if !q.is_empty() {
                                 result = q.as_bytes().to_vec();
                             }
+                            // Only the outside of each part is sent, so trim what is in the holes.
+                            if let Some(ws) = &workspace_clone.workspace {
+                                let selection = ws.get_selection();
+                                if get_bounds(selection.clone())
+                                    .iter()
+                                    .all(|bound| query.contains(bound))
+                                {
+                                    result = drop_elements_in_holes(&result, &selection);
+                                }
+                            }
                         }
                     }
                     RequestType::OpenRouterRequest() => {
                         if let Some(workspace_data) = &workspace_clone.workspace {
                             // Process the LLM request with automatic follow-up capability
-                            process_llm_request(&workspace_clone, workspace_data, &cs, 0);
+                            let cancel = cs.inner.lock().unwrap().cancel.clone();
+                            process_llm_request(
+                                &workspace_clone,
+                                workspace_data,
+                                &cs,
+                                &selection,
+                                &cancel,
+                                0,
+                            );
                         }
                     }
                     RequestType::OpenMeteoRequest(_open_meteo_request) => {}
+                    // Drawn locally, never queued.
+                    RequestType::UserSketch => {}
                 }
 
                 request.raw_data = result.clone();
@@ -136,182 +179,124 @@ pub fn cleanup_tasks(mut commands: Commands, mut tasks: Query<(Entity, &mut Task
 // Recursive function to handle LLM requests with automatic follow-up
 fn process_llm_request(
     workspace_clone: &Workspace,
-    workspace_data: &super::WorkspaceData,
+    workspace_data: &WorkspaceData,
     cs: &ChatState,
+    selection: &FeatureSelection,
+    cancel: &AtomicBool,
     recursion_depth: usize,
 ) {
     const MAX_RECURSION_DEPTH: usize = 5; // Prevent infinite loops
 
+    // The chat panel has already stopped waiting.
+    if cancel.load(Ordering::Relaxed) {
+        bevy::log::info!("LLM request cancelled");
+        return;
+    }
+
     if recursion_depth >= MAX_RECURSION_DEPTH {
         bevy::log::warn!("Maximum recursion depth reached for LLM requests");
         if let Ok(mut inner) = cs.inner.lock() {
+            inner.notice = Some(format!(
+                "Stopped after {MAX_RECURSION_DEPTH} rounds of tool calls. Ask again to continue."
+            ));
+            inner.streaming.clear();
             inner.is_processing = false;
         }
         return;
     }
 
-    if let Ok(q) = workspace_clone
-        .llm_agent
-        .send_openrouter_chat(&workspace_data.messages)
-    {
+    // Shown in the chat panel as it arrives, until the reply is complete.
+    let result = workspace_clone.llm_agent.chat_stream(
+        &workspace_data.messages,
+        &workspace_data.model,
+        &mut |token| {
+            let mut inner = cs.inner.lock().unwrap();
+            if !cancel.load(Ordering::Relaxed) {
+                inner.streaming.push_str(token);
+            }
+        },
+        cancel,
+    );
+    if cancel.load(Ordering::Relaxed) {
+        bevy::log::info!("LLM request cancelled");
+        return;
+    }
+    if let Ok(q) = result {
+        cs.inner
+            .lock()
+            .unwrap()
+            .new_usage
+            .add(&q.usage, workspace_data.model.pricing.as_ref());
         if let Some(choice) = q.choices.first() {
             let ai_message = choice.message.content.clone();
+            let tool_calls = choice.message.tool_calls.clone();
             let mut workspace_data_mut = workspace_data.clone();
-            workspace_data_mut.add_message("assistant", &ai_message);
-
-            // Update chat state with thread-safe access
-            if let Ok(mut inner) = cs.inner.lock() {
-                inner.chat_history.push(ChatMessage {
-                    content: ai_message.clone(),
-                    is_user: false,
-                });
-            }
 
-            if ai_message.len() > 2 && &ai_message[0..3] == "rq:" {
-                let command_part = &ai_message[3..].trim();
-                let mut parts = command_part.split_whitespace();
-
-                if let Some(cmd) = parts.next() {
-                    let response = match cmd {
-                        "i" => {
-                            // General info/stats
-                            workspace_clone.get_info()
-                        }
-                        "cnt" => {
-                            // Count features - can be for whole workspace or specific area
-                            format!("Feature count: {}", workspace_clone.count_workspace())
-                        }
-                        "nb" => {
-                            // Nearby features: rq: nb {51.5,-0.09} r500
-                            if let (Some(coord_str), Some(radius_str)) =
-                                (parts.next(), parts.next())
-                            {
-                                if let (Ok(coord), Ok(radius)) =
-                                    (parse_coord(coord_str), parse_radius(radius_str))
-                                {
-                                    let features = workspace_clone.nearby_point(coord, radius);
-                                    format!(
-                                        "Found {} nearby features: {:?}",
-                                        features.len(),
-                                        features.iter().map(|f| &f.id).collect::<Vec<_>>()
-                                    )
-                                } else {
-                                    "Invalid coordinate or radius format. Use: rq: nb {lat,lon} r<meters>".to_string()
-                                }
-                            } else {
-                                "Missing parameters. Use: rq: nb {lat,lon} r<meters>".to_string()
-                            }
-                        }
-                        "sm" => {
-                            // Summarize features: rq: sm
-                            workspace_clone.summarize_features()
-                        }
-                        "gt" => {
-                            // Feature details by ID: rq: gt 123456
-                            if let Some(id) = parts.next() {
-                                if let Some(feature) = workspace_clone.get_feature_by_id(id) {
-                                    format!("Feature {}: {:?}", id, feature)
-                                } else {
-                                    format!("Feature {} not found", id)
-                                }
-                            } else {
-                                "Missing feature ID. Use: rq: gt <feature_id>".to_string()
-                            }
-                        }
-                        "t" => {
-                            // Feature tags: rq: t 123456
-                            if let Some(id) = parts.next() {
-                                if let Some(tags) = workspace_clone.get_feature_tags(id) {
-                                    format!("Tags for feature {}: {}", id, tags)
-                                } else {
-                                    format!("Feature {} not found or has no tags", id)
-                                }
-                            } else {
-                                "Missing feature ID. Use: rq: t <feature_id>".to_string()
-                            }
-                        }
-                        "bb" => {
-                            // Features in bbox: rq: bb {51.4,-0.1,51.6,-0.08}
-                            if let Some(bbox_str) = parts.next() {
-                                if let Ok((min_lat, min_lon, max_lat, max_lon)) =
-                                    parse_bbox(bbox_str)
-                                {
-                                    let features = workspace_clone
-                                        .features_in_bbox(min_lat, min_lon, max_lat, max_lon);
-                                    format!(
-                                        "Found {} features in bbox: {:?}",
-                                        features.len(),
-                                        features.iter().map(|f| &f.id).collect::<Vec<_>>()
-                                    )
-                                } else {
-                                    "Invalid bbox format. Use: rq: bb {min_lat,min_lon,max_lat,max_lon}".to_string()
-                                }
-                            } else {
-                                "Missing bbox. Use: rq: bb {min_lat,min_lon,max_lat,max_lon}"
-                                    .to_string()
-                            }
-                        }
-                        "d" => {
-                            // Distance between points: rq: d {51.5,-0.09} {51.6,-0.10}
-                            if let (Some(coord1_str), Some(coord2_str)) =
-                                (parts.next(), parts.next())
-                            {
-                                if let (Ok(coord1), Ok(coord2)) =
-                                    (parse_coord(coord1_str), parse_coord(coord2_str))
-                                {
-                                    let distance = workspace_clone.distance_between(coord1, coord2);
-                                    format!("Distance: {:.2} meters", distance)
-                                } else {
-                                    "Invalid coordinate format. Use: rq: d {lat1,lon1} {lat2,lon2}"
-                                        .to_string()
-                                }
-                            } else {
-                                "Missing coordinates. Use: rq: d {lat1,lon1} {lat2,lon2}"
-                                    .to_string()
-                            }
-                        }
-                        "n" => {
-                            // Nearest feature: rq: n {51.5,-0.09}
-                            if let Some(coord_str) = parts.next() {
-                                if let Ok(coord) = parse_coord(coord_str) {
-                                    if let Some(feature) = workspace_clone.nearest_feature(coord) {
-                                        format!("Nearest feature: {} at {:?}", feature.id, feature)
-                                    } else {
-                                        "No features found".to_string()
-                                    }
-                                } else {
-                                    "Invalid coordinate format. Use: rq: n {lat,lon}".to_string()
-                                }
-                            } else {
-                                "Missing coordinate. Use: rq: n {lat,lon}".to_string()
-                            }
-                        }
-                        _ => {
-                            format!(
-                                "Unknown command: {}. Available commands: i, cnt, nb, sm, gt, t, bb, d, n",
-                                cmd
-                            )
+            if !tool_calls.is_empty() {
+                workspace_data_mut.add_tool_calls(&ai_message, tool_calls.clone());
+                for call in tool_calls {
+                    let args = serde_json::from_str(&call.function.arguments)
+                        .unwrap_or(serde_json::Value::Null);
+                    // Actions change the map, so they wait for the user instead of running here.
+                    let response = match MapAction::from_tool(&call.function.name, &args) {
+                        Some(Ok(action)) => {
+                            let response = format!(
+                                "Proposed to the user, it is applied once they confirm: {}",
+                                action.describe()
+                            );
+                            cs.inner.lock().unwrap().new_actions.push(action);
+                            response
                         }
+                        Some(Err(e)) => e,
+                        None => workspace_clone.run_command(&call.function.name, &args, selection),
                     };
-
-                    bevy::log::info!("Command executed: {} -> {}", command_part, response);
-                    workspace_data_mut.add_message("user", &response);
-                    bevy::log::info!("Command executed: {} -> {}", command_part, response);
-
-                    bevy::log::info!("Automatically following up with LLM after providing data...");
-                    process_llm_request(
-                        workspace_clone,
-                        &workspace_data_mut,
-                        cs,
-                        recursion_depth + 1,
+                    bevy::log::info!(
+                        "Tool executed: {}({}) -> {}",
+                        call.function.name,
+                        call.function.arguments,
+                        response
                     );
+                    workspace_data_mut.add_tool_result(&call.id, &response);
                 }
+                publish_messages(cs, workspace_data, &workspace_data_mut);
+
+                bevy::log::info!("Automatically following up with LLM after providing data...");
+                process_llm_request(
+                    workspace_clone,
+                    &workspace_data_mut,
+                    cs,
+                    selection,
+                    cancel,
+                    recursion_depth + 1,
+                );
+            } else if let Some(command) = parse_rq_command(&ai_message) {
+                // Fallback for models without tool support.
+                workspace_data_mut.add_message("assistant", &ai_message);
+                let response = match command {
+                    Ok((name, args)) => workspace_clone.run_command(name, &args, selection),
+                    Err(e) => e,
+                };
+                bevy::log::info!("Command executed: {} -> {}", ai_message, response);
+                workspace_data_mut.add_message("tool", &response);
+                publish_messages(cs, workspace_data, &workspace_data_mut);
+
+                bevy::log::info!("Automatically following up with LLM after providing data...");
+                process_llm_request(
+                    workspace_clone,
+                    &workspace_data_mut,
+                    cs,
+                    selection,
+                    cancel,
+                    recursion_depth + 1,
+                );
             } else {
                 // This is a final answer, not a data request - stop processing
                 bevy::log::info!(
                     "LLM provided final answer (no command detected): '{}'",
                     ai_message
                 );
+                workspace_data_mut.add_message("assistant", &ai_message);
+                publish_messages(cs, workspace_data, &workspace_data_mut);
                 bevy::log::info!("Setting is_processing = false");
                 if let Ok(mut inner) = cs.inner.lock() {
                     inner.is_processing = false;
@@ -322,85 +307,86 @@ fn process_llm_request(
             }
         } else {
             bevy::log::error!("No choices returned from LLM");
-            let mut workspace_data_mut = workspace_data.clone();
-            workspace_data_mut.add_message(
-                "assistant",
-                "Sorry, I didn't receive a proper response from the AI. Please try again.",
-            );
-
             if let Ok(mut inner) = cs.inner.lock() {
-                inner.chat_history.push(ChatMessage {
-                    content:
-                        "Sorry, I didn't receive a proper response from the AI. Please try again."
-                            .to_string(),
-                    is_user: false,
-                });
+                inner.notice = Some(
+                    "Sorry, I didn't receive a proper response from the AI. Please try again."
+                        .to_string(),
+                );
+                inner.streaming.clear();
                 inner.is_processing = false;
             }
         }
-    } else {
-        bevy::log::error!("Failed to send chat request to LLM");
+    } else if let Err(e) = result {
+        bevy::log::error!("Failed to send chat request to LLM: {e}");
         if let Ok(mut inner) = cs.inner.lock() {
+            inner.notice = Some(format!("Sorry, the request to the AI failed: {e}"));
+            inner.streaming.clear();
             inner.is_processing = false;
         }
     }
 }
 
-// Helper functions for parsing LLM command parameters
-fn parse_coord(coord_str: &str) -> Result<Coord, String> {
-    let coord_str = coord_str.trim_start_matches('{').trim_end_matches('}');
-    let parts: Vec<&str> = coord_str.split(',').collect();
-
-    if parts.len() != 2 {
-        return Err("Coordinate must have exactly 2 parts".to_string());
-    }
-
-    let lat = parts[0]
-        .trim()
-        .parse::<f32>()
-        .map_err(|_| "Invalid latitude")?;
-    let long = parts[1]
-        .trim()
-        .parse::<f32>()
-        .map_err(|_| "Invalid longitude")?;
-
-    Ok(Coord { lat, long })
+/// Hands the messages added since `before` over to `collect_chat_messages`, replacing the
+/// streamed text in the same step so the reply doesn't flicker.
+fn publish_messages(cs: &ChatState, before: &WorkspaceData, after: &WorkspaceData) {
+    let mut inner = cs.inner.lock().unwrap();
+    let added = after
+        .messages
+        .get(before.messages.len()..)
+        .unwrap_or_default();
+    inner.new_messages.extend_from_slice(added);
+    inner.streaming.clear();
 }
 
-fn parse_radius(radius_str: &str) -> Result<f64, String> {
-    if !radius_str.starts_with('r') {
-        return Err("Radius must start with 'r'".to_string());
+/// Adds the messages from the background chat request to the workspace it was sent from, and
+/// saves it.
+pub fn collect_chat_messages(
+    chat_state: Res<ChatState>,
+    mut workspace: ResMut<Workspace>,
+    mut tools: ResMut<ToolResources>,
+    mut map_actions: ResMut<MapActions>,
+) {
+    let (workspace_id, messages, usage, actions) = {
+        let mut inner = chat_state.inner.lock().unwrap();
+        if inner.new_messages.is_empty()
+            && inner.new_usage == ChatUsage::default()
+            && inner.new_actions.is_empty()
+        {
+            return;
+        }
+        (
+            inner.workspace_id.clone(),
+            std::mem::take(&mut inner.new_messages),
+            std::mem::take(&mut inner.new_usage),
+            std::mem::take(&mut inner.new_actions),
+        )
+    };
+    let Some(workspace_id) = workspace_id else {
+        return;
+    };
+    map_actions.propose(&workspace_id, actions);
+    if let Some(ws) = workspace
+        .workspace
+        .as_mut()
+        .filter(|ws| ws.id == workspace_id)
+    {
+        ws.messages.extend(messages);
+        ws.usage.merge(&usage);
+        tools.selection_areas.replace(ws.clone());
+        let _ = workspace.save_workspace();
+        return;
     }
-
-    radius_str[1..]
-        .parse::<f64>()
-        .map_err(|_| "Invalid radius value".to_string())
-}
-
-fn parse_bbox(bbox_str: &str) -> Result<(f64, f64, f64, f64), String> {
-    let bbox_str = bbox_str.trim_start_matches('{').trim_end_matches('}');
-    let parts: Vec<&str> = bbox_str.split(',').collect();
-
-    if parts.len() != 4 {
-        return Err("Bbox must have exactly 4 parts".to_string());
+    // The user has since switched to another workspace.
+    let area = tools
+        .selection_areas
+        .areas
+        .iter()
+        .find(|area| area.id == workspace_id)
+        .cloned();
+    if let Some(mut area) = area {
+        area.messages.extend(messages);
+        area.usage.merge(&usage);
+        let _ = area.save();
+        tools.selection_areas.replace(area);
     }
-
-    let min_lat = parts[0]
-        .trim()
-        .parse::<f64>()
-        .map_err(|_| "Invalid min_lat")?;
-    let min_lon = parts[1]
-        .trim()
-        .parse::<f64>()
-        .map_err(|_| "Invalid min_lon")?;
-    let max_lat = parts[2]
-        .trim()
-        .parse::<f64>()
-        .map_err(|_| "Invalid max_lat")?;
-    let max_lon = parts[3]
-        .trim()
-        .parse::<f64>()
-        .map_err(|_| "Invalid max_lon")?;
-
-    Ok((min_lat, min_lon, max_lat, max_lon))
 }
@@ -6,6 +6,7 @@ use std::{
 use bevy::prelude::*;
 use bevy_egui::EguiPreUpdateSet;
 use bevy_map_viewer::{Coord, TileMapResources};
+use geo::GeodesicArea;
 use rstar::{AABB, RTree, RTreeObject};
 use serde::{Deserialize, Serialize};
 use std::fs::File;
@@ -13,16 +14,23 @@ use std::io::prelude::*;
 use uuid::Uuid;
 
 use crate::{
-    geojson::{MapFeature, get_data_from_string_osm},
-    llm::Message,
+    geojson::{MapFeature, SymbolIcon, get_data_from_string_osm},
+    llm::{ChatUsage, Message, ModelParams, ToolCall},
+    tools::Pin,
     workspace::{ui::chat_box_ui, worker::load_workspaces},
 };
 
 use super::{
-    Workspace, WorkspaceData, WorkspacePlugin, WorkspaceRequest,
+    AttributeTable, SavedMeasurement, SymbolRule, Workspace, WorkspaceData, WorkspacePlugin,
+    WorkspaceRequest,
+    actions::{MapActions, apply_map_requests, map_actions_ui},
+    attribute_table::attribute_table_ui,
+    filter::{FeatureSearch, feature_search_ui},
+    history::{History, handle_history_keys},
     renderer::render_workspace_requests,
+    statistics::{StatisticsWindow, statistics_ui},
     ui::{ChatState, PersistentInfoWindows, item_info, workspace_actions_ui},
-    worker::{cleanup_tasks, process_requests},
+    worker::{cleanup_tasks, collect_chat_messages, process_requests},
 };
 
 impl Plugin for WorkspacePlugin {
@@ -30,15 +38,26 @@ impl Plugin for WorkspacePlugin {
         app.insert_resource(Workspace::default())
             .insert_resource(ChatState::default())
             .add_systems(FixedUpdate, (process_requests, cleanup_tasks))
-            .add_systems(Update, render_workspace_requests)
+            .add_systems(Update, (render_workspace_requests, collect_chat_messages))
             .add_systems(Startup, load_workspaces)
             .insert_resource(PersistentInfoWindows::default())
+            .insert_resource(AttributeTable::default())
+            .insert_resource(StatisticsWindow::default())
+            .insert_resource(FeatureSearch::default())
+            .insert_resource(MapActions::default())
+            .add_systems(Update, apply_map_requests)
+            .insert_resource(History::default())
             .add_systems(
                 Update,
                 ((
                     workspace_actions_ui.after(EguiPreUpdateSet::InitContexts),
                     chat_box_ui.after(EguiPreUpdateSet::InitContexts),
                     item_info.after(EguiPreUpdateSet::InitContexts),
+                    attribute_table_ui.after(EguiPreUpdateSet::InitContexts),
+                    statistics_ui.after(EguiPreUpdateSet::InitContexts),
+                    feature_search_ui.after(EguiPreUpdateSet::InitContexts),
+                    map_actions_ui.after(EguiPreUpdateSet::InitContexts),
+                    handle_history_keys.after(EguiPreUpdateSet::InitContexts),
                 ),),
             );
     }
@@ -60,11 +79,7 @@ impl Workspace {
     }
 
     pub fn save_workspace(&self) -> Result<(), std::io::Error> {
-        let workspace_data = self.workspace.clone().unwrap_or_default();
-        let serialized_data = serde_json::to_string(&workspace_data)?;
-        let mut file = File::create(format!("WS_{}.json", workspace_data.get_id()))?;
-        file.write_all(serialized_data.as_bytes())?;
-        Ok(())
+        self.workspace.clone().unwrap_or_default().save()
     }
 
     pub fn load_workspace(&mut self) -> Result<(), std::io::Error> {
@@ -158,6 +173,110 @@ impl Workspace {
         }
         rendered_requests
     }
+    /// The id of the current workspace's user sketch layer, if anything has been drawn yet.
+    pub fn sketch_layer_id(&self) -> Option<String> {
+        let loaded_requests = self.loaded_requests.lock().unwrap();
+        self.workspace
+            .as_ref()?
+            .requests
+            .iter()
+            .find(|id| {
+                loaded_requests
+                    .get(*id)
+                    .is_some_and(|request| matches!(request.request, RequestType::UserSketch))
+            })
+            .cloned()
+    }
+
+    /// The features of the user sketch. They are parsed once and kept until the layer changes,
+    /// the editor asks for them every frame.
+    pub fn get_sketch_features(&self) -> Vec<MapFeature> {
+        let Some(id) = self.sketch_layer_id() else {
+            return Vec::new();
+        };
+        let mut cache = self.sketch_cache.lock().unwrap();
+        if let Some((_, features)) = cache.as_ref().filter(|(cached, _)| *cached == id) {
+            return features.clone();
+        }
+        let features: Vec<MapFeature> = self
+            .loaded_requests
+            .lock()
+            .unwrap()
+            .get(&id)
+            .and_then(|request| serde_json::from_slice(&request.raw_data).ok())
+            .unwrap_or_default();
+        *cache = Some((id, features.clone()));
+        features
+    }
+
+    /// Replaces the user sketch of the current workspace. The layer is made on the first
+    /// feature and removed again, file and all, once the last one is deleted. Nothing else is
+    /// written to disk.
+    pub fn set_sketch_features(&mut self, features: Vec<MapFeature>) {
+        let existing = self.sketch_layer_id();
+        let Some(ws) = self.workspace.as_mut() else {
+            return;
+        };
+        let raw_data = serde_json::to_vec(&features).unwrap_or_default();
+        match existing {
+            Some(id) if features.is_empty() => {
+                ws.remove_request(id.clone());
+                self.loaded_requests.lock().unwrap().remove(&id);
+                let _ = std::fs::remove_file(format!("RQ_{id}.json"));
+                let _ = self.save_workspace();
+            }
+            Some(id) => {
+                if let Some(request) = self.loaded_requests.lock().unwrap().get_mut(&id) {
+                    request.set_raw_data(raw_data);
+                }
+                *self.sketch_cache.lock().unwrap() = Some((id, features));
+            }
+            None if features.is_empty() => {}
+            None => {
+                let mut request = WorkspaceRequest::new(
+                    Uuid::new_v4().to_string(),
+                    1,
+                    RequestType::UserSketch,
+                    raw_data,
+                );
+                // Text notes are labels, so show them as soon as labels are drawn at all.
+                request.set_style(LayerStyle {
+                    label_min_zoom: 14,
+                    ..Default::default()
+                });
+                request.process_request();
+                ws.add_request(request.get_id());
+                *self.sketch_cache.lock().unwrap() = Some((request.get_id(), features));
+                self.loaded_requests
+                    .lock()
+                    .unwrap()
+                    .insert(request.get_id(), request);
+                let _ = self.save_workspace();
+            }
+        }
+    }
+
+    /// Writes the user sketch and the pins of the current workspace out as one GeoJSON feature
+    /// collection, with the pins as points.
+    pub fn export_geojson(&self) -> Result<(), std::io::Error> {
+        let Some(ws) = &self.workspace else {
+            return Ok(());
+        };
+        let features: Vec<serde_json::Value> = self
+            .get_sketch_features()
+            .iter()
+            .map(MapFeature::to_geojson)
+            .chain(ws.pins.iter().map(Pin::to_geojson))
+            .collect();
+        let collection = serde_json::json!({
+            "type": "FeatureCollection",
+            "features": features,
+        });
+        let mut file = File::create(format!("EXPORT_{}.geojson", ws.get_id()))?;
+        file.write_all(serde_json::to_string_pretty(&collection)?.as_bytes())?;
+        Ok(())
+    }
+
     pub fn get_rendered_requests(&self) -> Vec<WorkspaceRequest> {
         let loaded_requests = self.loaded_requests.lock().unwrap();
         let mut rendered_requests = Vec::new();
@@ -182,13 +301,65 @@ impl WorkspaceData {
         self.messages.push(Message {
             role: role.to_string(),
             content: content.to_string(),
-            refusal: serde_json::Value::Null,
-            reasoning: serde_json::Value::Null,
+            ..Default::default()
+        });
+    }
+
+    /// Keeps an assistant turn that asked for tools, so their results can follow it.
+    pub fn add_tool_calls(&mut self, content: &str, tool_calls: Vec<ToolCall>) {
+        self.messages.push(Message {
+            role: "assistant".to_string(),
+            content: content.to_string(),
+            tool_calls,
+            ..Default::default()
+        });
+    }
+
+    pub fn add_tool_result(&mut self, tool_call_id: &str, content: &str) {
+        self.messages.push(Message {
+            role: "tool".to_string(),
+            content: content.to_string(),
+            tool_call_id: Some(tool_call_id.to_string()),
+            ..Default::default()
         });
     }
 
     pub fn clear_history(&mut self) {
         self.messages.clear();
+        // The running totals are kept, only the context is empty again.
+        self.usage.last_prompt_tokens = 0;
+    }
+
+    pub fn get_usage(&self) -> &ChatUsage {
+        &self.usage
+    }
+
+    pub fn get_messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    /// Writes the conversation as Markdown, leaving out tool results and the map context sent
+    /// along with each question.
+    pub fn export_conversation(&self) -> Result<(), std::io::Error> {
+        let mut markdown = format!("# {}\n", self.get_name());
+        for message in self.messages.iter().filter(|m| m.is_chat()) {
+            let who = if message.role == "user" {
+                "You"
+            } else {
+                "Assistant"
+            };
+            markdown.push_str(&format!("\n**{who}:**\n\n{}\n", message.display_text()));
+        }
+        let mut file = File::create(format!("CHAT_{}.md", self.get_id()))?;
+        file.write_all(markdown.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        let serialized_data = serde_json::to_string(self)?;
+        let mut file = File::create(format!("WS_{}.json", self.get_id()))?;
+        file.write_all(serialized_data.as_bytes())?;
+        Ok(())
     }
 }
 
@@ -196,6 +367,80 @@ impl WorkspaceData {
     pub fn get_color_properties(&self) -> HashMap<(String, serde_json::Value), Srgba> {
         self.properties.clone()
     }
+
+    pub fn get_pins(&self) -> Vec<Pin> {
+        self.pins.clone()
+    }
+
+    pub fn get_pin(&self, id: &str) -> Option<Pin> {
+        self.pins.iter().find(|pin| pin.id == id).cloned()
+    }
+
+    pub fn add_pin(&mut self, pin: Pin) {
+        self.pins.push(pin);
+        self.last_modified = chrono::Utc::now().timestamp();
+    }
+
+    /// Replaces the pin with the same id.
+    pub fn update_pin(&mut self, pin: Pin) {
+        if let Some(existing) = self.pins.iter_mut().find(|p| p.id == pin.id) {
+            *existing = pin;
+            self.last_modified = chrono::Utc::now().timestamp();
+        }
+    }
+
+    pub fn remove_pin(&mut self, id: &str) {
+        self.pins.retain(|pin| pin.id != id);
+        self.last_modified = chrono::Utc::now().timestamp();
+    }
+
+    pub fn get_model_params(&self) -> ModelParams {
+        self.model.clone()
+    }
+
+    pub fn set_model_params(&mut self, model: ModelParams) {
+        self.model = model;
+        self.last_modified = chrono::Utc::now().timestamp();
+    }
+
+    pub fn get_measurements(&self) -> Vec<SavedMeasurement> {
+        self.measurements.clone()
+    }
+
+    pub fn add_measurement(&mut self, measurement: SavedMeasurement) {
+        self.measurements.push(measurement);
+        self.last_modified = chrono::Utc::now().timestamp();
+    }
+
+    pub fn remove_measurement(&mut self, index: usize) {
+        if index < self.measurements.len() {
+            self.measurements.remove(index);
+            self.last_modified = chrono::Utc::now().timestamp();
+        }
+    }
+
+    pub fn get_symbol_rules(&self) -> Vec<SymbolRule> {
+        self.symbols.clone()
+    }
+
+    /// Sets the icon used for features tagged `key=value`, `None` goes back to the built in icon.
+    pub fn set_symbol(&mut self, key: String, value: serde_json::Value, icon: Option<SymbolIcon>) {
+        self.symbols
+            .retain(|rule| rule.key != key || rule.value != value);
+        if let Some(icon) = icon {
+            self.symbols.push(SymbolRule { key, value, icon });
+        }
+        self.last_modified = chrono::Utc::now().timestamp();
+    }
+
+    /// The icon to draw for a point feature with these tags.
+    pub fn get_symbol(&self, properties: &serde_json::Value) -> SymbolIcon {
+        self.symbols
+            .iter()
+            .find(|rule| properties.get(&rule.key) == Some(&rule.value))
+            .map(|rule| rule.icon)
+            .unwrap_or_else(|| SymbolIcon::for_properties(properties))
+    }
     pub fn get_id(&self) -> String {
         self.id.clone()
     }
@@ -265,6 +510,32 @@ impl WorkspaceData {
                 }
                 (0.0, bevy_map_viewer::DistanceType::Km)
             }
+            SelectionType::MULTIPOLYGON => {
+                let ring = |points: &Vec<Coord>| {
+                    geo::LineString::from(
+                        points
+                            .iter()
+                            .map(|p| (p.long as f64, p.lat as f64))
+                            .collect::<Vec<_>>(),
+                    )
+                };
+                let square_meters: f64 = self
+                    .selection
+                    .polygons
+                    .iter()
+                    .map(|polygon| {
+                        geo::Polygon::new(
+                            ring(&polygon.exterior),
+                            polygon.holes.iter().map(ring).collect(),
+                        )
+                        .geodesic_area_unsigned()
+                    })
+                    .sum();
+                (
+                    (square_meters / 1_000_000.0) as f32,
+                    bevy_map_viewer::DistanceType::Km,
+                )
+            }
             _ => (0.0, bevy_map_viewer::DistanceType::Km),
         }
     }
@@ -297,11 +568,25 @@ impl WorkspaceRequest {
                     }
                 }
             }
+            crate::workspace::RequestType::UserSketch => {
+                if let Ok(features) = serde_json::from_slice::<Vec<MapFeature>>(&self.raw_data) {
+                    for feature in features {
+                        self.processed_data.insert(feature);
+                    }
+                }
+            }
             crate::workspace::RequestType::OpenMeteoRequest(_) => {}
             crate::workspace::RequestType::OpenRouterRequest() => {}
         }
     }
 
+    /// Swaps the data behind the layer and processes it again.
+    pub fn set_raw_data(&mut self, raw_data: Vec<u8>) {
+        self.raw_data = raw_data;
+        self.processed_data = RTree::new();
+        self.process_request();
+    }
+
     pub fn get_raw_data(&self) -> Vec<u8> {
         self.raw_data.clone()
     }
@@ -309,6 +594,33 @@ impl WorkspaceRequest {
     pub fn get_last_query_date(&self) -> i64 {
         self.last_query_date
     }
+
+    pub fn get_style(&self) -> LayerStyle {
+        self.style.clone()
+    }
+
+    pub fn set_style(&mut self, style: LayerStyle) {
+        self.style = style;
+    }
+}
+
+/// Per layer style rules, these sit alongside the colour rules stored in `WorkspaceData`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LayerStyle {
+    /// The tag to take the label text from, `None` turns labels off for the layer.
+    pub label_field: Option<String>,
+    /// Labels are hidden below this zoom level.
+    pub label_min_zoom: u32,
+}
+
+impl Default for LayerStyle {
+    fn default() -> Self {
+        Self {
+            label_field: Some("name".to_string()),
+            label_min_zoom: 16,
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -332,6 +644,8 @@ pub enum RequestType {
     OpenMeteoRequest(OpenMeteoRequest),
     OverpassTurboRequest(String),
     OpenRouterRequest(),
+    /// Features drawn by the user with the sketch tool, kept as JSON `MapFeature`s.
+    UserSketch,
 }
 
 impl std::fmt::Debug for RequestType {
@@ -340,6 +654,7 @@ impl std::fmt::Debug for RequestType {
             RequestType::OpenMeteoRequest(_) => write!(f, "OpenMeteoRequest"),
             RequestType::OverpassTurboRequest(_) => write!(f, "OverpassTurboRequest"),
             RequestType::OpenRouterRequest() => write!(f, "OpenRouterRequest"),
+            RequestType::UserSketch => write!(f, "UserSketch"),
         }
     }
 }
@@ -368,12 +683,21 @@ impl WorkspaceRequest {
             raw_data,
             processed_data: RTree::new(),
             last_query_date: chrono::Utc::now().timestamp(),
+            style: LayerStyle::default(),
         }
     }
 
     pub fn get_processed_data(&self) -> RTree<MapFeature> {
         self.processed_data.clone()
     }
+
+    /// The features whose bounding boxes overlap `envelope`, without copying the whole tree.
+    pub fn features_in(&self, envelope: &AABB<[f64; 2]>) -> Vec<MapFeature> {
+        self.processed_data
+            .locate_in_envelope_intersecting(envelope)
+            .cloned()
+            .collect()
+    }
 }
 
 impl WorkspaceData {
@@ -386,7 +710,12 @@ impl WorkspaceData {
             last_modified: chrono::Utc::now().timestamp(),
             requests: HashSet::new(),
             properties: HashMap::new(),
+            symbols: Vec::new(),
+            measurements: Vec::new(),
+            pins: Vec::new(),
             messages: Vec::new(),
+            model: ModelParams::default(),
+            usage: ChatUsage::default(),
         }
     }
     pub fn empty() -> Self {
@@ -398,7 +727,12 @@ impl WorkspaceData {
             last_modified: chrono::Utc::now().timestamp(),
             requests: HashSet::new(),
             properties: HashMap::new(),
+            symbols: Vec::new(),
+            measurements: Vec::new(),
+            pins: Vec::new(),
             messages: Vec::new(),
+            model: ModelParams::default(),
+            usage: ChatUsage::default(),
         }
     }
 }
@@ -424,6 +758,8 @@ pub enum SelectionType {
     RECTANGLE,
     POLYGON,
     CIRCLE,
+    /// One or more polygons which can have holes, made from a feature or a boundary.
+    MULTIPOLYGON,
 }
 
 impl SelectionType {
@@ -433,6 +769,8 @@ impl SelectionType {
             SelectionType::RECTANGLE => *self = SelectionType::POLYGON,
             SelectionType::POLYGON => *self = SelectionType::CIRCLE,
             SelectionType::CIRCLE => *self = SelectionType::RECTANGLE,
+            // Can't be drawn by hand.
+            SelectionType::MULTIPOLYGON => *self = SelectionType::RECTANGLE,
         }
     }
 }
@@ -461,6 +799,26 @@ impl SelectionAreas {
     pub fn add(&mut self, selection: WorkspaceData) {
         self.areas.insert(selection);
     }
+
+    /// Swaps the area with the same id for `selection`, e.g. after its shape was edited.
+    pub fn replace(&mut self, selection: WorkspaceData) {
+        let existing = self
+            .areas
+            .iter()
+            .find(|area| area.get_id() == selection.get_id())
+            .cloned();
+        if let Some(existing) = existing {
+            self.areas.remove(&existing);
+        }
+        self.areas.insert(selection);
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        let existing = self.areas.iter().find(|area| area.get_id() == id).cloned();
+        if let Some(existing) = existing {
+            self.areas.remove(&existing);
+        }
+    }
 }
 
 #[derive(Component, Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
@@ -469,6 +827,18 @@ pub struct Selection {
     pub start: Option<Coord>,
     pub end: Option<Coord>,
     pub points: Option<Vec<Coord>>,
+    /// The parts of a `MULTIPOLYGON` selection.
+    #[serde(default)]
+    pub polygons: Vec<SelectionPolygon>,
+    /// The Overpass area the shape came from, queries use it to follow the exact boundary.
+    #[serde(default)]
+    pub area_id: Option<u64>,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SelectionPolygon {
+    pub exterior: Vec<Coord>,
+    pub holes: Vec<Vec<Coord>>,
 }
 
 impl Selection {
@@ -504,6 +874,8 @@ impl Selection {
             start: Some(start),
             end: Some(end),
             points: None,
+            polygons: Vec::new(),
+            area_id: None,
         }
     }
 
@@ -513,6 +885,8 @@ impl Selection {
             start: None,
             end: None,
             points: Some(vec![start]),
+            polygons: Vec::new(),
+            area_id: None,
         }
     }
 
@@ -522,10 +896,121 @@ impl Selection {
             start: None,
             end: None,
             points: None,
+            polygons: Vec::new(),
+            area_id: None,
+        }
+    }
+
+    /// A single polygon without holes stays an editable `POLYGON`, anything else becomes a
+    /// `MULTIPOLYGON`.
+    pub fn from_polygons(mut polygons: Vec<SelectionPolygon>, area_id: Option<u64>) -> Self {
+        if polygons.len() == 1 && polygons[0].holes.is_empty() {
+            let mut points = polygons.remove(0).exterior;
+            if points.len() > 1 && points.first() == points.last() {
+                points.pop();
+            }
+            return Self {
+                selection_type: SelectionType::POLYGON,
+                start: None,
+                end: None,
+                points: Some(points),
+                polygons: Vec::new(),
+                area_id,
+            };
+        }
+        Self {
+            selection_type: SelectionType::MULTIPOLYGON,
+            start: None,
+            end: None,
+            points: None,
+            polygons,
+            area_id,
         }
     }
 }
 
+/// These implementations convert to and from `geo` shapes, with x as latitude and y as longitude
+/// like the map features.
+impl Selection {
+    pub fn to_multi_polygon(&self) -> geo::MultiPolygon {
+        let ring = |points: &[Coord]| {
+            geo::LineString::from(
+                points
+                    .iter()
+                    .map(|p| (p.lat as f64, p.long as f64))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let polygons = match self.selection_type {
+            SelectionType::RECTANGLE => match (self.start, self.end) {
+                (Some(start), Some(end)) => vec![geo::Polygon::new(
+                    ring(&[
+                        start,
+                        Coord::new(start.lat, end.long),
+                        end,
+                        Coord::new(end.lat, start.long),
+                    ]),
+                    vec![],
+                )],
+                _ => vec![],
+            },
+            SelectionType::CIRCLE => match (self.start, self.end) {
+                (Some(center), Some(edge)) => {
+                    // Work in latitude degrees so the circle stays round away from the equator.
+                    let cos = center.lat.to_radians().cos().max(0.01);
+                    let radius = (edge.lat - center.lat).hypot((edge.long - center.long) * cos);
+                    let points: Vec<Coord> = (0..64)
+                        .map(|i| {
+                            let angle = i as f32 / 64.0 * std::f32::consts::TAU;
+                            Coord::new(
+                                center.lat + radius * angle.sin(),
+                                center.long + radius * angle.cos() / cos,
+                            )
+                        })
+                        .collect();
+                    vec![geo::Polygon::new(ring(&points), vec![])]
+                }
+                _ => vec![],
+            },
+            SelectionType::POLYGON => match &self.points {
+                Some(points) if points.len() >= 3 => {
+                    vec![geo::Polygon::new(ring(points), vec![])]
+                }
+                _ => vec![],
+            },
+            SelectionType::MULTIPOLYGON => self
+                .polygons
+                .iter()
+                .map(|polygon| {
+                    geo::Polygon::new(
+                        ring(&polygon.exterior),
+                        polygon.holes.iter().map(|hole| ring(hole)).collect(),
+                    )
+                })
+                .collect(),
+            SelectionType::NONE => vec![],
+        };
+        geo::MultiPolygon::new(polygons)
+    }
+
+    pub fn from_multi_polygon(multi_polygon: &geo::MultiPolygon) -> Self {
+        let ring = |line: &geo::LineString| {
+            line.0
+                .iter()
+                .map(|c| Coord::new(c.x as f32, c.y as f32))
+                .collect::<Vec<_>>()
+        };
+        let polygons = multi_polygon
+            .iter()
+            .map(|polygon| SelectionPolygon {
+                exterior: ring(polygon.exterior()),
+                holes: polygon.interiors().iter().map(ring).collect(),
+            })
+            .collect();
+        Self::from_polygons(polygons, None)
+    }
+}
+
 /// These implementations are for the RTreeObject trait.
 impl RTreeObject for Selection {
     type Envelope = AABB<[f64; 2]>;
@@ -574,6 +1059,18 @@ impl RTreeObject for Selection {
                 }
                 AABB::from_corners([0.0, 0.0], [0.0, 0.0])
             }
+            SelectionType::MULTIPOLYGON => {
+                let points: Vec<[f64; 2]> = self
+                    .polygons
+                    .iter()
+                    .flat_map(|polygon| polygon.exterior.iter())
+                    .map(|point| [point.lat as f64, point.long as f64])
+                    .collect();
+                if points.is_empty() {
+                    return AABB::from_corners([0.0, 0.0], [0.0, 0.0]);
+                }
+                AABB::from_points(&points)
+            }
             _ => AABB::from_corners([0.0, 0.0], [0.0, 0.0]),
         }
     }